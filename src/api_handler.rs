@@ -3,9 +3,12 @@ extern crate rustc_serialize;
 // use url;
 
 use nickel::{Nickel, HttpRouter, QueryString};
+use nickel::hyper::header::Headers;
+use std::net::SocketAddr;
 use std::str;
-// use std::sync::{Arc, Mutex};
-// use std::io::Read;
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::io::Read;
 // use std::error::Error;
 use nickel::mimes::MediaType;
 // use nickel::status::*;
@@ -15,10 +18,272 @@ use Context;
 
 // module
 
-use api_result;
+use api_result::{self, ApiError};
 use utils;
 use build;
 use errno;
+use throttle;
+use token;
+use jwt;
+use login_handler;
+use live::Settings;
+use tenant::Tenant;
+use client_auth;
+use ldap;
+use cache::TtlCache;
+use directory::{self, Profile};
+use audit::{AuditLog, Requester, Event, Kind};
+use config::ClientConf;
+use store::{Store, Namespace, TokenRecord};
+
+
+// Logic shared by legacy endpoints below and `/api/v1` ones (see `api_v1_handler`),
+// only the response format differs.
+
+pub fn system_info() -> api_result::SystemInfo {
+    api_result::SystemInfo {
+        server_time: utils::current_time_millis(),
+        git_rev: build::GIT_REV.to_string(),
+        version: build::VERSION.to_string()
+    }
+}
+
+/**
+ * Service client calling the API, see `client_auth`.
+ */
+pub fn authenticate_client<'a>(settings:&'a Settings, headers:&Headers, remote_addr:&SocketAddr)
+        -> Result<&'a ClientConf, ApiError> {
+    match client_auth::authenticate(&settings.conf.clients, headers) {
+        Some(client) => Ok(client),
+        None => {
+            warn!("Unauthorized client api access from {}", remote_addr);
+            Err(ApiError::Unauthorized)
+        }
+    }
+}
+
+/**
+ * Owner of access token looked up by service client, token from `Authorization: Bearer`
+ * header or request body. Token in query string is refused, it ends up in access logs.
+ */
+pub fn client_lookup(store:&Store, audit:&AuditLog, requester:&Requester, client:&ClientConf, headers:&Headers,
+                     body_token:Option<String>, query_token:bool) -> Result<api_result::Cred, ApiError> {
+    let event = Event::new(Kind::TokenLookup, requester).client(&client.id);
+
+    if query_token {
+        warn!("Client `{}` sent access token in query string, refused", client.id);
+        audit.record(event.failed("token_in_query"));
+        return Err(ApiError::BadRequest);
+    }
+    let access_token = match client_auth::bearer_token(headers).or(body_token) {
+        Some(access_token) => access_token,
+        None => {
+            audit.record(event.failed("missing_token"));
+            return Err(ApiError::BadRequest);
+        }
+    };
+
+    match lookup(store, &access_token) {
+        Ok(cred) => {
+            info!("Client `{}` looked up user `{}` ({})", client.id, cred.uid, cred.dn);
+            audit.record(event.user(&cred.uid, &cred.dn));
+            Ok(cred)
+        },
+        Err(e) => {
+            audit.record(event.failed(e.id()));
+            Err(e)
+        }
+    }
+}
+
+/**
+ * Owner of access token.
+ */
+pub fn lookup(store:&Store, access_token:&str) -> Result<api_result::Cred, ApiError> {
+    debug!("checking access token: {}", token::redact(access_token));
+
+    if !token::is_well_formed(access_token) {
+        warn!("Malformed access token: {}", token::redact(access_token));
+        return Err(ApiError::InvalidToken);
+    }
+
+    match try!(store.get_record::<TokenRecord>(Namespace::Token, access_token)) {
+        Some(TokenRecord { uid, dn }) => {
+            debug!("Authentic for `{}`", uid);
+            Ok(api_result::Cred::new(uid, dn))
+        },
+        None => {
+            warn!("Invalid access token or already expired: {}", token::redact(access_token));
+            Err(ApiError::InvalidToken)
+        }
+    }
+}
+
+/**
+ * Profile of user in tenant's directory with all `directory.attributes`,
+ * cached for `directory.cache_secs`.
+ */
+pub fn user_profile(cache:&TtlCache<Profile>, settings:&Settings, tenant:&Tenant, uid:&str)
+        -> Result<Profile, ApiError> {
+    if !directory::valid_uid(uid) {
+        return Err(ApiError::NotFound);
+    }
+    let conf = &settings.conf.directory;
+    let dn = tenant.base_dn();
+    let key = directory::cache_key(uid, dn);
+
+    if let Some(profile) = cache.get(&key, conf.cache_secs * 1000) {
+        return Ok(profile);
+    }
+
+    let ldap_conf = &tenant.ldap;
+    let entry = try!(ldap::connect(&ldap_conf.uri, &ldap_conf.admin_user, &ldap_conf.admin_password, dn)
+        .and_then(|conn| ldap::user_entry(&conn, uid, dn, &conf.attributes))
+        .map_err(|e| {
+            error!("Cannot read profile of `{}`: {}", uid, e);
+            ApiError::Internal
+        }));
+
+    match entry {
+        Some(entry) => {
+            let profile = Profile::new(uid, dn, &entry, &conf.attributes);
+            cache.put(&key, profile.clone());
+            Ok(profile)
+        },
+        None => Err(ApiError::NotFound)
+    }
+}
+
+/**
+ * Users of tenant's directory matching search `query` (checked with `directory::check_query`),
+ * sorted by uid with `directory.search_attributes`. None when more than
+ * `directory.search_max_results` match. Cached for `directory.cache_secs`.
+ */
+pub fn directory_search(cache:&TtlCache<Vec<Profile>>, settings:&Settings, tenant:&Tenant, query:&str)
+        -> Result<Option<Vec<Profile>>, ApiError> {
+    let conf = &settings.conf.directory;
+    let dn = tenant.base_dn();
+    let key = format!("{}/{}", dn.to_lowercase(), query.to_lowercase());
+
+    if let Some(results) = cache.get(&key, conf.cache_secs * 1000) {
+        return Ok(Some(results));
+    }
+
+    let filter = conf.search_filter.replace(directory::QUERY_PLACEHOLDER, &ldap::escape_filter(query));
+    let size_limit = cmp::min(conf.search_max_results, i32::max_value() as u64);
+    // uid needed for result even when not released as attribute.
+    let mut attributes = conf.search_attributes.clone();
+    if !attributes.iter().any(|a| a.eq_ignore_ascii_case("uid")) {
+        attributes.push("uid".to_string());
+    }
+
+    let ldap_conf = &tenant.ldap;
+    let entries = try!(ldap::connect(&ldap_conf.uri, &ldap_conf.admin_user, &ldap_conf.admin_password, dn)
+        .and_then(|conn| ldap::search_users(&conn, dn, &filter, &attributes, size_limit))
+        .map_err(|e| {
+            error!("Cannot search directory for `{}`: {}", query, e);
+            ApiError::Internal
+        }));
+
+    match entries {
+        Some(entries) => {
+            let results = directory::search_results(&entries, dn, &conf.search_attributes);
+            cache.put(&key, results.clone());
+            Ok(Some(results))
+        },
+        None => Ok(None)
+    }
+}
+
+/**
+ * Signed tokens of these sessions (`sid` claim) should be rejected until `expires`.
+ */
+pub fn revoked(store:&Store) -> Result<Vec<api_result::Revoked>, ApiError> {
+    Ok(try!(jwt::revoked(store))
+        .into_iter()
+        .map(|(sid, expires)| api_result::Revoked { sid: sid, expires: expires })
+        .collect())
+}
+
+/**
+ * New signed access token for opaque one, groups read again from LDAP of the token's tenant.
+ */
+pub fn refresh(store:&Mutex<Store>, settings:&Settings, signer:&Option<Arc<jwt::Signer>>, audit:&AuditLog,
+               requester:&Requester, refresh_token:&str) -> Result<api_result::SignedToken, ApiError> {
+    if !token::is_well_formed(refresh_token) {
+        warn!("Malformed refresh token: {}", token::redact(refresh_token));
+        return Err(ApiError::InvalidToken);
+    }
+
+    let record = {
+        let store = store.lock().unwrap();
+        try!(store.get_record::<TokenRecord>(Namespace::Token, refresh_token))
+    };
+
+    let TokenRecord { uid, dn } = match record {
+        Some(record) => record,
+        None => {
+            warn!("Invalid refresh token or already revoked: {}", token::redact(refresh_token));
+            return Err(ApiError::InvalidToken);
+        }
+    };
+
+    // tenant removed from config, token no longer valid.
+    let tenant = match settings.tenant_by_dn(&dn) {
+        Some(tenant) => tenant,
+        None => {
+            warn!("Refresh token of `{}` for unknown DN: {}", uid, dn);
+            return Err(ApiError::InvalidToken);
+        }
+    };
+
+    match login_handler::sign_access_token(&tenant.ldap, signer, &uid, &dn, refresh_token) {
+        Ok(Some(access_token)) => {
+            audit.record(Event::new(Kind::TokenIssued, requester).user(&uid, &dn).detail("refresh"));
+            Ok(api_result::SignedToken::new(refresh_token.to_string(), access_token, settings.conf.token.jwt_ttl_secs))
+        },
+        Ok(None) => Err(ApiError::NotFound),
+        Err(e) => {
+            error!("Cannot issue signed token for `{}`: {}", uid, e);
+            Err(ApiError::Internal)
+        }
+    }
+}
+
+/**
+ * `X-Admin-Key` header matches `admin.api_key` in config.
+ */
+pub fn admin_authorized(headers:&Headers, admin_api_key:&str) -> bool {
+    let given = headers.get_raw("X-Admin-Key")
+        .and_then(|v| v.first())
+        .and_then(|v| str::from_utf8(v).ok())
+        .unwrap_or("");
+    !admin_api_key.is_empty() && token::eq(given, admin_api_key)
+}
+
+/**
+ * Clear login lockout of user and/or ip.
+ */
+pub fn clear_lockout(store:&Store, audit:&AuditLog, requester:&Requester, user_name:&str, ip:&str)
+        -> Result<bool, ApiError> {
+    if user_name.is_empty() && ip.is_empty() {
+        return Err(ApiError::BadRequest);
+    }
+
+    if !user_name.is_empty() {
+        info!("Clearing login lockout for user `{}`", user_name);
+        try!(throttle::clear_user(store, user_name));
+    }
+    if !ip.is_empty() {
+        info!("Clearing login lockout for ip {}", ip);
+        try!(throttle::clear_ip(store, ip));
+    }
+
+    let action = if ip.is_empty() { "lockout_clear".to_string() } else { format!("lockout_clear ip={}", ip) };
+    audit.record(Event::new(Kind::Admin, requester).user(user_name, "").detail(&action));
+    Ok(true)
+}
+
 
 pub fn setup(ctx:&Context, server: &mut Nickel){
 
@@ -26,35 +291,118 @@ pub fn setup(ctx:&Context, server: &mut Nickel){
 
     server.get("/api/system/info", middleware! { |_req, mut _resp|
 
-        api_result_success_json!(api_result::SystemInfo {
-            server_time: utils::current_time_millis(),
-            git_rev: build::GIT_REV.to_string(),
-            version: build::VERSION.to_string()
-        }, _resp)
+        api_result_success_json!(system_info(), _resp)
 
     });
 
-    // for access token lookup
-    server.get("/api/lookup", middleware! { |_req, mut _resp|
-        let query = _req.query();
-        let access_token = query.get("access_token").unwrap_or("");
+    // for access token lookup by registered service client, token in
+    // `Authorization: Bearer` header (GET) or `access_token` form field (POST).
+    {
+        let store = store.clone();
+        let live = ctx.live.clone();
+        let audit = ctx.audit.clone();
 
-        let store = store.lock().unwrap();
+        server.get("/api/lookup", middleware! { |_req, mut _resp|
+            let settings = live.current();
+            let client = api_result_try!(authenticate_client(&settings, &_req.origin.headers, &_req.origin.remote_addr), _resp);
+            let requester = Requester::new(&_req.origin.headers, &_req.origin.remote_addr);
 
-        debug!("checking access token: {}", access_token);
+            let headers = _req.origin.headers.clone();
+            let query_token = _req.query().get("access_token").is_some();
 
-        match (store.get(&access_token), store.get(&format!("dn_{}", &access_token))){
-            (Some(uid), Some(dn)) => {
-                let info = format!("Authentic for `{}`", uid);
-                debug!("{}", info);
+            let store = store.lock().unwrap();
 
-                api_result_success_json!(api_result::Cred::new(uid, dn), _resp)
-            },
-            _ => {
-                warn!("Invalid access token or already expired: {}", access_token);
-                api_result_error_json!(errno::INVALID_TOKEN, errno::INVALID_TOKEN_STR, _resp)
-            }
+            let cred = api_result_try!(client_lookup(&store, &audit, &requester, client, &headers, None, query_token), _resp);
+            api_result_success_json!(cred, _resp)
+        });
+    }
+
+    {
+        let store = store.clone();
+        let live = ctx.live.clone();
+        let audit = ctx.audit.clone();
+
+        server.post("/api/lookup", middleware! { |_req, mut _resp|
+            let settings = live.current();
+            let client = api_result_try!(authenticate_client(&settings, &_req.origin.headers, &_req.origin.remote_addr), _resp);
+            let requester = Requester::new(&_req.origin.headers, &_req.origin.remote_addr);
+
+            let mut body = String::new();
+            let _ = _req.origin.read_to_string(&mut body);
+            let body_token = utils::parse_form(&body).remove("access_token");
+
+            let store = store.lock().unwrap();
+
+            let cred = api_result_try!(client_lookup(&store, &audit, &requester, client, &_req.origin.headers, body_token, false), _resp);
+            api_result_success_json!(cred, _resp)
+        });
+    }
+
+    // signed access tokens, `token.format = "jwt"`
+    if let Some(ref signer) = ctx.signer {
+        {
+            let signer = signer.clone();
+
+            // public keys for offline verification.
+            server.get("/.well-known/jwks.json", middleware! { |_req, mut _resp|
+                _resp.set(MediaType::Json);
+                signer.jwks()
+            });
         }
 
-    });
+        let store = ctx.store.clone();
+
+        server.get("/api/token/revoked", middleware! { |_req, mut _resp|
+            let store = store.lock().unwrap();
+
+            let list = api_result_try!(revoked(&store), _resp);
+            api_result_success_json!(list, _resp)
+        });
+
+        let store = ctx.store.clone();
+        let live = ctx.live.clone();
+        let signer = ctx.signer.clone();
+        let audit = ctx.audit.clone();
+
+        // new signed access token for opaque token (POST form `token=...`).
+        server.post("/api/token/refresh", middleware! { |_req, mut _resp|
+            let settings = live.current();
+            let requester = Requester::new(&_req.origin.headers, &_req.origin.remote_addr);
+            let mut body = String::new();
+            let _ = _req.origin.read_to_string(&mut body);
+            let form = utils::parse_form(&body);
+            let refresh_token = form.get("token").map(|t| t.as_str()).unwrap_or("");
+
+            let signed = api_result_try!(refresh(&store, &settings, &signer, &audit, &requester, refresh_token), _resp);
+            api_result_success_json!(signed, _resp)
+        });
+    }
+
+    // for clearing login lockout, eg: /api/admin/lockout/clear?user_name=robin&ip=127.0.0.1
+    // requires `X-Admin-Key` header matching `admin.api_key` in config.
+    {
+        let store = ctx.store.clone();
+        let audit = ctx.audit.clone();
+        let live = ctx.live.clone();
+
+        server.post("/api/admin/lockout/clear", middleware! { |_req, mut _resp|
+            let requester = Requester::new(&_req.origin.headers, &_req.origin.remote_addr);
+
+            if !admin_authorized(&_req.origin.headers, &live.current().conf.admin.api_key) {
+                warn!("Unauthorized admin api access from {}", _req.origin.remote_addr);
+                audit.record(Event::new(Kind::Admin, &requester).detail("lockout_clear").failed("unauthorized"));
+                let result = api_result_error_json!(errno::UNAUTHORIZED, errno::UNAUTHORIZED_STR, _resp);
+                return _resp.send(result);
+            }
+
+            let query = _req.query();
+            let user_name = query.get("user_name").unwrap_or("");
+            let ip = query.get("ip").unwrap_or("");
+
+            let store = store.lock().unwrap();
+
+            let cleared = api_result_try!(clear_lockout(&store, &audit, &requester, user_name, ip), _resp);
+            api_result_success_json!(cleared, _resp)
+        });
+    }
 }
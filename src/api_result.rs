@@ -1,4 +1,9 @@
 
+use nickel::hyper::status::StatusCode;
+use serialize::json::Json;
+
+use errno;
+use store::StoreError;
 
 #[derive(Decodable, Encodable)]
 struct ErrorResp {
@@ -15,8 +20,8 @@ pub struct ApiResult<T> {
 
 #[derive(Decodable, Encodable)]
 pub struct Cred {
-    uid: String,
-    dn: String
+    pub uid: String,
+    pub dn: String
 }
 
 impl Default for ErrorResp {
@@ -34,6 +39,85 @@ impl Cred {
     }
 }
 
+// issued when `token.format = "jwt"`, `token` is the refresh handle.
+#[derive(Decodable, Encodable)]
+pub struct SignedToken {
+    pub token: String,
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: u64
+}
+
+impl SignedToken {
+    pub fn new(token:String, access_token:String, expires_in:u64) -> Self {
+        SignedToken {
+            token: token,
+            access_token: access_token,
+            token_type: "Bearer".to_string(),
+            expires_in: expires_in
+        }
+    }
+}
+
+#[derive(Decodable, Encodable)]
+pub struct Revoked {
+    pub sid: String,
+    pub expires: u64
+}
+
+// `/api/v1/login` request body, `dn` selects tenant instead of request host.
+#[derive(Decodable, Encodable)]
+pub struct LoginRequest {
+    pub user_name: String,
+    pub password: String,
+    pub dn: Option<String>
+}
+
+// `/api/v1/login/webauthn` request body, binary fields base64url encoded.
+#[derive(Decodable, Encodable)]
+pub struct WebauthnLoginRequest {
+    pub challenge_id: String,
+    pub credential_id: String,
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String
+}
+
+// Opaque token doesn't expire until logout or revocation, `access_token` and
+// its `expires_in` only set when `token.format = "jwt"`.
+#[derive(Decodable, Encodable)]
+pub struct LoginResult {
+    pub token: String,
+    pub token_type: String,
+    pub access_token: Option<String>,
+    pub expires_in: Option<u64>,
+    pub user: Cred
+}
+
+impl LoginResult {
+    pub fn new(token:String, signed:Option<String>, expires_in:u64, user:Cred) -> Self {
+        LoginResult {
+            token: token,
+            token_type: "Bearer".to_string(),
+            expires_in: signed.as_ref().map(|_| expires_in),
+            access_token: signed,
+            user: user
+        }
+    }
+}
+
+// `/api/v1/lookup` request body, token may be in `Authorization: Bearer` header instead.
+#[derive(Decodable, Encodable)]
+pub struct LookupRequest {
+    pub access_token: Option<String>
+}
+
+// `/api/v1/token/refresh` request body.
+#[derive(Decodable, Encodable)]
+pub struct RefreshRequest {
+    pub token: String
+}
+
 #[derive(Decodable, Encodable)]
 pub struct SystemInfo {
     pub server_time: u64,
@@ -41,6 +125,121 @@ pub struct SystemInfo {
     pub version: String
 }
 
+/**
+ * Errors of API endpoints, `/api/v1` ones respond with HTTP status of the `errno` code
+ * and machine-readable `id` clients should match on instead of the message.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ApiError {
+    BadRequest,
+    Unauthorized,
+    NotFound,
+    InvalidToken,
+    InvalidCredentials,
+    SecondFactorRequired, // `details` has `challenge_id` and WebAuthn `options`
+    Locked,               // `details` has `retry_after_secs`
+    TooManyRequests,      // `details` has `retry_after_secs`
+    UnknownTenant,
+    Internal
+}
+
+impl ApiError {
+    pub fn code(&self) -> i32 {
+        match *self {
+            ApiError::BadRequest => errno::BAD_REQQUEST,
+            ApiError::Unauthorized => errno::UNAUTHORIZED,
+            ApiError::NotFound => errno::NOT_FOUND,
+            ApiError::InvalidToken => errno::INVALID_TOKEN,
+            ApiError::InvalidCredentials => errno::UNAUTHORIZED,
+            ApiError::SecondFactorRequired => errno::UNAUTHORIZED,
+            ApiError::Locked => errno::LOCKED,
+            ApiError::TooManyRequests => errno::TOO_MANY_REQUESTS,
+            ApiError::UnknownTenant => errno::NOT_FOUND,
+            ApiError::Internal => errno::INTERNAL_SERVER_ERROR
+        }
+    }
+
+    pub fn id(&self) -> &'static str {
+        match *self {
+            ApiError::BadRequest => "bad_request",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::NotFound => "not_found",
+            ApiError::InvalidToken => "invalid_token",
+            ApiError::InvalidCredentials => "invalid_credentials",
+            ApiError::SecondFactorRequired => "second_factor_required",
+            ApiError::Locked => "locked",
+            ApiError::TooManyRequests => "too_many_requests",
+            ApiError::UnknownTenant => "unknown_tenant",
+            ApiError::Internal => "internal_error"
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match *self {
+            ApiError::BadRequest => errno::BAD_REQUEST_STR,
+            ApiError::Unauthorized => errno::UNAUTHORIZED_STR,
+            ApiError::NotFound => errno::NOT_FOUND_STR,
+            ApiError::InvalidToken => errno::INVALID_TOKEN_STR,
+            ApiError::InvalidCredentials => errno::INVALID_CREDENTIALS_STR,
+            ApiError::SecondFactorRequired => errno::SECOND_FACTOR_REQUIRED_STR,
+            ApiError::Locked => errno::LOCKED_STR,
+            ApiError::TooManyRequests => errno::TOO_MANY_REQUESTS_STR,
+            ApiError::UnknownTenant => errno::UNKNOWN_TENANT_STR,
+            ApiError::Internal => errno::INTERNAL_SERVER_ERROR_STR
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        StatusCode::from_u16(self.code() as u16)
+    }
+}
+
+// storage failure logged here, API clients only see internal error.
+impl From<StoreError> for ApiError {
+    fn from(e:StoreError) -> ApiError {
+        error!("Store error: {}", e);
+        ApiError::Internal
+    }
+}
+
+#[derive(Encodable)]
+pub struct ErrorBody {
+    pub id: String,
+    pub code: i32,
+    pub message: String,
+    pub details: Option<Json> // error specific, see `ApiError`
+}
+
+/**
+ * Response of every `/api/v1` endpoint, either `data` or `error` is set (the other one `null`),
+ * `request_id` is the same as in `X-Request-Id` response header.
+ */
+#[derive(Encodable)]
+pub struct Envelope<T> {
+    pub data: Option<T>,
+    pub error: Option<ErrorBody>,
+    pub request_id: String
+}
+
+impl<T> Envelope<T> {
+    pub fn success(data:T, request_id:&str) -> Self {
+        Envelope { data: Some(data), error: None, request_id: request_id.to_string() }
+    }
+
+    pub fn failure(error:ApiError, details:Option<Json>, request_id:&str) -> Self {
+        Envelope {
+            data: None,
+            error: Some(ErrorBody {
+                id: error.id().to_string(),
+                code: error.code(),
+                message: error.message().to_string(),
+                details: details
+            }),
+            request_id: request_id.to_string()
+        }
+    }
+}
+
 pub fn success<T>(result: T) -> ApiResult<T> {
     ApiResult { error: Default::default(), result : result }
 }
@@ -49,6 +248,59 @@ pub fn error(code:i32, desc:&str) -> ApiResult<String> {
     ApiResult { error: ErrorResp { code: code, desc: desc.to_string() }, result : "".to_string() }
 }
 
+// unwrap result of shared API logic for legacy endpoints, error sent in `ApiResult` with HTTP 200.
+macro_rules! api_result_try {
+    ($result: expr, $resp: ident) => {
+        match $result {
+            Ok(v) => v,
+            Err(e) => {
+                let e:api_result::ApiError = e;
+                let result = api_result_error_json!(e.code(), e.message(), $resp);
+                return $resp.send(result);
+            }
+        }
+    }
+}
+
+// `/api/v1` success response.
+macro_rules! api_v1_success {
+    ($data: expr, $request_id: expr, $resp: ident) => {
+        {
+            let envelope = api_result::Envelope::success($data, &$request_id);
+
+            $resp.set(MediaType::Json);
+            return $resp.send(json::encode(&envelope).unwrap());
+        }
+    }
+}
+
+// `/api/v1` error response with HTTP status of the error.
+macro_rules! api_v1_error {
+    ($error: expr, $request_id: expr, $resp: ident) => {
+        api_v1_error!($error, None, $request_id, $resp)
+    };
+    ($error: expr, $details: expr, $request_id: expr, $resp: ident) => {
+        {
+            let error:api_result::ApiError = $error;
+            let envelope = api_result::Envelope::<()>::failure(error, $details, &$request_id);
+
+            $resp.set(error.status());
+            $resp.set(MediaType::Json);
+            return $resp.send(json::encode(&envelope).unwrap());
+        }
+    }
+}
+
+// unwrap result of shared API logic, or send `/api/v1` error response.
+macro_rules! api_v1_try {
+    ($result: expr, $request_id: expr, $resp: ident) => {
+        match $result {
+            Ok(v) => v,
+            Err(e) => api_v1_error!(e, $request_id, $resp)
+        }
+    }
+}
+
 macro_rules! api_result_success_json {
     ($result: expr, $resp: ident) => {
         {
@@ -0,0 +1,729 @@
+
+// Versioned JSON API, every response is `api_result::Envelope` with HTTP status
+// of the error (see `api_result::ApiError`) and `X-Request-Id` header.
+//
+// Endpoints are described next to their handlers, `/api/v1/openapi.json`
+// is generated from the registered ones.
+
+use nickel::{Nickel, HttpRouter, QueryString, Middleware};
+use nickel::hyper::method::Method;
+use nickel::mimes::MediaType;
+use serialize::base64::FromBase64;
+use serialize::json::{self, Json, ToJson};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::sync::Arc;
+use std::cmp;
+
+// module
+use Context;
+use api_result::{self, ApiError};
+use api_handler;
+use login_handler;
+use auth;
+use client_auth;
+use token;
+use cache::TtlCache;
+use directory;
+use audit::{self, Requester, Event, Kind};
+use webauthn;
+use openapi::{self, Endpoint, Param, ParamIn, Schema};
+use build;
+use utils;
+
+const CRED:Schema = Schema::Object(&[("uid", Schema::Str), ("dn", Schema::Str)]);
+
+const SIGNED_TOKEN:Schema = Schema::Object(&[
+    ("token", Schema::Str),
+    ("access_token", Schema::Str),
+    ("token_type", Schema::Str),
+    ("expires_in", Schema::Int)
+]);
+
+const LOGIN_RESULT:Schema = Schema::Object(&[
+    ("token", Schema::Str),
+    ("token_type", Schema::Str),
+    ("access_token", Schema::Nullable(&Schema::Str)),
+    ("expires_in", Schema::Nullable(&Schema::Int)),
+    ("user", CRED)
+]);
+
+static LOGIN:Endpoint = Endpoint {
+    method: Method::Post,
+    path: "/api/v1/login",
+    summary: "Login with user name and password, tenant selected by `dn` or request host",
+    params: &[],
+    body: Some(Schema::Object(&[
+        ("user_name", Schema::Str),
+        ("password", Schema::Str),
+        ("dn", Schema::Nullable(&Schema::Str))
+    ])),
+    response: LOGIN_RESULT,
+    errors: &[ApiError::BadRequest, ApiError::InvalidCredentials, ApiError::SecondFactorRequired,
+              ApiError::Locked, ApiError::TooManyRequests, ApiError::UnknownTenant, ApiError::Internal],
+    security: &[]
+};
+
+static LOGIN_WEBAUTHN:Endpoint = Endpoint {
+    method: Method::Post,
+    path: "/api/v1/login/webauthn",
+    summary: "Complete login with security key assertion for `challenge_id` of `second_factor_required` error",
+    params: &[],
+    body: Some(Schema::Object(&[
+        ("challenge_id", Schema::Str),
+        ("credential_id", Schema::Str),
+        ("client_data_json", Schema::Str),
+        ("authenticator_data", Schema::Str),
+        ("signature", Schema::Str)
+    ])),
+    response: LOGIN_RESULT,
+    errors: &[ApiError::BadRequest, ApiError::InvalidCredentials, ApiError::Locked,
+              ApiError::UnknownTenant, ApiError::Internal],
+    security: &[]
+};
+
+static SYSTEM_INFO:Endpoint = Endpoint {
+    method: Method::Get,
+    path: "/api/v1/system/info",
+    summary: "Server time and version",
+    params: &[],
+    body: None,
+    response: Schema::Object(&[("server_time", Schema::Int), ("git_rev", Schema::Str), ("version", Schema::Str)]),
+    errors: &[],
+    security: &[]
+};
+
+const PROFILE:Schema = Schema::Object(&[
+    ("uid", Schema::Str),
+    ("dn", Schema::Str),
+    ("attributes", Schema::Map(&Schema::Array(&Schema::Str)))
+]);
+
+static ME:Endpoint = Endpoint {
+    method: Method::Get,
+    path: "/api/v1/me",
+    summary: "Profile of the user owning access token given as `Authorization: Bearer`, with \
+              `directory.self_attributes`, or attributes released to the client also authenticated by `X-Api-Key`",
+    params: &[],
+    body: None,
+    response: PROFILE,
+    errors: &[ApiError::Unauthorized, ApiError::InvalidToken, ApiError::NotFound, ApiError::Internal],
+    security: &[openapi::BEARER]
+};
+
+static USER:Endpoint = Endpoint {
+    method: Method::Get,
+    path: "/api/v1/users/{uid}",
+    summary: "Profile of user with attributes released to the client, tenant selected by `dn` or request host",
+    params: &[
+        Param { name: "uid", location: ParamIn::Path, required: true, desc: "User id" },
+        Param { name: "dn", location: ParamIn::Query, required: false, desc: "Base DN of the user's tenant" }
+    ],
+    body: None,
+    response: PROFILE,
+    errors: &[ApiError::Unauthorized, ApiError::NotFound, ApiError::UnknownTenant, ApiError::Internal],
+    security: &[openapi::CLIENT_BASIC, openapi::CLIENT_API_KEY]
+};
+
+static DIRECTORY_SEARCH:Endpoint = Endpoint {
+    method: Method::Get,
+    path: "/api/v1/directory/search",
+    summary: "Users matching `q` (eg: for user picker) with `directory.search_attributes`, for service clients \
+              or signed-in users with their access token as `Authorization: Bearer`",
+    params: &[
+        Param { name: "q", location: ParamIn::Query, required: true, desc: "Search query, at least `directory.search_min_length` characters" },
+        Param { name: "offset", location: ParamIn::Query, required: false, desc: "Results to skip" },
+        Param { name: "limit", location: ParamIn::Query, required: false, desc: "Page size, at most `directory.search_page_size`" },
+        Param { name: "dn", location: ParamIn::Query, required: false, desc: "Base DN of tenant for service clients" }
+    ],
+    body: None,
+    response: Schema::Object(&[
+        ("items", Schema::Array(&PROFILE)),
+        ("total", Schema::Int),
+        ("offset", Schema::Int),
+        ("limit", Schema::Int)
+    ]),
+    errors: &[ApiError::BadRequest, ApiError::Unauthorized, ApiError::InvalidToken, ApiError::UnknownTenant,
+              ApiError::Internal],
+    security: &[openapi::CLIENT_BASIC, openapi::CLIENT_API_KEY, openapi::BEARER]
+};
+
+static LOOKUP:Endpoint = Endpoint {
+    method: Method::Post,
+    path: "/api/v1/lookup",
+    summary: "Owner of access token, for service clients. Token in body or `Authorization: Bearer` with API key",
+    params: &[],
+    body: Some(Schema::Object(&[("access_token", Schema::Nullable(&Schema::Str))])),
+    response: CRED,
+    errors: &[ApiError::BadRequest, ApiError::Unauthorized, ApiError::InvalidToken, ApiError::Internal],
+    security: &[openapi::CLIENT_BASIC, openapi::CLIENT_API_KEY]
+};
+
+static TOKEN_REVOKED:Endpoint = Endpoint {
+    method: Method::Get,
+    path: "/api/v1/token/revoked",
+    summary: "Sessions whose signed tokens should be rejected until `expires`",
+    params: &[],
+    body: None,
+    response: Schema::Array(&Schema::Object(&[("sid", Schema::Str), ("expires", Schema::Int)])),
+    errors: &[ApiError::Internal],
+    security: &[]
+};
+
+static TOKEN_REFRESH:Endpoint = Endpoint {
+    method: Method::Post,
+    path: "/api/v1/token/refresh",
+    summary: "New signed access token for opaque token",
+    params: &[],
+    body: Some(Schema::Object(&[("token", Schema::Str)])),
+    response: SIGNED_TOKEN,
+    errors: &[ApiError::BadRequest, ApiError::InvalidToken, ApiError::Internal],
+    security: &[]
+};
+
+static LOCKOUT_CLEAR:Endpoint = Endpoint {
+    method: Method::Post,
+    path: "/api/v1/admin/lockout/clear",
+    summary: "Clear login lockout of user and/or ip",
+    params: &[
+        Param { name: "user_name", location: ParamIn::Query, required: false, desc: "User name" },
+        Param { name: "ip", location: ParamIn::Query, required: false, desc: "Client ip address" }
+    ],
+    body: None,
+    response: Schema::Bool,
+    errors: &[ApiError::BadRequest, ApiError::Unauthorized, ApiError::Internal],
+    security: &[openapi::ADMIN_KEY]
+};
+
+// events per page of audit query.
+const AUDIT_PAGE_SIZE:usize = 100;
+const AUDIT_MAX_PAGE_SIZE:usize = 1000;
+
+static AUDIT:Endpoint = Endpoint {
+    method: Method::Get,
+    path: "/api/v1/admin/audit",
+    summary: "Audit events kept for `audit.retention_secs`, newest first",
+    params: &[
+        Param { name: "since", location: ParamIn::Query, required: false, desc: "Oldest event time, millis" },
+        Param { name: "until", location: ParamIn::Query, required: false, desc: "Newest event time, millis" },
+        Param { name: "uid", location: ParamIn::Query, required: false, desc: "Events of user" },
+        Param { name: "event", location: ParamIn::Query, required: false, desc: "login, token_issued, token_lookup, token_revoked or admin" },
+        Param { name: "outcome", location: ParamIn::Query, required: false, desc: "success or failure" },
+        Param { name: "limit", location: ParamIn::Query, required: false, desc: "Events returned, default 100, at most 1000" }
+    ],
+    body: None,
+    response: Schema::Array(&Schema::Object(&[
+        ("time", Schema::Int),
+        ("event", Schema::Str),
+        ("outcome", Schema::Str),
+        ("reason", Schema::Str),
+        ("uid", Schema::Str),
+        ("dn", Schema::Str),
+        ("client", Schema::Str),
+        ("detail", Schema::Str),
+        ("ip", Schema::Str),
+        ("user_agent", Schema::Str),
+        ("request_id", Schema::Str)
+    ])),
+    errors: &[ApiError::BadRequest, ApiError::Unauthorized, ApiError::Internal],
+    security: &[openapi::ADMIN_KEY]
+};
+
+/**
+ * API error of failed authentication, unknown user reported as invalid credentials.
+ */
+fn auth_error(failure:auth::Failure) -> (ApiError, Option<Json>) {
+    let retry_after = |until:u64| {
+        let mut details = BTreeMap::new();
+        let secs = (until.saturating_sub(utils::current_time_millis()) + 999) / 1000;
+        details.insert("retry_after_secs".to_string(), secs.to_json());
+        Some(Json::Object(details))
+    };
+    match failure {
+        auth::Failure::Locked { until } => (ApiError::Locked, retry_after(until)),
+        auth::Failure::Delayed { until } => (ApiError::TooManyRequests, retry_after(until)),
+        auth::Failure::InvalidCredentials | auth::Failure::UnknownUser => (ApiError::InvalidCredentials, None),
+        auth::Failure::Ldap(e) => {
+            error!("Cannot binding to LDAP service. {}.", e);
+            (ApiError::Internal, None)
+        },
+        auth::Failure::Store(e) => {
+            error!("Store error: {}", e);
+            (ApiError::Internal, None)
+        }
+    }
+}
+
+/**
+ * Non-negative number in query string, `dflt` when not given.
+ */
+fn query_count(value:Option<String>, dflt:usize) -> Result<usize, ApiError> {
+    match value {
+        Some(value) => value.parse::<usize>().map_err(|_| ApiError::BadRequest),
+        None => Ok(dflt)
+    }
+}
+
+fn details(key:&str, value:Json) -> Option<Json> {
+    let mut details = BTreeMap::new();
+    details.insert(key.to_string(), value);
+    Some(Json::Object(details))
+}
+
+/**
+ * Register handler of endpoint, and the endpoint for OpenAPI document.
+ */
+fn route<H:Middleware<()>>(server:&mut Nickel, endpoints:&mut Vec<&'static Endpoint>,
+                           endpoint:&'static Endpoint, handler:H) {
+    server.add_route(endpoint.method.clone(), endpoint.route(), handler);
+    endpoints.push(endpoint);
+}
+
+
+pub fn setup(ctx:&Context, server: &mut Nickel){
+
+    let mut endpoints = Vec::new();
+
+    {
+        let store = ctx.store.clone();
+        let live = ctx.live.clone();
+        let signer = ctx.signer.clone();
+        let audit = ctx.audit.clone();
+
+        // same checks as login form, but token returned instead of redirect and
+        // no browser session started.
+        route(server, &mut endpoints, &LOGIN, middleware! { |_req, mut _resp|
+            let request_id = utils::request_id(&_req.origin.headers);
+            let requester = Requester::new(&_req.origin.headers, &_req.origin.remote_addr);
+            let settings = live.current();
+            let conf = &settings.conf;
+            let client_ip = _req.origin.remote_addr.ip().to_string();
+            let host = utils::request_host(&_req.origin.headers);
+
+            let mut body = String::new();
+            let _ = _req.origin.read_to_string(&mut body);
+            let login = api_v1_try!(json::decode::<api_result::LoginRequest>(&body)
+                                    .map_err(|_| ApiError::BadRequest), request_id, _resp);
+
+            let tenant = match login.dn {
+                Some(ref dn) if !dn.is_empty() => settings.tenant_by_dn(dn),
+                _ => settings.tenant(host.as_ref().map(|h| h.as_str()), None)
+            };
+            let tenant = match tenant {
+                Some(tenant) => tenant,
+                None => {
+                    warn!("Unknown tenant on api login for `{}`: {:?}", login.user_name, login.dn);
+                    audit.record(Event::new(Kind::Login, &requester).user(&login.user_name, "").detail("api")
+                                 .failed("unknown_tenant"));
+                    api_v1_error!(ApiError::UnknownTenant, request_id, _resp)
+                }
+            };
+            let dn = tenant.base_dn();
+            let failed = |reason:&str| Event::new(Kind::Login, &requester).user(&login.user_name, dn).detail("api").failed(reason);
+
+            if let Err(failure) = auth::verify_password(&store, conf, tenant, &client_ip, &login.user_name, &login.password) {
+                audit.record(failed(failure.reason()));
+                let (error, details) = auth_error(failure);
+                if let Some(secs) = details.as_ref().and_then(|d| d.find("retry_after_secs")).and_then(|s| s.as_u64()) {
+                    _resp.headers_mut().set_raw("Retry-After", vec![secs.to_string().into_bytes()]);
+                }
+                api_v1_error!(error, details, request_id, _resp)
+            }
+
+            let second_factor = {
+                let store = store.lock().unwrap();
+                auth::start_second_factor(&store, conf, &login.user_name, dn, "")
+            };
+            match second_factor {
+                Ok(Some((challenge_id, options))) => {
+                    let mut details = BTreeMap::new();
+                    details.insert("challenge_id".to_string(), challenge_id.to_json());
+                    details.insert("options".to_string(), Json::from_str(&options).unwrap_or(Json::Null));
+                    api_v1_error!(ApiError::SecondFactorRequired, Some(Json::Object(details)), request_id, _resp)
+                },
+                Ok(None) => (),
+                Err(failure) => {
+                    audit.record(failed(failure.reason()));
+                    let (error, details) = auth_error(failure);
+                    api_v1_error!(error, details, request_id, _resp)
+                }
+            }
+
+            // groups read from LDAP without holding the store lock
+            let token = token::generate(&conf.token);
+            let signed = api_v1_try!(login_handler::sign_access_token(&tenant.ldap, &signer, &login.user_name, dn, &token)
+                                     .map_err(|e| {
+                                         error!("Cannot issue signed token for `{}`: {}", login.user_name, e);
+                                         ApiError::Internal
+                                     }), request_id, _resp);
+            {
+                let store = store.lock().unwrap();
+                api_v1_try!(login_handler::issue_token(&store, conf, &login.user_name, dn, &token), request_id, _resp);
+            }
+            audit.login_success(&requester, &login.user_name, dn, "api", "api");
+
+            let user = api_result::Cred::new(login.user_name.clone(), dn.to_string());
+            api_v1_success!(api_result::LoginResult::new(token, signed, conf.token.jwt_ttl_secs, user), request_id, _resp)
+        });
+    }
+
+    {
+        let store = ctx.store.clone();
+        let live = ctx.live.clone();
+        let signer = ctx.signer.clone();
+        let audit = ctx.audit.clone();
+
+        route(server, &mut endpoints, &LOGIN_WEBAUTHN, middleware! { |_req, mut _resp|
+            let request_id = utils::request_id(&_req.origin.headers);
+            let requester = Requester::new(&_req.origin.headers, &_req.origin.remote_addr);
+            let settings = live.current();
+            let conf = &settings.conf;
+            let client_ip = _req.origin.remote_addr.ip().to_string();
+
+            let mut body = String::new();
+            let _ = _req.origin.read_to_string(&mut body);
+            let login = api_v1_try!(json::decode::<api_result::WebauthnLoginRequest>(&body)
+                                    .map_err(|_| ApiError::BadRequest), request_id, _resp);
+
+            // expired or already used challenge, client should login again.
+            let taken = {
+                let store = store.lock().unwrap();
+                webauthn::take_challenge(&store, &login.challenge_id, webauthn::ChallengeKind::Login)
+            };
+            let pending = match api_v1_try!(taken, request_id, _resp) {
+                Some(pending) => pending,
+                None => {
+                    audit.record(Event::new(Kind::Login, &requester).detail("api_webauthn").failed("challenge_expired"));
+                    api_v1_error!(ApiError::InvalidCredentials, request_id, _resp)
+                }
+            };
+            let tenant = match settings.tenant_by_dn(&pending.dn) {
+                Some(tenant) => tenant,
+                None => {
+                    warn!("Unknown DN of pending login for `{}`: {}", pending.uid, pending.dn);
+                    api_v1_error!(ApiError::UnknownTenant, request_id, _resp)
+                }
+            };
+
+            let decode = |value:&str| value.from_base64().unwrap_or(Vec::new());
+            let assertion = auth::Assertion {
+                credential_id: login.credential_id.clone(),
+                client_data_json: decode(&login.client_data_json),
+                authenticator_data: decode(&login.authenticator_data),
+                signature: decode(&login.signature)
+            };
+
+            let verified = {
+                let store = store.lock().unwrap();
+                auth::verify_second_factor(&store, conf, &client_ip, &pending, &assertion)
+            };
+            if let Err(failure) = verified {
+                audit.record(Event::new(Kind::Login, &requester).user(&pending.uid, &pending.dn).detail("api_webauthn")
+                             .failed(failure.reason()));
+                let (error, details) = auth_error(failure);
+                api_v1_error!(error, details, request_id, _resp)
+            }
+
+            // groups read from LDAP without holding the store lock
+            let token = token::generate(&conf.token);
+            let signed = api_v1_try!(login_handler::sign_access_token(&tenant.ldap, &signer, &pending.uid, &pending.dn, &token)
+                                     .map_err(|e| {
+                                         error!("Cannot issue signed token for `{}`: {}", pending.uid, e);
+                                         ApiError::Internal
+                                     }), request_id, _resp);
+            {
+                let store = store.lock().unwrap();
+                api_v1_try!(login_handler::issue_token(&store, conf, &pending.uid, &pending.dn, &token), request_id, _resp);
+            }
+            audit.login_success(&requester, &pending.uid, &pending.dn, "api_webauthn", "api");
+
+            let user = api_result::Cred::new(pending.uid.clone(), pending.dn.clone());
+            api_v1_success!(api_result::LoginResult::new(token, signed, conf.token.jwt_ttl_secs, user), request_id, _resp)
+        });
+    }
+
+    route(server, &mut endpoints, &SYSTEM_INFO, middleware! { |_req, mut _resp|
+        let request_id = utils::request_id(&_req.origin.headers);
+        api_v1_success!(api_handler::system_info(), request_id, _resp)
+    });
+
+    {
+        let store = ctx.store.clone();
+
+        let live = ctx.live.clone();
+        let audit = ctx.audit.clone();
+
+        route(server, &mut endpoints, &LOOKUP, middleware! { |_req, mut _resp|
+            let request_id = utils::request_id(&_req.origin.headers);
+            let requester = Requester::new(&_req.origin.headers, &_req.origin.remote_addr);
+            let settings = live.current();
+            let client = api_v1_try!(api_handler::authenticate_client(&settings, &_req.origin.headers,
+                                                                      &_req.origin.remote_addr), request_id, _resp);
+
+            let mut body = String::new();
+            let _ = _req.origin.read_to_string(&mut body);
+            let body_token = if body.trim().is_empty() {
+                None
+            }else{
+                api_v1_try!(json::decode::<api_result::LookupRequest>(&body)
+                            .map_err(|_| ApiError::BadRequest), request_id, _resp).access_token
+            };
+
+            let store = store.lock().unwrap();
+
+            let cred = api_v1_try!(api_handler::client_lookup(&store, &audit, &requester, client, &_req.origin.headers,
+                                                              body_token, false),
+                                   request_id, _resp);
+            api_v1_success!(cred, request_id, _resp)
+        });
+    }
+
+    let profiles = Arc::new(TtlCache::new(directory::CACHE_ENTRIES));
+
+    {
+        let store = ctx.store.clone();
+        let live = ctx.live.clone();
+        let profiles = profiles.clone();
+
+        route(server, &mut endpoints, &ME, middleware! { |_req, mut _resp|
+            let request_id = utils::request_id(&_req.origin.headers);
+            let settings = live.current();
+            // user's own token, application backend may add its API key (`Authorization`
+            // carries the token) to get attributes released to it instead.
+            let client = match _req.origin.headers.get_raw(client_auth::API_KEY_HEADER) {
+                Some(_) => Some(api_v1_try!(api_handler::authenticate_client(&settings, &_req.origin.headers,
+                                                                             &_req.origin.remote_addr), request_id, _resp)),
+                None => None
+            };
+            let release = client.map_or(&settings.conf.directory.self_attributes, |c| &c.release_attributes);
+
+            let access_token = match client_auth::bearer_token(&_req.origin.headers) {
+                Some(access_token) => access_token,
+                None => api_v1_error!(ApiError::Unauthorized, request_id, _resp)
+            };
+            let cred = {
+                let store = store.lock().unwrap();
+                api_v1_try!(api_handler::lookup(&store, &access_token), request_id, _resp)
+            };
+            // tenant removed from config, token no longer valid.
+            let tenant = match settings.tenant_by_dn(&cred.dn) {
+                Some(tenant) => tenant,
+                None => api_v1_error!(ApiError::InvalidToken, request_id, _resp)
+            };
+
+            let profile = api_v1_try!(api_handler::user_profile(&profiles, &settings, tenant, &cred.uid),
+                                      request_id, _resp);
+            api_v1_success!(profile.released(release), request_id, _resp)
+        });
+    }
+
+    {
+        let live = ctx.live.clone();
+        let profiles = profiles.clone();
+
+        route(server, &mut endpoints, &USER, middleware! { |_req, mut _resp|
+            let request_id = utils::request_id(&_req.origin.headers);
+            let settings = live.current();
+            let client = api_v1_try!(api_handler::authenticate_client(&settings, &_req.origin.headers,
+                                                                      &_req.origin.remote_addr), request_id, _resp);
+
+            let uid = _req.param("uid").unwrap_or("").to_string();
+            let host = utils::request_host(&_req.origin.headers);
+            let tenant = match _req.query().get("dn") {
+                Some(dn) if !dn.is_empty() => settings.tenant_by_dn(dn),
+                _ => settings.tenant(host.as_ref().map(|h| h.as_str()), None)
+            };
+            let tenant = match tenant {
+                Some(tenant) => tenant,
+                None => api_v1_error!(ApiError::UnknownTenant, request_id, _resp)
+            };
+
+            let profile = api_v1_try!(api_handler::user_profile(&profiles, &settings, tenant, &uid),
+                                      request_id, _resp);
+            info!("Client `{}` read profile of `{}` ({})", client.id, profile.uid, profile.dn);
+            api_v1_success!(profile.released(&client.release_attributes), request_id, _resp)
+        });
+    }
+
+    {
+        let store = ctx.store.clone();
+        let live = ctx.live.clone();
+        let searches = Arc::new(TtlCache::new(directory::CACHE_ENTRIES));
+
+        route(server, &mut endpoints, &DIRECTORY_SEARCH, middleware! { |_req, mut _resp|
+            let request_id = utils::request_id(&_req.origin.headers);
+            let settings = live.current();
+            let conf = &settings.conf.directory;
+            let headers = _req.origin.headers.clone();
+            let host = utils::request_host(&headers);
+
+            let (q, offset, limit, dn) = {
+                let query = _req.query();
+                let get = |name:&str| query.get(name).map(|v| v.to_string());
+                (get("q").unwrap_or(String::new()), get("offset"), get("limit"), get("dn"))
+            };
+
+            // service client with its release rules, or user of an application
+            // searching their own tenant.
+            let client = client_auth::authenticate(&settings.conf.clients, &headers);
+            let bearer = client_auth::bearer_token(&headers);
+            let (tenant, release) = match (client, bearer) {
+                (Some(client), _) => {
+                    let tenant = match dn {
+                        Some(ref dn) if !dn.is_empty() => settings.tenant_by_dn(dn),
+                        _ => settings.tenant(host.as_ref().map(|h| h.as_str()), None)
+                    };
+                    match tenant {
+                        Some(tenant) => (tenant, Some(&client.release_attributes)),
+                        None => api_v1_error!(ApiError::UnknownTenant, request_id, _resp)
+                    }
+                },
+                (None, Some(ref access_token)) if headers.get_raw(client_auth::API_KEY_HEADER).is_none() => {
+                    let cred = {
+                        let store = store.lock().unwrap();
+                        api_v1_try!(api_handler::lookup(&store, access_token), request_id, _resp)
+                    };
+                    match settings.tenant_by_dn(&cred.dn) {
+                        Some(tenant) => (tenant, None),
+                        None => api_v1_error!(ApiError::InvalidToken, request_id, _resp)
+                    }
+                },
+                _ => {
+                    warn!("Unauthorized directory search from {}", _req.origin.remote_addr);
+                    api_v1_error!(ApiError::Unauthorized, request_id, _resp)
+                }
+            };
+
+            let query = match directory::check_query(&q, conf.search_min_length) {
+                Ok(query) => query,
+                Err(e) => api_v1_error!(ApiError::BadRequest, details("q", e.to_json()), request_id, _resp)
+            };
+            let page_size = conf.search_page_size as usize;
+            let offset = api_v1_try!(query_count(offset, 0), request_id, _resp);
+            let limit = cmp::min(api_v1_try!(query_count(limit, page_size), request_id, _resp), page_size);
+
+            let results = match api_v1_try!(api_handler::directory_search(&searches, &settings, tenant, query),
+                                             request_id, _resp) {
+                Some(results) => results,
+                None => api_v1_error!(ApiError::BadRequest, details("max_results", conf.search_max_results.to_json()),
+                                      request_id, _resp)
+            };
+
+            let mut page = directory::SearchPage::new(&results, offset, limit);
+            if let Some(allowed) = release {
+                page.items = page.items.into_iter().map(|p| p.released(allowed)).collect();
+            }
+            api_v1_success!(page, request_id, _resp)
+        });
+    }
+
+    // signed access tokens, `token.format = "jwt"`
+    if ctx.signer.is_some() {
+        {
+            let store = ctx.store.clone();
+
+            route(server, &mut endpoints, &TOKEN_REVOKED, middleware! { |_req, mut _resp|
+                let request_id = utils::request_id(&_req.origin.headers);
+                let store = store.lock().unwrap();
+
+                let list = api_v1_try!(api_handler::revoked(&store), request_id, _resp);
+                api_v1_success!(list, request_id, _resp)
+            });
+        }
+
+        let store = ctx.store.clone();
+        let live = ctx.live.clone();
+        let signer = ctx.signer.clone();
+        let audit = ctx.audit.clone();
+
+        route(server, &mut endpoints, &TOKEN_REFRESH, middleware! { |_req, mut _resp|
+            let request_id = utils::request_id(&_req.origin.headers);
+            let requester = Requester::new(&_req.origin.headers, &_req.origin.remote_addr);
+            let settings = live.current();
+
+            let mut body = String::new();
+            let _ = _req.origin.read_to_string(&mut body);
+            let refresh = api_v1_try!(json::decode::<api_result::RefreshRequest>(&body)
+                                      .map_err(|_| ApiError::BadRequest), request_id, _resp);
+
+            let signed = api_v1_try!(api_handler::refresh(&store, &settings, &signer, &audit, &requester, &refresh.token),
+                                     request_id, _resp);
+            api_v1_success!(signed, request_id, _resp)
+        });
+    }
+
+    {
+        let store = ctx.store.clone();
+        let audit = ctx.audit.clone();
+        let live = ctx.live.clone();
+
+        route(server, &mut endpoints, &LOCKOUT_CLEAR, middleware! { |_req, mut _resp|
+            let request_id = utils::request_id(&_req.origin.headers);
+            let requester = Requester::new(&_req.origin.headers, &_req.origin.remote_addr);
+
+            if !api_handler::admin_authorized(&_req.origin.headers, &live.current().conf.admin.api_key) {
+                warn!("Unauthorized admin api access from {}", _req.origin.remote_addr);
+                audit.record(Event::new(Kind::Admin, &requester).detail("lockout_clear").failed("unauthorized"));
+                api_v1_error!(ApiError::Unauthorized, request_id, _resp)
+            }
+
+            let query = _req.query();
+            let user_name = query.get("user_name").unwrap_or("");
+            let ip = query.get("ip").unwrap_or("");
+
+            let store = store.lock().unwrap();
+
+            let cleared = api_v1_try!(api_handler::clear_lockout(&store, &audit, &requester, user_name, ip), request_id, _resp);
+            api_v1_success!(cleared, request_id, _resp)
+        });
+    }
+
+    {
+        let store = ctx.store.clone();
+        let audit = ctx.audit.clone();
+        let live = ctx.live.clone();
+        let retention_secs = ctx.conf.audit.retention_secs;
+
+        route(server, &mut endpoints, &AUDIT, middleware! { |_req, mut _resp|
+            let request_id = utils::request_id(&_req.origin.headers);
+            let requester = Requester::new(&_req.origin.headers, &_req.origin.remote_addr);
+
+            if !api_handler::admin_authorized(&_req.origin.headers, &live.current().conf.admin.api_key) {
+                warn!("Unauthorized admin api access from {}", _req.origin.remote_addr);
+                audit.record(Event::new(Kind::Admin, &requester).detail("audit_query").failed("unauthorized"));
+                api_v1_error!(ApiError::Unauthorized, request_id, _resp)
+            }
+
+            let now = utils::current_time_millis();
+            let query = _req.query();
+            let get = |name:&str| query.get(name).map(|v| v.to_string());
+            let number = |name:&str, dflt:u64| match query.get(name) {
+                Some(v) => v.parse::<u64>().map_err(|_| ApiError::BadRequest),
+                None => Ok(dflt)
+            };
+
+            let q = audit::Query {
+                since: api_v1_try!(number("since", now.saturating_sub(retention_secs * 1000)), request_id, _resp),
+                until: api_v1_try!(number("until", now), request_id, _resp),
+                uid: get("uid").unwrap_or(String::new()),
+                event: get("event").unwrap_or(String::new()),
+                outcome: get("outcome").unwrap_or(String::new()),
+                limit: cmp::min(api_v1_try!(query_count(get("limit"), AUDIT_PAGE_SIZE), request_id, _resp),
+                                AUDIT_MAX_PAGE_SIZE)
+            };
+
+            let store = store.lock().unwrap();
+
+            let events = api_v1_try!(audit::query(&store, &q).map_err(ApiError::from), request_id, _resp);
+            audit.record(Event::new(Kind::Admin, &requester).user(&q.uid, "").detail("audit_query"));
+            api_v1_success!(events, request_id, _resp)
+        });
+    }
+
+    let document = openapi::document(&endpoints, build::VERSION).to_string();
+
+    server.get("/api/v1/openapi.json", middleware! { |_req, mut _resp|
+        _resp.set(MediaType::Json);
+        document.clone()
+    });
+}
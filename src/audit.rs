@@ -0,0 +1,444 @@
+
+// Audit log of authentication events, `[audit]` section.
+//
+// Every event is one JSON object with the client ip, user agent and request id,
+// written by background thread to rotating file (`audit.file`), local syslog
+// (`audit.syslog`) and the store, kept there `audit.retention_secs` for
+// `/api/v1/admin/audit`. Handlers never wait for the writes.
+//
+//     {"time":1520000000000,"event":"login","outcome":"failure","reason":"invalid_credentials",
+//      "uid":"robin","dn":"dc=example,dc=com","client":"","detail":"password",
+//      "ip":"10.0.0.1","user_agent":"Mozilla/5.0 ...","request_id":"5f0c..."}
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::os::unix::net::UnixDatagram;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use nickel::hyper::header::Headers;
+use rand::{self, Rng};
+use serialize::hex::ToHex;
+use serialize::json;
+
+use config::AuditConf;
+use metrics;
+use store::{Store, StoreResult, Namespace};
+use utils;
+
+const MAX_USER_AGENT_LEN:usize = 256;
+
+// expired events removed at most this often.
+const PURGE_INTERVAL_MILLIS:u64 = 60 * 60 * 1000;
+
+// facility authpriv (10), severity info (6).
+const SYSLOG_PRIORITY:u32 = 10 * 8 + 6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Login,
+    TokenIssued,
+    TokenLookup,
+    TokenRevoked,
+    Admin
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Kind::Login => "login",
+            Kind::TokenIssued => "token_issued",
+            Kind::TokenLookup => "token_lookup",
+            Kind::TokenRevoked => "token_revoked",
+            Kind::Admin => "admin"
+        }
+    }
+}
+
+/**
+ * Who made the request the event is about.
+ */
+pub struct Requester {
+    pub ip: String,
+    pub user_agent: String,
+    pub request_id: String
+}
+
+impl Requester {
+    pub fn new(headers:&Headers, remote_addr:&SocketAddr) -> Requester {
+        let user_agent = headers.get_raw("User-Agent")
+            .and_then(|v| v.first())
+            .and_then(|v| str::from_utf8(v).ok())
+            .unwrap_or("");
+        Requester {
+            ip: remote_addr.ip().to_string(),
+            user_agent: user_agent.chars().take(MAX_USER_AGENT_LEN).collect(),
+            request_id: utils::request_id(headers)
+        }
+    }
+}
+
+#[derive(Decodable, Encodable, Clone, Debug, PartialEq)]
+pub struct Event {
+    pub time: u64,          // millis
+    pub event: String,      // `Kind` name
+    pub outcome: String,    // success or failure
+    pub reason: String,     // of failure, eg: invalid_credentials
+    pub uid: String,
+    pub dn: String,
+    pub client: String,     // service client id, or application host token issued for
+    pub detail: String,     // login method, admin action, ...
+    pub ip: String,
+    pub user_agent: String,
+    pub request_id: String
+}
+
+impl Event {
+    pub fn new(kind:Kind, requester:&Requester) -> Event {
+        Event {
+            time: utils::current_time_millis(),
+            event: kind.name().to_string(),
+            outcome: "success".to_string(),
+            reason: String::new(),
+            uid: String::new(),
+            dn: String::new(),
+            client: String::new(),
+            detail: String::new(),
+            ip: requester.ip.clone(),
+            user_agent: requester.user_agent.clone(),
+            request_id: requester.request_id.clone()
+        }
+    }
+
+    pub fn user(mut self, uid:&str, dn:&str) -> Event {
+        self.uid = uid.to_string();
+        self.dn = dn.to_string();
+        self
+    }
+
+    pub fn client(mut self, client:&str) -> Event {
+        self.client = client.to_string();
+        self
+    }
+
+    pub fn detail(mut self, detail:&str) -> Event {
+        self.detail = detail.to_string();
+        self
+    }
+
+    pub fn failed(mut self, reason:&str) -> Event {
+        self.outcome = "failure".to_string();
+        self.reason = reason.to_string();
+        self
+    }
+}
+
+/**
+ * Events queued for the writer thread.
+ */
+pub struct AuditLog {
+    sender: Mutex<Sender<Event>>
+}
+
+impl AuditLog {
+    /**
+     * Open configured outputs and start writing events in background.
+     */
+    pub fn start(conf:&AuditConf, store:Arc<Mutex<Store>>) -> Result<AuditLog, String> {
+        let mut file = if conf.file.is_empty() {
+            None
+        }else{
+            Some(try!(RotatingFile::open(&conf.file, conf.max_file_bytes, conf.max_files)
+                .map_err(|e| format!("cannot open audit log `{}`: {}", conf.file, e))))
+        };
+        let syslog = if conf.syslog {
+            Some(try!(UnixDatagram::unbound().map_err(|e| format!("cannot create syslog socket: {}", e))))
+        }else{
+            None
+        };
+        let syslog_socket = conf.syslog_socket.clone();
+        let retention_millis = conf.retention_secs * 1000;
+
+        let (sender, receiver) = mpsc::channel::<Event>();
+
+        thread::spawn(move || {
+            let mut last_purge = 0;
+
+            for event in receiver.iter() {
+                let line = json::encode(&event).unwrap();
+
+                if let Some(ref mut file) = file {
+                    if let Err(e) = file.write_line(&line) {
+                        error!("Cannot write audit log: {}", e);
+                    }
+                }
+                if let Some(ref socket) = syslog {
+                    if let Err(e) = socket.send_to(syslog_message(&line).as_bytes(), &syslog_socket) {
+                        error!("Cannot send audit event to syslog `{}`: {}", syslog_socket, e);
+                    }
+                }
+                if retention_millis > 0 {
+                    let store = store.lock().unwrap();
+                    if let Err(e) = save(&store, &event) {
+                        error!("Cannot save audit event: {}", e);
+                    }
+                    if event.time >= last_purge + PURGE_INTERVAL_MILLIS {
+                        match purge(&store, event.time.saturating_sub(retention_millis)) {
+                            Ok(0) => (),
+                            Ok(count) => debug!("{} expired audit events removed", count),
+                            Err(e) => error!("Cannot remove expired audit events: {}", e)
+                        }
+                        last_purge = event.time;
+                    }
+                }
+            }
+        });
+
+        Ok(AuditLog { sender: Mutex::new(sender) })
+    }
+
+    /**
+     * Queue event for writing, login attempts and token lookups also counted in `metrics`.
+     */
+    pub fn record(&self, event:Event) {
+        count(&event);
+        if self.sender.lock().unwrap().send(event).is_err() {
+            error!("Audit log writer stopped, event lost");
+        }
+    }
+
+    /**
+     * Successful login with `method` (password, webauthn) and the token issued
+     * for `client` (application host, `api` for API login).
+     */
+    pub fn login_success(&self, requester:&Requester, uid:&str, dn:&str, method:&str, client:&str) {
+        self.record(Event::new(Kind::Login, requester).user(uid, dn).detail(method));
+        self.record(Event::new(Kind::TokenIssued, requester).user(uid, dn).client(client).detail(method));
+    }
+}
+
+// counted from events so every handler auditing them is measured the same way,
+// outcome is the failure reason.
+fn count(event:&Event) {
+    let outcome = if event.reason.is_empty() { "success" } else { event.reason.as_str() };
+    if event.event == Kind::Login.name() {
+        metrics::registry().inc(metrics::LOGIN_ATTEMPTS, &[("method", &event.detail), ("outcome", outcome)]);
+    }else if event.event == Kind::TokenLookup.name() {
+        metrics::registry().inc(metrics::TOKEN_LOOKUPS, &[("outcome", outcome)]);
+    }
+}
+
+fn syslog_message(line:&str) -> String {
+    format!("<{}>sso-audit: {}", SYSLOG_PRIORITY, line)
+}
+
+// ordered by time, random suffix for events in the same millisecond.
+fn event_id(event:&Event) -> String {
+    let mut buf = [0u8; 4];
+    rand::thread_rng().fill_bytes(&mut buf);
+    format!("{:013}-{}", event.time, buf.to_hex())
+}
+
+fn event_time(id:&str) -> Option<u64> {
+    id.split('-').next().and_then(|t| t.parse::<u64>().ok())
+}
+
+pub fn save(store:&Store, event:&Event) -> StoreResult<()> {
+    store.put_record(Namespace::Audit, &event_id(event), event)
+}
+
+/**
+ * Remove events older than `before` millis, returns how many.
+ */
+pub fn purge(store:&Store, before:u64) -> StoreResult<usize> {
+    let prefix = Namespace::Audit.prefix();
+    let mut batch = store.batch();
+    let mut count = 0;
+
+    for (key, _) in try!(store.scan(&prefix)) {
+        let id = &key[prefix.len()..];
+        if event_time(id).map(|t| t < before).unwrap_or(true) {
+            batch = batch.del_record(Namespace::Audit, id);
+            count += 1;
+        }
+    }
+    if count > 0 {
+        try!(batch.commit());
+    }
+    Ok(count)
+}
+
+// criteria of `/api/v1/admin/audit`, empty ones match anything.
+pub struct Query {
+    pub since: u64,
+    pub until: u64,
+    pub uid: String,
+    pub event: String,
+    pub outcome: String,
+    pub limit: usize
+}
+
+/**
+ * Stored events matching query, newest first.
+ */
+pub fn query(store:&Store, q:&Query) -> StoreResult<Vec<Event>> {
+    let prefix = Namespace::Audit.prefix();
+    let mut ids:Vec<String> = try!(store.scan(&prefix)).into_iter()
+        .map(|(key, _)| key[prefix.len()..].to_string())
+        .filter(|id| event_time(id).map(|t| t >= q.since && t <= q.until).unwrap_or(false))
+        .collect();
+    ids.sort_by(|a, b| b.cmp(a));
+
+    let mut events = Vec::new();
+    for id in ids {
+        if events.len() >= q.limit {
+            break;
+        }
+        let event = match try!(store.get_record::<Event>(Namespace::Audit, &id)) {
+            Some(event) => event,
+            None => continue
+        };
+        if (q.uid.is_empty() || event.uid == q.uid) && (q.event.is_empty() || event.event == q.event) &&
+           (q.outcome.is_empty() || event.outcome == q.outcome) {
+            events.push(event);
+        }
+    }
+    Ok(events)
+}
+
+
+// Appends lines to `path`, moved to `path.1` (and older ones shifted up to
+// `path.<max_files>`) before exceeding `max_bytes`.
+struct RotatingFile {
+    path: String,
+    max_bytes: u64,
+    max_files: u64,
+    file: File,
+    size: u64
+}
+
+impl RotatingFile {
+    fn open(path:&str, max_bytes:u64, max_files:u64) -> io::Result<RotatingFile> {
+        let file = try!(OpenOptions::new().create(true).append(true).open(path));
+        let size = try!(file.metadata()).len();
+        Ok(RotatingFile { path: path.to_string(), max_bytes: max_bytes, max_files: max_files, file: file, size: size })
+    }
+
+    fn write_line(&mut self, line:&str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_bytes {
+            try!(self.rotate());
+        }
+        try!(writeln!(self.file, "{}", line));
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..self.max_files).rev() {
+            let from = format!("{}.{}", self.path, n);
+            if fs::metadata(&from).is_ok() {
+                try!(fs::rename(&from, format!("{}.{}", self.path, n + 1)));
+            }
+        }
+        try!(fs::rename(&self.path, format!("{}.1", self.path)));
+        self.file = try!(OpenOptions::new().create(true).append(true).open(&self.path));
+        self.size = 0;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use store::{Store, MemoryStore};
+    use super::*;
+    use super::RotatingFile;
+
+    fn requester() -> Requester {
+        Requester { ip: "10.0.0.1".to_string(), user_agent: "curl".to_string(), request_id: "abc".to_string() }
+    }
+
+    fn event(time:u64, kind:Kind, uid:&str) -> Event {
+        let mut event = Event::new(kind, &requester()).user(uid, "dc=example,dc=com");
+        event.time = time;
+        event
+    }
+
+    #[test]
+    fn events() {
+        let event = Event::new(Kind::Login, &requester()).user("robin", "dc=example,dc=com")
+            .detail("password").failed("invalid_credentials");
+        assert_eq!(event.event, "login");
+        assert_eq!((event.outcome.as_str(), event.reason.as_str()), ("failure", "invalid_credentials"));
+        assert_eq!((event.ip.as_str(), event.request_id.as_str()), ("10.0.0.1", "abc"));
+
+        assert!(syslog_message("{}").starts_with("<86>"));
+        assert_eq!(event_time(&event_id(&event)), Some(event.time));
+    }
+
+    #[test]
+    fn store_window() {
+        let store = Store::with_backend(Box::new(MemoryStore::new()));
+        save(&store, &event(1000, Kind::Login, "robin")).unwrap();
+        save(&store, &event(2000, Kind::TokenIssued, "robin")).unwrap();
+        save(&store, &event(3000, Kind::Login, "alice")).unwrap();
+        save(&store, &event(4000, Kind::Admin, "")).unwrap();
+
+        let mut q = Query { since: 0, until: 10000, uid: String::new(), event: String::new(),
+                            outcome: String::new(), limit: 10 };
+        let times = |events:Vec<Event>| events.iter().map(|e| e.time).collect::<Vec<u64>>();
+        assert_eq!(times(query(&store, &q).unwrap()), vec![4000, 3000, 2000, 1000]);
+
+        q.limit = 2;
+        assert_eq!(times(query(&store, &q).unwrap()), vec![4000, 3000]);
+
+        q.limit = 10;
+        q.uid = "robin".to_string();
+        assert_eq!(times(query(&store, &q).unwrap()), vec![2000, 1000]);
+        q.event = "login".to_string();
+        assert_eq!(times(query(&store, &q).unwrap()), vec![1000]);
+        q.outcome = "failure".to_string();
+        assert!(query(&store, &q).unwrap().is_empty());
+
+        assert_eq!(purge(&store, 2500).unwrap(), 2);
+        let q = Query { since: 0, until: 3000, uid: String::new(), event: String::new(),
+                        outcome: String::new(), limit: 10 };
+        assert_eq!(times(query(&store, &q).unwrap()), vec![3000]);
+    }
+
+    #[test]
+    fn rotation() {
+        let dir = env::temp_dir().join(format!("sso-audit-test-{}", event_id(&event(0, Kind::Admin, ""))));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log").to_str().unwrap().to_string();
+
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in &["first", "second", "third", "fourth"] {
+            file.write_line(line).unwrap();
+        }
+
+        let read = |p:&str| {
+            let mut s = String::new();
+            fs::File::open(p).unwrap().read_to_string(&mut s).unwrap();
+            s
+        };
+        assert_eq!(read(&path), "fourth\n");
+        assert_eq!(read(&format!("{}.1", path)), "third\n");
+        assert_eq!(read(&format!("{}.2", path)), "second\n");
+        assert!(fs::metadata(format!("{}.3", path)).is_err());
+
+        // size of existing file counted after restart
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        file.write_line("fifth").unwrap();
+        assert_eq!(read(&path), "fifth\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,210 @@
+
+// Authentication shared by the browser login form and JSON login API: brute-force
+// throttling, password check against user entry in tenant's LDAP and security key
+// second factor. Handlers only differ in how they present the outcome.
+
+use std::error::Error;
+use std::sync::Mutex;
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use crypto::util::fixed_time_eq;
+use oldap::codes;
+use serialize::base64::FromBase64;
+
+use config::Conf;
+use directory;
+use ldap;
+use metrics::{self, Timer};
+use store::{Store, StoreError};
+use tenant::Tenant;
+use throttle;
+use webauthn::{self, ChallengeKind, PendingChallenge};
+
+pub enum Failure {
+    Locked { until: u64 },
+    Delayed { until: u64 }, // retry after previous failure's delay
+    InvalidCredentials, // wrong password or security key assertion
+    UnknownUser,        // no such user entry in directory
+    Ldap(String),       // directory not reachable or search failed
+    Store(StoreError)
+}
+
+impl Failure {
+    /**
+     * Reason recorded in audit log.
+     */
+    pub fn reason(&self) -> &'static str {
+        match *self {
+            Failure::Locked { .. } => "locked",
+            Failure::Delayed { .. } => "delayed",
+            Failure::InvalidCredentials => "invalid_credentials",
+            Failure::UnknownUser => "unknown_user",
+            Failure::Ldap(_) => "ldap_error",
+            Failure::Store(_) => "store_error"
+        }
+    }
+}
+
+impl From<StoreError> for Failure {
+    fn from(e:StoreError) -> Failure {
+        Failure::Store(e)
+    }
+}
+
+// Security key assertion posted by client, binary fields already decoded.
+pub struct Assertion {
+    pub credential_id: String,
+    pub client_data_json: Vec<u8>,
+    pub authenticator_data: Vec<u8>,
+    pub signature: Vec<u8>
+}
+
+fn check_password(challenge_password: &String, password: &String) -> bool {
+    if challenge_password.len() < 6 {
+        return false;
+    }
+    let algo = &challenge_password[..6];
+    if algo != "{SSHA}" {
+        error!("Unexpected pass hash algo: {}", algo);
+        return false;
+    }
+    let data = &challenge_password[6..];
+
+    let data_bytes = match data.from_base64() {
+        Ok(bytes) if bytes.len() > 20 => bytes,
+        _ => return false
+    };
+    let digest = &data_bytes[..20];
+    let salt = &data_bytes[20..];
+
+    let mut sha = Sha1::new();
+    sha.input_str(password);
+    sha.input(salt);
+
+    let mut calculated_sha_output = [0u8; 20];
+    sha.result(&mut calculated_sha_output);
+
+    fixed_time_eq(digest, &calculated_sha_output)
+}
+
+/**
+ * Check password of `user_name` in tenant's directory. Throttled per user and
+ * client ip, attempt too soon after previous failure is refused (`Delayed`) instead
+ * of waiting. Directory check happens without holding the store lock.
+ * Attempt counts as failure from the start (see `throttle::begin`), success clears it.
+ * User name not safe to put into DN is rejected before throttling.
+ */
+pub fn verify_password(store:&Mutex<Store>, conf:&Conf, tenant:&Tenant, client_ip:&str,
+                       user_name:&str, password:&str) -> Result<(), Failure> {
+    if !directory::valid_uid(user_name) {
+        warn!("Login with invalid user name from {}", client_ip);
+        return Err(Failure::InvalidCredentials);
+    }
+
+    let status = {
+        let store = store.lock().unwrap();
+        try!(throttle::begin(&store, &conf.throttle, client_ip, user_name))
+    };
+
+    let attempt = match status {
+        throttle::Status::Locked { until } => {
+            warn!("Login for `{}` from {} rejected, locked out", user_name, client_ip);
+            return Err(Failure::Locked { until: until });
+        },
+        throttle::Status::Delayed { until } => {
+            debug!("Login for `{}` from {} rejected, retry after previous failure", user_name, client_ip);
+            return Err(Failure::Delayed { until: until });
+        },
+        throttle::Status::Allowed { attempt } => attempt
+    };
+
+    let result = check_directory_password(tenant, user_name, password);
+
+    let store = store.lock().unwrap();
+    match result {
+        Ok(()) => try!(throttle::record_success(&store, &conf.throttle, client_ip, user_name, attempt)),
+        Err(Failure::Ldap(_)) => try!(throttle::release(&store, &conf.throttle, client_ip, user_name, attempt)),
+        Err(_) => ()
+    }
+    result
+}
+
+// password check against user entry, failure other than `Ldap` is user's.
+fn check_directory_password(tenant:&Tenant, user_name:&str, password:&str) -> Result<(), Failure> {
+    let conn = try!(ldap::connect(&tenant.ldap.uri, &tenant.ldap.admin_user,
+                                  &tenant.ldap.admin_password, tenant.base_dn()).map_err(Failure::Ldap));
+
+    let dn_query = format!("uid={},ou=People,{}", user_name, tenant.base_dn());
+    debug!("dn_query: {}", dn_query);
+
+    let timer = Timer::start();
+    let found = conn.simple_search(&dn_query, codes::scopes::LDAP_SCOPE_BASE);
+    timer.observe(metrics::LDAP_DURATION, "search");
+
+    let result = match found {
+        Ok(result) => result,
+        Err(err) => {
+            return match err.description() {
+                "No such object" => Err(Failure::UnknownUser),
+                another_error => Err(Failure::Ldap(another_error.to_string()))
+            };
+        }
+    };
+
+    let user_password = result.first()
+        .and_then(|entry| entry.get("userPassword"))
+        .and_then(|values| values.first())
+        .cloned()
+        .unwrap_or(String::new());
+
+    if !check_password(&user_password, &password.to_string()) {
+        return Err(Failure::InvalidCredentials);
+    }
+    Ok(())
+}
+
+/**
+ * Start second factor when user has registered security key, returns
+ * pending challenge id and WebAuthn request options for the client.
+ */
+pub fn start_second_factor(store:&Store, conf:&Conf, user_name:&str, dn:&str, cont:&str)
+        -> Result<Option<(String, String)>, Failure> {
+    if !conf.webauthn.enabled() {
+        return Ok(None);
+    }
+    let creds = try!(webauthn::load_credentials(store, user_name));
+    if creds.is_empty() {
+        return Ok(None);
+    }
+
+    let pending = PendingChallenge::new(ChallengeKind::Login, user_name, dn, cont);
+    let pending_id = try!(webauthn::put_challenge(store, &pending));
+    Ok(Some((pending_id, webauthn::request_options(&conf.webauthn, &pending, &creds))))
+}
+
+/**
+ * Verify security key assertion for pending challenge, failure counted towards lockout.
+ */
+pub fn verify_second_factor(store:&Store, conf:&Conf, client_ip:&str, pending:&PendingChallenge,
+                            assertion:&Assertion) -> Result<(), Failure> {
+    let mut creds = try!(webauthn::load_credentials(store, &pending.uid));
+
+    let verified = match creds.iter().position(|c| c.id == assertion.credential_id) {
+        Some(idx) => {
+            webauthn::verify_assertion(&conf.webauthn, &pending.challenge, &creds[idx],
+                    &assertion.client_data_json, &assertion.authenticator_data, &assertion.signature)
+                .map(|sign_count| creds[idx].sign_count = sign_count)
+        },
+        None => Err("Unknown credential".to_string())
+    };
+
+    if let Err(e) = verified {
+        warn!("WebAuthn assertion failed for `{}`: {}", pending.uid, e);
+        try!(throttle::record_failure(store, &conf.throttle, client_ip, &pending.uid));
+        return Err(Failure::InvalidCredentials);
+    }
+
+    try!(webauthn::save_credentials(store, &pending.uid, &creds));
+    Ok(())
+}
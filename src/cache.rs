@@ -0,0 +1,97 @@
+
+// Short-term in-memory cache of directory reads (user profiles, search results),
+// so applications polling the API don't hit LDAP on every request.
+//
+// Entries expire after `ttl_millis` given on read, so a reloaded config applies
+// right away. Not shared between SSO nodes, every node keeps its own.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use utils;
+
+pub struct TtlCache<V:Clone> {
+    max_entries:usize,
+    entries:Mutex<HashMap<String, (u64, V)>>, // key => (stored millis, value)
+}
+
+impl<V:Clone> TtlCache<V> {
+    pub fn new(max_entries:usize) -> TtlCache<V> {
+        TtlCache { max_entries: max_entries, entries: Mutex::new(HashMap::new()) }
+    }
+
+    /**
+     * Value stored less than `ttl_millis` ago.
+     */
+    pub fn get(&self, key:&str, ttl_millis:u64) -> Option<V> {
+        self.get_at(key, ttl_millis, utils::current_time_millis())
+    }
+
+    pub fn put(&self, key:&str, value:V) {
+        self.put_at(key, value, utils::current_time_millis())
+    }
+
+    fn get_at(&self, key:&str, ttl_millis:u64, now:u64) -> Option<V> {
+        if ttl_millis == 0 {
+            return None;
+        }
+        let entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(&(stored, ref value)) if now < stored + ttl_millis => Some(value.clone()),
+            _ => None
+        }
+    }
+
+    fn put_at(&self, key:&str, value:V, now:u64) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries && !entries.contains_key(key) {
+            // full, drop oldest half rather than tracking every access
+            let mut stored:Vec<u64> = entries.values().map(|&(stored, _)| stored).collect();
+            stored.sort();
+            let keep_from = stored[stored.len() / 2];
+            entries.retain(|_, &mut (stored, _)| stored >= keep_from && stored != 0);
+            if entries.len() >= self.max_entries {
+                entries.clear();
+            }
+        }
+        entries.insert(key.to_string(), (now, value));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry() {
+        let cache = TtlCache::new(10);
+        cache.put_at("robin", "Robin".to_string(), 1000);
+
+        assert_eq!(cache.get_at("robin", 500, 1499), Some("Robin".to_string()));
+        assert_eq!(cache.get_at("robin", 500, 1500), None);
+        // ttl of reloaded config applies to stored entries too
+        assert_eq!(cache.get_at("robin", 1000, 1500), Some("Robin".to_string()));
+        assert_eq!(cache.get_at("robin", 0, 1000), None);
+        assert_eq!(cache.get_at("other", 500, 1000), None);
+    }
+
+    #[test]
+    fn bounded() {
+        let cache = TtlCache::new(4);
+        for i in 0..4 {
+            cache.put_at(&format!("k{}", i), i, 1000 + i as u64);
+        }
+        cache.put_at("k4", 4, 2000);
+
+        let len = cache.entries.lock().unwrap().len();
+        assert!(len <= 4, "{} entries", len);
+        assert_eq!(cache.get_at("k4", 500, 2000), Some(4));
+        assert_eq!(cache.get_at("k0", 5000, 2000), None);
+        assert_eq!(cache.get_at("k3", 5000, 2000), Some(3));
+
+        // existing key replaced without eviction
+        cache.put_at("k4", 5, 2001);
+        assert_eq!(cache.get_at("k4", 500, 2001), Some(5));
+    }
+}
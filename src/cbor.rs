@@ -0,0 +1,221 @@
+
+// Minimal CBOR (RFC 7049) support, only what we need to deal with
+// WebAuthn attestation objects and COSE keys.
+// Indefinite-length items and floating point values are not supported.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Unsigned(u64),
+    Negative(i64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Bool(bool),
+    Null
+}
+
+impl Value {
+
+    /**
+     * Get map entry by text key, eg: "fmt", "authData".
+     */
+    pub fn get(&self, key:&str) -> Option<&Value> {
+        self.get_by(&Value::Text(key.to_string()))
+    }
+
+    /**
+     * Get map entry by integer key, used by COSE keys, eg: 1, 3, -1, -2.
+     */
+    pub fn get_int(&self, key:i64) -> Option<&Value> {
+        if key < 0 {
+            self.get_by(&Value::Negative(key))
+        }else{
+            self.get_by(&Value::Unsigned(key as u64))
+        }
+    }
+
+    fn get_by(&self, key:&Value) -> Option<&Value> {
+        match *self {
+            Value::Map(ref entries) => entries.iter().find(|&&(ref k, _)| k == key).map(|&(_, ref v)| v),
+            _ => None
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match *self {
+            Value::Bytes(ref b) => Some(b),
+            _ => None
+        }
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match *self {
+            Value::Text(ref s) => Some(s),
+            _ => None
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match *self {
+            Value::Unsigned(n) => Some(n as i64),
+            Value::Negative(n) => Some(n),
+            _ => None
+        }
+    }
+}
+
+/**
+ * Decode single CBOR item from the beginning of `data`,
+ * returns the item and number of bytes consumed.
+ */
+pub fn decode(data:&[u8]) -> Result<(Value, usize), String> {
+    decode_at(data, 0, 0)
+}
+
+fn decode_at(data:&[u8], pos:usize, depth:usize) -> Result<(Value, usize), String> {
+    if depth > 16 {
+        return Err("CBOR nesting too deep".to_string());
+    }
+
+    let initial = *try!(data.get(pos).ok_or("Unexpected end of CBOR data".to_string()));
+    let major = initial >> 5;
+    let info = initial & 0x1f;
+
+    let (arg, mut pos) = try!(read_arg(data, pos + 1, info));
+
+    let value = match major {
+        0 => Value::Unsigned(arg),
+        1 => Value::Negative(-1 - (arg as i64)),
+        2 | 3 => {
+            // length is attacker supplied, may overflow
+            let end = match pos.checked_add(arg as usize) {
+                Some(end) if end <= data.len() => end,
+                _ => return Err("Unexpected end of CBOR data".to_string())
+            };
+            let bytes = data[pos..end].to_vec();
+            pos = end;
+            if major == 2 {
+                Value::Bytes(bytes)
+            }else{
+                Value::Text(try!(String::from_utf8(bytes).map_err(|_| "Invalid CBOR text string".to_string())))
+            }
+        },
+        4 => {
+            let mut items = Vec::new();
+            for _ in 0..arg {
+                let (item, next) = try!(decode_at(data, pos, depth + 1));
+                items.push(item);
+                pos = next;
+            }
+            Value::Array(items)
+        },
+        5 => {
+            let mut entries = Vec::new();
+            for _ in 0..arg {
+                let (k, next) = try!(decode_at(data, pos, depth + 1));
+                let (v, next) = try!(decode_at(data, next, depth + 1));
+                entries.push((k, v));
+                pos = next;
+            }
+            Value::Map(entries)
+        },
+        6 => {
+            // semantic tag, we don't care about it, just take the tagged item.
+            let (item, next) = try!(decode_at(data, pos, depth + 1));
+            pos = next;
+            item
+        },
+        _ => match info {
+            20 => Value::Bool(false),
+            21 => Value::Bool(true),
+            22 | 23 => Value::Null,
+            _ => return Err(format!("Unsupported CBOR simple value: {}", info))
+        }
+    };
+
+    Ok((value, pos))
+}
+
+fn read_arg(data:&[u8], pos:usize, info:u8) -> Result<(u64, usize), String> {
+    let len = match info {
+        0...23 => return Ok((info as u64, pos)),
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        _ => return Err(format!("Unsupported CBOR additional info: {}", info))
+    };
+    if pos + len > data.len() {
+        return Err("Unexpected end of CBOR data".to_string());
+    }
+    let mut arg = 0u64;
+    for b in &data[pos..pos + len] {
+        arg = (arg << 8) | (*b as u64);
+    }
+    Ok((arg, pos + len))
+}
+
+/**
+ * Encode value into CBOR bytes (definite length only).
+ */
+pub fn encode(value:&Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_into(value, &mut out);
+    out
+}
+
+fn encode_into(value:&Value, out:&mut Vec<u8>){
+    match *value {
+        Value::Unsigned(n) => write_arg(0, n, out),
+        Value::Negative(n) => write_arg(1, (-1 - n) as u64, out),
+        Value::Bytes(ref b) => {
+            write_arg(2, b.len() as u64, out);
+            out.extend_from_slice(b);
+        },
+        Value::Text(ref s) => {
+            write_arg(3, s.len() as u64, out);
+            out.extend_from_slice(s.as_bytes());
+        },
+        Value::Array(ref items) => {
+            write_arg(4, items.len() as u64, out);
+            for item in items {
+                encode_into(item, out);
+            }
+        },
+        Value::Map(ref entries) => {
+            write_arg(5, entries.len() as u64, out);
+            for &(ref k, ref v) in entries {
+                encode_into(k, out);
+                encode_into(v, out);
+            }
+        },
+        Value::Bool(false) => out.push(0xf4),
+        Value::Bool(true) => out.push(0xf5),
+        Value::Null => out.push(0xf6)
+    }
+}
+
+fn write_arg(major:u8, arg:u64, out:&mut Vec<u8>){
+    let m = major << 5;
+    if arg < 24 {
+        out.push(m | arg as u8);
+    }else if arg <= 0xff {
+        out.push(m | 24);
+        out.push(arg as u8);
+    }else if arg <= 0xffff {
+        out.push(m | 25);
+        out.push((arg >> 8) as u8);
+        out.push(arg as u8);
+    }else if arg <= 0xffff_ffff {
+        out.push(m | 26);
+        for i in (0..4).rev() {
+            out.push((arg >> (i * 8)) as u8);
+        }
+    }else{
+        out.push(m | 27);
+        for i in (0..8).rev() {
+            out.push((arg >> (i * 8)) as u8);
+        }
+    }
+}
@@ -0,0 +1,385 @@
+
+// Operator commands working directly on the store, run instead of the HTTP server:
+//
+//   sso-pdip CONFIG-FILE backup DIR      copy whole store into new DIR (or sqlite file)
+//   sso-pdip CONFIG-FILE restore DIR     replace store content with backup from DIR
+//
+// RocksDB is backed up and restored by its backup engine before the store is opened
+// (see `run_files`), other backends copied record by record. Replication bookkeeping
+// (`_repl/`) belongs to the node, never restored.
+//   sso-pdip CONFIG-FILE export [FILE]   dump records as JSON lines (stdout by default)
+//   sso-pdip CONFIG-FILE import [FILE]   load records dumped by `export` (stdin by default)
+//   sso-pdip CONFIG-FILE stats           record counts per type
+//   sso-pdip CONFIG-FILE check-config    validate config, secrets and keys (store not opened)
+
+use std::io::{self, Write, BufRead, BufReader};
+use std::fs::File;
+use std::path::Path;
+use std::collections::BTreeMap;
+use serialize::json::Json;
+
+use config::Conf;
+use store::{Store, StoreResult, Keyring};
+use jwt;
+use store::rocks;
+use store::replica::META_PREFIX;
+use store::schema::NAMESPACES;
+
+pub const COMMANDS:[&'static str; 6] = ["backup", "restore", "export", "import", "stats", "check-config"];
+
+
+pub fn is_command(name:&str) -> bool {
+    COMMANDS.contains(&name)
+}
+
+/**
+ * Run command against opened store, returns error description on failure.
+ */
+pub fn run(conf:&Conf, store:&Store, command:&str, args:&[String]) -> Result<(), String> {
+    let arg = args.first().map(|a| a.as_str());
+
+    match (command, arg) {
+        ("backup", Some(dir)) => {
+            if Path::new(dir).exists() {
+                return Err(format!("`{}` already exists, backup needs new location", dir));
+            }
+            let target = try!(Store::new_at(&conf.store, dir).map_err(|e| format!("{}", e)));
+            let count = try!(replace_all(store, &target).map_err(|e| format!("{}", e)));
+            println!("{} entries backed up to {}", count, dir);
+            Ok(())
+        },
+        ("restore", Some(dir)) => {
+            if !Path::new(dir).exists() {
+                return Err(format!("backup `{}` not found", dir));
+            }
+            let source = try!(Store::new_at(&conf.store, dir).map_err(|e| format!("{}", e)));
+            let count = try!(replace_all(&source, store).map_err(|e| format!("{}", e)));
+            println!("{} entries restored from {}", count, dir);
+            Ok(())
+        },
+        ("export", file) => {
+            let count = match file {
+                Some(file) => {
+                    let mut out = try!(File::create(file).map_err(|e| format!("cannot create `{}`: {}", file, e)));
+                    try!(export(store, &mut out))
+                },
+                None => {
+                    let stdout = io::stdout();
+                    let mut out = stdout.lock();
+                    try!(export(store, &mut out))
+                }
+            };
+            // keep stdout clean for the dump itself.
+            let _ = writeln!(io::stderr(), "{} records exported", count);
+            Ok(())
+        },
+        ("import", file) => {
+            let count = match file {
+                Some(file) => {
+                    let f = try!(File::open(file).map_err(|e| format!("cannot open `{}`: {}", file, e)));
+                    try!(import(store, &mut BufReader::new(f)))
+                },
+                None => {
+                    let stdin = io::stdin();
+                    let mut input = stdin.lock();
+                    try!(import(store, &mut input))
+                }
+            };
+            println!("{} records imported", count);
+            Ok(())
+        },
+        ("stats", _) => {
+            let counts = try!(stats(store).map_err(|e| format!("{}", e)));
+            println!("backend: {}", conf.store.backend);
+            println!("encrypted: {}", store.keyring().is_some());
+            for &(ref name, count) in &counts {
+                println!("{:>20}: {}", name, count);
+            }
+            Ok(())
+        },
+        (command, None) if command == "backup" || command == "restore" =>
+            Err(format!("`{}` requires backup location", command)),
+        (command, _) => Err(format!("unknown command `{}`", command))
+    }
+}
+
+/**
+ * Commands working on store files, run instead of `run` without opening the store.
+ */
+pub fn is_file_command(conf:&Conf, command:&str) -> bool {
+    conf.store.backend == "rocksdb" && (command == "backup" || command == "restore")
+}
+
+/**
+ * Backup and restore of RocksDB store by its backup engine,
+ * consistent at single point in time without reading every record.
+ */
+pub fn run_files(conf:&Conf, command:&str, args:&[String]) -> Result<(), String> {
+    let path = &conf.store.path;
+
+    match (command, args.first()) {
+        ("backup", Some(dir)) => {
+            if Path::new(dir).exists() {
+                return Err(format!("`{}` already exists, backup needs new location", dir));
+            }
+            try!(rocks::backup(path, dir).map_err(|e| format!("{}", e)));
+            println!("{} backed up to {}", path, dir);
+            Ok(())
+        },
+        ("restore", Some(dir)) => {
+            if !Path::new(dir).exists() {
+                return Err(format!("backup `{}` not found", dir));
+            }
+            try!(rocks::restore(dir, path).map_err(|e| format!("{}", e)));
+            println!("{} restored from {}", path, dir);
+            Ok(())
+        },
+        (command, None) => Err(format!("`{}` requires backup location", command)),
+        (command, _) => Err(format!("unknown command `{}`", command))
+    }
+}
+
+/**
+ * Check what config validation can't: keys and key files parse,
+ * config itself already validated when loaded.
+ */
+pub fn check_config(conf:&Conf) -> Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+
+    if let Err(e) = Keyring::load(&conf.store) {
+        errors.push(format!("{}", e));
+    }
+    if let Err(e) = jwt::Signer::load(&conf.token) {
+        errors.push(format!("Invalid signing key configuration: {}", e));
+    }
+    if conf.security.csrf_secret.is_empty() {
+        println!("warning: `security.csrf_secret` not set, login forms invalidated on restart");
+    }
+    if conf.admin.api_key.is_empty() {
+        println!("warning: `admin.api_key` not set, admin API disabled");
+    }
+
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
+/**
+ * Replace every entry in `to` with entries of `from` in single batch,
+ * values copied as is (still encrypted when encryption enabled).
+ * Scan reads from single consistent view of `from`.
+ * Replication bookkeeping of neither side is copied or kept.
+ */
+pub fn replace_all(from:&Store, to:&Store) -> StoreResult<usize> {
+    let entries:Vec<_> = try!(from.scan("")).into_iter()
+        .filter(|&(ref key, _)| !key.starts_with(META_PREFIX))
+        .collect();
+    let existing = try!(to.scan(""));
+
+    let mut batch = to.batch();
+    for (key, _) in existing {
+        batch = batch.del(&key);
+    }
+    for &(ref key, ref value) in &entries {
+        batch = batch.put(key, value);
+    }
+    try!(batch.commit());
+
+    Ok(entries.len())
+}
+
+/**
+ * Write every record as JSON line: `{"type":"session","key":"session/...","hashed":false,"data":{...}}`,
+ * `hashed` tells the id in key is keyed hash (store encrypted at rest).
+ */
+pub fn export(store:&Store, out:&mut Write) -> Result<usize, String> {
+    let mut count = 0;
+
+    for ns in NAMESPACES.iter() {
+        for (key, value) in try!(store.scan(&ns.prefix()).map_err(|e| format!("{}", e))) {
+            let plain = try!(store.unseal(&key, &value).map_err(|e| format!("{}", e)));
+            let data = try!(Json::from_str(&plain).map_err(|e| format!("value of `{}`: {}", key, e)));
+
+            let mut line = BTreeMap::new();
+            line.insert("type".to_string(), Json::String(ns.name().to_string()));
+            line.insert("key".to_string(), Json::String(key));
+            line.insert("hashed".to_string(), Json::Boolean(ns.is_secret() && store.keyring().is_some()));
+            line.insert("data".to_string(), data);
+
+            try!(writeln!(out, "{}", Json::Object(line)).map_err(|e| format!("{}", e)));
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/**
+ * Load records written by `export`, all or nothing.
+ */
+pub fn import(store:&Store, input:&mut BufRead) -> Result<usize, String> {
+    let mut batch = store.batch();
+    let mut count = 0;
+
+    for (n, line) in input.lines().enumerate() {
+        let line = try!(line.map_err(|e| format!("{}", e)));
+        if line.trim().is_empty() {
+            continue;
+        }
+        let err = |desc:&str| format!("line {}: {}", n + 1, desc);
+
+        let json = try!(Json::from_str(&line).map_err(|e| err(&format!("{}", e))));
+        let key = try!(json.find("key").and_then(|k| k.as_string()).ok_or(err("missing `key`")));
+        let data = try!(json.find("data").ok_or(err("missing `data`"))).to_string();
+        let hashed = json.find("hashed").and_then(|h| h.as_boolean()).unwrap_or(false);
+
+        let ns = try!(NAMESPACES.iter().find(|ns| key.starts_with(&ns.prefix()))
+            .ok_or(err(&format!("unknown record type of `{}`", key))));
+
+        batch = if hashed {
+            if store.keyring().is_none() {
+                return Err(err("exported from encrypted store, configure the same encryption keys"));
+            }
+            batch.put_sealed(key, &data)
+        }else{
+            // plain id, hashed by the store when needed.
+            batch.put_encoded(*ns, &key[ns.prefix().len()..], &data)
+        };
+        count += 1;
+    }
+
+    try!(batch.commit().map_err(|e| format!("{}", e)));
+    Ok(count)
+}
+
+/**
+ * Number of entries per record type.
+ */
+pub fn stats(store:&Store) -> StoreResult<Vec<(String, usize)>> {
+    let total = try!(store.scan("")).len();
+    let mut counts = Vec::new();
+    let mut known = 0;
+
+    for ns in NAMESPACES.iter() {
+        let count = try!(store.scan(&ns.prefix())).len();
+        known += count;
+        counts.push((ns.name().to_string(), count));
+    }
+    counts.push(("other".to_string(), total - known));
+    counts.push(("total".to_string(), total));
+
+    Ok(counts)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use store::{Store, MemoryStore, Keyring, Namespace, TokenRecord, UserIndex};
+    use super::*;
+
+    const KEY1:&'static str = "k1:AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    fn memory_store(key:Option<&str>) -> Store {
+        Store::with_backend(Box::new(MemoryStore::new()))
+            .with_keyring(key.map(|k| Keyring::parse(k).unwrap()))
+    }
+
+    fn fill(store:&Store) {
+        store.batch()
+            .put_record(Namespace::Token, "token1", &TokenRecord { uid: "robin".to_string(), dn: "dc=example,dc=com".to_string() })
+            .put_record(Namespace::User, "robin", &UserIndex { token: "token1".to_string() })
+            .put("meta/schema_version", "1")
+            .commit().unwrap();
+    }
+
+    fn dump(store:&Store) -> String {
+        let mut out = Vec::new();
+        assert_eq!(export(store, &mut out).unwrap(), 2);
+        String::from_utf8(out).unwrap()
+    }
+
+    fn check(store:&Store) {
+        assert_eq!(store.get_record::<TokenRecord>(Namespace::Token, "token1").unwrap().unwrap().uid, "robin");
+        assert_eq!(store.get_record::<UserIndex>(Namespace::User, "robin").unwrap().unwrap().token, "token1");
+    }
+
+    #[test]
+    fn export_import_plain() {
+        let source = memory_store(None);
+        fill(&source);
+
+        let data = dump(&source);
+        assert!(data.contains(r#""key":"token/token1""#));
+
+        let target = memory_store(None);
+        assert_eq!(import(&target, &mut Cursor::new(data)).unwrap(), 2);
+        check(&target);
+    }
+
+    #[test]
+    fn export_import_encrypted() {
+        let source = memory_store(Some(KEY1));
+        fill(&source);
+
+        // decrypted in the dump, but token id stays hashed
+        let data = dump(&source);
+        assert!(data.contains("dc=example,dc=com"));
+        assert!(!data.contains("token/token1"));
+
+        let target = memory_store(Some(KEY1));
+        import(&target, &mut Cursor::new(data.clone())).unwrap();
+        check(&target);
+
+        assert!(import(&memory_store(None), &mut Cursor::new(data)).is_err());
+    }
+
+    #[test]
+    fn import_plain_into_encrypted() {
+        let source = memory_store(None);
+        fill(&source);
+
+        let target = memory_store(Some(KEY1));
+        import(&target, &mut Cursor::new(dump(&source))).unwrap();
+        check(&target);
+        assert!(target.scan("").unwrap().iter().all(|&(ref k, ref v)| !k.contains("token1") && v.starts_with("enc:")));
+    }
+
+    #[test]
+    fn import_rejects_malformed() {
+        let target = memory_store(None);
+        assert!(import(&target, &mut Cursor::new("{\"key\":\"token/a\",\"data\":{}}\nnot json\n")).is_err());
+        assert!(import(&target, &mut Cursor::new("{\"key\":\"unknown/a\",\"data\":{}}\n")).is_err());
+        // nothing written on failure
+        assert!(target.scan("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn replace_and_stats() {
+        let source = memory_store(Some(KEY1));
+        fill(&source);
+
+        let target = memory_store(None);
+        target.put("leftover", "x").unwrap();
+        assert_eq!(replace_all(&source, &target).unwrap(), 3);
+        assert_eq!(target.scan("").unwrap(), source.scan("").unwrap());
+
+        let counts = stats(&target).unwrap();
+        let count = |name:&str| counts.iter().find(|c| c.0 == name).unwrap().1;
+        assert_eq!(count("token"), 1);
+        assert_eq!(count("user"), 1);
+        assert_eq!(count("session"), 0);
+        assert_eq!(count("other"), 1);
+        assert_eq!(count("total"), 3);
+    }
+
+    #[test]
+    fn replace_skips_replication_bookkeeping() {
+        let source = memory_store(None);
+        fill(&source);
+        source.put("_repl/seq", "42").unwrap();
+
+        let target = memory_store(None);
+        target.put("_repl/ack/http://peer", "7").unwrap();
+        assert_eq!(replace_all(&source, &target).unwrap(), 3);
+        assert!(target.scan("_repl/").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+
+// Authentication of registered service clients (`[clients.<id>]` with `secret`
+// or `api_key`) calling the API, eg: token lookup.
+//
+//     Authorization: Basic base64(<client id>:<secret>)
+//     X-Api-Key: <api key>
+//
+// With API key the `Authorization` header is free to carry the user's token
+// as `Bearer <token>`, with Basic the token has to be in the request body.
+
+use std::str;
+
+use nickel::hyper::header::Headers;
+use serialize::base64::FromBase64;
+
+use config::ClientConf;
+use token;
+
+pub const API_KEY_HEADER:&'static str = "X-Api-Key";
+
+fn header<'a>(headers:&'a Headers, name:&str) -> Option<&'a str> {
+    headers.get_raw(name)
+        .and_then(|v| v.first())
+        .and_then(|v| str::from_utf8(v).ok())
+        .map(|v| v.trim())
+}
+
+// value of `Authorization` header with given scheme, scheme is case-insensitive.
+fn authorization<'a>(headers:&'a Headers, scheme:&str) -> Option<&'a str> {
+    header(headers, "Authorization").and_then(|value| {
+        let mut parts = value.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some(s), Some(credentials)) if s.eq_ignore_ascii_case(scheme) => Some(credentials.trim()),
+            _ => None
+        }
+    })
+}
+
+/**
+ * Registered client authenticated by the request, None when credentials
+ * missing or wrong.
+ */
+pub fn authenticate<'a>(clients:&'a [ClientConf], headers:&Headers) -> Option<&'a ClientConf> {
+    if let Some(key) = header(headers, API_KEY_HEADER) {
+        return clients.iter().find(|c| !c.api_key.is_empty() && token::eq(key, &c.api_key));
+    }
+
+    let decoded = match authorization(headers, "Basic").and_then(|c| c.from_base64().ok()) {
+        Some(decoded) => decoded,
+        None => return None
+    };
+    let decoded = match String::from_utf8(decoded) {
+        Ok(decoded) => decoded,
+        Err(_) => return None
+    };
+    let mut parts = decoded.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(id), Some(secret)) => clients.iter()
+            .find(|c| c.id == id && !c.secret.is_empty() && token::eq(secret, &c.secret)),
+        _ => None
+    }
+}
+
+/**
+ * Token given as `Authorization: Bearer <token>`.
+ */
+pub fn bearer_token(headers:&Headers) -> Option<String> {
+    authorization(headers, "Bearer").map(|t| t.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use nickel::hyper::header::Headers;
+    use serialize::base64::{self, ToBase64};
+    use config::ClientConf;
+    use super::*;
+
+    fn clients() -> Vec<ClientConf> {
+        let client = |id:&str, secret:&str, api_key:&str| ClientConf {
+            id: id.to_string(),
+            host: String::new(),
+            continue_urls: Vec::new(),
+            backchannel_logout_url: String::new(),
+            frontchannel_logout_url: String::new(),
+            secret: secret.to_string(),
+            api_key: api_key.to_string(),
+            release_attributes: Vec::new(),
+            tenants: Vec::new()
+        };
+        vec![client("billing", "billing-secret-0123", ""), client("crm", "", "crm-api-key-0123456"),
+             client("app", "", "")]
+    }
+
+    fn headers(pairs:&[(&str, &str)]) -> Headers {
+        let mut headers = Headers::new();
+        for &(name, value) in pairs {
+            headers.set_raw(name.to_string(), vec![value.as_bytes().to_vec()]);
+        }
+        headers
+    }
+
+    fn basic(credentials:&str) -> String {
+        format!("Basic {}", credentials.as_bytes().to_base64(base64::STANDARD))
+    }
+
+    #[test]
+    fn basic_credentials() {
+        let clients = clients();
+        let found = authenticate(&clients, &headers(&[("Authorization", &basic("billing:billing-secret-0123"))]));
+        assert_eq!(found.unwrap().id, "billing");
+
+        for value in &[basic("billing:wrong"), basic("billing"), basic("app:"), basic("crm:crm-api-key-0123456"),
+                       "Basic !!!".to_string(), "Bearer billing-secret-0123".to_string()] {
+            assert!(authenticate(&clients, &headers(&[("Authorization", value)])).is_none(), "{}", value);
+        }
+        assert!(authenticate(&clients, &headers(&[])).is_none());
+    }
+
+    #[test]
+    fn api_key() {
+        let clients = clients();
+        let found = authenticate(&clients, &headers(&[(API_KEY_HEADER, "crm-api-key-0123456"),
+                                                      ("Authorization", "Bearer user-token")]));
+        assert_eq!(found.unwrap().id, "crm");
+        assert!(authenticate(&clients, &headers(&[(API_KEY_HEADER, "")])).is_none());
+        // API key given, Basic isn't tried
+        assert!(authenticate(&clients, &headers(&[(API_KEY_HEADER, "wrong"),
+                                                  ("Authorization", &basic("billing:billing-secret-0123"))])).is_none());
+    }
+
+    #[test]
+    fn bearer() {
+        assert_eq!(bearer_token(&headers(&[("Authorization", "Bearer abc")])), Some("abc".to_string()));
+        assert_eq!(bearer_token(&headers(&[("Authorization", "bearer  abc ")])), Some("abc".to_string()));
+        assert_eq!(bearer_token(&headers(&[("Authorization", &basic("a:b"))])), None);
+        assert_eq!(bearer_token(&headers(&[])), None);
+    }
+}
@@ -1,29 +1,258 @@
 
 use std::fs::File;
 use std::io::Read;
+use std::collections::{BTreeMap, HashMap};
+use std::{env, fmt};
 
 use toml;
 use toml::Value;
+use toml::value::Table;
+use serde_path_to_error;
+
+use token;
+use listener;
+use continue_url;
+use tenant;
+use directory;
 
 const DEFAULT_DB_STORE:&'static str = "/tmp/sso-store";
+const DEFAULT_LISTEN:&'static str = "127.0.0.1:8080";
+
+// Environment variable overriding config value: `SSO_<SECTION>__<KEY>`,
+// eg: `SSO_LDAP__ADMIN_PASSWORD`, top level keys without section: `SSO_DATA_STORE`.
+pub const ENV_PREFIX:&'static str = "SSO_";
+
+// suffix of key holding path to file with the secret, eg: `admin_password_file`.
+const SECRET_FILE_SUFFIX:&'static str = "_file";
+
+// secrets which may be read from `<key>_file` instead.
+const SECRETS:&'static [(&'static str, &'static str)] = &[
+    ("ldap", "admin_password"), ("admin", "api_key"), ("security", "csrf_secret"),
+    ("replication", "secret"), ("metrics", "bearer_token"),
+];
+
+// same, in every `[clients.<id>]` or `[tenants.<id>]` sub-section.
+const DYNAMIC_SECRETS:&'static [(&'static str, &'static str)] = &[
+    ("clients", "secret"), ("clients", "api_key"), ("tenants", "admin_password"),
+];
+
+// guessing secret of service client is as good as guessing tokens.
+const MIN_CLIENT_SECRET_LEN:usize = 16;
+
+/**
+ * Invalid configuration value, `line` in config file when known.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfError {
+    pub key: String,
+    pub line: Option<usize>,
+    pub desc: String
+}
+
+impl ConfError {
+    fn new(key:&str, line:Option<usize>, desc:String) -> ConfError {
+        ConfError { key: key.to_string(), line: line, desc: desc }
+    }
+}
+
+impl fmt::Display for ConfError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        if let Some(line) = self.line {
+            try!(write!(f, "line {}: ", line));
+        }
+        if self.key.is_empty() {
+            write!(f, "{}", self.desc)
+        }else{
+            write!(f, "`{}` {}", self.key, self.desc)
+        }
+    }
+}
+
+fn join_key(section:&str, key:&str) -> String {
+    match (section.is_empty(), key.is_empty()) {
+        (true, _) => key.to_string(),
+        (false, true) => section.to_string(),
+        (false, false) => format!("{}.{}", section, key)
+    }
+}
+
+/**
+ * Line number of `key` in `[section]` of config text, or of `[section.key]` header.
+ */
+fn key_line(data:&str, section:&str, key:&str) -> Option<usize> {
+    let mut current = String::new();
+
+    for (n, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            current = line.trim_matches(|c| c == '[' || c == ']').trim().to_string();
+            // section itself, or key holding table
+            if current == join_key(section, key) {
+                return Some(n + 1);
+            }
+        }else if !key.is_empty() && current == section && line.contains('=') {
+            let name = line.split('=').next().unwrap_or("").trim().trim_matches('"');
+            if name == key {
+                return Some(n + 1);
+            }
+        }
+    }
+    None
+}
+
+// toml appends key and line to description, `ConfError` shows them itself.
+fn toml_desc(e:&toml::de::Error) -> String {
+    let desc = e.to_string();
+    let end = desc.find(" for key `").or_else(|| desc.find(" at line ")).unwrap_or(desc.len());
+    desc[..end].to_string()
+}
+
+/**
+ * Error deserializing value at `path`, eg: `clients.app.logout`.
+ */
+fn path_error(data:&str, path:&str, e:&toml::de::Error) -> ConfError {
+    // item of list reported as the list
+    let path = match path.split('[').next().unwrap_or("") {
+        "." => "",
+        path => path
+    };
+    let (section, key) = match path.rfind('.') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path)
+    };
+    ConfError::new(path, key_line(data, section, key), toml_desc(e))
+}
+
+fn section_mut<'a>(toml:&'a mut Table, section:&str) -> Option<&'a mut Table> {
+    if section.is_empty() {
+        return Some(toml);
+    }
+    // not a table reported when deserialized
+    match *toml.entry(section.to_string()).or_insert(Value::Table(Table::new())) {
+        Value::Table(ref mut tbl) => Some(tbl),
+        _ => None
+    }
+}
 
-// inline simple read parsed toml object macro
-macro_rules! simple_toml_read {
-    ($toml:ident, $a:expr, $dflt:expr) => {
-        match $toml.get($a){
-            Some(&Value::String(ref s)) => s.to_string(),
-            _ => $dflt
+fn env_name(section:&str, key:&str) -> String {
+    if section.is_empty() {
+        format!("{}{}", ENV_PREFIX, key.to_uppercase())
+    }else{
+        format!("{}{}__{}", ENV_PREFIX, section.to_uppercase(), key.to_uppercase())
+    }
+}
+
+// parse environment variable as the type of default value, `Err` describes expected type.
+fn env_value(raw:&str, dflt:&Value) -> Result<Value, &'static str> {
+    match *dflt {
+        Value::Integer(_) => raw.trim().parse::<u64>().map(|i| Value::Integer(i as i64))
+            .map_err(|_| "should be non-negative integer"),
+        Value::Boolean(_) => match raw.trim() {
+            "true" | "1" => Ok(Value::Boolean(true)),
+            "false" | "0" => Ok(Value::Boolean(false)),
+            _ => Err("should be true or false")
+        },
+        Value::Array(_) => Ok(Value::Array(raw.split(',')
+            .map(|v| v.trim()).filter(|v| !v.is_empty())
+            .map(|v| Value::String(v.to_string())).collect())),
+        _ => Ok(Value::String(raw.to_string()))
+    }
+}
+
+/**
+ * Override config values by `SSO_<SECTION>__<KEY>` environment variables,
+ * every key of `ConfFile` default can be overridden.
+ */
+fn apply_env(toml:&mut Table, env:&HashMap<String, String>) -> Vec<ConfError> {
+    let mut errors = Vec::new();
+
+    let mut keys = Vec::new();
+    if let Ok(Value::Table(defaults)) = Value::try_from(ConfFile::default()) {
+        for (name, value) in defaults {
+            match value {
+                // `clients` and `tenants` empty by default
+                Value::Table(section) => keys.extend(section.into_iter().map(|(key, dflt)| (name.clone(), key, dflt))),
+                dflt => keys.push((String::new(), name, dflt))
+            }
         }
+    }
+    for &(section, key) in SECRETS {
+        keys.push((section.to_string(), format!("{}{}", key, SECRET_FILE_SUFFIX), Value::String(String::new())));
+    }
+
+    for (section, key, dflt) in keys {
+        let name = env_name(&section, &key);
+        let raw = match env.get(&name) {
+            Some(raw) => raw,
+            None => continue
+        };
+        match env_value(raw, &dflt) {
+            Ok(value) => {
+                debug!("`{}` overridden by {}", join_key(&section, &key), name);
+                if let Some(tbl) = section_mut(toml, &section) {
+                    tbl.insert(key.clone(), value);
+                }
+            },
+            Err(desc) => errors.push(ConfError::new(&join_key(&section, &key), None, format!("{} (from {})", desc, name)))
+        }
+    }
+    errors
+}
+
+fn read_secret_file(errors:&mut Vec<ConfError>, tbl:&mut Table, data:&str, section:&str, key:&str) {
+    let file_key = format!("{}{}", key, SECRET_FILE_SUFFIX);
+
+    let path = match tbl.remove(&file_key) {
+        Some(Value::String(path)) => path,
+        Some(other) => {
+            // type error reported when deserialized
+            tbl.insert(file_key, other);
+            return;
+        },
+        None => return
     };
-    ($toml:ident, $tbl:expr, $a:tt, $dflt:expr) => {
-        match $toml.get($tbl){
-            Some(&Value::Table(ref _tbl)) => simple_toml_read!(_tbl, $a, $dflt),
-            _ => $dflt
+    let err = |desc:String| ConfError::new(&join_key(section, &file_key), key_line(data, section, &file_key), desc);
+
+    if tbl.contains_key(key) {
+        errors.push(err(format!("can't be used together with `{}`", key)));
+        return;
+    }
+    let mut secret = String::new();
+    match File::open(&path).and_then(|mut f| f.read_to_string(&mut secret)) {
+        Ok(_) => {
+            let secret = secret.trim_right_matches(|c| c == '\n' || c == '\r').to_string();
+            tbl.insert(key.to_string(), Value::String(secret));
+        },
+        Err(e) => errors.push(err(format!("cannot read `{}`: {}", path, e)))
+    }
+}
+
+/**
+ * Replace secret `<key>_file` by content of the file (trailing new line removed).
+ */
+fn read_secret_files(toml:&mut Table, data:&str) -> Vec<ConfError> {
+    let mut errors = Vec::new();
+
+    for &(section, key) in SECRETS {
+        if let Some(&mut Value::Table(ref mut tbl)) = toml.get_mut(section) {
+            read_secret_file(&mut errors, tbl, data, section, key);
         }
     }
+
+    for &(name, key) in DYNAMIC_SECRETS {
+        if let Some(&mut Value::Table(ref mut sections)) = toml.get_mut(name) {
+            for (id, tbl) in sections.iter_mut() {
+                if let Value::Table(ref mut tbl) = *tbl {
+                    read_secret_file(&mut errors, tbl, data, &format!("{}.{}", name, id), key);
+                }
+            }
+        }
+    }
+    errors
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct LdapConf {
     pub uri:String,
     pub default_dn:String,
@@ -42,11 +271,353 @@ impl Default for LdapConf {
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebauthnConf {
+    pub rp_id:String,
+    pub rp_name:String,
+    pub origin:String,
+}
+
+impl Default for WebauthnConf {
+    fn default() -> WebauthnConf {
+        WebauthnConf {
+            rp_id: String::new(),
+            rp_name: String::new(),
+            origin: String::new()
+        }
+    }
+}
+
+impl WebauthnConf {
+    // WebAuthn second factor only active when relying party is configured.
+    pub fn enabled(&self) -> bool {
+        !self.rp_id.is_empty() && !self.origin.is_empty()
+    }
+}
+
+// Brute-force protection for `/login`.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThrottleConf {
+    pub window_secs:u64,            // sliding window for counting failed attempts
+    pub max_attempts_per_user:u64,  // failures before username get locked
+    pub max_attempts_per_ip:u64,    // failures before client ip get locked
+    pub lockout_secs:u64,
+    pub delay_millis:u64,           // per failure in window, earlier retry refused (429)
+    pub max_delay_millis:u64,
+}
+
+impl Default for ThrottleConf {
+    fn default() -> ThrottleConf {
+        ThrottleConf {
+            window_secs: 15 * 60,
+            max_attempts_per_user: 5,
+            max_attempts_per_ip: 20,
+            lockout_secs: 15 * 60,
+            delay_millis: 500,
+            max_delay_millis: 5000
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConf {
+    pub api_key:String
+}
+
+impl Default for AdminConf {
+    fn default() -> AdminConf {
+        AdminConf {
+            api_key: String::new()
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConf {
+    pub csrf_secret:String,   // random one generated on startup when not set
+    pub secure_cookies:bool,  // set `Secure` flag on cookies, disable only for plain http development
+    pub hsts_max_age:u64,     // 0 to disable `Strict-Transport-Security` header
+}
+
+impl Default for SecurityConf {
+    fn default() -> SecurityConf {
+        SecurityConf {
+            csrf_secret: String::new(),
+            secure_cookies: true,
+            hsts_max_age: 31536000
+        }
+    }
+}
+
+// Browser SSO session.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConf {
+    pub ttl_secs:u64
+}
+
+impl Default for SessionConf {
+    fn default() -> SessionConf {
+        SessionConf {
+            ttl_secs: 8 * 60 * 60
+        }
+    }
+}
+
+// Opaque token format, `[token]` section.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TokenConf {
+    pub bytes:u64,        // random bytes per token
+    pub encoding:String,  // base64url (default) or hex
+    pub prefixed:bool,    // `sso_at_`/`sso_rt_` prefix and checksum, recognized by secret scanners
+    pub format:String,    // opaque (default), or jwt to also issue signed access token
+    pub jwt_ttl_secs:u64, // signed access token lifetime
+    pub issuer:String,    // `iss` claim, eg: https://sso.example.com
+    pub signing_key:String,      // `<key-id>:<base64 32 bytes Ed25519 seed>`, comma separated, first one signs
+    pub signing_key_file:String, // same format one key per line, used instead of `signing_key`
+}
+
+impl Default for TokenConf {
+    fn default() -> TokenConf {
+        TokenConf {
+            bytes: 32,
+            encoding: "base64url".to_string(),
+            prefixed: false,
+            format: "opaque".to_string(),
+            jwt_ttl_secs: 5 * 60,
+            issuer: String::new(),
+            signing_key: String::new(),
+            signing_key_file: String::new()
+        }
+    }
+}
+
+impl TokenConf {
+    pub fn signed(&self) -> bool {
+        self.format == "jwt"
+    }
+}
+
+// Session store backend, `[store]` section.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreConf {
+    pub backend:String,      // rocksdb (default), memory, sqlite or redis
+    #[serde(default)]
+    pub path:String,         // rocksdb directory or sqlite file, default to `data_store`
+    pub redis_url:String,    // eg: redis://:password@127.0.0.1:6379/0
+    pub redis_prefix:String, // prefix for every redis key
+    pub encryption_key:String,      // `<key-id>:<base64 32 bytes>`, comma separated, first one active
+    pub encryption_key_file:String, // same format one key per line, used instead of `encryption_key`
+    #[serde(skip)]
+    pub replication: ReplicationConf // read from `[replication]` section
+}
+
+// Store replication between SSO nodes, `[replication]` section.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplicationConf {
+    pub node_id:String,          // unique name of this node
+    pub peers:Vec<String>,       // base url of every other node, eg: http://10.0.0.2:8080
+    pub secret:String,           // shared by all nodes, signs replication requests
+    pub retry_millis:u64,        // delay before resending to unreachable peer
+    pub tombstone_ttl_secs:u64,  // how long deletions remembered
+    pub wal_max_entries:u64,     // log kept for unreachable peers, older entries dropped (peer needs restore)
+}
+
+impl ReplicationConf {
+    pub fn enabled(&self) -> bool {
+        !self.peers.is_empty()
+    }
+}
+
+impl Default for ReplicationConf {
+    fn default() -> ReplicationConf {
+        ReplicationConf {
+            node_id: String::new(),
+            peers: Vec::new(),
+            secret: String::new(),
+            retry_millis: 1000,
+            tombstone_ttl_secs: 7 * 24 * 3600,
+            wal_max_entries: 1000000
+        }
+    }
+}
+
+impl Default for StoreConf {
+    fn default() -> StoreConf {
+        StoreConf {
+            backend: "rocksdb".to_string(),
+            path: DEFAULT_DB_STORE.to_string(),
+            redis_url: "redis://127.0.0.1:6379/0".to_string(),
+            redis_prefix: "sso:".to_string(),
+            encryption_key: String::new(),
+            encryption_key_file: String::new(),
+            replication: Default::default()
+        }
+    }
+}
+
+// HTTP listeners, `[http]` section.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConf {
+    #[serde(default)]
+    pub listen:Vec<String>,       // plain http `ip:port`, 127.0.0.1:8080 when no listener configured
+    pub https_listen:Vec<String>, // https `ip:port`, needs `tls_cert` and `tls_key`
+    pub tls_cert:String,          // PEM certificate chain, reloaded on SIGHUP
+    pub tls_key:String,           // PEM private key, reloaded on SIGHUP
+    pub unix_socket:String,       // unix domain socket path for local reverse proxy
+}
+
+impl Default for HttpConf {
+    fn default() -> HttpConf {
+        HttpConf {
+            listen: vec![DEFAULT_LISTEN.to_string()],
+            https_listen: Vec::new(),
+            tls_cert: String::new(),
+            tls_key: String::new(),
+            unix_socket: String::new()
+        }
+    }
+}
+
+// User profiles served by the API, `[directory]` section.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DirectoryConf {
+    pub attributes:Vec<String>, // read from user entry, default when empty
+    pub cache_secs:u64,         // profiles and search results cached per node, 0 disables
+    pub search_filter:String,   // `{q}` replaced by escaped query
+    pub search_attributes:Vec<String>, // in search results, some of `attributes`
+    pub search_min_length:u64,  // shortest query
+    pub search_max_results:u64, // LDAP size limit, query matching more should be refined
+    pub search_page_size:u64,   // default and largest `limit`
+    pub self_attributes:Vec<String>, // released to the user themself (`/api/v1/me` with token only)
+}
+
+impl Default for DirectoryConf {
+    fn default() -> DirectoryConf {
+        DirectoryConf {
+            attributes: vec!["uid".to_string(), "cn".to_string(), "displayName".to_string(), "mail".to_string()],
+            cache_secs: 60,
+            search_filter: "(|(uid={q}*)(cn=*{q}*)(mail={q}*))".to_string(),
+            search_attributes: vec!["uid".to_string(), "cn".to_string(), "mail".to_string()],
+            search_min_length: 2,
+            search_max_results: 200,
+            search_page_size: 20,
+            self_attributes: vec!["uid".to_string(), "cn".to_string(), "displayName".to_string(), "mail".to_string()]
+        }
+    }
+}
+
+// Audit log of authentication events, `[audit]` section, applied on start.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConf {
+    pub file:String,          // JSON lines, not written when empty
+    pub max_file_bytes:u64,   // rotated to `<file>.1` when exceeded
+    pub max_files:u64,        // rotated files kept
+    pub syslog:bool,          // also send to local syslog, facility authpriv
+    pub syslog_socket:String,
+    pub retention_secs:u64,   // kept in store for `/api/v1/admin/audit`, 0 disables
+}
+
+impl Default for AuditConf {
+    fn default() -> AuditConf {
+        AuditConf {
+            file: String::new(),
+            max_file_bytes: 10 * 1024 * 1024,
+            max_files: 5,
+            syslog: false,
+            syslog_socket: "/dev/log".to_string(),
+            retention_secs: 30 * 24 * 3600
+        }
+    }
+}
+
+// Prometheus `/metrics` endpoint, `[metrics]` section.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConf {
+    pub enabled:bool,         // route registered on start
+    pub bearer_token:String,  // required from scraper as `Authorization: Bearer`, open when empty
+}
+
+impl Default for MetricsConf {
+    fn default() -> MetricsConf {
+        MetricsConf {
+            enabled: false,
+            bearer_token: String::new()
+        }
+    }
+}
+
+// Registered relying application, `[clients.<id>]` section.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConf {
+    #[serde(skip)]
+    pub id:String,                      // key of the section
+    pub host:String,                    // continue url host this client receive tokens on
+    pub continue_urls:Vec<String>,      // allowed continue targets of this client, see `continue_url`
+    pub backchannel_logout_url:String,  // server-to-server logout notification
+    pub frontchannel_logout_url:String, // loaded in iframe on logout page
+    pub secret:String,                  // service authentication with HTTP Basic `<id>:<secret>`
+    pub api_key:String,                 // or with `X-Api-Key` header
+    pub release_attributes:Vec<String>, // profile attributes this client may read
+    pub tenants:Vec<String>,            // tenants allowing `continue_urls`, default tenant only when empty
+}
+
+impl ClientConf {
+    /**
+     * Whether continue urls of this client are allowed for tenant `id`.
+     */
+    pub fn serves_tenant(&self, id:&str) -> bool {
+        if self.tenants.is_empty() {
+            id == tenant::DEFAULT_ID
+        } else {
+            self.tenants.iter().any(|t| t == id)
+        }
+    }
+}
+
+// Named tenant with its own directory and branding, `[tenants.<id>]` section.
+#[derive(Clone)]
+pub struct TenantConf {
+    pub id:String,
+    pub hosts:Vec<String>,      // selected by request host, eg: sso.acme.com
+    pub path_prefix:String,     // or by path prefix, eg: /acme (login page at /acme/)
+    pub ldap:LdapConf,          // `ldap_uri`, `base_dn`, `admin_user` and `admin_password` keys
+    pub login_caption:String,   // default to top level `login_caption`
+    pub logo:String,            // image on login page, eg: /assets/img/acme.png
+    pub allowed_continue_urls:Vec<String>,
+}
+
 #[derive(Clone)]
 pub struct Conf {
     pub data_store:String,
-    pub allowed_continue_domain:String,
+    pub store: StoreConf,
+    pub allowed_continue_urls:Vec<String>, // see `continue_url` for pattern format
+    pub allowed_continue_domain:String,    // deprecated, `example.com|.?example.org`
     pub ldap: LdapConf,
+    pub webauthn: WebauthnConf,
+    pub throttle: ThrottleConf,
+    pub admin: AdminConf,
+    pub security: SecurityConf,
+    pub session: SessionConf,
+    pub token: TokenConf,
+    pub http: HttpConf,
+    pub directory: DirectoryConf,
+    pub audit: AuditConf,
+    pub metrics: MetricsConf,
+    pub clients: Vec<ClientConf>,
+    pub tenants: Vec<TenantConf>, // besides the default one from `[ldap]` section
     pub login_caption:String
 }
 
@@ -54,46 +625,598 @@ impl Default for Conf {
     fn default() -> Conf {
         Conf {
             data_store: String::new(),
+            store: Default::default(),
+            allowed_continue_urls: Vec::new(),
             allowed_continue_domain: String::new(),
             ldap: Default::default(),
+            webauthn: Default::default(),
+            throttle: Default::default(),
+            admin: Default::default(),
+            security: Default::default(),
+            session: Default::default(),
+            token: Default::default(),
+            http: Default::default(),
+            directory: Default::default(),
+            audit: Default::default(),
+            metrics: Default::default(),
+            clients: Vec::new(),
+            tenants: Vec::new(),
             login_caption: String::new()
         }
     }
 }
 
+// `[tenants.<id>]` section as written, see `TenantConf`.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TenantSection {
+    hosts:Vec<String>,
+    path_prefix:String,
+    ldap_uri:String,
+    base_dn:String,
+    admin_user:String,
+    admin_password:String,
+    login_caption:Option<String>,
+    logo:String,
+    allowed_continue_urls:Vec<String>,
+}
+
+// Config file layout, see `into_conf` for values derived from others.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfFile {
+    #[serde(default = "default_data_store")]
+    data_store:String,
+    allowed_continue_urls:Vec<String>,
+    allowed_continue_domain:String,
+    login_caption:String,
+    ldap:LdapConf,
+    webauthn:WebauthnConf,
+    login_throttle:ThrottleConf,
+    admin:AdminConf,
+    security:SecurityConf,
+    session:SessionConf,
+    token:TokenConf,
+    #[serde(default = "default_store")]
+    store:StoreConf,
+    replication:ReplicationConf,
+    http:HttpConf,
+    directory:DirectoryConf,
+    audit:AuditConf,
+    metrics:MetricsConf,
+    clients:BTreeMap<String, ClientConf>,
+    tenants:BTreeMap<String, TenantSection>,
+}
+
+fn default_data_store() -> String {
+    DEFAULT_DB_STORE.to_string()
+}
+
+// `path` set from `data_store` when not in `[store]`
+fn default_store() -> StoreConf {
+    StoreConf { path: String::new(), ..Default::default() }
+}
+
+impl ConfFile {
+    fn into_conf(self) -> Conf {
+        let mut store = self.store;
+        if store.path.is_empty() {
+            store.path = self.data_store.clone();
+        }
+        store.replication = self.replication;
+
+        let mut http = self.http;
+        if http.listen.is_empty() && http.https_listen.is_empty() && http.unix_socket.is_empty() {
+            http.listen = HttpConf::default().listen;
+        }
+
+        let dflt_directory:DirectoryConf = Default::default();
+        let mut directory = self.directory;
+        if directory.attributes.is_empty() {
+            directory.attributes = dflt_directory.attributes;
+        }
+        if directory.search_attributes.is_empty() {
+            directory.search_attributes = dflt_directory.search_attributes;
+        }
+        if directory.self_attributes.is_empty() {
+            directory.self_attributes = dflt_directory.self_attributes;
+        }
+
+        let clients = self.clients.into_iter().map(|(id, client)| ClientConf { id: id, ..client }).collect();
+        let login_caption = self.login_caption;
+        let tenants = self.tenants.into_iter().map(|(id, tenant)| TenantConf {
+            id: id,
+            hosts: tenant.hosts.iter().map(|h| h.to_lowercase()).collect(),
+            path_prefix: tenant.path_prefix,
+            ldap: LdapConf {
+                uri: tenant.ldap_uri,
+                default_dn: tenant.base_dn,
+                admin_user: tenant.admin_user,
+                admin_password: tenant.admin_password,
+            },
+            login_caption: tenant.login_caption.unwrap_or_else(|| login_caption.clone()),
+            logo: tenant.logo,
+            allowed_continue_urls: tenant.allowed_continue_urls,
+        }).collect();
+
+        Conf {
+            data_store: self.data_store,
+            store: store,
+            allowed_continue_urls: self.allowed_continue_urls,
+            allowed_continue_domain: self.allowed_continue_domain,
+            ldap: self.ldap,
+            webauthn: self.webauthn,
+            throttle: self.login_throttle,
+            admin: self.admin,
+            security: self.security,
+            session: self.session,
+            token: self.token,
+            http: http,
+            directory: directory,
+            audit: self.audit,
+            metrics: self.metrics,
+            clients: clients,
+            tenants: tenants,
+            login_caption: login_caption
+        }
+    }
+}
+
 impl Conf {
 
-    pub fn read_file(path:&str) -> Conf {
+    /**
+     * Read and validate config file, environment overrides applied.
+     */
+    pub fn read_file(path:&str) -> Result<Conf, Vec<ConfError>> {
         let mut input = String::new();
-        File::open(path).and_then(|mut f| {
-            f.read_to_string(&mut input)
-        }).unwrap();
-        Conf::read_str(&input)
-    }
-
-    pub fn read_str(data:&str) -> Conf {
-        let mut parser = toml::Parser::new(data);
-        match parser.parse() {
-            Some(toml) => {
-                debug!("toml: {:?}", toml);
-
-                let data_store = simple_toml_read!(toml, "data_store", DEFAULT_DB_STORE.to_string());
-                let allowed_continue_domain = simple_toml_read!(toml, "allowed_continue_domain", "".to_string());
-                let ldap_conf = LdapConf {
-                    uri : simple_toml_read!(toml, "ldap", "uri", "".to_string()),
-                    default_dn : simple_toml_read!(toml, "ldap", "default_dn", "".to_string()),
-                    admin_user : simple_toml_read!(toml, "ldap", "admin_user", "".to_string()),
-                    admin_password : simple_toml_read!(toml, "ldap", "admin_password", "".to_string()),
-                };
-
-                Conf {
-                    data_store: data_store,
-                    allowed_continue_domain: allowed_continue_domain,
-                    ldap: ldap_conf,
-                    login_caption: simple_toml_read!(toml, "login_caption", "".to_string())
+        try!(File::open(path).and_then(|mut f| f.read_to_string(&mut input))
+            .map_err(|e| vec![ConfError::new("", None, format!("cannot read `{}`: {}", path, e))]));
+        Conf::read_str(&input, &env::vars().collect())
+    }
+
+    /**
+     * Parse and validate config, `env` overrides values, see `ENV_PREFIX`.
+     */
+    pub fn read_str(data:&str, env:&HashMap<String, String>) -> Result<Conf, Vec<ConfError>> {
+        let mut toml = match data.parse::<Value>() {
+            Ok(Value::Table(toml)) => toml,
+            Ok(_) => Table::new(),
+            Err(e) => return Err(vec![ConfError::new("", e.line_col().map(|(line, _)| line + 1), toml_desc(&e))])
+        };
+
+        let mut errors = apply_env(&mut toml, env);
+        errors.extend(read_secret_files(&mut toml, data));
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let file:ConfFile = try!(serde_path_to_error::deserialize(Value::Table(toml))
+            .map_err(|e| vec![path_error(data, &e.path().to_string(), e.inner())]));
+        let conf = file.into_conf();
+        let errors:Vec<ConfError> = conf.validate().into_iter()
+            .map(|(section, key, desc)| ConfError::new(&join_key(section, key), key_line(data, section, key), desc))
+            .collect();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(conf)
+    }
+
+    /**
+     * Semantic checks, returns `(section, key, description)` of every problem.
+     */
+    fn validate(&self) -> Vec<(&'static str, &'static str, String)> {
+        let mut errors = Vec::new();
+        let required = "required".to_string();
+
+        if self.ldap.uri.is_empty() && self.tenants.is_empty() {
+            errors.push(("ldap", "uri", required.clone()));
+        }
+        match self.store.backend.as_ref() {
+            "rocksdb" | "memory" | "sqlite" | "redis" => (),
+            other => errors.push(("store", "backend",
+                format!("unknown backend `{}`, supported: rocksdb, memory, sqlite, redis", other)))
+        }
+        if !self.webauthn.rp_id.is_empty() && self.webauthn.origin.is_empty() {
+            errors.push(("webauthn", "origin", "required when `rp_id` set".to_string()));
+        }
+        if self.session.ttl_secs == 0 {
+            errors.push(("session", "ttl_secs", "should be greater than 0".to_string()));
+        }
+        if let Err((key, desc)) = token::check_conf(&self.token) {
+            errors.push(("token", key, desc));
+        }
+
+        let replication = &self.store.replication;
+        if replication.enabled() {
+            if replication.node_id.is_empty() {
+                errors.push(("replication", "node_id", "required when `peers` set".to_string()));
+            }
+            if replication.secret.is_empty() {
+                errors.push(("replication", "secret", "required when `peers` set".to_string()));
+            }
+            if let Some(peer) = replication.peers.iter().find(|p| !p.starts_with("http://") && !p.starts_with("https://")) {
+                errors.push(("replication", "peers", format!("`{}` should be http(s) url", peer)));
+            }
+            if replication.wal_max_entries == 0 {
+                errors.push(("replication", "wal_max_entries", "should be greater than 0".to_string()));
+            }
+        }
+
+        if let Err(e) = continue_url::legacy_patterns(&self.allowed_continue_domain) {
+            errors.push(("", "allowed_continue_domain", e));
+        }
+        for pattern in &self.allowed_continue_urls {
+            if let Err(e) = continue_url::Pattern::parse(pattern) {
+                errors.push(("", "allowed_continue_urls", e));
+            }
+        }
+
+        let http = &self.http;
+        let mut addrs = Vec::new();
+        for (key, list) in vec![("listen", &http.listen), ("https_listen", &http.https_listen)] {
+            for addr in list {
+                if let Err(e) = listener::parse_addr(addr) {
+                    errors.push(("http", key, e));
+                }else if addrs.contains(&addr) {
+                    errors.push(("http", key, format!("`{}` used more than once", addr)));
                 }
-            },
-            None => Default::default()
+                addrs.push(addr);
+            }
+        }
+        if !http.https_listen.is_empty() {
+            if http.tls_cert.is_empty() {
+                errors.push(("http", "tls_cert", "required when `https_listen` set".to_string()));
+            }
+            if http.tls_key.is_empty() {
+                errors.push(("http", "tls_key", "required when `https_listen` set".to_string()));
+            }
+        }
+
+        let dir = &self.directory;
+        if let Some(name) = dir.attributes.iter().find(|a| !directory::valid_attribute(a)) {
+            errors.push(("directory", "attributes", format!("`{}` should be attribute name", name)));
+        }
+        for &(key, list) in &[("search_attributes", &dir.search_attributes), ("self_attributes", &dir.self_attributes)] {
+            if let Some(name) = list.iter().find(|a| !dir.attributes.iter().any(|b| b.eq_ignore_ascii_case(a))) {
+                errors.push(("directory", key, format!("`{}` not in `attributes`", name)));
+            }
+        }
+        if let Err(e) = directory::check_search_filter(&dir.search_filter) {
+            errors.push(("directory", "search_filter", e));
+        }
+        for &(key, value) in &[("search_max_results", dir.search_max_results), ("search_page_size", dir.search_page_size)] {
+            if value == 0 {
+                errors.push(("directory", key, "should be greater than 0".to_string()));
+            }
         }
+
+        if !self.audit.file.is_empty() {
+            for &(key, value) in &[("max_file_bytes", self.audit.max_file_bytes), ("max_files", self.audit.max_files)] {
+                if value == 0 {
+                    errors.push(("audit", key, "should be greater than 0".to_string()));
+                }
+            }
+        }
+        if self.audit.syslog && self.audit.syslog_socket.is_empty() {
+            errors.push(("audit", "syslog_socket", "required when `syslog` set".to_string()));
+        }
+
+        let mut selectors = Vec::new();
+        let mut base_dns = vec![self.ldap.default_dn.clone()];
+        for tenant in &self.tenants {
+            let mut err = |desc:String| errors.push(("tenants", "", format!("`tenants.{}.{}", tenant.id, desc)));
+
+            if tenant.ldap.uri.is_empty() {
+                err("ldap_uri` required".to_string());
+            }
+            if tenant.ldap.default_dn.is_empty() {
+                err("base_dn` required".to_string());
+            }else if base_dns.iter().any(|dn| dn.trim().eq_ignore_ascii_case(tenant.ldap.default_dn.trim())) {
+                // compared as `Tenant::owns_dn` does
+                err(format!("base_dn` `{}` already used by another tenant", tenant.ldap.default_dn));
+            }
+            base_dns.push(tenant.ldap.default_dn.clone());
+
+            if tenant.hosts.is_empty() && tenant.path_prefix.is_empty() {
+                err("hosts` or `path_prefix` required".to_string());
+            }
+            if !tenant.path_prefix.is_empty() {
+                if let Err(e) = tenant::check_path_prefix(&tenant.path_prefix) {
+                    err(format!("path_prefix` {}", e));
+                }
+            }
+            for selector in tenant.hosts.iter().chain(Some(&tenant.path_prefix).into_iter().filter(|p| !p.is_empty())) {
+                if selectors.contains(selector) {
+                    err(format!("hosts` or `path_prefix` `{}` already used by another tenant", selector));
+                }
+                selectors.push(selector.clone());
+            }
+            for pattern in &tenant.allowed_continue_urls {
+                if let Err(e) = continue_url::Pattern::parse(pattern) {
+                    err(format!("allowed_continue_urls` {}", e));
+                }
+            }
+        }
+
+        for client in &self.clients {
+            // service only using the API doesn't receive tokens.
+            let service = !client.secret.is_empty() || !client.api_key.is_empty();
+            if client.host.is_empty() && !service {
+                // key of dynamic section, reported by its header line
+                errors.push(("clients", "", format!("`clients.{}.host` required", client.id)));
+            }
+            for &(key, value) in &[("secret", &client.secret), ("api_key", &client.api_key)] {
+                if !value.is_empty() && value.len() < MIN_CLIENT_SECRET_LEN {
+                    errors.push(("clients", "", format!("`clients.{}.{}` should be at least {} characters",
+                                                        client.id, key, MIN_CLIENT_SECRET_LEN)));
+                }
+            }
+            for pattern in &client.continue_urls {
+                if let Err(e) = continue_url::Pattern::parse(pattern) {
+                    errors.push(("clients", "", format!("`clients.{}.continue_urls` {}", client.id, e)));
+                }
+            }
+            for id in &client.tenants {
+                let known = if id == tenant::DEFAULT_ID { !self.ldap.uri.is_empty() } else { self.tenants.iter().any(|t| t.id == *id) };
+                if !known {
+                    errors.push(("clients", "", format!("`clients.{}.tenants` `{}` not configured", client.id, id)));
+                }
+            }
+            for name in &client.release_attributes {
+                if !self.directory.attributes.iter().any(|a| a.eq_ignore_ascii_case(name)) {
+                    errors.push(("clients", "", format!("`clients.{}.release_attributes` `{}` not in `directory.attributes`",
+                                                        client.id, name)));
+                }
+            }
+        }
+        errors
+    }
+
+    /**
+     * Find registered client by id.
+     */
+    pub fn client(&self, id:&str) -> Option<&ClientConf> {
+        self.clients.iter().find(|c| c.id == id)
+    }
+
+    /**
+     * Find registered client by continue url host.
+     */
+    pub fn client_by_host(&self, host:&str) -> Option<&ClientConf> {
+        self.clients.iter().find(|c| c.host == host)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use super::*;
+
+    fn read(data:&str) -> Result<Conf, Vec<ConfError>> {
+        Conf::read_str(data, &HashMap::new())
+    }
+
+    fn errors(data:&str) -> Vec<String> {
+        read(data).err().unwrap().iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn example_config() {
+        let conf = read(include_str!("../example.toml")).unwrap();
+        assert_eq!(conf.ldap.uri, "ldap://127.0.0.1");
+        assert_eq!(conf.throttle.window_secs, 900);
+        assert_eq!(conf.security.secure_cookies, false);
+        assert_eq!(conf.clients[0].host, "app.example.com");
+        assert_eq!(conf.clients[0].continue_urls, vec!["https://app.example.com/sso/callback".to_string()]);
+        assert_eq!(conf.allowed_continue_urls.len(), 3);
+        assert_eq!(conf.token.bytes, 32);
+    }
+
+    #[test]
+    fn continue_url_patterns() {
+        let data = "allowed_continue_urls = [\"https://*.example.com\", \"example.com\"]\n\
+                    allowed_continue_domain = \".*\"\n[ldap]\nuri = \"ldap://x\"\n\n\
+                    [clients.app]\nhost = \"app.example.com\"\ncontinue_urls = [\"ftp://app.example.com\"]\n";
+        assert_eq!(errors(data), vec![
+            "line 2: `allowed_continue_domain` `.*` should be domain name, use `allowed_continue_urls` for anything else",
+            "line 1: `allowed_continue_urls` `example.com` should be url, eg: https://app.example.com",
+            "`clients` `clients.app.continue_urls` `ftp://app.example.com` should be http(s) url",
+        ]);
+    }
+
+    #[test]
+    fn client_credentials() {
+        let data = "[ldap]\nuri = \"ldap://x\"\n\n\
+                    [clients.billing]\nsecret = \"0123456789abcdef0\"\n\n\
+                    [clients.short]\napi_key = \"123\"\n\n\
+                    [clients.app]\ncontinue_urls = []\ntenants = [\"default\", \"acme\"]\n";
+        assert_eq!(errors(data), vec![
+            "`clients` `clients.app.host` required",
+            "`clients` `clients.app.tenants` `acme` not configured",
+            "`clients` `clients.short.api_key` should be at least 16 characters",
+        ]);
+
+        let conf = read("[ldap]\nuri = \"ldap://x\"\n[clients.billing]\napi_key = \"0123456789abcdef0\"\n").unwrap();
+        assert_eq!(conf.client("billing").unwrap().api_key, "0123456789abcdef0");
+        assert!(conf.client("other").is_none());
+    }
+
+    #[test]
+    fn directory_attributes() {
+        let conf = read("[ldap]\nuri = \"ldap://x\"\n").unwrap();
+        assert_eq!(conf.directory.attributes, vec!["uid", "cn", "displayName", "mail"]);
+
+        let data = "[ldap]\nuri = \"ldap://x\"\n\n[directory]\nattributes = [\"mail\", \"mail;binary\"]\n\n\
+                    [clients.billing]\nsecret = \"0123456789abcdef0\"\nrelease_attributes = [\"MAIL\", \"cn\"]\n";
+        assert_eq!(errors(data), vec![
+            "line 5: `directory.attributes` `mail;binary` should be attribute name",
+            "`directory.search_attributes` `uid` not in `attributes`",
+            "`directory.self_attributes` `uid` not in `attributes`",
+            "`clients` `clients.billing.release_attributes` `cn` not in `directory.attributes`",
+        ]);
+
+        let data = "[ldap]\nuri = \"ldap://x\"\n\n[directory]\nsearch_filter = \"(uid=*)\"\nsearch_page_size = 0\n";
+        assert_eq!(errors(data), vec![
+            "line 5: `directory.search_filter` should contain `{q}`",
+            "line 6: `directory.search_page_size` should be greater than 0",
+        ]);
+    }
+
+    #[test]
+    fn audit_outputs() {
+        let conf = read("[ldap]\nuri = \"ldap://x\"\n").unwrap();
+        assert!(conf.audit.file.is_empty());
+        assert_eq!(conf.audit.retention_secs, 30 * 24 * 3600);
+
+        let data = "[ldap]\nuri = \"ldap://x\"\n\n[audit]\nfile = \"audit.log\"\nmax_files = 0\n\
+                    syslog = true\nsyslog_socket = \"\"\n";
+        assert_eq!(errors(data), vec![
+            "line 6: `audit.max_files` should be greater than 0",
+            "line 8: `audit.syslog_socket` required when `syslog` set",
+        ]);
+    }
+
+    #[test]
+    fn syntax_error_line() {
+        assert_eq!(read("[ldap]\nuri = \"ldap://x\"\nadmin_user = admin\n").err().unwrap()[0].line, Some(3));
+    }
+
+    #[test]
+    fn unknown_keys_and_types() {
+        let data = "[ldap]\nuri = \"ldap://x\"\nadmin_pasword = \"x\"\n";
+        assert_eq!(errors(data), vec![
+            "line 3: `ldap.admin_pasword` unknown field `admin_pasword`, expected one of `uri`, `default_dn`, `admin_user`, `admin_password`",
+        ]);
+        let data = "[ldap]\nuri = \"ldap://x\"\n\n[login_throttle]\nwindow_secs = \"15m\"\n";
+        assert_eq!(errors(data), vec!["line 5: `login_throttle.window_secs` invalid type: string \"15m\", expected u64"]);
+        let data = "[ldap]\nuri = \"ldap://x\"\n\n[session]\nttl_secs = -1\n";
+        assert_eq!(errors(data), vec!["line 5: `session.ttl_secs` invalid value: integer `-1`, expected u64"]);
+        let data = "[ldap]\nuri = \"ldap://x\"\n\n[clients.app]\nhost = \"a\"\nlogout = \"x\"\n";
+        assert_eq!(errors(data)[0], "line 6: `clients.app.logout` unknown field `logout`, \
+                                     expected one of `host`, `continue_urls`, `backchannel_logout_url`, \
+                                     `frontchannel_logout_url`, `secret`, `api_key`, `release_attributes`, `tenants`");
+        let data = "[ldap]\nuri = \"ldap://x\"\n\n[http]\nlisten = [80]\n";
+        assert_eq!(errors(data), vec!["line 5: `http.listen` invalid type: integer `80`, expected a string"]);
+        assert!(errors("ldap = \"ldap://x\"\n")[0].starts_with("line 1: `ldap` invalid type: string"));
+        assert!(errors("[ldap]\nuri = \"ldap://x\"\n\n[ldpa]\nuri = \"x\"\n")[0].starts_with("line 4: `ldpa` unknown field `ldpa`"));
+    }
+
+    #[test]
+    fn semantic_errors() {
+        let data = "[ldap]\nuri = \"\"\n\n[store]\nbackend = \"mongo\"\n\n[replication]\npeers = [\"10.0.0.2:8080\"]\n";
+        assert_eq!(errors(data), vec![
+            "line 2: `ldap.uri` required",
+            "line 5: `store.backend` unknown backend `mongo`, supported: rocksdb, memory, sqlite, redis",
+            "`replication.node_id` required when `peers` set",
+            "`replication.secret` required when `peers` set",
+            "line 8: `replication.peers` `10.0.0.2:8080` should be http(s) url",
+        ]);
+    }
+
+    #[test]
+    fn tenants() {
+        // no default tenant needed
+        let conf = read("[tenants.acme]\nhosts = [\"sso.acme.com\"]\nldap_uri = \"ldap://acme\"\n\
+                         base_dn = \"dc=acme,dc=com\"\n").unwrap();
+        assert_eq!(conf.tenants[0].ldap.default_dn, "dc=acme,dc=com");
+
+        let data = "[ldap]\nuri = \"ldap://x\"\ndefault_dn = \"dc=example,dc=com\"\n\n\
+                    [tenants.a]\nhosts = [\"sso.acme.com\"]\nldap_uri = \"ldap://a\"\nbase_dn = \"dc=example,dc=com\"\n\n\
+                    [tenants.b]\nhosts = [\"sso.acme.com\"]\npath_prefix = \"/login\"\nbase_dn = \"dc=b\"\n\n\
+                    [tenants.c]\nldap_uri = \"ldap://c\"\nbase_dn = \"dc=c\"\nlogo = 1\n";
+        assert_eq!(errors(data), vec![
+            "line 18: `tenants.c.logo` invalid type: integer `1`, expected a string",
+        ]);
+        assert_eq!(errors(&data.replace("logo = 1\n", "")), vec![
+            "`tenants` `tenants.a.base_dn` `dc=example,dc=com` already used by another tenant",
+            "`tenants` `tenants.b.ldap_uri` required",
+            "`tenants` `tenants.b.path_prefix` `/login` is already used by SSO",
+            "`tenants` `tenants.b.hosts` or `path_prefix` `sso.acme.com` already used by another tenant",
+            "`tenants` `tenants.c.hosts` or `path_prefix` required",
+        ]);
+        assert_eq!(errors(&data.replace("logo = 1
+", "").replace("base_dn = \"dc=example,dc=com\"", "base_dn = \"DC=Example,dc=com \""))[0],
+                   "`tenants` `tenants.a.base_dn` `DC=Example,dc=com ` already used by another tenant");
+    }
+
+    #[test]
+    fn http_listeners() {
+        let conf = read("[ldap]\nuri = \"ldap://x\"\n").unwrap();
+        assert_eq!(conf.http.listen, vec!["127.0.0.1:8080".to_string()]);
+
+        // only unix socket, no default tcp listener
+        let conf = read("[ldap]\nuri = \"ldap://x\"\n\n[http]\nunix_socket = \"/run/sso.sock\"\n").unwrap();
+        assert!(conf.http.listen.is_empty());
+
+        let data = "[ldap]\nuri = \"ldap://x\"\n\n[http]\nlisten = [\"0.0.0.0:80\", \"localhost\"]\n\
+                    https_listen = [\"0.0.0.0:80\"]\n";
+        assert_eq!(errors(data), vec![
+            "line 5: `http.listen` `localhost` should be `ip:port`",
+            "line 6: `http.https_listen` `0.0.0.0:80` used more than once",
+            "`http.tls_cert` required when `https_listen` set",
+            "`http.tls_key` required when `https_listen` set",
+        ]);
+    }
+
+    #[test]
+    fn store_path() {
+        let conf = read("data_store = \"/var/lib/sso\"\n[ldap]\nuri = \"ldap://x\"\n").unwrap();
+        assert_eq!(conf.store.path, "/var/lib/sso");
+        let conf = read("data_store = \"/var/lib/sso\"\n[ldap]\nuri = \"ldap://x\"\n[store]\nbackend = \"sqlite\"\n").unwrap();
+        assert_eq!(conf.store.path, "/var/lib/sso");
+        let conf = read("[ldap]\nuri = \"ldap://x\"\n[store]\npath = \"/var/lib/sso.db\"\n").unwrap();
+        assert_eq!(conf.store.path, "/var/lib/sso.db");
+    }
+
+    #[test]
+    fn env_overrides() {
+        let mut env = HashMap::new();
+        env.insert("SSO_LDAP__ADMIN_PASSWORD".to_string(), "from-env".to_string());
+        env.insert("SSO_LOGIN_THROTTLE__WINDOW_SECS".to_string(), "60".to_string());
+        env.insert("SSO_SECURITY__SECURE_COOKIES".to_string(), "false".to_string());
+        env.insert("SSO_REPLICATION__PEERS".to_string(), "http://a, http://b".to_string());
+        env.insert("SSO_REPLICATION__NODE_ID".to_string(), "c".to_string());
+        env.insert("SSO_REPLICATION__SECRET".to_string(), "s3cret".to_string());
+        env.insert("SSO_DATA_STORE".to_string(), "/var/lib/sso".to_string());
+
+        let conf = Conf::read_str("[ldap]\nuri = \"ldap://x\"\nadmin_password = \"123\"\n", &env).unwrap();
+        assert_eq!(conf.ldap.admin_password, "from-env");
+        assert_eq!(conf.throttle.window_secs, 60);
+        assert_eq!(conf.security.secure_cookies, false);
+        assert_eq!(conf.store.replication.peers, vec!["http://a".to_string(), "http://b".to_string()]);
+        assert_eq!(conf.store.path, "/var/lib/sso");
+
+        env.insert("SSO_SESSION__TTL_SECS".to_string(), "8h".to_string());
+        let errors = Conf::read_str("[ldap]\nuri = \"ldap://x\"\n", &env).err().unwrap();
+        assert_eq!(errors[0].to_string(), "`session.ttl_secs` should be non-negative integer (from SSO_SESSION__TTL_SECS)");
+    }
+
+    #[test]
+    fn secret_files() {
+        let path = env::temp_dir().join("sso-config-test-secret");
+        File::create(&path).unwrap().write_all(b"s3cret\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let conf = read(&format!("[ldap]\nuri = \"ldap://x\"\nadmin_password_file = \"{}\"\n", path)).unwrap();
+        assert_eq!(conf.ldap.admin_password, "s3cret");
+
+        assert_eq!(errors(&format!("[ldap]\nuri = \"ldap://x\"\nadmin_password = \"x\"\nadmin_password_file = \"{}\"\n", path)),
+            vec!["line 4: `ldap.admin_password_file` can't be used together with `admin_password`"]);
+        assert!(errors("[ldap]\nuri = \"ldap://x\"\nadmin_password_file = \"/nonexistent/secret\"\n")[0]
+            .starts_with("line 3: `ldap.admin_password_file` cannot read `/nonexistent/secret`"));
+        // only for secrets
+        assert_eq!(errors("[ldap]\nuri = \"ldap://x\"\nuri_file = \"x\"\n"),
+            vec!["line 3: `ldap.uri_file` unknown field `uri_file`, expected one of `uri`, `default_dn`, `admin_user`, `admin_password`"]);
+    }
+
+    #[test]
+    fn missing_file() {
+        assert!(Conf::read_file("/nonexistent/config.toml").err().unwrap()[0].to_string()
+            .starts_with("cannot read `/nonexistent/config.toml`"));
     }
 }
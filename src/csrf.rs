@@ -0,0 +1,107 @@
+
+// Anti-CSRF token for login form, using double-submit cookie:
+// every render set random `sso_csrf` cookie and put token signed (HMAC)
+// together with the cookie value into the form, so forged cross-site post
+// can't provide matching pair.
+
+use rand::{Rng, OsRng};
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use serialize::base64::{self, ToBase64, FromBase64};
+use nickel::hyper::header::Headers;
+
+use config::SecurityConf;
+use utils;
+
+pub const COOKIE_NAME:&'static str = "sso_csrf";
+
+// how long rendered login form stay valid.
+const TOKEN_MAX_AGE_MILLIS:u64 = 2 * 60 * 60 * 1000;
+
+// form rendered by another SSO node with clock slightly ahead.
+const MAX_CLOCK_SKEW_MILLIS:u64 = 60 * 1000;
+
+
+pub fn random_secret() -> String {
+    let mut buf = [0u8; 32];
+    OsRng::new().expect("Cannot access OS random source").fill_bytes(&mut buf);
+    buf.to_base64(base64::URL_SAFE)
+}
+
+fn sign(secret:&str, cookie:&str, ts:u64) -> Vec<u8> {
+    let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+    hmac.input(cookie.as_bytes());
+    hmac.input(b".");
+    hmac.input(ts.to_string().as_bytes());
+    hmac.result().code().to_vec()
+}
+
+/**
+ * Generate new cookie value and it's token,
+ * set the cookie into response headers and return the token for the form.
+ */
+pub fn issue(conf:&SecurityConf, headers:&mut Headers) -> String {
+    let cookie = random_secret();
+    let ts = utils::current_time_millis();
+    let token = format!("{}.{}", ts, sign(&conf.csrf_secret, &cookie, ts).to_base64(base64::URL_SAFE));
+
+    let secure = if conf.secure_cookies { "; Secure" } else { "" };
+    utils::set_cookie(headers, format!("{}={}; Path=/; HttpOnly; SameSite=Strict{}", COOKIE_NAME, cookie, secure));
+
+    token
+}
+
+/**
+ * Verify token posted by login form against the cookie.
+ */
+pub fn verify(conf:&SecurityConf, headers:&Headers, token:&str) -> bool {
+    let cookie = match utils::get_cookie(headers, COOKIE_NAME) {
+        Some(c) => c,
+        None => return false
+    };
+
+    let mut parts = token.splitn(2, '.');
+    let (ts, sig) = match (parts.next().and_then(|t| t.parse::<u64>().ok()),
+                           parts.next().and_then(|s| s.from_base64().ok())) {
+        (Some(ts), Some(sig)) => (ts, sig),
+        _ => return false
+    };
+
+    let now = utils::current_time_millis();
+    if now > ts.saturating_add(TOKEN_MAX_AGE_MILLIS) || ts > now + MAX_CLOCK_SKEW_MILLIS {
+        return false;
+    }
+
+    fixed_time_eq(&sign(&conf.csrf_secret, &cookie, ts), &sig)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use nickel::hyper::header::Headers;
+    use serialize::base64::{self, ToBase64};
+
+    use config::SecurityConf;
+    use utils;
+    use super::*;
+
+    fn token(conf:&SecurityConf, ts:u64) -> String {
+        format!("{}.{}", ts, sign(&conf.csrf_secret, "cookie", ts).to_base64(base64::URL_SAFE))
+    }
+
+    #[test]
+    fn token_age() {
+        let conf = SecurityConf { csrf_secret: "secret".to_string(), ..Default::default() };
+        let mut headers = Headers::new();
+        headers.set_raw("Cookie", vec![format!("{}=cookie", COOKIE_NAME).into_bytes()]);
+        let now = utils::current_time_millis();
+
+        assert!(verify(&conf, &headers, &token(&conf, now)));
+        assert!(!verify(&conf, &headers, &token(&conf, now - TOKEN_MAX_AGE_MILLIS - 1000)));
+        assert!(!verify(&conf, &headers, &token(&conf, now + 10 * MAX_CLOCK_SKEW_MILLIS)));
+        assert!(!verify(&conf, &headers, &token(&conf, u64::max_value())));
+        assert!(!verify(&conf, &headers, &token(&SecurityConf { csrf_secret: "other".to_string(), ..Default::default() }, now)));
+    }
+}
@@ -0,0 +1,242 @@
+
+// User profiles read from the directory for the API, `[directory]` section.
+//
+// Only `directory.attributes` are read from the user's LDAP entry, a service
+// client gets those listed in its `release_attributes`, the user themself
+// (`/api/v1/me` with own token) `self_attributes`. Search results (`/api/v1/directory/search`)
+// have only `directory.search_attributes`.
+
+use std::collections::{BTreeMap, HashMap};
+
+// cached profiles per node, see `cache`.
+pub const CACHE_ENTRIES:usize = 10000;
+
+// placeholder for escaped query in `directory.search_filter`.
+pub const QUERY_PLACEHOLDER:&'static str = "{q}";
+
+const MAX_QUERY_LEN:usize = 64;
+
+#[derive(Clone, Encodable)]
+pub struct Profile {
+    pub uid: String,
+    pub dn: String,
+    pub attributes: BTreeMap<String, Vec<String>> // configured name => values
+}
+
+impl Profile {
+    pub fn new(uid:&str, dn:&str, entry:&HashMap<String, Vec<String>>, attributes:&[String]) -> Profile {
+        Profile {
+            uid: uid.to_string(),
+            dn: dn.to_string(),
+            attributes: project(entry, attributes)
+        }
+    }
+
+    /**
+     * Profile with only attributes released to the client, uid and dn always released.
+     */
+    pub fn released(mut self, allowed:&[String]) -> Profile {
+        self.attributes = self.attributes.into_iter()
+            .filter(|&(ref name, _)| allowed.iter().any(|a| a.eq_ignore_ascii_case(name)))
+            .collect();
+        self
+    }
+}
+
+/**
+ * Values of `attributes` in LDAP entry, names matched case-insensitively
+ * (as LDAP does) and keyed as configured. Missing ones left out.
+ */
+pub fn project(entry:&HashMap<String, Vec<String>>, attributes:&[String]) -> BTreeMap<String, Vec<String>> {
+    let mut result = BTreeMap::new();
+    for name in attributes {
+        if let Some((_, values)) = entry.iter().find(|&(k, _)| k.eq_ignore_ascii_case(name)) {
+            result.insert(name.clone(), values.clone());
+        }
+    }
+    result
+}
+
+/**
+ * Attribute description allowed in config, eg: `mail`, `telephoneNumber`.
+ */
+pub fn valid_attribute(name:&str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() => chars.all(|c| c.is_ascii_alphanumeric() || c == '-'),
+        _ => false
+    }
+}
+
+/**
+ * User id safe to put into DN `uid=<uid>,ou=People,<dn>` without escaping.
+ */
+pub fn valid_uid(uid:&str) -> bool {
+    !uid.is_empty() && uid.len() <= 64 &&
+        uid.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' || c == '@')
+}
+
+pub fn cache_key(uid:&str, dn:&str) -> String {
+    format!("{}/{}", dn.to_lowercase(), uid)
+}
+
+// one page of search results, `total` is count of all matches.
+#[derive(Encodable)]
+pub struct SearchPage {
+    pub items: Vec<Profile>,
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize
+}
+
+impl SearchPage {
+    /**
+     * Page of results sorted by uid.
+     */
+    pub fn new(results:&[Profile], offset:usize, limit:usize) -> SearchPage {
+        SearchPage {
+            items: results.iter().skip(offset).take(limit).cloned().collect(),
+            total: results.len(),
+            offset: offset,
+            limit: limit
+        }
+    }
+}
+
+/**
+ * Profiles of search result entries sorted by uid, entries without `uid` left out.
+ */
+pub fn search_results(entries:&[HashMap<String, Vec<String>>], dn:&str, attributes:&[String]) -> Vec<Profile> {
+    let mut results:Vec<Profile> = entries.iter()
+        .filter_map(|entry| {
+            let uid = entry.iter()
+                .find(|&(k, _)| k.eq_ignore_ascii_case("uid"))
+                .and_then(|(_, values)| values.first());
+            uid.map(|uid| Profile::new(uid, dn, entry, attributes))
+        })
+        .collect();
+    results.sort_by(|a, b| a.uid.cmp(&b.uid));
+    results
+}
+
+/**
+ * Search query long enough to not list the whole directory.
+ */
+pub fn check_query(query:&str, min_len:u64) -> Result<&str, String> {
+    let query = query.trim();
+    let len = query.chars().count();
+    if (len as u64) < min_len {
+        Err(format!("should be at least {} characters", min_len))
+    }else if len > MAX_QUERY_LEN {
+        Err(format!("should be at most {} characters", MAX_QUERY_LEN))
+    }else{
+        Ok(query)
+    }
+}
+
+/**
+ * Search filter template in config, eg: `(|(uid={q}*)(cn=*{q}*))`.
+ */
+pub fn check_search_filter(template:&str) -> Result<(), String> {
+    if !template.contains(QUERY_PLACEHOLDER) {
+        return Err(format!("should contain `{}`", QUERY_PLACEHOLDER));
+    }
+    let mut depth = 0;
+    for (i, c) in template.chars().enumerate() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            ')' => return Err("has unbalanced parentheses".to_string()),
+            _ if depth == 0 => return Err(format!("should be enclosed in parentheses, `{}` at {}", c, i)),
+            _ => ()
+        }
+        if depth == 0 && i + 1 < template.chars().count() {
+            return Err("should be single filter, combine with `(|...)` or `(&...)`".to_string());
+        }
+    }
+    if depth != 0 {
+        return Err("has unbalanced parentheses".to_string());
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+
+    fn entry() -> HashMap<String, Vec<String>> {
+        let mut entry = HashMap::new();
+        entry.insert("uid".to_string(), vec!["robin".to_string()]);
+        entry.insert("displayname".to_string(), vec!["Robin".to_string()]);
+        entry.insert("mail".to_string(), vec!["robin@example.com".to_string(), "r@example.com".to_string()]);
+        entry.insert("userPassword".to_string(), vec!["{SSHA}x".to_string()]);
+        entry
+    }
+
+    fn names(list:&[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn projection() {
+        let profile = Profile::new("robin", "dc=example,dc=com", &entry(), &names(&["displayName", "mail", "telephoneNumber"]));
+        let keys:Vec<&String> = profile.attributes.keys().collect();
+        assert_eq!(keys, vec!["displayName", "mail"]);
+        assert_eq!(profile.attributes["mail"].len(), 2);
+
+        let released = profile.clone().released(&names(&["MAIL", "uid"]));
+        assert_eq!(released.uid, "robin");
+        assert_eq!(released.attributes.keys().collect::<Vec<_>>(), vec!["mail"]);
+        assert!(profile.released(&[]).attributes.is_empty());
+    }
+
+    #[test]
+    fn search() {
+        let mut other = entry();
+        other.insert("uid".to_string(), vec!["alice".to_string()]);
+        let mut no_uid = entry();
+        no_uid.remove("uid");
+
+        let results = search_results(&[entry(), other, no_uid], "dc=example,dc=com", &names(&["uid", "mail"]));
+        assert_eq!(results.iter().map(|p| p.uid.as_str()).collect::<Vec<_>>(), vec!["alice", "robin"]);
+        assert_eq!(results[0].attributes.keys().collect::<Vec<_>>(), vec!["mail", "uid"]);
+
+        let page = SearchPage::new(&results, 1, 10);
+        assert_eq!((page.items.len(), page.total), (1, 2));
+        assert_eq!(page.items[0].uid, "robin");
+        assert!(SearchPage::new(&results, 5, 10).items.is_empty());
+
+        assert_eq!(check_query(" ro ", 2), Ok("ro"));
+        assert!(check_query("r", 2).is_err());
+        assert!(check_query(&"r".repeat(65), 2).is_err());
+    }
+
+    #[test]
+    fn search_filters() {
+        for template in &["(uid={q}*)", "(|(uid={q}*)(cn=*{q}*)(mail={q}*))", "(&(objectClass=person)(cn=*{q}*))"] {
+            assert_eq!(check_search_filter(template), Ok(()), "{}", template);
+        }
+        for template in &["(uid=robin)", "uid={q}", "(uid={q})(cn={q})", "(|(uid={q})", "(uid={q}))", "(uid={q}) "] {
+            assert!(check_search_filter(template).is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn names_and_uids() {
+        for name in &["mail", "telephoneNumber", "x-custom-1"] {
+            assert!(valid_attribute(name), "{}", name);
+        }
+        for name in &["", "1mail", "mail;binary", "(mail)", "*"] {
+            assert!(!valid_attribute(name), "{}", name);
+        }
+
+        for uid in &["robin", "robin.hood", "r_h-1", "robin@example.com"] {
+            assert!(valid_uid(uid), "{}", uid);
+        }
+        for uid in &["", "robin,ou=Admin", "a+b", "robin hood", "*", &"x".repeat(65)] {
+            assert!(!valid_uid(uid), "{}", uid);
+        }
+    }
+}
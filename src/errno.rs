@@ -4,6 +4,8 @@
 pub const BAD_REQQUEST:i32 = 400; // Bad Request
 pub const UNAUTHORIZED:i32 = 401; // Unauthorized
 pub const NOT_FOUND:i32 = 404; // Not found
+pub const LOCKED:i32 = 423; // Locked
+pub const TOO_MANY_REQUESTS:i32 = 429; // Too Many Requests
 pub const INVALID_TOKEN:i32 = 498;
 pub const INTERNAL_SERVER_ERROR:i32 = 500;
 
@@ -11,4 +13,9 @@ pub static BAD_REQUEST_STR:&'static str = "Bad request";
 pub static UNAUTHORIZED_STR:&'static str = "Access denied";
 pub static NOT_FOUND_STR:&'static str = "Not found";
 pub static INVALID_TOKEN_STR:&'static str = "Invalid token";
+pub static LOCKED_STR:&'static str = "Too many failed login attempts";
+pub static TOO_MANY_REQUESTS_STR:&'static str = "Login attempt too soon after failed one";
+pub static INVALID_CREDENTIALS_STR:&'static str = "Invalid user name or password";
+pub static SECOND_FACTOR_REQUIRED_STR:&'static str = "Security key required";
+pub static UNKNOWN_TENANT_STR:&'static str = "Unknown directory";
 pub static INTERNAL_SERVER_ERROR_STR:&'static str = "Internal server error";
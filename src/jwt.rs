@@ -0,0 +1,353 @@
+
+// Self-contained access tokens (`token.format = "jwt"`): short-lived JWT signed
+// with Ed25519 (`EdDSA`), services verify it offline with public keys from
+// `/.well-known/jwks.json` instead of calling `/api/lookup`.
+//
+// Opaque token stays the refresh handle, every JWT carries `sid` (hash of it),
+// revoking the opaque token puts the `sid` into the list served by
+// `/api/token/revoked` until its JWTs expire.
+
+use std::fs::File;
+use std::io::Read;
+use crypto::ed25519;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rand::{Rng, OsRng};
+use serialize::json::{self, Json};
+use serialize::base64::{self, ToBase64, FromBase64};
+use std::collections::BTreeMap;
+
+use config::TokenConf;
+use store::{Store, StoreResult, WriteBatchWrapper, Namespace, RevokedRecord};
+use utils;
+
+const SEED_LEN:usize = 32;
+
+
+struct SigningKey {
+    id: String,
+    secret: [u8; 64],
+    public: [u8; 32]
+}
+
+pub struct Signer {
+    keys: Vec<SigningKey>,
+    issuer: String,
+    ttl_secs: u64
+}
+
+#[derive(Decodable, Encodable, Clone, Debug, PartialEq)]
+pub struct Claims {
+    pub iss: String,
+    pub sub: String,         // uid
+    pub dn: String,
+    pub groups: Vec<String>, // cn of LDAP groups the user is member of
+    pub sid: String,         // see `session_id`
+    pub jti: String,
+    pub iat: u64,
+    pub exp: u64
+}
+
+#[derive(Decodable, Encodable)]
+struct Header {
+    alg: String,
+    typ: String,
+    kid: String
+}
+
+fn b64url(data:&[u8]) -> String {
+    data.to_base64(base64::URL_SAFE)
+}
+
+fn now_secs() -> u64 {
+    utils::current_time_millis() / 1000
+}
+
+/**
+ * Revocation handle of opaque token, included in every JWT issued for it.
+ */
+pub fn session_id(token:&str) -> String {
+    let mut sha = Sha256::new();
+    sha.input_str("sso-sid:");
+    sha.input_str(token);
+    let mut out = [0u8; 32];
+    sha.result(&mut out);
+    b64url(&out[..16])
+}
+
+impl Signer {
+    /**
+     * Parse keys, `<key-id>:<base64 32 bytes seed>` separated by comma or new line,
+     * `#` comments ignored, first key signs, the others only published for verification.
+     */
+    pub fn parse(conf:&TokenConf, data:&str) -> Result<Signer, String> {
+        let mut keys:Vec<SigningKey> = Vec::new();
+
+        for entry in data.split(|c| c == ',' || c == '\n') {
+            let entry = entry.trim();
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+            let mut parts = entry.splitn(2, ':');
+            let id = parts.next().unwrap_or("").trim();
+            let seed = parts.next().unwrap_or("").trim();
+
+            if id.is_empty() || !id.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+                return Err(format!("invalid signing key id `{}`", id));
+            }
+            if keys.iter().any(|k| k.id == id) {
+                return Err(format!("duplicate signing key id `{}`", id));
+            }
+            match seed.from_base64() {
+                Ok(ref seed) if seed.len() == SEED_LEN => {
+                    let (secret, public) = ed25519::keypair(seed);
+                    keys.push(SigningKey { id: id.to_string(), secret: secret, public: public });
+                },
+                _ => return Err(format!("signing key `{}` should be {} bytes, base64 encoded", id, SEED_LEN))
+            }
+        }
+
+        if keys.is_empty() {
+            return Err("`token.signing_key` required for jwt format".to_string());
+        }
+        Ok(Signer { keys: keys, issuer: conf.issuer.clone(), ttl_secs: conf.jwt_ttl_secs })
+    }
+
+    /**
+     * Load signer configured in `[token]` section, None for opaque tokens only.
+     */
+    pub fn load(conf:&TokenConf) -> Result<Option<Signer>, String> {
+        if !conf.signed() {
+            return Ok(None);
+        }
+        if !conf.signing_key_file.is_empty() {
+            let mut data = String::new();
+            try!(File::open(&conf.signing_key_file)
+                .and_then(|mut f| f.read_to_string(&mut data))
+                .map_err(|e| format!("cannot read `{}`: {}", conf.signing_key_file, e)));
+            Signer::parse(conf, &data).map(Some)
+        }else{
+            Signer::parse(conf, &conf.signing_key).map(Some)
+        }
+    }
+
+    pub fn ttl_secs(&self) -> u64 {
+        self.ttl_secs
+    }
+
+    /**
+     * Claims of new token issued for opaque `token`.
+     */
+    pub fn claims(&self, uid:&str, dn:&str, groups:Vec<String>, token:&str) -> Claims {
+        let mut jti = [0u8; 16];
+        OsRng::new().expect("Cannot access OS random source").fill_bytes(&mut jti);
+        let now = now_secs();
+
+        Claims {
+            iss: self.issuer.clone(),
+            sub: uid.to_string(),
+            dn: dn.to_string(),
+            groups: groups,
+            sid: session_id(token),
+            jti: b64url(&jti),
+            iat: now,
+            exp: now + self.ttl_secs
+        }
+    }
+
+    pub fn sign(&self, claims:&Claims) -> String {
+        let key = &self.keys[0];
+        let header = Header { alg: "EdDSA".to_string(), typ: "JWT".to_string(), kid: key.id.clone() };
+
+        let input = format!("{}.{}",
+            b64url(json::encode(&header).unwrap().as_bytes()),
+            b64url(json::encode(claims).unwrap().as_bytes()));
+        let sig = ed25519::signature(input.as_bytes(), &key.secret);
+
+        format!("{}.{}", input, b64url(&sig))
+    }
+
+    /**
+     * Check signature and expiry, revocation list isn't consulted.
+     */
+    pub fn verify(&self, token:&str) -> Result<Claims, String> {
+        let parts:Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 {
+            return Err("malformed token".to_string());
+        }
+        let decode = |part:&str| part.from_base64().map_err(|_| "invalid encoding".to_string());
+
+        let header:Header = try!(String::from_utf8(try!(decode(parts[0]))).ok()
+            .and_then(|h| json::decode(&h).ok())
+            .ok_or("invalid header".to_string()));
+        if header.alg != "EdDSA" {
+            return Err(format!("unexpected algorithm `{}`", header.alg));
+        }
+        let key = try!(self.keys.iter().find(|k| k.id == header.kid)
+            .ok_or(format!("unknown key `{}`", header.kid)));
+
+        let input = format!("{}.{}", parts[0], parts[1]);
+        if !ed25519::verify(input.as_bytes(), &key.public, &try!(decode(parts[2]))) {
+            return Err("invalid signature".to_string());
+        }
+
+        let claims:Claims = try!(String::from_utf8(try!(decode(parts[1]))).ok()
+            .and_then(|c| json::decode(&c).ok())
+            .ok_or("invalid claims".to_string()));
+        if claims.exp <= now_secs() {
+            return Err("expired".to_string());
+        }
+        Ok(claims)
+    }
+
+    /**
+     * Public keys as JSON Web Key Set.
+     */
+    pub fn jwks(&self) -> String {
+        let keys = self.keys.iter().map(|k| {
+            let mut jwk = BTreeMap::new();
+            jwk.insert("kty".to_string(), Json::String("OKP".to_string()));
+            jwk.insert("crv".to_string(), Json::String("Ed25519".to_string()));
+            jwk.insert("alg".to_string(), Json::String("EdDSA".to_string()));
+            jwk.insert("use".to_string(), Json::String("sig".to_string()));
+            jwk.insert("kid".to_string(), Json::String(k.id.clone()));
+            jwk.insert("x".to_string(), Json::String(b64url(&k.public)));
+            Json::Object(jwk)
+        }).collect();
+
+        let mut set = BTreeMap::new();
+        set.insert("keys".to_string(), Json::Array(keys));
+        Json::Object(set).to_string()
+    }
+}
+
+/**
+ * Add revocation of JWTs issued for opaque `token` into the batch,
+ * nothing to do when only opaque tokens issued.
+ */
+pub fn revoke<'a>(batch:WriteBatchWrapper<'a>, conf:&TokenConf, token:&str) -> WriteBatchWrapper<'a> {
+    if !conf.signed() {
+        return batch;
+    }
+    batch.put_record(Namespace::Revoked, &session_id(token),
+        &RevokedRecord { expires: now_secs() + conf.jwt_ttl_secs })
+}
+
+/**
+ * Revoked session ids with the time they can be forgotten,
+ * expired entries are removed.
+ */
+pub fn revoked(store:&Store) -> StoreResult<Vec<(String, u64)>> {
+    let now = now_secs();
+    let mut list = Vec::new();
+    let mut batch = store.batch();
+    let mut expired = false;
+
+    for (key, _) in try!(store.scan(&Namespace::Revoked.prefix())) {
+        let sid = &key[Namespace::Revoked.prefix().len()..];
+        match try!(store.get_record::<RevokedRecord>(Namespace::Revoked, sid)) {
+            Some(ref r) if r.expires > now => list.push((sid.to_string(), r.expires)),
+            _ => {
+                batch = batch.del_record(Namespace::Revoked, sid);
+                expired = true;
+            }
+        }
+    }
+    if expired {
+        try!(batch.commit());
+    }
+    Ok(list)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use config::TokenConf;
+    use store::{Store, MemoryStore, Namespace, RevokedRecord};
+    use super::*;
+
+    const KEY1:&'static str = "k1:AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+    const KEY2:&'static str = "k2:HxsdHBsaGRgXFhUUExIREA8ODQwLCgkIBwYFBAMCAQA=";
+
+    fn conf() -> TokenConf {
+        TokenConf {
+            format: "jwt".to_string(),
+            issuer: "https://sso.example.com".to_string(),
+            signing_key: KEY1.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sign_verify() {
+        let signer = Signer::load(&conf()).unwrap().unwrap();
+        let claims = signer.claims("robin", "dc=example,dc=com", vec!["admins".to_string()], "opaque1");
+        let token = signer.sign(&claims);
+
+        assert_eq!(token.split('.').count(), 3);
+        assert_eq!(signer.verify(&token).unwrap(), claims);
+        assert_eq!(claims.sid, session_id("opaque1"));
+        assert_eq!(claims.exp - claims.iat, 300);
+
+        // tampered claims
+        let parts:Vec<&str> = token.split('.').collect();
+        let mut forged = claims.clone();
+        forged.groups.push("root".to_string());
+        let forged = format!("{}.{}.{}", parts[0],
+            b64url(json::encode(&forged).unwrap().as_bytes()), parts[2]);
+        assert!(signer.verify(&forged).is_err());
+
+        // expired
+        let mut old = claims.clone();
+        old.exp = old.iat - 1;
+        assert!(signer.verify(&signer.sign(&old)).is_err());
+    }
+
+    #[test]
+    fn key_rotation() {
+        let old = Signer::load(&conf()).unwrap().unwrap();
+        let token = old.sign(&old.claims("robin", "dc=example,dc=com", vec![], "opaque1"));
+
+        let mut rotated = conf();
+        rotated.signing_key = format!("{},{}", KEY2, KEY1);
+        let rotated = Signer::load(&rotated).unwrap().unwrap();
+
+        assert!(rotated.verify(&token).is_ok());
+        assert!(rotated.sign(&rotated.claims("robin", "", vec![], "x")).starts_with(
+            &b64url(br#"{"alg":"EdDSA","typ":"JWT","kid":"k2"}"#)));
+
+        let jwks = Json::from_str(&rotated.jwks()).unwrap();
+        let keys = jwks.find("keys").unwrap().as_array().unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[1].find("kid").unwrap().as_string(), Some("k1"));
+    }
+
+    #[test]
+    fn opaque_only() {
+        assert!(Signer::load(&Default::default()).unwrap().is_none());
+
+        let mut c = conf();
+        c.signing_key = String::new();
+        assert!(Signer::load(&c).is_err());
+        c.signing_key = "k1:c2hvcnQ=".to_string();
+        assert!(Signer::load(&c).is_err());
+    }
+
+    #[test]
+    fn revocation_list() {
+        let store = Store::with_backend(Box::new(MemoryStore::new()));
+
+        // not recorded for opaque tokens
+        revoke(store.batch(), &Default::default(), "opaque0").commit().unwrap();
+        assert!(revoked(&store).unwrap().is_empty());
+
+        revoke(store.batch(), &conf(), "opaque1").commit().unwrap();
+        store.put_record(Namespace::Revoked, "old", &RevokedRecord { expires: 1 }).unwrap();
+
+        let list = revoked(&store).unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].0, session_id("opaque1"));
+        // expired entry removed
+        assert!(store.get("revoked/old").unwrap().is_none());
+    }
+}
@@ -1,17 +1,25 @@
 
+use std::ptr;
+use std::collections::HashMap;
+use std::error::Error;
 use oldap::*;
+
+use metrics::{self, Timer};
 // use oldap::errors::*;
 
 
 pub fn connect(uri:&str, admin:&str, password:&str, dn:&str) -> Result<RustLDAP, String> {
 
-    debug!("conneting to ldap using: uri: {}, admin: {}, password: {}, dn: {}",
-        uri, admin, password, dn);
+    debug!("conneting to ldap using: uri: {}, admin: {}, dn: {}", uri, admin, dn);
 
     let conn = RustLDAP::new(uri).unwrap();
     conn.set_option(codes::options::LDAP_OPT_PROTOCOL_VERSION, &codes::versions::LDAP_VERSION3);
 
-    match conn.simple_bind(&format!("cn={},{}", admin, dn), password){
+    let timer = Timer::start();
+    let bound = conn.simple_bind(&format!("cn={},{}", admin, dn), password);
+    timer.observe(metrics::LDAP_DURATION, "bind");
+
+    match bound {
         Ok(_) => Ok(conn), // ini gak salah, bener
         Err(e) => {
             error!("{}", e);
@@ -20,3 +28,94 @@ pub fn connect(uri:&str, admin:&str, password:&str, dn:&str) -> Result<RustLDAP,
         }
     }
 }
+
+/**
+ * Escape value put into search filter (RFC 4515).
+ */
+pub fn escape_filter(value:&str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\5c"),
+            '*' => out.push_str("\\2a"),
+            '(' => out.push_str("\\28"),
+            ')' => out.push_str("\\29"),
+            '\0' => out.push_str("\\00"),
+            c => out.push(c)
+        }
+    }
+    out
+}
+
+/**
+ * Names (`cn`) of groups under `ou=Group,<dn>` the user is member of,
+ * posixGroup (`memberUid`) and groupOfNames / groupOfUniqueNames supported.
+ */
+pub fn user_groups(conn:&RustLDAP, uid:&str, dn:&str) -> Result<Vec<String>, String> {
+    let user_dn = escape_filter(&format!("uid={},ou=People,{}", uid, dn));
+    let filter = format!("(|(memberUid={})(member={})(uniqueMember={}))", escape_filter(uid), user_dn, user_dn);
+
+    let timer = Timer::start();
+    let found = conn.ldap_search(&format!("ou=Group,{}", dn), codes::scopes::LDAP_SCOPE_SUBTREE,
+                                 Some(&filter), Some(vec!["cn"]), false, None, None, ptr::null_mut(), -1);
+    timer.observe(metrics::LDAP_DURATION, "search");
+
+    match found {
+        Ok(result) => Ok(result.iter()
+            .filter_map(|entry| entry.get("cn").and_then(|cn| cn.first()).cloned())
+            .collect()),
+        // no groups defined in the directory
+        Err(ref e) if e.description() == "No such object" => Ok(Vec::new()),
+        Err(e) => {
+            error!("{}", e);
+            Err("Cannot read user groups from LDAP server".to_string())
+        }
+    }
+}
+
+/**
+ * Given attributes of user entry `uid=<uid>,ou=People,<dn>`, None when no such user.
+ * `uid` should be checked with `directory::valid_uid` first, it isn't escaped.
+ */
+pub fn user_entry(conn:&RustLDAP, uid:&str, dn:&str, attributes:&[String])
+        -> Result<Option<HashMap<String, Vec<String>>>, String> {
+    let attrs:Vec<&str> = attributes.iter().map(|a| a.as_str()).collect();
+
+    let timer = Timer::start();
+    let found = conn.ldap_search(&format!("uid={},ou=People,{}", uid, dn), codes::scopes::LDAP_SCOPE_BASE,
+                                 Some("(objectClass=*)"), Some(attrs), false, None, None, ptr::null_mut(), -1);
+    timer.observe(metrics::LDAP_DURATION, "search");
+
+    match found {
+        Ok(mut result) => Ok(result.pop()),
+        Err(ref e) if e.description() == "No such object" => Ok(None),
+        Err(e) => {
+            error!("{}", e);
+            Err("Cannot read user entry from LDAP server".to_string())
+        }
+    }
+}
+
+/**
+ * User entries directly under `ou=People,<dn>` matching search `filter`, given attributes
+ * only. None when more than `size_limit` entries match.
+ */
+pub fn search_users(conn:&RustLDAP, dn:&str, filter:&str, attributes:&[String], size_limit:u64)
+        -> Result<Option<Vec<HashMap<String, Vec<String>>>>, String> {
+    let attrs:Vec<&str> = attributes.iter().map(|a| a.as_str()).collect();
+
+    let timer = Timer::start();
+    let found = conn.ldap_search(&format!("ou=People,{}", dn), codes::scopes::LDAP_SCOPE_ONELEVEL,
+                                 Some(filter), Some(attrs), false, None, None, ptr::null_mut(), size_limit as i32);
+    timer.observe(metrics::LDAP_DURATION, "search");
+
+    match found {
+        Ok(result) => Ok(Some(result)),
+        Err(ref e) if e.description() == "Size limit exceeded" => Ok(None),
+        Err(ref e) if e.description() == "No such object" => Ok(Some(Vec::new())),
+        Err(e) => {
+            error!("{}", e);
+            Err("Cannot search LDAP server".to_string())
+        }
+    }
+}
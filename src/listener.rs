@@ -0,0 +1,216 @@
+
+// HTTP listeners configured in `[http]` section.
+//
+// Nickel only listens on TCP, connections accepted on unix domain socket are
+// forwarded to internal listener bound on loopback interface. That port is open
+// to every local user, so the internal listener serves only connections made by
+// the forwarder (see `Forwarded`), with client ip given by the reverse proxy.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::str::{self, FromStr};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use nickel::hyper::header::Headers;
+
+// internal listener for unix socket connections, port assigned by OS.
+pub const INTERNAL_ADDR:&'static str = "127.0.0.1:0";
+
+// reverse proxy connects as member of SSO group.
+const SOCKET_MODE:u32 = 0o660;
+
+/**
+ * Local addresses of connections forwarded from unix socket to internal listener,
+ * ie: the peer addresses it should serve.
+ */
+#[derive(Clone)]
+pub struct Forwarded(Arc<Mutex<HashSet<SocketAddr>>>);
+
+impl Forwarded {
+    pub fn new() -> Forwarded {
+        Forwarded(Arc::new(Mutex::new(HashSet::new())))
+    }
+
+    pub fn contains(&self, addr:&SocketAddr) -> bool {
+        self.0.lock().unwrap().contains(addr)
+    }
+}
+
+// address registered while forwarded connection is open.
+struct Registered {
+    forwarded:Forwarded,
+    addr:SocketAddr
+}
+
+impl Registered {
+    fn new(forwarded:&Forwarded, addr:SocketAddr) -> Registered {
+        forwarded.0.lock().unwrap().insert(addr);
+        Registered { forwarded: forwarded.clone(), addr: addr }
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.forwarded.0.lock().unwrap().remove(&self.addr);
+    }
+}
+
+pub fn parse_addr(addr:&str) -> Result<SocketAddr, String> {
+    SocketAddr::from_str(addr).map_err(|_| format!("`{}` should be `ip:port`", addr))
+}
+
+/**
+ * Bind unix socket at `path`, stale socket of previous run removed,
+ * fails when another process still listening on it.
+ */
+pub fn bind_unix(path:&str) -> io::Result<UnixListener> {
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("`{}` exists and is not a socket", path)));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("`{}` is in use by another process", path)));
+        }
+        try!(fs::remove_file(path));
+    }
+    let listener = try!(UnixListener::bind(path));
+    try!(fs::set_permissions(path, fs::Permissions::from_mode(SOCKET_MODE)));
+    Ok(listener)
+}
+
+/**
+ * Client ip of request forwarded by reverse proxy from unix socket: `X-Real-IP`,
+ * or the last `X-Forwarded-For` entry (added by the proxy itself).
+ */
+pub fn client_ip(headers:&Headers) -> Option<IpAddr> {
+    let header = |name:&str| headers.get_raw(name)
+        .and_then(|v| v.last())
+        .and_then(|v| str::from_utf8(v).ok())
+        .map(|v| v.to_string());
+
+    if let Some(ip) = header("X-Real-IP") {
+        return IpAddr::from_str(ip.trim()).ok();
+    }
+    header("X-Forwarded-For")
+        .and_then(|list| list.rsplit(',').next().and_then(|ip| IpAddr::from_str(ip.trim()).ok()))
+}
+
+/**
+ * Accept connections on unix socket in background, forwarding each to `backend`
+ * and registering it in `forwarded` while open.
+ */
+pub fn serve_unix(listener:UnixListener, backend:SocketAddr, forwarded:Forwarded) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let forwarded = forwarded.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = forward(stream, backend, &forwarded) {
+                            warn!("Cannot forward unix socket connection to {}: {}", backend, e);
+                        }
+                    });
+                },
+                Err(e) => error!("Cannot accept unix socket connection: {}", e)
+            }
+        }
+    })
+}
+
+fn forward(client:UnixStream, backend:SocketAddr, forwarded:&Forwarded) -> io::Result<()> {
+    let upstream = try!(TcpStream::connect(backend));
+    // before any request byte is sent
+    let _registered = Registered::new(forwarded, try!(upstream.local_addr()));
+
+    let mut client_read = try!(client.try_clone());
+    let mut upstream_write = try!(upstream.try_clone());
+    let request = thread::spawn(move || {
+        let _ = io::copy(&mut client_read, &mut upstream_write);
+        let _ = upstream_write.shutdown(Shutdown::Write);
+    });
+
+    let (mut upstream_read, mut client_write) = (upstream, client);
+    let _ = io::copy(&mut upstream_read, &mut client_write);
+    let _ = client_write.shutdown(Shutdown::Write);
+    let _ = request.join();
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use super::*;
+
+    #[test]
+    fn addresses() {
+        assert!(parse_addr("127.0.0.1:8080").is_ok());
+        assert!(parse_addr("[::1]:8443").is_ok());
+        assert_eq!(parse_addr("localhost:8080").err().unwrap(), "`localhost:8080` should be `ip:port`");
+        assert!(parse_addr("0.0.0.0").is_err());
+    }
+
+    #[test]
+    fn client_ips() {
+        let headers = |pairs:&[(&str, &str)]| {
+            let mut headers = Headers::new();
+            for &(name, value) in pairs {
+                headers.set_raw(name.to_string(), vec![value.as_bytes().to_vec()]);
+            }
+            headers
+        };
+        assert_eq!(client_ip(&headers(&[("X-Real-IP", " 10.0.0.7 ")])), Some(IpAddr::from_str("10.0.0.7").unwrap()));
+        assert_eq!(client_ip(&headers(&[("X-Forwarded-For", "1.2.3.4, 2001:db8::1")])),
+                   Some(IpAddr::from_str("2001:db8::1").unwrap()));
+        assert_eq!(client_ip(&headers(&[("X-Real-IP", "proxy"), ("X-Forwarded-For", "1.2.3.4")])), None);
+        assert_eq!(client_ip(&headers(&[])), None);
+    }
+
+    #[test]
+    fn forwards_unix_socket() {
+        let backend = TcpListener::bind(INTERNAL_ADDR).unwrap();
+        let addr = backend.local_addr().unwrap();
+        let forwarded = Forwarded::new();
+        let served = forwarded.clone();
+        thread::spawn(move || {
+            for conn in backend.incoming() {
+                let mut conn = conn.unwrap();
+                let mut req = String::new();
+                conn.read_to_string(&mut req).unwrap();
+                let from_socket = served.contains(&conn.peer_addr().unwrap());
+                conn.write_all(format!("echo {} {}", from_socket, req).as_bytes()).unwrap();
+            }
+        });
+
+        let path = env::temp_dir().join(format!("sso-listener-test-{}.sock", addr.port()));
+        let path = path.to_str().unwrap();
+        // stale socket file replaced
+        drop(bind_unix(path).unwrap());
+        serve_unix(bind_unix(path).unwrap(), addr, forwarded.clone());
+        assert!(bind_unix(path).is_err());
+
+        let mut client = UnixStream::connect(path).unwrap();
+        client.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).unwrap();
+        assert_eq!(resp, "echo true GET / HTTP/1.0\r\n\r\n");
+
+        // direct connection to the internal port isn't registered
+        let mut direct = TcpStream::connect(addr).unwrap();
+        direct.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        direct.shutdown(Shutdown::Write).unwrap();
+        let mut resp = String::new();
+        direct.read_to_string(&mut resp).unwrap();
+        assert!(resp.starts_with("echo false "));
+    }
+}
@@ -0,0 +1,188 @@
+
+// Configuration reloaded without restart, on SIGHUP or when config file modified.
+//
+// Handlers take snapshot with `current()` once per request, new config and its
+// tenants (with their continue url allow-lists) are swapped together so request never sees mix of
+// old and new settings. Invalid config is rejected and the old one stays active.
+//
+// Store, `[http]`, `[token]`, `[replication]` and `[audit]` are only read on startup,
+// as are `metrics.enabled` and whether `[webauthn]` is configured, they decide which
+// routes are registered.
+
+use std::fs;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use config::Conf;
+use continue_url::Target;
+use tenant::{self, Tenant};
+use signals;
+
+const WATCH_INTERVAL_SECS:u64 = 2;
+
+pub struct Settings {
+    pub conf:Conf,
+    pub tenants:Vec<Tenant>,
+}
+
+impl Settings {
+    pub fn new(conf:Conf) -> Result<Settings, String> {
+        let tenants = try!(tenant::from_conf(&conf));
+        Ok(Settings { conf: conf, tenants: tenants })
+    }
+
+    /**
+     * Tenant of request, see `tenant::select`.
+     */
+    pub fn tenant(&self, host:Option<&str>, prefix:Option<&str>) -> Option<&Tenant> {
+        tenant::select(&self.tenants, host, prefix)
+    }
+
+    pub fn tenant_by_dn(&self, dn:&str) -> Option<&Tenant> {
+        tenant::by_dn(&self.tenants, dn)
+    }
+
+    /**
+     * Continue target allowed by any tenant, eg: after logout.
+     */
+    pub fn check_continue_any(&self, cont:&str) -> Target {
+        let mut result = Target::Denied;
+        for tenant in &self.tenants {
+            match tenant.continue_allow.check(cont) {
+                Target::Denied => continue,
+                target => { result = target; break }
+            }
+        }
+        result
+    }
+}
+
+pub struct LiveConf {
+    path:String,
+    current:RwLock<Arc<Settings>>,
+    modified:Mutex<Option<SystemTime>>,
+}
+
+fn modified_time(path:&str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+impl LiveConf {
+    pub fn new(path:&str, conf:Conf) -> Result<LiveConf, String> {
+        let settings = try!(Settings::new(conf));
+        Ok(LiveConf {
+            path: path.to_string(),
+            current: RwLock::new(Arc::new(settings)),
+            modified: Mutex::new(modified_time(path))
+        })
+    }
+
+    pub fn current(&self) -> Arc<Settings> {
+        self.current.read().unwrap().clone()
+    }
+
+    /**
+     * Read config file again and make it active when valid.
+     */
+    pub fn reload(&self) -> Result<(), Vec<String>> {
+        *self.modified.lock().unwrap() = modified_time(&self.path);
+
+        let mut conf = try!(Conf::read_file(&self.path)
+            .map_err(|errors| errors.iter().map(|e| e.to_string()).collect::<Vec<String>>()));
+
+        let current = self.current();
+        if conf.security.csrf_secret.is_empty() {
+            // random one generated on startup, keep login forms already shown valid
+            conf.security.csrf_secret = current.conf.security.csrf_secret.clone();
+        }
+
+        let settings = try!(Settings::new(conf).map_err(|e| vec![e]));
+        *self.current.write().unwrap() = Arc::new(settings);
+        Ok(())
+    }
+
+    /**
+     * Config file modified since last (re)load.
+     */
+    fn file_changed(&self) -> bool {
+        let modified = modified_time(&self.path);
+        modified.is_some() && modified != *self.modified.lock().unwrap()
+    }
+
+    fn reload_logged(&self, reason:&str) {
+        match self.reload() {
+            Ok(_) => info!("Configuration `{}` reloaded ({})", self.path, reason),
+            Err(errors) => {
+                error!("Configuration `{}` not reloaded ({}), keeping the current one:", self.path, reason);
+                for e in errors {
+                    error!("    {}", e);
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Reload config on SIGHUP and when the file modified.
+ */
+pub fn watch(live:Arc<LiveConf>) {
+    {
+        let live = live.clone();
+        signals::on_hangup(move || live.reload_logged("SIGHUP"));
+    }
+
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(WATCH_INTERVAL_SECS));
+        if live.file_changed() {
+            live.reload_logged("file modified");
+        }
+    });
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use rand::{self, Rng};
+    use config::Conf;
+    use super::*;
+
+    fn write(path:&str, data:&str) {
+        File::create(path).unwrap().write_all(data.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn reload_valid_only() {
+        let suffix:String = rand::thread_rng().gen_ascii_chars().take(10).collect();
+        let path = env::temp_dir().join(format!("sso-live-test-{}.toml", suffix));
+        let path = path.to_str().unwrap();
+        write(path, "login_caption = \"One\"\n[ldap]\nuri = \"ldap://a\"\n");
+
+        let mut conf = Conf::read_file(path).unwrap();
+        conf.security.csrf_secret = "generated".to_string();
+        let live = LiveConf::new(path, conf).unwrap();
+        let before = live.current();
+
+        write(path, "login_caption = \"Two\"\nallowed_continue_urls = [\"https://app.example.com\"]\n\
+                     [ldap]\nuri = \"ldap://b\"\n");
+        assert!(live.reload().is_ok());
+        let after = live.current();
+        assert_eq!(after.conf.login_caption, "Two");
+        assert_eq!(after.conf.ldap.uri, "ldap://b");
+        assert_eq!(after.conf.security.csrf_secret, "generated");
+        assert!(after.tenants[0].continue_allow.is_allowed("https://app.example.com/"));
+        // snapshot taken before still the old one
+        assert_eq!(before.conf.login_caption, "One");
+        assert!(!before.tenants[0].continue_allow.is_allowed("https://app.example.com/"));
+
+        write(path, "login_caption = \"Three\"\n[ldap]\nuri = \"\"\n");
+        let errors = live.reload().err().unwrap();
+        assert_eq!(errors, vec!["line 3: `ldap.uri` required".to_string()]);
+        assert_eq!(live.current().conf.login_caption, "Two");
+        assert!(!live.file_changed());
+        let _ = ::std::fs::remove_file(path);
+    }
+}
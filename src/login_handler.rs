@@ -4,28 +4,33 @@ use serialize::base64::{FromBase64};
 // use serialize::hex::FromHex;
 use serialize::json;
 use nickel::MediaType;
-// use std::collections::HashMap;
 use nickel::{Nickel, HttpRouter, QueryString, Response};
-// use nickel::status::StatusCode;
+use nickel::hyper::header::Headers;
+use nickel::status::StatusCode;
 use nickel::extensions::Redirect;
 use std::str;
+use std::sync::Arc;
 // use std::sync::{Arc, Mutex};
 // use crypto::bcrypt;
-use crypto::digest::Digest;
-use crypto::sha1::Sha1;
 use std::io::Read;
-use std::error::Error;
-use oldap::codes;
 // use oldap::errors::*;
-use regex::Regex;
-use url::{Url, ParseError};
+use url::ParseError;
 use mustache::{MapBuilder};
 use nickel_mustache::Render;
 
 // module
 use ldap;
-// use store::Store;
+use auth;
+use continue_url;
+use store::{Store, StoreResult, Namespace, TokenRecord, UserIndex};
 use token;
+use jwt;
+use webauthn;
+use csrf;
+use session::{self, Session};
+use config::{Conf, LdapConf};
+use tenant::Tenant;
+use audit::{Requester, Event, Kind};
 use Context;
 use api_result;
 // use errno;
@@ -34,250 +39,488 @@ use utils;
 
 
 macro_rules! show_error{
-    ($error:expr, $cont:expr, $conf:ident, $target_dn:expr, $_resp:ident) => {{
-        // let mut data = HashMap::new();
-        let cont:String = utils::encode_url($cont);
-
-        let data = MapBuilder::new()
-            .insert_str("continue", cont)
-            .insert_str("login_caption", $conf.login_caption.clone())
-            .insert_str("version", build::VERSION.to_string())
+    ($error:expr, $cont:expr, $conf:ident, $tenant:expr, $_resp:ident) => {{
+        let data = login_page($tenant, $conf, $_resp.headers_mut(), $cont)
             .insert_bool("error", true)
             .insert_str("error_desc", $error.to_string())
-            .insert_str("dn", $target_dn.to_string())
             .build();
 
-        // data.insert("continue", cont.to_string());
-        // data.insert("login_caption", $conf.login_caption.clone());
-        // data.insert("version", build::VERSION.to_string());
-        // data.insert("error", true);
-        // data.insert("error_desc", $error.to_string());
+        return Render::render_data($_resp, "tmpl/index.html", &data);
+    }}
+}
 
-        // return $_resp.render("tmpl/index.html", &data);
+macro_rules! show_locked{
+    ($until:expr, $cont:expr, $conf:ident, $tenant:expr, $_resp:ident) => {{
+        let minutes = ($until.saturating_sub(utils::current_time_millis()) + 59999) / 60000;
 
+        let data = login_page($tenant, $conf, $_resp.headers_mut(), $cont)
+            .insert_bool("locked", true)
+            .insert_str("locked_minutes", minutes.to_string())
+            .build();
 
         return Render::render_data($_resp, "tmpl/index.html", &data);
     }}
 }
 
+// login attempt too soon after failed one, page says when to retry.
+macro_rules! show_delayed{
+    ($until:expr, $cont:expr, $conf:ident, $tenant:expr, $_resp:ident) => {{
+        let secs = ($until.saturating_sub(utils::current_time_millis()) + 999) / 1000;
+        $_resp.set(StatusCode::TooManyRequests);
+        $_resp.headers_mut().set_raw("Retry-After", vec![secs.to_string().into_bytes()]);
+        show_error!(format!("Terlalu cepat setelah login gagal, silahkan coba lagi dalam {} detik.", secs),
+                $cont, $conf, $tenant, $_resp)
+    }}
+}
 
-fn check_password(challenge_password: &String, password: &String) -> bool {
-    let algo = &challenge_password[..6];
-    if algo != "{SSHA}" {
-        error!("Unexpected pass hash algo: {}", algo);
-        return false;
-    }
-    let data = &challenge_password[6..];
-
-    let data_bytes = data.from_base64().unwrap();
-    let digest = &data_bytes[..20];
-    let salt = &data_bytes[20..];
+// no tenant configured for request host or path prefix.
+macro_rules! show_unknown_tenant{
+    ($_resp:ident) => {{
+        $_resp.set(StatusCode::NotFound);
+        return $_resp.send("Direktori tidak ditemukan.");
+    }}
+}
 
-    debug!("data: {:?}", data_bytes);
-    debug!("digest: {:?}", digest);
-    debug!("salt: {:?}", salt);
+// tenant of request, by path prefix of `/:tenant/...` routes or `Host` header.
+macro_rules! request_tenant{
+    ($settings:ident, $req:ident, $_resp:ident) => {{
+        let host = utils::request_host(&$req.origin.headers);
+        let prefix = $req.param("tenant").map(|p| p.to_string());
+        match $settings.tenant(host.as_ref().map(|h| h.as_str()), prefix.as_ref().map(|p| p.as_str())) {
+            Some(tenant) => tenant,
+            None => {
+                debug!("No tenant for host {:?}, prefix {:?}", host, prefix);
+                show_unknown_tenant!($_resp)
+            }
+        }
+    }}
+}
 
-    let mut sha = Sha1::new();
-    sha.input_str(password);
-    sha.input(salt);
+// login page for failed authentication.
+macro_rules! show_auth_failure{
+    ($failure:expr, $cont:expr, $conf:ident, $tenant:expr, $_resp:ident) => {{
+        match $failure {
+            auth::Failure::Locked { until } => show_locked!(until, $cont, $conf, $tenant, $_resp),
+            auth::Failure::Delayed { until } => show_delayed!(until, $cont, $conf, $tenant, $_resp),
+            auth::Failure::InvalidCredentials => {
+                show_error!("Identitas atau kata kunci tidak benar, mohon pastikan identitas atau kata kunci yang Anda masukkan benar.",
+                        $cont, $conf, $tenant, $_resp)
+            },
+            auth::Failure::UnknownUser => {
+                show_error!("Kredensial tidak ditemukan, mohon periksa identitas masuk Anda.",
+                        $cont, $conf, $tenant, $_resp)
+            },
+            auth::Failure::Ldap(e) => {
+                error!("Cannot binding to LDAP service. {}.", e);
+                show_error!("Internal server error. Gagal terhubung dengan server LDAP.",
+                        $cont, $conf, $tenant, $_resp)
+            },
+            auth::Failure::Store(e) => {
+                error!("Store error: {}", e);
+                show_error!("Internal server error. Silahkan coba beberapa saat lagi.",
+                        $cont, $conf, $tenant, $_resp)
+            }
+        }
+    }}
+}
 
-    let mut calculated_sha_output = [0u8; 20];
-    sha.result(&mut calculated_sha_output);
+// unwrap store result, or show error page when storage failed.
+macro_rules! try_store{
+    ($result:expr, $cont:expr, $conf:ident, $tenant:expr, $_resp:ident) => {{
+        match $result {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Store error: {}", e);
+                show_error!("Internal server error. Silahkan coba beberapa saat lagi.",
+                        $cont, $conf, $tenant, $_resp)
+            }
+        }
+    }}
+}
 
-    let matched = digest[..] == calculated_sha_output[..];
+// redirect to continue target with the token when allowed by the tenant,
+// or just return the token as json when no continue target given.
+// signed access token (`token.format = "jwt"`) passed along as `access_token`.
+macro_rules! continue_with_token{
+    ($token:expr, $signed:expr, $cont:expr, $conf:ident, $tenant:expr, $_resp:ident) => {{
+        let cont:&str = $cont;
+        let signed:Option<String> = $signed;
+
+        match $tenant.continue_allow.check(cont) {
+            continue_url::Target::Allowed(mut url) => {
+                url.query_pairs_mut().append_pair("token", &$token);
+                if let Some(ref access_token) = signed {
+                    url.query_pairs_mut().append_pair("access_token", access_token);
+                }
 
-    debug!("sha: {:?}", calculated_sha_output);
-    debug!("matched: {}", matched);
+                return $_resp.redirect(url.into_string());
+            },
+            continue_url::Target::Denied => {
+                warn!("Continue target not allowed for tenant `{}`: {}", $tenant.id, cont);
+                show_error!("Unauthorized continue target parameter. Please contact administrator.",
+                        cont, $conf, $tenant, $_resp)
+            },
+            continue_url::Target::None => {
+                match signed {
+                    Some(access_token) => api_result_success_json!(
+                        api_result::SignedToken::new($token, access_token, $conf.token.jwt_ttl_secs), $_resp),
+                    None => api_result_success_json!($token, $_resp)
+                }
+            }
+        }
+    }}
+}
 
-    matched
+// signed access token for the opaque one, or show error page when groups can't be read.
+macro_rules! try_sign{
+    ($signer:ident, $uid:expr, $dn:expr, $token:expr, $cont:expr, $conf:ident, $tenant:expr, $_resp:ident) => {{
+        match sign_access_token(&$tenant.ldap, &$signer, $uid, $dn, $token) {
+            Ok(signed) => signed,
+            Err(e) => show_error!(e, $cont, $conf, $tenant, $_resp)
+        }
+    }}
 }
 
 
-pub fn setup(ctx:&Context, server: &mut Nickel){
+/**
+ * Login page data common to every state: tenant branding, form target and CSRF token.
+ */
+fn login_page(tenant:&Tenant, conf:&Conf, headers:&mut Headers, cont:&str) -> MapBuilder {
+    MapBuilder::new()
+        .insert_str("continue", utils::encode_url(cont))
+        .insert_str("dn", utils::encode_url(tenant.base_dn()))
+        .insert_str("prefix", tenant.path_prefix.clone())
+        .insert_str("login_caption", tenant.login_caption.clone())
+        .insert_str("logo", tenant.logo.clone())
+        .insert_bool("has_logo", !tenant.logo.is_empty())
+        .insert_str("version", build::VERSION.to_string())
+        .insert_str("csrf_token", csrf::issue(&conf.security, headers))
+}
 
-    let store = ctx.store.clone();
-    let conf = ctx.conf.clone();
 
-    // for security reason we only accept for specific domain/sub-domain provided in config.
-    let re_str = format!(r"^https?://[a-zA-Z0-9\.\\-_]*({}).*$", ctx.conf.allowed_continue_domain.replace(".", "\\."));
-    debug!("re_str: {}", re_str);
+/**
+ * Store access token generated for user (`token::generate`), previous token
+ * owned by the user will be revoked. Signed access token should be issued
+ * before, so failure to sign leaves nothing behind.
+ */
+pub fn issue_token(store:&Store, conf:&Conf, user_name:&str, dn:&str, generated_token:&str) -> StoreResult<()> {
+    let record = TokenRecord { uid: user_name.to_string(), dn: dn.to_string() };
 
+    let mut batch = store.batch();
 
-    // @FIXME
-    let url_re = Regex::new("^https?://.+$").unwrap();
+    if let Some(old) = try!(store.get_record::<UserIndex>(Namespace::User, user_name)) {
+        // remove old token record, JWTs issued for it revoked too
+        batch = jwt::revoke(batch.del_record(Namespace::Token, &old.token), &conf.token, &old.token);
+    }
 
+    batch
+        .put_record(Namespace::Token, generated_token, &record)
+        .put_record(Namespace::User, user_name, &UserIndex { token: generated_token.to_string() }) // for reverse loookup
+        .commit()
+}
 
-    let cont_re = match Regex::new(&re_str){
-        Ok(_r) => _r,
-        Err(e) => {
-            error!("{:?}", e);
-            panic!("Invalid `allowed_continue_domain` format, please check your configuration file.")
-        }
+/**
+ * Issue signed access token for opaque `token` when `token.format = "jwt"`,
+ * groups read from tenant's LDAP on every issue so membership changes apply on refresh.
+ */
+pub fn sign_access_token(ldap_conf:&LdapConf, signer:&Option<Arc<jwt::Signer>>, uid:&str, dn:&str, token:&str)
+        -> Result<Option<String>, String> {
+    let signer = match *signer {
+        Some(ref signer) => signer,
+        None => return Ok(None)
     };
 
-    server.post("/login", middleware! { |_req, mut _resp|
-
-        let mut user_name = String::new();
-        let mut given_password = String::new();
-
-        {
-            let mut body = String::new();
-
-            _req.origin.read_to_string(&mut body).unwrap();
+    let conn = try!(ldap::connect(&ldap_conf.uri, &ldap_conf.admin_user, &ldap_conf.admin_password, dn));
+    let groups = try!(ldap::user_groups(&conn, uid, dn));
 
-            for (key, value) in url::form_urlencoded::parse(body.as_bytes()){
-                if key == "user_name" { user_name = value.clone().into_owned(); }
-                if key == "password" { given_password = value.clone().into_owned(); }
-            }
+    Ok(Some(signer.sign(&signer.claims(uid, dn, groups, token))))
+}
 
+/**
+ * Password (and second factor) verified, issue token
+ * and start new SSO session for the browser, replacing the one of `previous` cookie.
+ * Tokens of the same user's previous session are kept in the new one for single logout.
+ */
+fn start_session(store:&Store, conf:&Conf, headers:&mut Headers, previous:Option<String>, user_name:&str, dn:&str,
+                 generated_token:&str, client_host:Option<String>) -> StoreResult<()> {
+    try!(issue_token(store, conf, user_name, dn, generated_token));
+
+    let mut session = try!(session::create(store, conf, user_name, dn));
+    if let Some(old) = try!(previous.map_or(Ok(None), |id| session::get(store, &id))) {
+        if old.uid == user_name {
+            session.tokens = old.tokens;
+            session.clients = old.clients;
         }
+        try!(session::destroy(store, &old.id));
+    }
+    session::add_token(&mut session, generated_token, client_host);
+    try!(session::save(store, &session));
+    session::set_cookie(conf, headers, &session);
 
-        let store = store.lock().unwrap();
-
-        // // before
-        // debug!("before (user_name): {:?}", store.get("user_name"));
-        //
-        // store.put("user_name", &user_name);
-        // // after
-        // debug!("after (user_name): {:?}", store.get("user_name"));
-
-        let query = _req.query();
-        let cont = query.get("continue").unwrap_or("?");
-
-
-        debug!("user_name: {:?}", user_name);
-
-        let dn = query.get("dn").unwrap_or("dc=ansvia,dc=org").to_string();
-        let conn = ldap::connect(&conf.ldap.uri, &conf.ldap.admin_user,
-            &conf.ldap.admin_password, &dn);
-
+    Ok(())
+}
 
-        if conn.is_err() {
-            show_error!(conn.err().unwrap(), cont, conf, dn, _resp);
-        }
+/**
+ * Issue new token for another application from existing SSO session,
+ * tokens issued for other applications are kept.
+ */
+fn issue_session_token(store:&Store, mut session:Session, generated_token:&str, client_host:Option<String>) -> StoreResult<()> {
+    try!(store.put_record(Namespace::Token, generated_token,
+        &TokenRecord { uid: session.uid.clone(), dn: session.dn.clone() }));
 
-        let conn = conn.unwrap();
+    session::add_token(&mut session, generated_token, client_host);
+    session::save(store, &session)
+}
 
-        let dn_query = &format!("uid={},ou=People,{}", user_name, dn);
 
-        debug!("dn_query: {}", dn_query);
+pub fn setup(ctx:&Context, server: &mut Nickel){
 
-        match conn.simple_search(dn_query,
-            codes::scopes::LDAP_SCOPE_BASE){
-            Ok(result) => {
+    let store = ctx.store.clone();
+    let live = ctx.live.clone();
+
+    // login form of tenant selected by host, or by path prefix, eg: /acme/
+    for path in &["/", "/:tenant/"] {
+        let store = store.clone();
+        let live = live.clone();
+        let signer = ctx.signer.clone();
+        let audit = ctx.audit.clone();
+
+        server.get(*path, middleware! { |_req, mut _resp|
+            let settings = live.current();
+            let conf = &settings.conf;
+            let tenant = request_tenant!(settings, _req, _resp);
+            let requester = Requester::new(&_req.origin.headers, &_req.origin.remote_addr);
+
+            let session = {
+                let store = store.lock().unwrap();
+                session::from_headers(&store, &_req.origin.headers)
+            };
+
+            let query = _req.query();
+            let cont = query.get("continue").unwrap_or("/");
+
+            if let Some(target_dn) = query.get("target_dn") {
+                if !target_dn.is_empty() && !tenant.owns_dn(target_dn) {
+                    warn!("Unknown target DN for tenant `{}`: {}", tenant.id, target_dn);
+                    show_error!("DN tidak dikenal, mohon hubungi administrator.", cont, conf, tenant, _resp);
+                }
+            }
 
-                let user_password = {
-                    let owned = result.to_owned();
-                    match owned.first(){
-                        Some(ref o) => {
-                            let v = o.get("userPassword").unwrap_or(&Vec::<String>::new()).to_owned();
-                            match v.first() {
-                                Some(v) => v.clone(),
-                                None => "".to_string()
-                            }
-                        },
-                        _ => "".to_string()
+            // user already logged in, skip login form and go straight back to the application
+            // with fresh token, unless application ask for re-authentication (`prompt=login`).
+            let session = try_store!(session, cont, conf, tenant, _resp);
+
+            if let Some(session) = session {
+                if let continue_url::Target::Allowed(mut url) = tenant.continue_allow.check(cont) {
+                    if query.get("prompt") != Some("login") && tenant.owns_dn(&session.dn) {
+                        debug!("reusing SSO session of `{}` for continue: {}", session.uid, cont);
+
+                        let (uid, dn) = (session.uid.clone(), session.dn.clone());
+                        let client_host = url.host_str().map(|h| h.to_string());
+                        let generated_token = token::generate(&conf.token);
+                        let signed = try_sign!(signer, &uid, &dn, &generated_token, cont, conf, tenant, _resp);
+                        {
+                            let store = store.lock().unwrap();
+                            try_store!(issue_session_token(&store, session, &generated_token, client_host.clone()),
+                                       cont, conf, tenant, _resp);
+                        }
+                        audit.record(Event::new(Kind::TokenIssued, &requester).user(&uid, &dn)
+                                     .client(client_host.as_ref().map(|h| h.as_str()).unwrap_or("")).detail("session"));
+
+                        url.query_pairs_mut().append_pair("token", &generated_token);
+                        if let Some(ref access_token) = signed {
+                            url.query_pairs_mut().append_pair("access_token", access_token);
+                        }
+
+                        return _resp.redirect(url.into_string());
                     }
-                };
+                }
+            }
 
-                // let mut result_str = "".to_owned();
+            debug!("tenant: {}, cont: {}", tenant.id, cont);
+            let data = login_page(tenant, conf, _resp.headers_mut(), cont).build();
+            return Render::render_data(_resp, "tmpl/index.html", &data);
+        });
+    }
 
-                let password_is_ok = check_password(&user_password, &given_password);
+    {
+        let store = store.clone();
+        let live = live.clone();
+        let signer = ctx.signer.clone();
+        let audit = ctx.audit.clone();
 
-                // debug!("userPassword: {:?}, check_password(): {}", user_password, password_is_ok);
+        // second step of login for user with security key (WebAuthn assertion).
+        server.post("/login/webauthn", middleware! { |_req, mut _resp|
+            let settings = live.current();
+            let conf = &settings.conf;
+            let request_tenant = request_tenant!(settings, _req, _resp);
+            let requester = Requester::new(&_req.origin.headers, &_req.origin.remote_addr);
 
-                if !password_is_ok {
-                    // return _resp.send("Access Denied");
+            let mut body = String::new();
+            _req.origin.read_to_string(&mut body).unwrap();
 
-                    // let result = api_result_error_json!(errno::UNAUTHORIZED, errno::UNAUTHORIZED_STR, _resp);
-                    // return _resp.send(result);
+            let form = utils::parse_form(&body);
+            let field = |name:&str| form.get(name).map(|v| v.as_str()).unwrap_or("");
+            let decode = |name:&str| field(name).from_base64().unwrap_or(Vec::new());
+
+            let client_ip = _req.origin.remote_addr.ip().to_string();
+
+            let pending = {
+                let store = store.lock().unwrap();
+                match try_store!(webauthn::take_challenge(&store, field("pending_id"), webauthn::ChallengeKind::Login),
+                                 "?", conf, request_tenant, _resp) {
+                    Some(p) => p,
+                    None => {
+                        audit.record(Event::new(Kind::Login, &requester).detail("webauthn").failed("challenge_expired"));
+                        show_error!("Sesi login telah kedaluwarsa, silahkan ulangi login.",
+                                "?", conf, request_tenant, _resp)
+                    }
+                }
+            };
 
+            let cont = pending.cont.clone();
+            let dn = pending.dn.clone();
 
-                    show_error!("Identitas atau kata kunci tidak benar, mohon pastikan identitas atau kata kunci yang Anda masukkan benar.",
-                            cont, conf, dn, _resp);
+            // tenant removed by config reload while waiting for security key.
+            let tenant = match settings.tenant_by_dn(&dn) {
+                Some(tenant) => tenant,
+                None => {
+                    warn!("Unknown DN of pending login for `{}`: {}", pending.uid, dn);
+                    show_unknown_tenant!(_resp)
                 }
+            };
+
+            let assertion = auth::Assertion {
+                credential_id: field("credential_id").to_string(),
+                client_data_json: decode("client_data_json"),
+                authenticator_data: decode("authenticator_data"),
+                signature: decode("signature")
+            };
+
+            let verified = {
+                let store = store.lock().unwrap();
+                auth::verify_second_factor(&store, conf, &client_ip, &pending, &assertion)
+            };
+            if let Err(failure) = verified {
+                audit.record(Event::new(Kind::Login, &requester).user(&pending.uid, &dn).detail("webauthn")
+                             .failed(failure.reason()));
+                show_auth_failure!(failure, &cont, conf, tenant, _resp);
+            }
 
-                // for debugging purposes only.
-                // for res in result {
-                //     println!("simple search result: {:?}", res);
-                //     for (key, value) in res {
-                //         println!("- key: {:?}", key);
-                //         result_str.push_str("    ");
-                //         result_str.push_str(&key);
-                //         result_str.push_str(" :  ");
-                //         for res_val in value {
-                //             println!("    + {:?}", res_val);
-                //             result_str.push_str(&res_val);
-                //             result_str.push_str("\n");
-                //         }
-                //     }
-                // }
-
-
-                let generated_token = token::generate();
-
-                match store.get(&user_name){
-                    Some(old_token) => {
-                        store.batch()
-                            .del(&old_token) // remove old token records
-                            .del(&format!("dn_{}", &old_token)) // and it's dn
-                            .commit()
-                    },
-                    _ => ()
-                }
+            // groups read from LDAP without holding the store lock
+            let previous = session::cookie_id(&_req.origin.headers);
+            let client_host = tenant.continue_allow.check(&cont).host();
+            let generated_token = token::generate(&conf.token);
+            let signed = try_sign!(signer, &pending.uid, &dn, &generated_token, &cont, conf, tenant, _resp);
+
+            {
+                let store = store.lock().unwrap();
+                try_store!(start_session(&store, conf, _resp.headers_mut(), previous, &pending.uid, &dn, &generated_token,
+                                         client_host.clone()),
+                           &cont, conf, tenant, _resp);
+            }
+            audit.login_success(&requester, &pending.uid, &dn, "webauthn",
+                                client_host.as_ref().map(|h| h.as_str()).unwrap_or(""));
 
-                store.batch()
-                    .put(&generated_token, &user_name)
-                    .put(&user_name, &generated_token) // for reverse loookup
-                    .put(&format!("dn_{}", &generated_token), &dn)
-                    .commit();
+            debug!("continue: {}", cont);
 
-                debug!("continue: {}", cont);
+            continue_with_token!(generated_token, signed, &cont, conf, tenant, _resp)
+        });
+    }
 
-                if cont_re.is_match(cont){
+    for path in &["/login", "/:tenant/login"] {
+        let store = store.clone();
+        let live = live.clone();
+        let signer = ctx.signer.clone();
+        let audit = ctx.audit.clone();
 
-                    let mut url = Url::parse(cont).unwrap();
-                    url.query_pairs_mut().append_pair("token", &generated_token);
+        server.post(*path, middleware! { |_req, mut _resp|
+            let settings = live.current();
+            let conf = &settings.conf;
+            let tenant = request_tenant!(settings, _req, _resp);
+            let requester = Requester::new(&_req.origin.headers, &_req.origin.remote_addr);
 
-                    return _resp.redirect(url.into_string());
+            let mut body = String::new();
+            _req.origin.read_to_string(&mut body).unwrap();
 
-                }else if url_re.is_match(cont){
-                    // api_result_error_json!(errno::UNAUTHORIZED, errno::UNAUTHORIZED_STR, _resp)
+            let form = utils::parse_form(&body);
+            let field = |name:&str| form.get(name).map(|v| v.as_str()).unwrap_or("");
+            let user_name = field("user_name");
 
-                    // return _resp.send(show_error(ctx, "Identitas atau kata kunci salah", cont, &_resp));
+            let client_ip = _req.origin.remote_addr.ip().to_string();
+            let csrf_ok = csrf::verify(&conf.security, &_req.origin.headers, field("csrf_token"));
 
-                    show_error!("Unauthorized continue target parameter. Please contact administrator.",
-                            cont, conf, dn, _resp)
-                }else{
-                    api_result_success_json!(generated_token, _resp)
-                }
+            let query = _req.query();
+            let cont = query.get("continue").unwrap_or("?");
 
+            debug!("user_name: {:?}", user_name);
 
-            },
-            Err(err) => {
-                match err.description().as_ref() {
-                    "No such object" => {
-                        // format!("Credential for `{}` didn't exists.", user_name)
-                        // api_result_error_json!(errno::UNAUTHORIZED, errno::UNAUTHORIZED_STR, _resp)
+            let dn = tenant.base_dn().to_string();
+            let failed = |reason:&str| Event::new(Kind::Login, &requester).user(user_name, &dn).detail("password").failed(reason);
 
-                        show_error!("Kredensial tidak ditemukan, mohon periksa identitas masuk Anda.",
-                                cont, conf, dn, _resp)
-                    },
-                    another_error => {
-                        // api_result_error_json!(errno::INTERNAL_SERVER_ERROR,
-                        //     &format!("Cannot binding to LDAP service. {}.", another_error), _resp)
+            if !csrf_ok {
+                warn!("Invalid CSRF token on login for `{}` from {}", user_name, client_ip);
+                audit.record(failed("csrf"));
+                show_error!("Formulir login sudah tidak berlaku, silahkan ulangi login.", cont, conf, tenant, _resp);
+            }
 
-                        error!("Cannot binding to LDAP service. {}.", another_error);
+            // form of another tenant, DN never taken from request as is.
+            if let Some(given_dn) = query.get("dn") {
+                if !given_dn.is_empty() && !tenant.owns_dn(given_dn) {
+                    warn!("Unknown DN for tenant `{}` on login for `{}`: {}", tenant.id, user_name, given_dn);
+                    audit.record(failed("unknown_dn"));
+                    show_error!("DN tidak dikenal, mohon hubungi administrator.", cont, conf, tenant, _resp);
+                }
+            }
 
-                        show_error!("Internal server error. Gagal terhubung dengan server LDAP.",
-                                cont, conf, dn, _resp)
+            if let Err(failure) = auth::verify_password(&store, conf, tenant, &client_ip, user_name, field("password")) {
+                audit.record(failed(failure.reason()));
+                show_auth_failure!(failure, cont, conf, tenant, _resp);
+            }
 
-                        // format!("Error: {}", another_error)
-                    }
+            // second factor, when user has registered security key
+            let second_factor = {
+                let store = store.lock().unwrap();
+                auth::start_second_factor(&store, conf, user_name, &dn, cont)
+            };
+            match second_factor {
+                Ok(Some((pending_id, options))) => {
+                    let data = MapBuilder::new()
+                        .insert_str("login_caption", tenant.login_caption.clone())
+                        .insert_str("logo", tenant.logo.clone())
+                        .insert_bool("has_logo", !tenant.logo.is_empty())
+                        .insert_str("version", build::VERSION.to_string())
+                        .insert_str("pending_id", pending_id)
+                        .insert_str("options", options)
+                        .build();
+
+                    return Render::render_data(_resp, "tmpl/webauthn.html", &data);
+                },
+                Ok(None) => (),
+                Err(failure) => {
+                    audit.record(failed(failure.reason()));
+                    show_auth_failure!(failure, cont, conf, tenant, _resp)
                 }
+            }
 
+            // groups read from LDAP without holding the store lock
+            let previous = session::cookie_id(&_req.origin.headers);
+            let client_host = tenant.continue_allow.check(cont).host();
+            let generated_token = token::generate(&conf.token);
+            let signed = try_sign!(signer, user_name, &dn, &generated_token, cont, conf, tenant, _resp);
+
+            {
+                let store = store.lock().unwrap();
+                try_store!(start_session(&store, conf, _resp.headers_mut(), previous, user_name, &dn, &generated_token,
+                                         client_host.clone()),
+                           cont, conf, tenant, _resp);
             }
-        }
+            audit.login_success(&requester, user_name, &dn, "password",
+                                client_host.as_ref().map(|h| h.as_str()).unwrap_or(""));
 
-    });
+            debug!("continue: {}", cont);
+
+            continue_with_token!(generated_token, signed, cont, conf, tenant, _resp)
+        });
+    }
 }
@@ -0,0 +1,224 @@
+
+// Single logout: end SSO session, revoke every token issued within it
+// and notify the applications that received those tokens.
+
+use std::thread;
+use std::time::Duration;
+use std::io::Read;
+use nickel::hyper::Client;
+use nickel::hyper::header::ContentType;
+use url::form_urlencoded;
+
+use config::{Conf, ClientConf};
+use session::{self, Session};
+use store::{Store, StoreResult, Namespace, UserIndex};
+use token;
+use jwt;
+
+// back-channel delivery attempts, delay doubled after every failure.
+const BACKCHANNEL_ATTEMPTS:u32 = 4;
+const BACKCHANNEL_RETRY_DELAY_MILLIS:u64 = 1000;
+const BACKCHANNEL_TIMEOUT_SECS:u64 = 10;
+
+
+/**
+ * Revoke session tokens and remove the session,
+ * returns registered clients that need to be notified.
+ */
+pub fn end_session<'a>(store:&Store, conf:&'a Conf, session:&Session) -> StoreResult<Vec<&'a ClientConf>> {
+    let user_index = try!(store.get_record::<UserIndex>(Namespace::User, &session.uid));
+
+    let mut batch = store.batch();
+    for token in &session.tokens {
+        batch = jwt::revoke(batch.del_record(Namespace::Token, token), &conf.token, token);
+        if user_index.as_ref().map_or(false, |i| token::eq(&i.token, token)) {
+            batch = batch.del_record(Namespace::User, &session.uid);
+        }
+    }
+    try!(batch.commit());
+
+    try!(session::destroy(store, &session.id));
+
+    info!("SSO session of `{}` ended, {} token(s) revoked", session.uid, session.tokens.len());
+
+    let mut clients:Vec<&ClientConf> = Vec::new();
+    for host in &session.clients {
+        match conf.client_by_host(host) {
+            Some(client) => {
+                if !clients.iter().any(|c| c.id == client.id) {
+                    clients.push(client);
+                }
+            },
+            None => debug!("no registered client for host `{}`, not notified", host)
+        }
+    }
+    Ok(clients)
+}
+
+/**
+ * Logout notification body sent to client logout urls.
+ */
+pub fn notification_params(session:&Session) -> String {
+    form_urlencoded::Serializer::new(String::new())
+        .append_pair("event", "logout")
+        .append_pair("uid", &session.uid)
+        .append_pair("sid", &session.id)
+        .finish()
+}
+
+/**
+ * Deliver back-channel logout to the clients in background.
+ */
+pub fn notify_backchannel(clients:&Vec<&ClientConf>, session:&Session){
+    let body = notification_params(session);
+
+    for client in clients {
+        if client.backchannel_logout_url.is_empty() {
+            continue;
+        }
+        let url = client.backchannel_logout_url.clone();
+        let body = body.clone();
+        let client_id = client.id.clone();
+
+        thread::spawn(move || {
+            match post_with_retries(&url, &body, BACKCHANNEL_ATTEMPTS, BACKCHANNEL_RETRY_DELAY_MILLIS) {
+                Ok(_) => debug!("back-channel logout delivered to `{}`", client_id),
+                Err(e) => error!("back-channel logout to `{}` ({}) failed: {}", client_id, url, e)
+            }
+        });
+    }
+}
+
+/**
+ * POST form body to url, retry when failed or non 2xx response.
+ */
+pub fn post_with_retries(url:&str, body:&str, attempts:u32, retry_delay_millis:u64) -> Result<(), String> {
+    let mut client = Client::new();
+    client.set_read_timeout(Some(Duration::from_secs(BACKCHANNEL_TIMEOUT_SECS)));
+    client.set_write_timeout(Some(Duration::from_secs(BACKCHANNEL_TIMEOUT_SECS)));
+
+    let mut delay = retry_delay_millis;
+    let mut last_error = String::new();
+
+    for attempt in 1..(attempts + 1) {
+        match client.post(url).header(ContentType::form_url_encoded()).body(body).send() {
+            Ok(mut resp) => {
+                // drain the body so the connection can be reused.
+                let mut ignored = String::new();
+                let _ = resp.read_to_string(&mut ignored);
+
+                if resp.status.is_success() {
+                    return Ok(());
+                }
+                last_error = format!("unexpected response status {}", resp.status);
+            },
+            Err(e) => last_error = format!("{}", e)
+        }
+
+        warn!("back-channel logout to {} failed (attempt {}/{}): {}", url, attempt, attempts, last_error);
+
+        if attempt < attempts {
+            thread::sleep(Duration::from_millis(delay));
+            delay *= 2;
+        }
+    }
+
+    Err(last_error)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write, BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use super::post_with_retries;
+
+    // Local HTTP stub acting as relying application logout endpoint,
+    // responds with given statuses in order (last one repeated)
+    // and records received request bodies.
+    struct StubServer {
+        url: String,
+        bodies: Arc<Mutex<Vec<String>>>
+    }
+
+    impl StubServer {
+        fn start(statuses:Vec<u16>) -> StubServer {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/sso/logout", listener.local_addr().unwrap());
+            let bodies = Arc::new(Mutex::new(Vec::new()));
+            let recorded = bodies.clone();
+
+            thread::spawn(move || {
+                let mut served = 0;
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let body = {
+                        let mut reader = BufReader::new(&mut stream);
+                        let mut content_length = 0;
+                        loop {
+                            let mut line = String::new();
+                            reader.read_line(&mut line).unwrap();
+                            let line = line.trim().to_lowercase();
+                            if line.is_empty() {
+                                break;
+                            }
+                            if line.starts_with("content-length:") {
+                                content_length = line[15..].trim().parse().unwrap();
+                            }
+                        }
+                        let mut body = vec![0u8; content_length];
+                        reader.read_exact(&mut body).unwrap();
+                        String::from_utf8(body).unwrap()
+                    };
+                    recorded.lock().unwrap().push(body);
+
+                    let status = statuses[if served < statuses.len() { served } else { statuses.len() - 1 }];
+                    served += 1;
+                    write!(stream, "HTTP/1.1 {} Stub\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+                }
+            });
+
+            StubServer {
+                url: url,
+                bodies: bodies
+            }
+        }
+
+        fn received(&self) -> Vec<String> {
+            self.bodies.lock().unwrap().clone()
+        }
+    }
+
+    #[test]
+    fn delivered_on_first_attempt() {
+        let stub = StubServer::start(vec![200]);
+        assert!(post_with_retries(&stub.url, "event=logout&uid=robin&sid=abc", 3, 10).is_ok());
+        assert_eq!(stub.received(), vec!["event=logout&uid=robin&sid=abc".to_string()]);
+    }
+
+    #[test]
+    fn retried_until_success() {
+        let stub = StubServer::start(vec![500, 503, 204]);
+        assert!(post_with_retries(&stub.url, "event=logout", 4, 10).is_ok());
+        assert_eq!(stub.received().len(), 3);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let stub = StubServer::start(vec![500]);
+        assert!(post_with_retries(&stub.url, "event=logout", 3, 10).is_err());
+        assert_eq!(stub.received().len(), 3);
+    }
+
+    #[test]
+    fn unreachable_endpoint() {
+        let url = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/sso/logout", listener.local_addr().unwrap())
+        };
+        assert!(post_with_retries(&url, "event=logout", 2, 10).is_err());
+    }
+}
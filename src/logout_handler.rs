@@ -0,0 +1,90 @@
+
+use nickel::{Nickel, HttpRouter, QueryString};
+use nickel::status::StatusCode;
+use url::Url;
+use mustache::{MapBuilder};
+use nickel_mustache::Render;
+
+// module
+use Context;
+use session;
+use logout;
+use continue_url;
+use middleware;
+use build;
+use audit::{Requester, Event, Kind};
+
+
+pub fn setup(ctx:&Context, server: &mut Nickel){
+
+    let store = ctx.store.clone();
+    let live = ctx.live.clone();
+    let audit = ctx.audit.clone();
+
+    // single logout, eg: /logout?continue=https://app.example.com/
+    server.get("/logout", middleware! { |_req, mut _resp|
+        let settings = live.current();
+        let conf = &settings.conf;
+        let requester = Requester::new(&_req.origin.headers, &_req.origin.remote_addr);
+
+        let clients = {
+            let store = store.lock().unwrap();
+
+            // session kept on storage failure, don't pretend user is logged out.
+            let ended = session::from_headers(&store, &_req.origin.headers)
+                .and_then(|session| match session {
+                    Some(session) => logout::end_session(&store, conf, &session).map(|clients| Some((session, clients))),
+                    None => Ok(None)
+                });
+
+            match ended {
+                Ok(Some((session, clients))) => {
+                    // every token of the session, see `logout::end_session`
+                    audit.record(Event::new(Kind::TokenRevoked, &requester).user(&session.uid, &session.dn)
+                                 .detail("logout"));
+                    logout::notify_backchannel(&clients, &session);
+
+                    let params = logout::notification_params(&session);
+
+                    clients.iter()
+                        .filter(|c| !c.frontchannel_logout_url.is_empty())
+                        .filter_map(|c| Url::parse(&c.frontchannel_logout_url).ok())
+                        .map(|mut url| {
+                            url.set_query(Some(&params));
+                            url
+                        })
+                        .collect::<Vec<Url>>()
+                },
+                Ok(None) => Vec::new(),
+                Err(e) => {
+                    error!("Store error: {}", e);
+                    _resp.set(StatusCode::InternalServerError);
+                    return _resp.send("Internal server error");
+                }
+            }
+        };
+
+        session::clear_cookie(conf, _resp.headers_mut());
+
+        let query = _req.query();
+        let allowed = match settings.check_continue_any(query.get("continue").unwrap_or("")) {
+            continue_url::Target::Allowed(url) => Some(url.into_string()),
+            _ => None
+        };
+
+        // front-channel logout iframes need to be allowed.
+        let origins:Vec<String> = clients.iter().map(|url| url.origin().ascii_serialization()).collect();
+        _resp.headers_mut().set_raw("Content-Security-Policy",
+            vec![middleware::content_security_policy_with_frames(&origins).into_bytes()]);
+
+        let data = MapBuilder::new()
+            .insert_str("login_caption", conf.login_caption.clone())
+            .insert_str("version", build::VERSION.to_string())
+            .insert_vec("frames", |builder| clients.iter().fold(builder, |b, url| b.push_str(url.as_str())))
+            .insert_bool("continue", allowed.is_some())
+            .insert_str("continue_url", allowed.unwrap_or(String::new()))
+            .build();
+
+        return Render::render_data(_resp, "tmpl/logout.html", &data);
+    });
+}
@@ -8,41 +8,128 @@ extern crate rustc_serialize as serialize;
 #[macro_use] extern crate log;
 extern crate env_logger;
 extern crate rocksdb;
+extern crate rusqlite;
 extern crate toml;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate serde_path_to_error;
 extern crate rand;
 extern crate time;
-extern crate regex;
 extern crate mustache;
 extern crate nickel_mustache;
+extern crate libc;
+extern crate openssl;
 
 
 use serialize::base64::{self, ToBase64};
 // use serialize::hex::FromHex;
-use std::collections::HashMap;
+// use std::collections::HashMap;
 use nickel::{Nickel, HttpRouter, QueryString, StaticFilesHandler};
 // use url::percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET};
 
-use std::{str, env};
+use std::{str, env, thread};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use crypto::bcrypt;
 // use std::io::Read;
 
 mod config;
 mod ldap;
+mod auth;
+mod client_auth;
 mod store;
 mod token;
+mod jwt;
 #[macro_use] mod api_result;
 mod utils;
 mod build;
 mod errno;
+mod cbor;
+mod webauthn;
+mod throttle;
+mod csrf;
+mod middleware;
+mod session;
+mod logout;
+mod cli;
+mod listener;
+mod tls;
+mod signals;
+mod live;
+mod continue_url;
+mod tenant;
+mod openapi;
+mod cache;
+mod directory;
+mod audit;
+mod metrics;
 
 // handlers
 mod login_handler;
 mod api_handler;
+mod api_v1_handler;
+mod webauthn_handler;
+mod logout_handler;
+mod replication_handler;
+mod metrics_handler;
+
+// expired sessions and unanswered security key challenges removed this often.
+const PURGE_INTERVAL_SECS:u64 = 10 * 60;
 
 pub struct Context {
-    conf:config::Conf,
-    store:Arc<Mutex<store::Store>>
+    conf:config::Conf, // as read on startup, see `live` for settings applied on reload
+    live:Arc<live::LiveConf>,
+    store:Arc<Mutex<store::Store>>,
+    signer:Option<Arc<jwt::Signer>>, // when `token.format = "jwt"`
+    audit:Arc<audit::AuditLog>
+}
+
+/**
+ * Open and migrate configured store, exit when not possible.
+ */
+fn open_store(conf:&config::Conf) -> store::Store {
+    let store = match store::Store::new(&conf.store) {
+        Ok(store) => store,
+        Err(store::StoreError::Locked(e)) => {
+            println!("Cannot open data store `{}`, it is locked by another process.", conf.store.path);
+            println!("Is another SSO instance already running with the same `data_store`? ({})", e);
+            std::process::exit(16); // EBUSY (Linux system error code for device or resource busy)
+        },
+        Err(e) => {
+            println!("Cannot open data store: {}", e);
+            std::process::exit(5); // EIO (Linux system error code for I/O error)
+        }
+    };
+
+    if let Err(e) = store::migrate(&store) {
+        println!("Cannot migrate data store: {}", e);
+        std::process::exit(5);
+    }
+
+    store
+}
+
+/**
+ * Remove expired records nobody looks up again in background,
+ * the ones looked up are removed on access. First run on start counts active sessions.
+ */
+fn start_purge(store:Arc<Mutex<store::Store>>) {
+    thread::spawn(move || loop {
+        {
+            let store = store.lock().unwrap();
+            match session::purge(&store) {
+                Ok(0) => (),
+                Ok(count) => debug!("{} expired sessions removed", count),
+                Err(e) => error!("Cannot remove expired sessions: {}", e)
+            }
+            match webauthn::purge_challenges(&store) {
+                Ok(0) => (),
+                Ok(count) => debug!("{} expired security key challenges removed", count),
+                Err(e) => error!("Cannot remove expired security key challenges: {}", e)
+            }
+        }
+        thread::sleep(Duration::from_secs(PURGE_INTERVAL_SECS));
+    });
 }
 
 fn main() {
@@ -56,46 +143,205 @@ fn main() {
 
     if args.len() < 2 {
         println!("No configuration file specified");
-        println!("Usage: {} [CONFIG-FILE]", args[0]);
+        println!("Usage: {} [CONFIG-FILE] [COMMAND]", args[0]);
+        println!("Commands (store maintenance, without starting the server):");
+        println!("    backup DIR      copy the store into new DIR");
+        println!("    restore DIR     replace the store with backup in DIR");
+        println!("    export [FILE]   dump records as JSON lines");
+        println!("    import [FILE]   load records from `export` dump");
+        println!("    stats           record counts per type");
+        println!("    check-config    validate configuration and exit");
         std::process::exit(22); // EINVAL (Linux system error code for invalid argument)
     }
 
+    let mut conf = match config::Conf::read_file(&args[1]) {
+        Ok(conf) => conf,
+        Err(errors) => {
+            println!("Invalid configuration `{}`:", args[1]);
+            for e in &errors {
+                println!("    {}", e);
+            }
+            std::process::exit(22);
+        }
+    };
+
+    if args.len() > 2 {
+        let command = &args[2];
+        if !cli::is_command(command) {
+            println!("Unknown command `{}`, expecting one of: {}", command, cli::COMMANDS.join(", "));
+            std::process::exit(22);
+        }
+
+        if command == "check-config" {
+            match cli::check_config(&conf) {
+                Ok(_) => println!("Configuration `{}` OK", args[1]),
+                Err(errors) => {
+                    println!("Invalid configuration `{}`:", args[1]);
+                    for e in &errors {
+                        println!("    {}", e);
+                    }
+                    std::process::exit(22);
+                }
+            }
+            return;
+        }
+
+        if cli::is_file_command(&conf, command) {
+            if let Err(e) = cli::run_files(&conf, command, &args[3..]) {
+                println!("{} failed: {}", command, e);
+                std::process::exit(1);
+            }
+            return;
+        }
+
+        // maintenance works on this node's data only.
+        conf.store.replication = Default::default();
+        let store = open_store(&conf);
+
+        if let Err(e) = cli::run(&conf, &store, command, &args[3..]) {
+            println!("{} failed: {}", command, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     println!("\nSSO service v{}\n", build::VERSION);
 
-    let conf = config::Conf::read_file(&args[1]);
-    let store = store::Store::new(&conf.data_store);
+    if conf.security.csrf_secret.is_empty() {
+        warn!("`security.csrf_secret` not set, using random one, login forms will be invalidated on restart.");
+        conf.security.csrf_secret = csrf::random_secret();
+    }
+
+    let signer = match jwt::Signer::load(&conf.token) {
+        Ok(signer) => signer.map(Arc::new),
+        Err(e) => {
+            println!("Invalid configuration: {}", e);
+            std::process::exit(22);
+        }
+    };
+
+    let live = match live::LiveConf::new(&args[1], conf.clone()) {
+        Ok(live) => Arc::new(live),
+        Err(e) => {
+            println!("Invalid configuration: {}", e);
+            std::process::exit(22);
+        }
+    };
+
+    let store = open_store(&conf);
+
+    match store.keyring() {
+        Some(keyring) => info!("Store encrypted at rest, active key `{}`", keyring.active_id()),
+        None => warn!("`store.encryption_key` not set, tokens and sessions are stored unencrypted.")
+    }
+
+    live::watch(live.clone());
+
+    let store = Arc::new(Mutex::new(store));
+    start_purge(store.clone());
+
+    let audit = match audit::AuditLog::start(&conf.audit, store.clone()) {
+        Ok(audit) => Arc::new(audit),
+        Err(e) => {
+            println!("Cannot start audit log: {}", e);
+            std::process::exit(5);
+        }
+    };
 
     let ctx = Context {
         conf: conf,
-        store: Arc::new(Mutex::new(store))
+        live: live,
+        store: store,
+        signer: signer,
+        audit: audit
     };
 
-    debug!("data_store: {:?}", ctx.conf.data_store);
+    debug!("store.backend: {}", ctx.conf.store.backend);
+    debug!("store.path: {:?}", ctx.conf.store.path);
     debug!("ldap.uri: {}", ctx.conf.ldap.uri);
     debug!("ldap.default_dn: {}", ctx.conf.ldap.default_dn);
     debug!("ldap.admin_user: {}", ctx.conf.ldap.admin_user);
-    debug!("ldap.admin_password: {}", ctx.conf.ldap.admin_password);
 
     println!("Starting...");
 
-    let mut server:Nickel = Nickel::new();
+    let http = ctx.conf.http.clone();
+    let mut listening = Vec::new();
 
-    server.utilize(StaticFilesHandler::new("static/"));
+    for addr in &http.listen {
+        match build_server(&ctx, None).listen(addr.as_str()) {
+            Ok(server) => listening.push(server),
+            Err(e) => {
+                println!("Cannot listen on {}: {}", addr, e);
+                std::process::exit(98); // EADDRINUSE (Linux system error code for address already in use)
+            }
+        }
+    }
+
+    if !http.https_listen.is_empty() {
+        let tls = match tls::TlsServer::load(&http.tls_cert, &http.tls_key) {
+            Ok(tls) => tls,
+            Err(e) => {
+                println!("Cannot start HTTPS: {}", e);
+                std::process::exit(22);
+            }
+        };
+        for addr in &http.https_listen {
+            match build_server(&ctx, None).listen_https(addr.as_str(), tls.clone()) {
+                Ok(server) => listening.push(server),
+                Err(e) => {
+                    println!("Cannot listen on {}: {}", addr, e);
+                    std::process::exit(98);
+                }
+            }
+        }
+        signals::on_hangup(move || match tls.reload() {
+            Ok(_) => info!("TLS certificate `{}` reloaded", http.tls_cert),
+            Err(e) => error!("Cannot reload TLS certificate, keeping the current one: {}", e)
+        });
+    }
 
-    let conf = ctx.conf.clone();
+    if !ctx.conf.http.unix_socket.is_empty() {
+        let path = &ctx.conf.http.unix_socket;
+        let forwarded = listener::Forwarded::new();
+        let internal = match build_server(&ctx, Some(forwarded.clone())).listen(listener::INTERNAL_ADDR) {
+            Ok(server) => server,
+            Err(e) => {
+                println!("Cannot start internal listener for unix socket: {}", e);
+                std::process::exit(98);
+            }
+        };
+        match listener::bind_unix(path) {
+            Ok(unix) => {
+                listener::serve_unix(unix, internal.socket(), forwarded);
+                println!("Listening on unix:{}", path);
+            },
+            Err(e) => {
+                println!("Cannot listen on unix socket `{}`: {}", path, e);
+                std::process::exit(98);
+            }
+        }
+        listening.push(internal);
+    }
 
-    server.get("/", middleware! { |_req, _resp|
-        let mut data = HashMap::new();
-        let query = _req.query();
-        let cont:String = utils::encode_url(query.get("continue").unwrap_or("/"));
-        let dn:String = utils::encode_url(query.get("target_dn").unwrap_or(&conf.ldap.default_dn));
-        debug!("cont: {}, dn: {}", cont, dn);
-        data.insert("dn", dn);
-        data.insert("continue", cont);
-        data.insert("login_caption", conf.login_caption.clone());
-        data.insert("version", build::VERSION.to_string());
-        return _resp.render("tmpl/index.html", &data);
-    });
+    // dropping listening servers waits for their threads, ie: serve until killed.
+    drop(listening);
+}
+
+/**
+ * Server with every handler set up, one per listener as `listen` consumes it.
+ * Internal listener of unix socket serves only connections in `forwarded`.
+ */
+fn build_server(ctx:&Context, forwarded:Option<listener::Forwarded>) -> Nickel {
+    let mut server:Nickel = Nickel::new();
+
+    if let Some(forwarded) = forwarded {
+        server.utilize(middleware::UnixForwarded::new(forwarded));
+    }
+
+    server.utilize(middleware::RequestId);
+    server.utilize(middleware::ResponseMetrics);
+    server.utilize(middleware::SecurityHeaders::new(ctx.live.clone()));
+    server.utilize(StaticFilesHandler::new("static/"));
 
     server.get("/genPass", middleware! { |_req, _resp|
         let query = _req.query();
@@ -110,8 +356,13 @@ fn main() {
         format!("crypted pass: {}", s)
     });
 
-    api_handler::setup(&ctx, &mut server);
-    login_handler::setup(&ctx, &mut server);
+    api_handler::setup(ctx, &mut server);
+    api_v1_handler::setup(ctx, &mut server);
+    login_handler::setup(ctx, &mut server);
+    webauthn_handler::setup(ctx, &mut server);
+    logout_handler::setup(ctx, &mut server);
+    replication_handler::setup(ctx, &mut server);
+    metrics_handler::setup(ctx, &mut server);
 
-    server.listen("127.0.0.1:8080").unwrap();
+    server
 }
@@ -0,0 +1,276 @@
+
+// Prometheus metrics served at `/metrics`, `[metrics]` section.
+//
+// Process-wide registry, so `ldap` and `store` calls are timed without passing
+// it around. Rendered in text exposition format (version 0.0.4):
+//
+//     # HELP sso_login_attempts_total Login attempts by method and outcome.
+//     # TYPE sso_login_attempts_total counter
+//     sso_login_attempts_total{method="password",outcome="invalid_credentials"} 3
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, Once};
+
+use time;
+
+use store::replica;
+
+pub const LOGIN_ATTEMPTS:&'static str = "sso_login_attempts_total";
+pub const TOKEN_LOOKUPS:&'static str = "sso_token_lookups_total";
+pub const HTTP_RESPONSES:&'static str = "sso_http_responses_total";
+pub const LDAP_DURATION:&'static str = "sso_ldap_duration_seconds";
+pub const STORE_DURATION:&'static str = "sso_store_duration_seconds";
+pub const ACTIVE_SESSIONS:&'static str = "sso_active_sessions";
+
+pub const CONTENT_TYPE:&'static str = "text/plain; version=0.0.4; charset=utf-8";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Type {
+    Counter,
+    Gauge,
+    Histogram
+}
+
+impl Type {
+    fn name(&self) -> &'static str {
+        match *self {
+            Type::Counter => "counter",
+            Type::Gauge => "gauge",
+            Type::Histogram => "histogram"
+        }
+    }
+}
+
+// every metric rendered, in this order, even without samples yet.
+const METRICS:&'static [(&'static str, Type, &'static str)] = &[
+    (LOGIN_ATTEMPTS, Type::Counter, "Login attempts by method and outcome (success or failure reason)."),
+    (TOKEN_LOOKUPS, Type::Counter, "Access token lookups by service clients, by outcome."),
+    (HTTP_RESPONSES, Type::Counter, "HTTP responses by route and status."),
+    (LDAP_DURATION, Type::Histogram, "LDAP operation latency in seconds."),
+    (STORE_DURATION, Type::Histogram, "Store operation latency in seconds."),
+    (ACTIVE_SESSIONS, Type::Gauge, "Unexpired sessions in store, recounted every purge, created and ended on this node in between."),
+];
+
+// histogram upper bounds in seconds, `+Inf` implied.
+const BUCKETS:&'static [f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// route templates for `route` label, `:name` matches any single path segment.
+const ROUTES:&'static [&'static str] = &[
+    "/", "/login", "/login/webauthn", "/logout", "/genPass", "/webauthn/register", "/metrics",
+    "/.well-known/jwks.json",
+    "/api/system/info", "/api/lookup", "/api/token/revoked", "/api/token/refresh", "/api/admin/lockout/clear",
+    "/api/v1/openapi.json", "/api/v1/login", "/api/v1/login/webauthn", "/api/v1/system/info", "/api/v1/me",
+    "/api/v1/users/:uid", "/api/v1/directory/search", "/api/v1/lookup", "/api/v1/token/revoked",
+    "/api/v1/token/refresh", "/api/v1/admin/lockout/clear", "/api/v1/admin/audit",
+    replica::APPLY_PATH,
+    // tenant selected by path prefix
+    "/:tenant/", "/:tenant/login",
+];
+
+// served by static files handler.
+const STATIC_PREFIXES:&'static [&'static str] = &["/assets/", "/themes/"];
+
+struct Histogram {
+    counts: Vec<u64>, // per bucket, not cumulative, last one is `+Inf`
+    sum: f64
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram { counts: vec![0; BUCKETS.len() + 1], sum: 0.0 }
+    }
+
+    fn observe(&mut self, value:f64) {
+        let i = BUCKETS.iter().position(|&le| value <= le).unwrap_or(BUCKETS.len());
+        self.counts[i] += 1;
+        self.sum += value;
+    }
+}
+
+enum Sample {
+    Value(f64),
+    Histogram(Histogram)
+}
+
+pub struct Registry {
+    samples: Mutex<BTreeMap<(&'static str, String), Sample>> // (name, formatted labels) => sample
+}
+
+impl Registry {
+    fn new() -> Registry {
+        Registry { samples: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn inc(&self, name:&'static str, labels:&[(&str, &str)]) {
+        self.add(name, labels, 1.0);
+    }
+
+    /**
+     * Change counter or gauge by `delta`, gauge can go down.
+     */
+    pub fn add(&self, name:&'static str, labels:&[(&str, &str)], delta:f64) {
+        let mut samples = self.samples.lock().unwrap();
+        match *samples.entry((name, format_labels(labels))).or_insert(Sample::Value(0.0)) {
+            Sample::Value(ref mut value) => *value += delta,
+            Sample::Histogram(_) => ()
+        }
+    }
+
+    pub fn set(&self, name:&'static str, labels:&[(&str, &str)], value:f64) {
+        self.samples.lock().unwrap().insert((name, format_labels(labels)), Sample::Value(value));
+    }
+
+    pub fn observe(&self, name:&'static str, labels:&[(&str, &str)], value:f64) {
+        let mut samples = self.samples.lock().unwrap();
+        match *samples.entry((name, format_labels(labels))).or_insert_with(|| Sample::Histogram(Histogram::new())) {
+            Sample::Histogram(ref mut histogram) => histogram.observe(value),
+            Sample::Value(_) => ()
+        }
+    }
+
+    /**
+     * All metrics in text exposition format.
+     */
+    pub fn render(&self) -> String {
+        let samples = self.samples.lock().unwrap();
+        let mut out = String::new();
+        for &(metric, kind, help) in METRICS {
+            let _ = writeln!(out, "# HELP {} {}", metric, help);
+            let _ = writeln!(out, "# TYPE {} {}", metric, kind.name());
+            for (&(_, ref labels), sample) in samples.iter().filter(|&(&(name, _), _)| name == metric) {
+                match *sample {
+                    Sample::Value(value) => {
+                        let _ = writeln!(out, "{}{} {}", metric, braced(labels, ""), value);
+                    },
+                    Sample::Histogram(ref histogram) => {
+                        let mut cumulative = 0;
+                        for (i, count) in histogram.counts.iter().enumerate() {
+                            cumulative += *count;
+                            let le = BUCKETS.get(i).map(|le| le.to_string()).unwrap_or_else(|| "+Inf".to_string());
+                            let _ = writeln!(out, "{}_bucket{} {}", metric,
+                                             braced(labels, &format!("le=\"{}\"", le)), cumulative);
+                        }
+                        let _ = writeln!(out, "{}_sum{} {}", metric, braced(labels, ""), histogram.sum);
+                        let _ = writeln!(out, "{}_count{} {}", metric, braced(labels, ""), cumulative);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+static INIT:Once = Once::new();
+static mut REGISTRY:*const Registry = 0 as *const Registry;
+
+/**
+ * The process-wide registry.
+ */
+pub fn registry() -> &'static Registry {
+    unsafe {
+        INIT.call_once(|| REGISTRY = Box::into_raw(Box::new(Registry::new())));
+        &*REGISTRY
+    }
+}
+
+/**
+ * Latency of one operation, observed into histogram with `operation` label.
+ */
+pub struct Timer(u64);
+
+impl Timer {
+    pub fn start() -> Timer {
+        Timer(time::precise_time_ns())
+    }
+
+    pub fn observe(self, name:&'static str, operation:&str) {
+        let secs = time::precise_time_ns().saturating_sub(self.0) as f64 / 1e9;
+        registry().observe(name, &[("operation", operation)], secs);
+    }
+}
+
+/**
+ * Route template of request path, so ids and tenant names don't become labels.
+ */
+pub fn route_label(path:&str) -> &'static str {
+    if STATIC_PREFIXES.iter().any(|prefix| path.starts_with(prefix)) {
+        return "static";
+    }
+    let segments:Vec<&str> = path.split('/').collect();
+    ROUTES.iter()
+        .find(|route| {
+            let parts:Vec<&str> = route.split('/').collect();
+            parts.len() == segments.len() && parts.iter().zip(&segments)
+                .all(|(part, segment)| part == segment || (part.starts_with(':') && !segment.is_empty()))
+        })
+        .cloned()
+        .unwrap_or("other")
+}
+
+fn format_labels(labels:&[(&str, &str)]) -> String {
+    labels.iter()
+        .map(|&(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn braced(labels:&str, extra:&str) -> String {
+    match (labels.is_empty(), extra.is_empty()) {
+        (true, true) => String::new(),
+        (false, true) => format!("{{{}}}", labels),
+        (true, false) => format!("{{{}}}", extra),
+        (false, false) => format!("{{{},{}}}", labels, extra)
+    }
+}
+
+fn escape(value:&str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let registry = Registry::new();
+        registry.inc(LOGIN_ATTEMPTS, &[("method", "password"), ("outcome", "success")]);
+        registry.inc(LOGIN_ATTEMPTS, &[("method", "password"), ("outcome", "success")]);
+        registry.inc(HTTP_RESPONSES, &[("route", "/a\"b\\"), ("status", "200")]);
+        registry.set(ACTIVE_SESSIONS, &[], 7.0);
+        registry.add(ACTIVE_SESSIONS, &[], -1.0);
+        registry.observe(STORE_DURATION, &[("operation", "get")], 0.003);
+        registry.observe(STORE_DURATION, &[("operation", "get")], 20.0);
+
+        let out = registry.render();
+        let lines:Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "# HELP sso_login_attempts_total Login attempts by method and outcome (success or failure reason).");
+        assert_eq!(lines[1], "# TYPE sso_login_attempts_total counter");
+        assert_eq!(lines[2], "sso_login_attempts_total{method=\"password\",outcome=\"success\"} 2");
+        assert!(lines.contains(&"sso_http_responses_total{route=\"/a\\\"b\\\\\",status=\"200\"} 1"));
+        assert!(lines.contains(&"sso_active_sessions 6"));
+        assert!(lines.contains(&"# TYPE sso_ldap_duration_seconds histogram"));
+
+        assert!(lines.contains(&"sso_store_duration_seconds_bucket{operation=\"get\",le=\"0.001\"} 0"));
+        assert!(lines.contains(&"sso_store_duration_seconds_bucket{operation=\"get\",le=\"0.005\"} 1"));
+        assert!(lines.contains(&"sso_store_duration_seconds_bucket{operation=\"get\",le=\"10\"} 1"));
+        assert!(lines.contains(&"sso_store_duration_seconds_bucket{operation=\"get\",le=\"+Inf\"} 2"));
+        assert!(lines.contains(&"sso_store_duration_seconds_sum{operation=\"get\"} 20.003"));
+        assert!(lines.contains(&"sso_store_duration_seconds_count{operation=\"get\"} 2"));
+    }
+
+    #[test]
+    fn route_labels() {
+        assert_eq!(route_label("/"), "/");
+        assert_eq!(route_label("/login"), "/login");
+        assert_eq!(route_label("/api/v1/users/robin"), "/api/v1/users/:uid");
+        assert_eq!(route_label("/api/v1/users/"), "other");
+        assert_eq!(route_label("/acme/"), "/:tenant/");
+        assert_eq!(route_label("/acme/login"), "/:tenant/login");
+        assert_eq!(route_label("/_replication/apply"), "/_replication/apply");
+        assert_eq!(route_label("/assets/css/main.css"), "static");
+        assert_eq!(route_label("/wp-admin/setup.php"), "other");
+    }
+}
@@ -0,0 +1,40 @@
+
+use nickel::{Nickel, HttpRouter};
+use nickel::status::StatusCode;
+
+// module
+use Context;
+use client_auth;
+use metrics;
+use token;
+
+
+pub fn setup(ctx:&Context, server: &mut Nickel){
+
+    if !ctx.conf.metrics.enabled {
+        return;
+    }
+    if ctx.conf.metrics.bearer_token.is_empty() {
+        warn!("Metrics served without `metrics.bearer_token`, open to anyone reaching /metrics");
+    }
+
+    let live = ctx.live.clone();
+
+    // scraped by Prometheus, see `metrics`.
+    server.get("/metrics", middleware! { |_req, mut _resp|
+        let settings = live.current();
+        let expected = &settings.conf.metrics.bearer_token;
+
+        let authorized = expected.is_empty() || client_auth::bearer_token(&_req.origin.headers)
+            .map_or(false, |given| token::eq(&given, expected));
+        if !authorized {
+            warn!("Unauthorized metrics access from {}", _req.origin.remote_addr);
+            _resp.set(StatusCode::Unauthorized);
+            _resp.headers_mut().set_raw("WWW-Authenticate", vec![b"Bearer".to_vec()]);
+            return _resp.send("Unauthorized\n");
+        }
+
+        _resp.headers_mut().set_raw("Content-Type", vec![metrics::CONTENT_TYPE.as_bytes().to_vec()]);
+        metrics::registry().render()
+    });
+}
@@ -0,0 +1,137 @@
+
+// Middlewares applied across all routes.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use nickel::{Request, Response, Middleware, MiddlewareResult};
+use nickel::status::StatusCode;
+use rand::{self, Rng};
+use serialize::hex::ToHex;
+
+use listener::{self, Forwarded};
+use live::LiveConf;
+use metrics;
+use utils;
+
+
+const CONTENT_SECURITY_POLICY:&'static str = "default-src 'self'; \
+    script-src 'self' 'unsafe-inline'; \
+    style-src 'self' 'unsafe-inline' https://fonts.googleapis.com; \
+    font-src 'self' data: https://fonts.gstatic.com; \
+    img-src 'self' data:; \
+    frame-ancestors 'none'; \
+    base-uri 'none'";
+
+/**
+ * Content security policy allowing iframes from given origins,
+ * used by front-channel logout page.
+ */
+pub fn content_security_policy_with_frames(origins:&[String]) -> String {
+    format!("{}; frame-src {}", CONTENT_SECURITY_POLICY, origins.join(" "))
+}
+
+// Set secure response headers (CSP, framing, HSTS, etc).
+pub struct SecurityHeaders {
+    live: Arc<LiveConf>
+}
+
+impl SecurityHeaders {
+    pub fn new(live:Arc<LiveConf>) -> SecurityHeaders {
+        SecurityHeaders {
+            live: live
+        }
+    }
+}
+
+impl<D> Middleware<D> for SecurityHeaders {
+    fn invoke<'mw, 'conn>(&'mw self, _req: &mut Request<'mw, 'conn, D>, mut res: Response<'mw, D>)
+            -> MiddlewareResult<'mw, D> {
+        let hsts_max_age = self.live.current().conf.security.hsts_max_age;
+        {
+            let headers = res.headers_mut();
+            headers.set_raw("Content-Security-Policy", vec![CONTENT_SECURITY_POLICY.as_bytes().to_vec()]);
+            headers.set_raw("X-Frame-Options", vec![b"DENY".to_vec()]);
+            headers.set_raw("X-Content-Type-Options", vec![b"nosniff".to_vec()]);
+            headers.set_raw("Referrer-Policy", vec![b"no-referrer".to_vec()]);
+            if hsts_max_age > 0 {
+                headers.set_raw("Strict-Transport-Security",
+                    vec![format!("max-age={}; includeSubDomains", hsts_max_age).into_bytes()]);
+            }
+        }
+        res.next_middleware()
+    }
+}
+
+// Request ID for logs and API responses, taken from reverse proxy (`X-Request-Id`)
+// or generated. Set on request headers for handlers and echoed in response.
+pub struct RequestId;
+
+impl<D> Middleware<D> for RequestId {
+    fn invoke<'mw, 'conn>(&'mw self, req: &mut Request<'mw, 'conn, D>, mut res: Response<'mw, D>)
+            -> MiddlewareResult<'mw, D> {
+        let given = utils::request_id(&req.origin.headers);
+        let id = if utils::valid_request_id(&given) {
+            given
+        }else{
+            let mut buf = [0u8; 8];
+            rand::thread_rng().fill_bytes(&mut buf);
+            buf.to_hex()
+        };
+
+        req.origin.headers.set_raw(utils::REQUEST_ID_HEADER, vec![id.clone().into_bytes()]);
+        res.headers_mut().set_raw(utils::REQUEST_ID_HEADER, vec![id.into_bytes()]);
+        res.next_middleware()
+    }
+}
+
+// Count responses by route and status for `/metrics`, taken when response
+// is sent so statuses set by later middlewares and handlers are included.
+pub struct ResponseMetrics;
+
+impl<D> Middleware<D> for ResponseMetrics {
+    fn invoke<'mw, 'conn>(&'mw self, req: &mut Request<'mw, 'conn, D>, mut res: Response<'mw, D>)
+            -> MiddlewareResult<'mw, D> {
+        let route = metrics::route_label(req.path_without_query().unwrap_or(""));
+        res.on_send(move |res| {
+            let status = res.status().to_u16().to_string();
+            metrics::registry().inc(metrics::HTTP_RESPONSES, &[("route", route), ("status", &status)]);
+        });
+        res.next_middleware()
+    }
+}
+
+// Internal listener behind unix socket: refuse local connections not forwarded
+// from the socket, and use client ip given by reverse proxy as remote address,
+// so throttling and audit don't see every request coming from loopback.
+pub struct UnixForwarded {
+    forwarded: Forwarded
+}
+
+impl UnixForwarded {
+    pub fn new(forwarded:Forwarded) -> UnixForwarded {
+        UnixForwarded {
+            forwarded: forwarded
+        }
+    }
+}
+
+impl<D> Middleware<D> for UnixForwarded {
+    fn invoke<'mw, 'conn>(&'mw self, req: &mut Request<'mw, 'conn, D>, mut res: Response<'mw, D>)
+            -> MiddlewareResult<'mw, D> {
+        if !self.forwarded.contains(&req.origin.remote_addr) {
+            warn!("Connection to internal unix socket listener from {} refused", req.origin.remote_addr);
+            res.set(StatusCode::Forbidden);
+            return res.send("Forbidden\n");
+        }
+        match listener::client_ip(&req.origin.headers) {
+            Some(ip) => req.origin.remote_addr = SocketAddr::new(ip, 0),
+            None => {
+                warn!("Request on unix socket without `X-Real-IP` or `X-Forwarded-For`");
+                res.set(StatusCode::BadRequest);
+                return res.send("Client address required from reverse proxy\n");
+            }
+        }
+        res.next_middleware()
+    }
+}
@@ -0,0 +1,268 @@
+
+// OpenAPI document of `/api/v1`.
+//
+// Every endpoint is described by `Endpoint` and registered together with its handler
+// (see `api_v1_handler::route`), the document served at `/api/v1/openapi.json` is
+// built from the registered ones so it can't drift from the routes.
+
+use std::collections::BTreeMap;
+
+use nickel::hyper::method::Method;
+use serialize::json::{Json, ToJson};
+
+use api_result::ApiError;
+
+pub const ADMIN_KEY:&'static str = "admin_key";
+pub const CLIENT_BASIC:&'static str = "client_basic";
+pub const CLIENT_API_KEY:&'static str = "client_api_key";
+pub const BEARER:&'static str = "bearer"; // user's access token
+
+pub enum Schema {
+    Str,
+    Int,
+    Bool,
+    Array(&'static Schema),
+    Object(&'static [(&'static str, Schema)]),
+    Map(&'static Schema), // object with arbitrary keys
+    Nullable(&'static Schema) // optional field of object
+}
+
+pub enum ParamIn {
+    Query,
+    Path
+}
+
+pub struct Param {
+    pub name:&'static str,
+    pub location:ParamIn,
+    pub required:bool,
+    pub desc:&'static str
+}
+
+pub struct Endpoint {
+    pub method:Method,
+    pub path:&'static str, // OpenAPI form, eg: `/api/v1/users/{uid}`
+    pub summary:&'static str,
+    pub params:&'static [Param],
+    pub body:Option<Schema>, // JSON request body
+    pub response:Schema,     // `data` of the envelope
+    pub errors:&'static [ApiError],
+    pub security:&'static [&'static str] // any one of these schemes
+}
+
+impl Endpoint {
+    /**
+     * Route path for nickel router, `{uid}` becomes `:uid`.
+     */
+    pub fn route(&self) -> String {
+        self.path.replace('{', ":").replace('}', "")
+    }
+}
+
+fn object(pairs:Vec<(&str, Json)>) -> Json {
+    let mut map = BTreeMap::new();
+    for (key, value) in pairs {
+        map.insert(key.to_string(), value);
+    }
+    Json::Object(map)
+}
+
+fn schema_json(schema:&Schema) -> Json {
+    match *schema {
+        Schema::Str => object(vec![("type", "string".to_json())]),
+        Schema::Int => object(vec![("type", "integer".to_json())]),
+        Schema::Bool => object(vec![("type", "boolean".to_json())]),
+        Schema::Array(items) => object(vec![("type", "array".to_json()), ("items", schema_json(items))]),
+        Schema::Object(fields) => {
+            let props = fields.iter().map(|&(name, ref s)| (name, schema_json(s))).collect();
+            let required:Vec<String> = fields.iter()
+                .filter(|&&(_, ref s)| match *s { Schema::Nullable(_) => false, _ => true })
+                .map(|&(name, _)| name.to_string())
+                .collect();
+            object(vec![("type", "object".to_json()), ("properties", object(props)), ("required", required.to_json())])
+        },
+        Schema::Map(values) => object(vec![("type", "object".to_json()), ("additionalProperties", schema_json(values))]),
+        Schema::Nullable(inner) => {
+            let mut json = schema_json(inner);
+            if let Json::Object(ref mut map) = json {
+                map.insert("nullable".to_string(), true.to_json());
+            }
+            json
+        }
+    }
+}
+
+fn json_content(schema:Json) -> Json {
+    object(vec![("application/json", object(vec![("schema", schema)]))])
+}
+
+fn success_envelope(data:&Schema) -> Json {
+    object(vec![
+        ("type", "object".to_json()),
+        ("properties", object(vec![
+            ("data", schema_json(data)),
+            ("error", object(vec![("nullable", true.to_json())])),
+            ("request_id", schema_json(&Schema::Str))
+        ]))
+    ])
+}
+
+fn error_envelope(ids:&[&str]) -> Json {
+    let ids:Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+    object(vec![
+        ("type", "object".to_json()),
+        ("properties", object(vec![
+            ("data", object(vec![("nullable", true.to_json())])),
+            ("error", object(vec![
+                ("type", "object".to_json()),
+                ("properties", object(vec![
+                    ("id", object(vec![("type", "string".to_json()), ("enum", ids.to_json())])),
+                    ("code", schema_json(&Schema::Int)),
+                    ("message", schema_json(&Schema::Str)),
+                    ("details", object(vec![("type", "object".to_json()), ("nullable", true.to_json())]))
+                ]))
+            ])),
+            ("request_id", schema_json(&Schema::Str))
+        ]))
+    ])
+}
+
+fn operation(endpoint:&Endpoint) -> Json {
+    let mut responses = vec![
+        ("200".to_string(), object(vec![("description", "OK".to_json()), ("content", json_content(success_envelope(&endpoint.response)))]))
+    ];
+
+    // errors sharing status code documented together, told apart by `error.id`.
+    let mut codes:Vec<i32> = endpoint.errors.iter().map(|e| e.code()).collect();
+    codes.sort();
+    codes.dedup();
+    for code in codes {
+        let errors:Vec<&ApiError> = endpoint.errors.iter().filter(|e| e.code() == code).collect();
+        let ids:Vec<&str> = errors.iter().map(|e| e.id()).collect();
+        let desc:Vec<&str> = errors.iter().map(|e| e.message()).collect();
+        responses.push((code.to_string(), object(vec![
+            ("description", desc.join(", ").to_json()),
+            ("content", json_content(error_envelope(&ids)))
+        ])));
+    }
+
+    let params:Vec<Json> = endpoint.params.iter().map(|p| object(vec![
+        ("name", p.name.to_json()),
+        ("in", match p.location { ParamIn::Query => "query", ParamIn::Path => "path" }.to_json()),
+        ("required", p.required.to_json()),
+        ("description", p.desc.to_json()),
+        ("schema", schema_json(&Schema::Str))
+    ])).collect();
+
+    let mut op = vec![
+        ("summary", endpoint.summary.to_json()),
+        ("parameters", Json::Array(params)),
+        ("responses", Json::Object(responses.into_iter().collect()))
+    ];
+    if let Some(ref body) = endpoint.body {
+        op.push(("requestBody", object(vec![("required", true.to_json()), ("content", json_content(schema_json(body)))])));
+    }
+    if !endpoint.security.is_empty() {
+        let security:Vec<Json> = endpoint.security.iter().map(|s| object(vec![(*s, Json::Array(Vec::new()))])).collect();
+        op.push(("security", Json::Array(security)));
+    }
+    object(op)
+}
+
+/**
+ * OpenAPI 3 document of registered endpoints.
+ */
+pub fn document(endpoints:&[&Endpoint], version:&str) -> Json {
+    let mut paths:BTreeMap<String, Json> = BTreeMap::new();
+    for endpoint in endpoints {
+        let method = endpoint.method.as_ref().to_lowercase();
+        let item = paths.entry(endpoint.path.to_string()).or_insert_with(|| Json::Object(BTreeMap::new()));
+        if let Json::Object(ref mut ops) = *item {
+            ops.insert(method, operation(endpoint));
+        }
+    }
+
+    object(vec![
+        ("openapi", "3.0.3".to_json()),
+        ("info", object(vec![("title", "SSO API".to_json()), ("version", version.to_json())])),
+        ("paths", Json::Object(paths)),
+        ("components", object(vec![
+            ("securitySchemes", object(vec![
+                (ADMIN_KEY, object(vec![("type", "apiKey".to_json()), ("in", "header".to_json()), ("name", "X-Admin-Key".to_json())])),
+                (CLIENT_BASIC, object(vec![("type", "http".to_json()), ("scheme", "basic".to_json())])),
+                (CLIENT_API_KEY, object(vec![("type", "apiKey".to_json()), ("in", "header".to_json()), ("name", "X-Api-Key".to_json())])),
+                (BEARER, object(vec![("type", "http".to_json()), ("scheme", "bearer".to_json())]))
+            ]))
+        ]))
+    ])
+}
+
+
+#[cfg(test)]
+mod tests {
+    use nickel::hyper::method::Method;
+    use api_result::ApiError;
+    use super::*;
+
+    static LOOKUP:Endpoint = Endpoint {
+        method: Method::Get,
+        path: "/api/v1/lookup",
+        summary: "Owner of access token",
+        params: &[Param { name: "access_token", location: ParamIn::Query, required: true, desc: "token" }],
+        body: None,
+        response: Schema::Object(&[("uid", Schema::Str), ("dn", Schema::Str)]),
+        errors: &[ApiError::InvalidToken, ApiError::BadRequest, ApiError::Internal],
+        security: &[]
+    };
+
+    static CLEAR:Endpoint = Endpoint {
+        method: Method::Post,
+        path: "/api/v1/users/{uid}/lockout",
+        summary: "Clear lockout",
+        params: &[],
+        body: Some(Schema::Object(&[("ip", Schema::Str), ("note", Schema::Nullable(&Schema::Str))])),
+        response: Schema::Map(&Schema::Array(&Schema::Bool)),
+        errors: &[ApiError::Unauthorized],
+        security: &[ADMIN_KEY]
+    };
+
+    #[test]
+    fn routes() {
+        assert_eq!(LOOKUP.route(), "/api/v1/lookup");
+        assert_eq!(CLEAR.route(), "/api/v1/users/:uid/lockout");
+    }
+
+    #[test]
+    fn generated_document() {
+        let doc = document(&[&LOOKUP, &CLEAR], "1.2.3");
+        assert_eq!(doc.find_path(&["info", "version"]).unwrap().as_string(), Some("1.2.3"));
+
+        let lookup = doc.find_path(&["paths", "/api/v1/lookup", "get"]).unwrap();
+        assert_eq!(lookup.find_path(&["parameters"]).unwrap()[0].find("in").unwrap().as_string(), Some("query"));
+        let data = lookup.find_path(&["responses", "200", "content", "application/json", "schema", "properties", "data"]).unwrap();
+        assert_eq!(data.find_path(&["properties", "uid", "type"]).unwrap().as_string(), Some("string"));
+
+        let invalid = lookup.find_path(&["responses", "498", "content", "application/json", "schema",
+                                         "properties", "error", "properties", "id", "enum"]).unwrap();
+        assert_eq!(invalid.as_array().unwrap()[0].as_string(), Some("invalid_token"));
+        assert!(lookup.find_path(&["responses", "400"]).is_some());
+        assert!(lookup.find_path(&["responses", "500"]).is_some());
+        assert!(lookup.find("security").is_none());
+
+        let clear = doc.find_path(&["paths", "/api/v1/users/{uid}/lockout", "post"]).unwrap();
+        let body = clear.find_path(&["requestBody", "content", "application/json", "schema"]).unwrap();
+        assert_eq!(body.find("required").unwrap().as_array().unwrap().len(), 1);
+        assert_eq!(body.find_path(&["properties", "note", "nullable"]).unwrap().as_boolean(), Some(true));
+        let response = clear.find_path(&["responses", "200", "content", "application/json", "schema", "properties", "data"]).unwrap();
+        assert_eq!(response.find_path(&["additionalProperties", "items", "type"]).unwrap().as_string(), Some("boolean"));
+        assert!(clear.find_path(&["security"]).unwrap()[0].find(ADMIN_KEY).is_some());
+        assert!(doc.find_path(&["components", "securitySchemes", ADMIN_KEY]).is_some());
+    }
+
+    #[test]
+    fn error_statuses() {
+        assert_eq!(ApiError::InvalidToken.status().to_u16(), 498);
+        assert_eq!(ApiError::Unauthorized.status().to_u16(), 401);
+        assert_eq!(ApiError::Internal.id(), "internal_error");
+    }
+}
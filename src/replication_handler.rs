@@ -0,0 +1,198 @@
+use nickel::{Nickel, HttpRouter};
+use nickel::mimes::MediaType;
+use nickel::status::StatusCode;
+use serialize::json;
+use std::io::Read;
+use std::str;
+
+// module
+use Context;
+use api_result;
+use errno;
+use store::replica;
+
+
+pub fn setup(ctx:&Context, server: &mut Nickel){
+
+    if !ctx.conf.store.replication.enabled() {
+        return;
+    }
+
+    let store = ctx.store.clone();
+    let secret = ctx.conf.store.replication.secret.clone();
+
+    // writes shipped by peer nodes, see `store::replica`.
+    server.post(replica::APPLY_PATH, middleware! { |_req, mut _resp|
+
+        let signature = _req.origin.headers.get_raw(replica::SIGNATURE_HEADER)
+            .and_then(|v| v.first())
+            .and_then(|v| str::from_utf8(v).ok())
+            .unwrap_or("")
+            .to_string();
+
+        let mut body = String::new();
+        if _req.origin.read_to_string(&mut body).is_err() {
+            _resp.set(StatusCode::BadRequest);
+            let result = api_result_error_json!(errno::BAD_REQQUEST, errno::BAD_REQUEST_STR, _resp);
+            return _resp.send(result);
+        }
+
+        // peer retries until it gets success status.
+        if !replica::verify_signature(&secret, &signature, &body) {
+            warn!("Invalid replication request signature from {}", _req.origin.remote_addr);
+            _resp.set(StatusCode::Unauthorized);
+            let result = api_result_error_json!(errno::UNAUTHORIZED, errno::UNAUTHORIZED_STR, _resp);
+            return _resp.send(result);
+        }
+
+        let entries = match replica::decode_entries(&body) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Invalid replication request from {}: {}", _req.origin.remote_addr, e);
+                _resp.set(StatusCode::BadRequest);
+                let result = api_result_error_json!(errno::BAD_REQQUEST, errno::BAD_REQUEST_STR, _resp);
+                return _resp.send(result);
+            }
+        };
+
+        let applied = {
+            let store = store.lock().unwrap();
+            match store.apply_replicated(entries) {
+                Ok(applied) => applied,
+                Err(e) => {
+                    error!("Store error: {}", e);
+                    _resp.set(StatusCode::InternalServerError);
+                    let result = api_result_error_json!(errno::INTERNAL_SERVER_ERROR, errno::INTERNAL_SERVER_ERROR_STR, _resp);
+                    return _resp.send(result);
+                }
+            }
+        };
+
+        api_result_success_json!(applied, _resp)
+    });
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use nickel::Nickel;
+
+    use Context;
+    use audit::AuditLog;
+    use config::{Conf, ReplicationConf};
+    use live::LiveConf;
+    use store::Store;
+    use super::setup;
+
+    fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    // node with in-memory store replicating to the node on `peer_port`.
+    fn node(name:&str, peer_port:u16) -> Context {
+        let mut conf = Conf::default();
+        conf.store.backend = "memory".to_string();
+        conf.store.replication = ReplicationConf {
+            node_id: name.to_string(),
+            peers: vec![format!("http://127.0.0.1:{}", peer_port)],
+            secret: "s3cret".to_string(),
+            retry_millis: 50,
+            tombstone_ttl_secs: 3600,
+            wal_max_entries: 1000
+        };
+
+        let store = Arc::new(Mutex::new(Store::new(&conf.store).unwrap()));
+        Context {
+            live: Arc::new(LiveConf::new("", conf.clone()).unwrap()),
+            audit: Arc::new(AuditLog::start(&conf.audit, store.clone()).unwrap()),
+            store: store,
+            signer: None,
+            conf: conf
+        }
+    }
+
+    // start accepting replication requests (heal partition),
+    // served until the test process exits.
+    fn listen(ctx:&Context, port:u16) {
+        let mut server = Nickel::new();
+        setup(ctx, &mut server);
+        mem::forget(server.listen(&format!("127.0.0.1:{}", port)[..]).unwrap());
+    }
+
+    fn get(ctx:&Context, key:&str) -> Option<String> {
+        ctx.store.lock().unwrap().get(key).unwrap()
+    }
+
+    fn eventually<F:Fn() -> bool>(check:F) -> bool {
+        for _ in 0..200 {
+            if check() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    #[test]
+    fn two_nodes() {
+        let (port_a, port_b) = (free_port(), free_port());
+        let a = node("a", port_b);
+        let b = node("b", port_a);
+        listen(&a, port_a);
+        listen(&b, port_b);
+
+        // token issued on A usable on B
+        a.store.lock().unwrap().batch()
+            .put("token/t1", "robin")
+            .put("user/robin", "t1")
+            .commit().unwrap();
+        assert!(eventually(|| get(&b, "token/t1") == Some("robin".to_string())));
+        assert_eq!(get(&b, "user/robin"), Some("t1".to_string()));
+
+        // revoked on B, gone on A
+        b.store.lock().unwrap().del("token/t1").unwrap();
+        assert!(eventually(|| get(&a, "token/t1").is_none()));
+    }
+
+    #[test]
+    fn two_nodes_partitioned() {
+        let (port_a, port_b) = (free_port(), free_port());
+        let a = node("a", port_b);
+        let b = node("b", port_a);
+
+        // while nodes can't reach each other
+        b.store.lock().unwrap().put("token/t2", "robin").unwrap();
+        a.store.lock().unwrap().del("token/t2").unwrap();
+        a.store.lock().unwrap().put("user/robin", "from-a").unwrap();
+        thread::sleep(Duration::from_millis(5));
+        b.store.lock().unwrap().put("user/robin", "from-b").unwrap();
+        b.store.lock().unwrap().put("token/t2", "robin").unwrap();
+
+        listen(&a, port_a);
+        listen(&b, port_b);
+
+        // converged: revocation kept, newer write wins
+        assert!(eventually(|| get(&b, "token/t2").is_none() && get(&a, "user/robin") == Some("from-b".to_string())));
+        assert!(get(&a, "token/t2").is_none());
+        assert_eq!(get(&b, "user/robin"), Some("from-b".to_string()));
+    }
+
+    #[test]
+    fn wrong_signature_refused() {
+        let (port_a, port_b) = (free_port(), free_port());
+        let a = node("a", port_b);
+        let mut b = node("b", port_a);
+        b.conf.store.replication.secret = "other".to_string();
+        listen(&a, port_a);
+        listen(&b, port_b);
+
+        a.store.lock().unwrap().put("token/t3", "robin").unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert!(get(&b, "token/t3").is_none());
+    }
+}
@@ -0,0 +1,175 @@
+
+// Browser SSO session, identified by `sso_session` cookie and stored server-side,
+// so user logged in once doesn't need to re-enter password for other applications.
+
+use nickel::hyper::header::Headers;
+
+use config::Conf;
+use store::{Store, StoreResult, Namespace};
+use csrf;
+use metrics;
+use utils;
+
+pub const COOKIE_NAME:&'static str = "sso_session";
+
+
+#[derive(Decodable, Encodable, Clone, Debug)]
+pub struct Session {
+    pub id: String,
+    pub uid: String,
+    pub dn: String,
+    pub created: u64,
+    pub expires: u64,
+    pub tokens: Vec<String>, // access tokens issued within this session
+    pub clients: Vec<String> // continue hosts the tokens were issued to
+}
+
+impl Session {
+    pub fn is_expired(&self) -> bool {
+        utils::current_time_millis() > self.expires
+    }
+}
+
+/**
+ * Create and store new session for user.
+ */
+pub fn create(store:&Store, conf:&Conf, uid:&str, dn:&str) -> StoreResult<Session> {
+    let now = utils::current_time_millis();
+    let session = Session {
+        id: csrf::random_secret(),
+        uid: uid.to_string(),
+        dn: dn.to_string(),
+        created: now,
+        expires: now + conf.session.ttl_secs * 1000,
+        tokens: Vec::new(),
+        clients: Vec::new()
+    };
+    try!(save(store, &session));
+    metrics::registry().add(metrics::ACTIVE_SESSIONS, &[], 1.0);
+    Ok(session)
+}
+
+/**
+ * Record token issued within the session, and the host it was sent to.
+ */
+pub fn add_token(session:&mut Session, token:&str, client_host:Option<String>){
+    session.tokens.push(token.to_string());
+    if let Some(host) = client_host {
+        if !session.clients.contains(&host) {
+            session.clients.push(host);
+        }
+    }
+}
+
+pub fn save(store:&Store, session:&Session) -> StoreResult<()> {
+    store.put_record(Namespace::Session, &session.id, session)
+}
+
+/**
+ * Get live session by id, expired session will be removed.
+ */
+pub fn get(store:&Store, id:&str) -> StoreResult<Option<Session>> {
+    match try!(store.get_record::<Session>(Namespace::Session, id)) {
+        Some(ref s) if s.is_expired() => {
+            try!(destroy(store, id));
+            Ok(None)
+        },
+        s => Ok(s)
+    }
+}
+
+pub fn destroy(store:&Store, id:&str) -> StoreResult<()> {
+    // counted only when there was one, eg: not already ended on another node.
+    if try!(store.get_record::<Session>(Namespace::Session, id)).is_some() {
+        try!(store.del_record(Namespace::Session, id));
+        metrics::registry().add(metrics::ACTIVE_SESSIONS, &[], -1.0);
+    }
+    Ok(())
+}
+
+/**
+ * Remove expired sessions never looked up again, returns how many.
+ * Active sessions are recounted, including the ones replicated from other nodes.
+ */
+pub fn purge(store:&Store) -> StoreResult<usize> {
+    let mut batch = store.batch();
+    let mut count = 0;
+    let mut active = 0;
+
+    for (key, session) in try!(store.scan_records::<Session>(Namespace::Session)) {
+        if session.is_expired() {
+            batch = batch.del(&key);
+            count += 1;
+        }else{
+            active += 1;
+        }
+    }
+    if count > 0 {
+        try!(batch.commit());
+    }
+    metrics::registry().set(metrics::ACTIVE_SESSIONS, &[], active as f64);
+    Ok(count)
+}
+
+pub fn cookie_id(headers:&Headers) -> Option<String> {
+    utils::get_cookie(headers, COOKIE_NAME)
+}
+
+/**
+ * Get session referenced by request cookie.
+ */
+pub fn from_headers(store:&Store, headers:&Headers) -> StoreResult<Option<Session>> {
+    match cookie_id(headers) {
+        Some(id) => get(store, &id),
+        None => Ok(None)
+    }
+}
+
+pub fn set_cookie(conf:&Conf, headers:&mut Headers, session:&Session){
+    let secure = if conf.security.secure_cookies { "; Secure" } else { "" };
+    // Lax, so the cookie still sent when application redirect user to us.
+    utils::set_cookie(headers, format!("{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        COOKIE_NAME, session.id, conf.session.ttl_secs, secure));
+}
+
+pub fn clear_cookie(conf:&Conf, headers:&mut Headers){
+    let secure = if conf.security.secure_cookies { "; Secure" } else { "" };
+    utils::set_cookie(headers, format!("{}=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax{}", COOKIE_NAME, secure));
+}
+
+
+#[cfg(test)]
+mod tests {
+    use store::{Store, MemoryStore, Keyring};
+    use utils;
+    use super::*;
+
+    fn session(id:&str, expires:u64) -> Session {
+        Session {
+            id: id.to_string(),
+            uid: "robin".to_string(),
+            dn: "dc=example,dc=com".to_string(),
+            created: 0,
+            expires: expires,
+            tokens: Vec::new(),
+            clients: Vec::new()
+        }
+    }
+
+    #[test]
+    fn purge_removes_expired_only() {
+        let store = Store::with_backend(Box::new(MemoryStore::new()))
+            .with_keyring(Some(Keyring::parse("k1:AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=").unwrap()));
+        let now = utils::current_time_millis();
+        save(&store, &session("live", now + 60000)).unwrap();
+        save(&store, &session("expired", now - 1)).unwrap();
+
+        assert_eq!(purge(&store).unwrap(), 1);
+        assert_eq!(store.scan(&Namespace::Session.prefix()).unwrap().len(), 1);
+        assert!(get(&store, "live").unwrap().is_some());
+
+        destroy(&store, "live").unwrap();
+        destroy(&store, "live").unwrap();
+        assert!(store.scan(&Namespace::Session.prefix()).unwrap().is_empty());
+    }
+}
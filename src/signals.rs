@@ -0,0 +1,58 @@
+
+// Unix signal notifications, eg: SIGHUP for reloading certificates.
+//
+// Signal handler only counts received signals, watchers poll the counter
+// from their own thread so they can do any work.
+
+use libc;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::{Once, ONCE_INIT};
+use std::thread;
+use std::time::Duration;
+
+const POLL_MILLIS:u64 = 250;
+
+static HANGUPS:AtomicUsize = ATOMIC_USIZE_INIT;
+static INSTALL:Once = ONCE_INIT;
+
+extern "C" fn count_hangup(_:libc::c_int) {
+    HANGUPS.fetch_add(1, Ordering::SeqCst);
+}
+
+/**
+ * Call `f` from background thread after every SIGHUP,
+ * signals received while `f` still running are coalesced.
+ */
+pub fn on_hangup<F>(f:F) where F: Fn() + Send + 'static {
+    INSTALL.call_once(|| unsafe {
+        libc::signal(libc::SIGHUP, count_hangup as libc::sighandler_t);
+    });
+
+    let mut seen = HANGUPS.load(Ordering::SeqCst);
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(POLL_MILLIS));
+        let current = HANGUPS.load(Ordering::SeqCst);
+        if current != seen {
+            seen = current;
+            f();
+        }
+    });
+}
+
+
+#[cfg(test)]
+mod tests {
+    use libc;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn hangup_notified() {
+        let (tx, rx) = channel();
+        on_hangup(move || tx.send(()).unwrap());
+
+        unsafe { libc::raise(libc::SIGHUP); }
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+}
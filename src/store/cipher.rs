@@ -0,0 +1,248 @@
+
+// Encryption at rest: record values sealed with AES-256-GCM (store key as
+// associated data), bearer ids (tokens, session ids) replaced by HMAC so
+// reading the data directory doesn't reveal usable credentials.
+//
+// Keyring holds one or more keys, the first one used for new writes,
+// the others kept so records written before rotation stay readable.
+
+use std::fs::File;
+use std::io::Read;
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use crypto::aead::{AeadEncryptor, AeadDecryptor};
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use rand::{OsRng, Rng};
+use serialize::base64::{self, ToBase64, FromBase64};
+
+use config::StoreConf;
+use super::{StoreError, StoreResult};
+
+const ENCRYPTED_PREFIX:&'static str = "enc:";
+const KEY_LEN:usize = 32;
+const NONCE_LEN:usize = 12;
+const TAG_LEN:usize = 16;
+
+
+struct StoreKey {
+    id: String,
+    enc_key: Vec<u8>,
+    lookup_key: Vec<u8>
+}
+
+pub struct Keyring {
+    keys: Vec<StoreKey>
+}
+
+fn hmac(key:&[u8], data:&[u8]) -> Vec<u8> {
+    let mut mac = Hmac::new(Sha256::new(), key);
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+fn b64url(data:&[u8]) -> String {
+    data.to_base64(base64::URL_SAFE)
+}
+
+pub fn is_encrypted(value:&str) -> bool {
+    value.starts_with(ENCRYPTED_PREFIX)
+}
+
+impl StoreKey {
+    fn new(id:&str, master:&[u8]) -> StoreKey {
+        // separate sub keys, so the lookup hash reveals nothing about encryption key.
+        StoreKey {
+            id: id.to_string(),
+            enc_key: hmac(master, b"sso-store-encryption"),
+            lookup_key: hmac(master, b"sso-store-lookup")
+        }
+    }
+}
+
+impl Keyring {
+    /**
+     * Parse keys, `<key-id>:<base64 key>` separated by comma or new line,
+     * empty lines and `#` comments ignored, first key is the active one.
+     */
+    pub fn parse(data:&str) -> StoreResult<Keyring> {
+        let mut keys:Vec<StoreKey> = Vec::new();
+
+        for entry in data.split(|c| c == ',' || c == '\n') {
+            let entry = entry.trim();
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+            let mut parts = entry.splitn(2, ':');
+            let id = parts.next().unwrap_or("").trim();
+            let key = parts.next().unwrap_or("").trim();
+
+            if id.is_empty() || !id.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+                return Err(StoreError::Config(format!("invalid encryption key id `{}`", id)));
+            }
+            if keys.iter().any(|k| k.id == id) {
+                return Err(StoreError::Config(format!("duplicate encryption key id `{}`", id)));
+            }
+            match key.from_base64() {
+                Ok(ref master) if master.len() == KEY_LEN => keys.push(StoreKey::new(id, master)),
+                _ => return Err(StoreError::Config(
+                    format!("encryption key `{}` should be {} bytes, base64 encoded", id, KEY_LEN)))
+            }
+        }
+
+        if keys.is_empty() {
+            return Err(StoreError::Config("no encryption key given".to_string()));
+        }
+        Ok(Keyring { keys: keys })
+    }
+
+    /**
+     * Load keyring configured in `[store]` section, if any.
+     */
+    pub fn load(conf:&StoreConf) -> StoreResult<Option<Keyring>> {
+        if !conf.encryption_key_file.is_empty() {
+            let mut data = String::new();
+            try!(File::open(&conf.encryption_key_file)
+                .and_then(|mut f| f.read_to_string(&mut data))
+                .map_err(|e| StoreError::Config(format!("cannot read `{}`: {}", conf.encryption_key_file, e))));
+            Keyring::parse(&data).map(Some)
+        }else if !conf.encryption_key.is_empty() {
+            Keyring::parse(&conf.encryption_key).map(Some)
+        }else{
+            Ok(None)
+        }
+    }
+
+    pub fn active_id(&self) -> &str {
+        &self.keys[0].id
+    }
+
+    /**
+     * Keyed hash of bearer id under every key, active key first.
+     */
+    pub fn lookup_ids(&self, id:&str) -> Vec<String> {
+        self.keys.iter().map(|k| b64url(&hmac(&k.lookup_key, id.as_bytes()))).collect()
+    }
+
+    /**
+     * Seal value with active key, eg: `enc:<key-id>:<nonce|ciphertext|tag>`.
+     */
+    pub fn encrypt(&self, aad:&str, plain:&str) -> String {
+        let key = &self.keys[0];
+
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng::new().expect("Cannot access OS random source").fill_bytes(&mut nonce);
+
+        let mut out = vec![0u8; NONCE_LEN + plain.len() + TAG_LEN];
+        out[..NONCE_LEN].copy_from_slice(&nonce);
+        {
+            let (body, tag) = out[NONCE_LEN..].split_at_mut(plain.len());
+            AesGcm::new(KeySize::KeySize256, &key.enc_key, &nonce, aad.as_bytes())
+                .encrypt(plain.as_bytes(), body, tag);
+        }
+
+        format!("{}{}:{}", ENCRYPTED_PREFIX, key.id, b64url(&out))
+    }
+
+    /**
+     * Open sealed value, returns the plain text and whether it was sealed
+     * with non active key (should be re-encrypted).
+     */
+    pub fn decrypt(&self, aad:&str, value:&str) -> StoreResult<(String, bool)> {
+        let err = |desc:&str| StoreError::Decode(format!("value of `{}`: {}", aad, desc));
+
+        let mut parts = value[ENCRYPTED_PREFIX.len()..].splitn(2, ':');
+        let id = parts.next().unwrap_or("");
+        let data = try!(parts.next().unwrap_or("").from_base64().map_err(|_| err("invalid encoding")));
+
+        let idx = match self.keys.iter().position(|k| k.id == id) {
+            Some(idx) => idx,
+            None => return Err(err(&format!("encrypted with unknown key `{}`", id)))
+        };
+        if data.len() < NONCE_LEN + TAG_LEN {
+            return Err(err("truncated"));
+        }
+
+        let (nonce, rest) = data.split_at(NONCE_LEN);
+        let (body, tag) = rest.split_at(rest.len() - TAG_LEN);
+        let mut plain = vec![0u8; body.len()];
+
+        if !AesGcm::new(KeySize::KeySize256, &self.keys[idx].enc_key, nonce, aad.as_bytes())
+                .decrypt(body, &mut plain, tag) {
+            return Err(err("authentication failed"));
+        }
+
+        String::from_utf8(plain)
+            .map(|plain| (plain, idx > 0))
+            .map_err(|_| err("not valid utf-8"))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY1:&'static str = "k1:AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+    const KEY2:&'static str = "k2:HxsdHBsaGRgXFhUUExIREA8ODQwLCgkIBwYFBAMCAQA=";
+
+    #[test]
+    fn encrypt_decrypt() {
+        let keyring = Keyring::parse(KEY1).unwrap();
+        let sealed = keyring.encrypt("session/abc", "{\"dn\":\"dc=example,dc=com\"}");
+
+        assert!(is_encrypted(&sealed));
+        assert!(sealed.starts_with("enc:k1:"));
+        assert!(!sealed.contains("example"));
+        assert_eq!(keyring.decrypt("session/abc", &sealed).unwrap(),
+            ("{\"dn\":\"dc=example,dc=com\"}".to_string(), false));
+
+        // random nonce
+        assert!(keyring.encrypt("session/abc", "x") != keyring.encrypt("session/abc", "x"));
+    }
+
+    #[test]
+    fn bound_to_store_key() {
+        let keyring = Keyring::parse(KEY1).unwrap();
+        let sealed = keyring.encrypt("session/abc", "secret");
+        assert!(keyring.decrypt("session/xyz", &sealed).is_err());
+    }
+
+    #[test]
+    fn tampered_value_rejected() {
+        let keyring = Keyring::parse(KEY1).unwrap();
+        let sealed = keyring.encrypt("token/abc", "secret");
+        let mut data = sealed[7..].from_base64().unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert!(keyring.decrypt("token/abc", &format!("enc:k1:{}", b64url(&data))).is_err());
+    }
+
+    #[test]
+    fn rotation() {
+        let old = Keyring::parse(KEY1).unwrap();
+        let rotated = Keyring::parse(&format!("{}\n# previous key\n{}\n", KEY2, KEY1)).unwrap();
+        assert_eq!(rotated.active_id(), "k2");
+
+        // written before rotation, still readable, flagged for re-encryption.
+        let sealed = old.encrypt("user/robin", "data");
+        assert_eq!(rotated.decrypt("user/robin", &sealed).unwrap(), ("data".to_string(), true));
+
+        let lookup = rotated.lookup_ids("token1");
+        assert_eq!(lookup.len(), 2);
+        assert_eq!(lookup[1], old.lookup_ids("token1")[0]);
+        assert!(lookup[0] != lookup[1]);
+
+        // removed key
+        assert!(Keyring::parse(KEY2).unwrap().decrypt("user/robin", &sealed).is_err());
+    }
+
+    #[test]
+    fn invalid_keys() {
+        assert!(Keyring::parse("").is_err());
+        assert!(Keyring::parse("k1:c2hvcnQ=").is_err());
+        assert!(Keyring::parse("k:1:AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=").is_err());
+        assert!(Keyring::parse(&format!("{},{}", KEY1, KEY1)).is_err());
+    }
+}
@@ -0,0 +1,40 @@
+
+use std::fmt;
+use std::error::Error;
+
+
+#[derive(Debug)]
+pub enum StoreError {
+    // storage engine failure, eg: full disk, connection lost.
+    Backend(String),
+    // data directory already used by another process.
+    Locked(String),
+    // stored value can't be decoded.
+    Decode(String),
+    // invalid store configuration.
+    Config(String)
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StoreError::Backend(ref e) => write!(f, "Store backend error: {}", e),
+            StoreError::Locked(ref e) => write!(f, "Store locked by another process: {}", e),
+            StoreError::Decode(ref e) => write!(f, "Cannot decode stored value: {}", e),
+            StoreError::Config(ref e) => write!(f, "Invalid store configuration: {}", e)
+        }
+    }
+}
+
+impl Error for StoreError {
+    fn description(&self) -> &str {
+        match *self {
+            StoreError::Backend(_) => "store backend error",
+            StoreError::Locked(_) => "store locked",
+            StoreError::Decode(_) => "cannot decode stored value",
+            StoreError::Config(_) => "invalid store configuration"
+        }
+    }
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
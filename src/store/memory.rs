@@ -0,0 +1,56 @@
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::{SessionStore, BatchOp, StoreResult};
+
+
+// Non persistent storage engine, for testing and single process development.
+pub struct MemoryStore {
+    data: Mutex<HashMap<String, String>>
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore {
+            data: Mutex::new(HashMap::new())
+        }
+    }
+}
+
+impl SessionStore for MemoryStore {
+
+    fn put(&self, key:&str, value:&str) -> StoreResult<()> {
+        self.data.lock().unwrap().insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    fn get(&self, key:&str) -> StoreResult<Option<String>> {
+        Ok(self.data.lock().unwrap().get(key).cloned())
+    }
+
+    fn del(&self, key:&str) -> StoreResult<()> {
+        self.data.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn write(&self, ops:Vec<BatchOp>) -> StoreResult<()> {
+        let mut data = self.data.lock().unwrap();
+        for op in ops {
+            match op {
+                BatchOp::Put(key, value) => { data.insert(key, value); },
+                BatchOp::Del(key) => { data.remove(&key); }
+            }
+        }
+        Ok(())
+    }
+
+    fn scan(&self, prefix:&str) -> StoreResult<Vec<(String, String)>> {
+        let mut result:Vec<(String, String)> = self.data.lock().unwrap().iter()
+            .filter(|&(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        result.sort();
+        Ok(result)
+    }
+}
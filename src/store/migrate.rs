@@ -0,0 +1,277 @@
+
+// Upgrade store written by older version to current key schema.
+//
+// Legacy (unversioned) layout mixed everything in one keyspace:
+//   <token> -> uid, <uid> -> token, dn_<token> -> dn,
+//   session_<id>, webauthn_<uid>, webauthn_challenge_<id> -> JSON,
+//   attempts_<user|ip>_<id> -> comma separated millis, lockout_<user|ip>_<id> -> millis.
+
+use std::collections::HashMap;
+
+use super::{Store, StoreError, StoreResult, Namespace, TokenRecord, UserIndex, AttemptsRecord, LockoutRecord};
+use super::schema::{self, SCHEMA_VERSION, SCHEMA_VERSION_KEY, ENCRYPTED_KEY, NAMESPACES};
+use super::cipher;
+
+
+/**
+ * Bring the store to current schema version and encrypt plain records
+ * when encryption at rest enabled, every step done in single atomic batch.
+ * Called on startup before serving any request.
+ */
+pub fn migrate(store:&Store) -> StoreResult<()> {
+    match try!(store.get(SCHEMA_VERSION_KEY)) {
+        Some(ref v) if *v == SCHEMA_VERSION.to_string() => (),
+        Some(v) => return Err(StoreError::Decode(
+            format!("unsupported store schema version {}, written by newer SSO version?", v))),
+        None => try!(migrate_legacy(store))
+    }
+
+    encrypt_existing(store)
+}
+
+fn migrate_legacy(store:&Store) -> StoreResult<()> {
+    let entries = try!(store.scan(""));
+
+    if !entries.is_empty() {
+        info!("Migrating {} legacy store entries to schema version {}", entries.len(), SCHEMA_VERSION);
+    }
+
+    let values:HashMap<&str, &str> = entries.iter().map(|&(ref k, ref v)| (k.as_str(), v.as_str())).collect();
+
+    let mut batch = store.batch();
+    let mut skipped = 0;
+
+    for &(ref key, ref value) in &entries {
+        let key = key.as_str();
+        let value = value.as_str();
+
+        let dn = if is_legacy_token(key) { values.get(format!("dn_{}", key).as_str()) } else { None };
+
+        if let Some(dn) = dn {
+            // access token, has it's dn record
+            batch = batch.put_record(Namespace::Token, key, &TokenRecord { uid: value.to_string(), dn: dn.to_string() });
+        }else if is_legacy_token(value) && values.get(value) == Some(&key)
+                && values.contains_key(format!("dn_{}", value).as_str()) {
+            // reverse lookup uid -> token
+            batch = batch.put_record(Namespace::User, key, &UserIndex { token: value.to_string() });
+        }else if key.starts_with("dn_") {
+            // moved into token record above, or orphan.
+        }else if key.starts_with("session_") {
+            match schema::wrap_json(value) {
+                Some(data) => batch = batch.put_encoded(Namespace::Session, &key[8..], &data),
+                None => warn!("Dropping undecodable legacy session `{}`", key)
+            }
+        }else if key.starts_with("webauthn_challenge_") {
+            // short-lived, user just need to retry the ceremony.
+        }else if key.starts_with("webauthn_") {
+            match schema::wrap_json(value) {
+                Some(data) => batch = batch.put_encoded(Namespace::Webauthn, &key[9..], &data),
+                None => {
+                    warn!("Undecodable legacy security keys `{}`, left untouched", key);
+                    skipped += 1;
+                    continue;
+                }
+            }
+        }else if let Some(id) = throttle_id(key, "attempts_") {
+            let timestamps = value.split(',').filter_map(|ts| ts.parse::<u64>().ok()).collect();
+            batch = batch.put_record(Namespace::Attempts, &id, &AttemptsRecord { timestamps: timestamps });
+        }else if let Some(id) = throttle_id(key, "lockout_") {
+            if let Ok(until) = value.parse::<u64>() {
+                batch = batch.put_record(Namespace::Lockout, &id, &LockoutRecord { until: until });
+            }
+        }else{
+            warn!("Unknown legacy store key `{}`, left untouched", key);
+            skipped += 1;
+            continue;
+        }
+
+        batch = batch.del(key);
+    }
+
+    let migrated = batch.ops.len();
+
+    try!(batch.put(SCHEMA_VERSION_KEY, &SCHEMA_VERSION.to_string()).commit());
+
+    if !entries.is_empty() {
+        info!("Store migrated ({} operations, {} entries skipped)", migrated, skipped);
+    }
+
+    Ok(())
+}
+
+// records written while encryption at rest disabled (ids in plain too).
+fn encrypt_existing(store:&Store) -> StoreResult<()> {
+    let encrypted = try!(store.get(ENCRYPTED_KEY)).is_some();
+
+    let keyring = match store.keyring() {
+        Some(keyring) => keyring,
+        None if encrypted => return Err(StoreError::Config(
+            "store is encrypted at rest, `encryption_key` or `encryption_key_file` required".to_string())),
+        None => return Ok(())
+    };
+
+    let mut batch = store.batch();
+    let mut count = 0;
+
+    for ns in NAMESPACES.iter() {
+        let prefix = ns.prefix();
+        for (key, value) in try!(store.scan(&prefix)) {
+            if cipher::is_encrypted(&value) {
+                continue;
+            }
+            batch = batch.del(&key).put_encoded(*ns, &key[prefix.len()..], &value);
+            count += 1;
+        }
+    }
+
+    if count > 0 {
+        info!("Encrypting {} store records with key `{}`", count, keyring.active_id());
+    }
+    if !encrypted {
+        batch = batch.put(ENCRYPTED_KEY, "1");
+    }
+    batch.commit()
+}
+
+// tokens generated by older version, 50 alphanumeric characters.
+fn is_legacy_token(s:&str) -> bool {
+    s.len() == 50 && s.bytes().all(|b| match b {
+        b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' => true,
+        _ => false
+    })
+}
+
+// `attempts_user_robin` -> `user/robin`
+fn throttle_id(key:&str, prefix:&str) -> Option<String> {
+    if !key.starts_with(prefix) {
+        return None;
+    }
+    let rest = &key[prefix.len()..];
+    for kind in &["user_", "ip_"] {
+        if rest.starts_with(kind) {
+            return Some(format!("{}/{}", &kind[..kind.len() - 1], &rest[kind.len()..]));
+        }
+    }
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use super::super::schema::{SCHEMA_VERSION_KEY, ENCRYPTED_KEY};
+
+    const TOKEN1:&'static str = "Xq3fI0mZ7uWb2LkN9pRt4VyA6cE8gH1jK5oS0dT2uV4wX6yZ8a";
+    const TOKEN2:&'static str = "Bq3fI0mZ7uWb2LkN9pRt4VyA6cE8gH1jK5oS0dT2uV4wX6yZ8b";
+
+    fn legacy_store() -> Store {
+        let store = Store::with_backend(Box::new(MemoryStore::new()));
+        store.batch()
+            // password login
+            .put(TOKEN1, "robin")
+            .put("robin", TOKEN1)
+            .put(&format!("dn_{}", TOKEN1), "dc=example,dc=com")
+            // token issued from SSO session, no reverse lookup
+            .put(TOKEN2, "robin")
+            .put(&format!("dn_{}", TOKEN2), "dc=example,dc=com")
+            // user named like legacy record prefix
+            .put("dn_robin", "dn_token")
+            .put("session_abc", r#"{"id":"abc","uid":"robin","dn":"dc=example,dc=com","created":1,"expires":2,"tokens":[],"clients":[]}"#)
+            .put("webauthn_robin", "[]")
+            .put("webauthn_challenge_xyz", "{}")
+            .put("attempts_user_robin", "100,200")
+            .put("attempts_ip_127.0.0.1", "300")
+            .put("lockout_user_robin", "400")
+            .put("something_else", "?")
+            .commit().unwrap();
+        store
+    }
+
+    #[test]
+    fn migrate_legacy_layout() {
+        let store = legacy_store();
+        migrate(&store).unwrap();
+
+        assert_eq!(store.get(SCHEMA_VERSION_KEY).unwrap(), Some("1".to_string()));
+
+        let record = store.get_record::<TokenRecord>(Namespace::Token, TOKEN1).unwrap().unwrap();
+        assert_eq!(record.uid, "robin");
+        assert_eq!(record.dn, "dc=example,dc=com");
+        assert!(store.get_record::<TokenRecord>(Namespace::Token, TOKEN2).unwrap().is_some());
+
+        assert_eq!(store.get_record::<UserIndex>(Namespace::User, "robin").unwrap(),
+            Some(UserIndex { token: TOKEN1.to_string() }));
+
+        assert!(store.get("session/abc").unwrap().unwrap().starts_with(r#"{"v":1,"data":{"id":"abc""#));
+        assert_eq!(store.get("webauthn/robin").unwrap(), Some(r#"{"v":1,"data":[]}"#.to_string()));
+
+        assert_eq!(store.get_record::<AttemptsRecord>(Namespace::Attempts, "user/robin").unwrap(),
+            Some(AttemptsRecord { timestamps: vec![100, 200] }));
+        assert_eq!(store.get_record::<AttemptsRecord>(Namespace::Attempts, "ip/127.0.0.1").unwrap(),
+            Some(AttemptsRecord { timestamps: vec![300] }));
+        assert_eq!(store.get_record::<LockoutRecord>(Namespace::Lockout, "user/robin").unwrap(),
+            Some(LockoutRecord { until: 400 }));
+
+        // legacy keys removed, unknown one kept
+        for key in &[TOKEN1, "robin", "dn_robin", "session_abc", "webauthn_challenge_xyz", "attempts_user_robin"] {
+            assert_eq!(store.get(key).unwrap(), None);
+        }
+        assert_eq!(store.get("something_else").unwrap(), Some("?".to_string()));
+    }
+
+    #[test]
+    fn migrate_is_idempotent() {
+        let store = legacy_store();
+        migrate(&store).unwrap();
+        let first = store.scan("").unwrap();
+        migrate(&store).unwrap();
+        assert_eq!(store.scan("").unwrap(), first);
+    }
+
+    #[test]
+    fn migrate_empty_store() {
+        let store = Store::with_backend(Box::new(MemoryStore::new()));
+        migrate(&store).unwrap();
+        assert_eq!(store.scan("").unwrap(), vec![(SCHEMA_VERSION_KEY.to_string(), "1".to_string())]);
+    }
+
+    #[test]
+    fn encrypt_plain_store() {
+        let store = legacy_store();
+        migrate(&store).unwrap();
+
+        let keyring = Keyring::parse("k1:AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=").unwrap();
+        let store = store.with_keyring(Some(keyring));
+        migrate(&store).unwrap();
+
+        assert_eq!(store.get(ENCRYPTED_KEY).unwrap(), Some("1".to_string()));
+
+        // no raw token nor plain value left
+        for (key, value) in store.scan("").unwrap() {
+            assert!(!key.contains(TOKEN1) && !value.contains(TOKEN1), "{} leaks token", key);
+            if !key.starts_with("meta/") && key != "something_else" {
+                assert!(value.starts_with("enc:k1:"), "{} not encrypted", key);
+            }
+        }
+
+        let record = store.get_record::<TokenRecord>(Namespace::Token, TOKEN1).unwrap().unwrap();
+        assert_eq!(record.uid, "robin");
+        assert_eq!(store.get_record::<UserIndex>(Namespace::User, "robin").unwrap(),
+            Some(UserIndex { token: TOKEN1.to_string() }));
+        assert_eq!(store.get_record::<LockoutRecord>(Namespace::Lockout, "user/robin").unwrap(),
+            Some(LockoutRecord { until: 400 }));
+
+        // refuse to run without the key
+        let store = Store::with_backend(Box::new(MemoryStore::new()));
+        store.put(SCHEMA_VERSION_KEY, "1").unwrap();
+        store.put(ENCRYPTED_KEY, "1").unwrap();
+        assert!(migrate(&store).is_err());
+    }
+
+    #[test]
+    fn reject_newer_schema() {
+        let store = Store::with_backend(Box::new(MemoryStore::new()));
+        store.put(SCHEMA_VERSION_KEY, "99").unwrap();
+        assert!(migrate(&store).is_err());
+    }
+}
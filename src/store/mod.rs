@@ -0,0 +1,488 @@
+
+// Session store, backed by pluggable storage engine (see `SessionStore`),
+// selected by `[store] backend = ...` in config, RocksDB is the default.
+
+use serialize::{Decodable, Encodable};
+
+use config::StoreConf;
+use metrics::{self, Timer};
+
+mod error;
+pub mod schema;
+mod cipher;
+mod migrate;
+pub mod rocks;
+mod memory;
+mod sqlite;
+mod redis;
+pub mod replica;
+
+pub use self::error::{StoreError, StoreResult};
+pub use self::schema::{Namespace, TokenRecord, UserIndex, AttemptsRecord, LockoutRecord, RevokedRecord};
+pub use self::migrate::migrate;
+pub use self::cipher::Keyring;
+pub use self::rocks::RocksStore;
+pub use self::memory::MemoryStore;
+pub use self::sqlite::SqliteStore;
+pub use self::redis::RedisStore;
+pub use self::replica::{ReplicatedStore, WalEntry};
+
+
+pub enum BatchOp {
+    Put(String, String),
+    Del(String)
+}
+
+// Storage engine interface, every backend should apply `write`
+// atomically (all or nothing).
+pub trait SessionStore: Send {
+    fn put(&self, key:&str, value:&str) -> StoreResult<()>;
+    fn get(&self, key:&str) -> StoreResult<Option<String>>;
+    fn del(&self, key:&str) -> StoreResult<()>;
+    fn write(&self, ops:Vec<BatchOp>) -> StoreResult<()>;
+    // all key-value pairs with key starting with `prefix`, ordered by key.
+    fn scan(&self, prefix:&str) -> StoreResult<Vec<(String, String)>>;
+
+    // at most `limit` pairs of `prefix` from key `from` on, ordered by key.
+    fn scan_range(&self, prefix:&str, from:&str, limit:usize) -> StoreResult<Vec<(String, String)>> {
+        Ok(try!(self.scan(prefix)).into_iter().filter(|&(ref k, _)| k.as_str() >= from).take(limit).collect())
+    }
+
+    // apply writes received from another node, see `ReplicatedStore`.
+    fn apply_remote(&self, _entries:Vec<WalEntry>) -> StoreResult<usize> {
+        Err(StoreError::Config("replication not enabled".to_string()))
+    }
+}
+
+
+pub struct Store {
+    backend: Box<SessionStore>,
+    keyring: Option<Keyring>
+}
+
+pub struct WriteBatchWrapper<'a> {
+    pub ops: Vec<BatchOp>,
+    store: &'a Store
+}
+
+
+impl Store {
+    /**
+     * Open store using backend configured in `[store]` section.
+     */
+    pub fn new(conf:&StoreConf) -> StoreResult<Store> {
+        let backend:Box<SessionStore> = match conf.backend.as_ref() {
+            "rocksdb" => Box::new(try!(RocksStore::open(&conf.path))),
+            "memory" => Box::new(MemoryStore::new()),
+            "sqlite" => Box::new(try!(SqliteStore::open(&conf.path))),
+            "redis" => Box::new(try!(RedisStore::open(&conf.redis_url, &conf.redis_prefix))),
+            other => return Err(StoreError::Config(
+                format!("unknown backend `{}`, supported: rocksdb, memory, sqlite, redis", other)))
+        };
+        let backend:Box<SessionStore> = if conf.replication.enabled() {
+            Box::new(try!(ReplicatedStore::open(backend, &conf.replication)))
+        }else{
+            backend
+        };
+        Ok(Store::with_backend(backend).with_keyring(try!(Keyring::load(conf))))
+    }
+
+    /**
+     * Open store of the same backend at another location, for backup and restore.
+     */
+    pub fn new_at(conf:&StoreConf, path:&str) -> StoreResult<Store> {
+        match conf.backend.as_ref() {
+            "rocksdb" | "sqlite" => {
+                let mut conf = conf.clone();
+                conf.path = path.to_string();
+                // copy stays local
+                conf.replication = Default::default();
+                Store::new(&conf)
+            },
+            other => Err(StoreError::Config(format!("`{}` backend has no local data to copy", other)))
+        }
+    }
+
+    pub fn with_backend(backend:Box<SessionStore>) -> Store {
+        Store {
+            backend: backend,
+            keyring: None
+        }
+    }
+
+    /**
+     * Enable encryption at rest for records.
+     */
+    pub fn with_keyring(mut self, keyring:Option<Keyring>) -> Store {
+        self.keyring = keyring;
+        self
+    }
+
+    pub fn keyring(&self) -> Option<&Keyring> {
+        self.keyring.as_ref()
+    }
+
+    pub fn put(&self, key:&str, value:&str) -> StoreResult<()> {
+        self.timed("put", |backend| backend.put(key, value))
+    }
+
+    pub fn get(&self, key:&str) -> StoreResult<Option<String>> {
+        self.timed("get", |backend| backend.get(key))
+    }
+
+    pub fn del(&self, key:&str) -> StoreResult<()> {
+        self.timed("del", |backend| backend.del(key))
+    }
+
+    pub fn scan(&self, prefix:&str) -> StoreResult<Vec<(String, String)>> {
+        self.timed("scan", |backend| backend.scan(prefix))
+    }
+
+    /**
+     * Apply entries shipped by peer node, returns number of writes applied.
+     */
+    pub fn apply_replicated(&self, entries:Vec<WalEntry>) -> StoreResult<usize> {
+        self.timed("apply_replicated", |backend| backend.apply_remote(entries))
+    }
+
+    // store keys the record may live under, the one for active key first.
+    fn record_keys(&self, ns:Namespace, id:&str) -> Vec<String> {
+        match self.keyring {
+            Some(ref keyring) if ns.is_secret() =>
+                keyring.lookup_ids(id).iter().map(|hashed| ns.key(hashed)).collect(),
+            _ => vec![ns.key(id)]
+        }
+    }
+
+    fn seal(&self, key:&str, data:&str) -> String {
+        match self.keyring {
+            Some(ref keyring) => keyring.encrypt(key, data),
+            None => data.to_string()
+        }
+    }
+
+    // decode record, also tells whether it should be re-written with active key.
+    fn open<T:Decodable>(&self, key:&str, data:&str) -> StoreResult<(T, bool)> {
+        let (plain, stale) = if cipher::is_encrypted(data) {
+            match self.keyring {
+                Some(ref keyring) => try!(keyring.decrypt(key, data)),
+                None => return Err(StoreError::Decode(
+                    format!("value of `{}` is encrypted, but no encryption key configured", key)))
+            }
+        }else{
+            (data.to_string(), self.keyring.is_some())
+        };
+        Ok((try!(schema::decode(key, &plain)), stale))
+    }
+
+    /**
+     * Plain (versioned JSON) value of a record as found by `scan`.
+     */
+    pub fn unseal(&self, key:&str, value:&str) -> StoreResult<String> {
+        if !cipher::is_encrypted(value) {
+            return Ok(value.to_string());
+        }
+        match self.keyring {
+            Some(ref keyring) => keyring.decrypt(key, value).map(|(plain, _)| plain),
+            None => Err(StoreError::Decode(format!("value of `{}` is encrypted, but no encryption key configured", key)))
+        }
+    }
+
+    /**
+     * Get typed record, eg: `store.get_record::<TokenRecord>(Namespace::Token, token)`.
+     */
+    pub fn get_record<T:Decodable + Encodable>(&self, ns:Namespace, id:&str) -> StoreResult<Option<T>> {
+        for (i, key) in self.record_keys(ns, id).iter().enumerate() {
+            if let Some(data) = try!(self.timed("get", |backend| backend.get(key))) {
+                let (record, stale) = try!(self.open::<T>(key, &data));
+                // written before key rotation, move it to active key.
+                if i > 0 || stale {
+                    try!(self.batch().put_record(ns, id, &record).commit());
+                }
+                return Ok(Some(record));
+            }
+        }
+        Ok(None)
+    }
+
+    /**
+     * Every record of namespace with the store key it's under (ids of secret
+     * namespaces are hashed when encrypted), undecodable ones are skipped.
+     */
+    pub fn scan_records<T:Decodable>(&self, ns:Namespace) -> StoreResult<Vec<(String, T)>> {
+        let mut records = Vec::new();
+        for (key, data) in try!(self.scan(&ns.prefix())) {
+            match self.open::<T>(&key, &data) {
+                Ok((record, _)) => records.push((key, record)),
+                Err(e) => warn!("Skipping record `{}`: {}", key, e)
+            }
+        }
+        Ok(records)
+    }
+
+    pub fn put_record<T:Encodable>(&self, ns:Namespace, id:&str, record:&T) -> StoreResult<()> {
+        self.batch().put_record(ns, id, record).commit()
+    }
+
+    pub fn del_record(&self, ns:Namespace, id:&str) -> StoreResult<()> {
+        self.batch().del_record(ns, id).commit()
+    }
+
+    pub fn batch(&self) -> WriteBatchWrapper {
+        WriteBatchWrapper {
+            ops: Vec::new(),
+            store: self
+        }
+    }
+
+    pub fn commit(&self, wbw:WriteBatchWrapper) -> StoreResult<()> {
+        self.timed("write", |backend| backend.write(wbw.ops))
+    }
+
+    // backend call with its latency recorded, see `metrics`.
+    fn timed<T, F:FnOnce(&SessionStore) -> T>(&self, operation:&str, f:F) -> T {
+        let timer = Timer::start();
+        let result = f(&*self.backend);
+        timer.observe(metrics::STORE_DURATION, operation);
+        result
+    }
+}
+
+// wrapper for batch write for easy use
+// using pipe-like style, eg:
+// store.batch()
+//      .put(b"key1", b"value1")
+//      .put(b"key2", b"value2")
+//      .put(b"key3", b"value3")
+//      .commit();
+//
+impl<'a> WriteBatchWrapper<'a> {
+
+    pub fn put(mut self, key:&str, value:&str) -> Self {
+        self.ops.push(BatchOp::Put(key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn del(mut self, key:&str) -> Self {
+        self.ops.push(BatchOp::Del(key.to_string()));
+        self
+    }
+
+    pub fn put_record<T:Encodable>(self, ns:Namespace, id:&str, record:&T) -> Self {
+        self.put_encoded(ns, id, &schema::encode(record))
+    }
+
+    /**
+     * Put record already encoded by `schema::encode` (or wrapped legacy JSON).
+     */
+    pub fn put_encoded(mut self, ns:Namespace, id:&str, data:&str) -> Self {
+        let keys = self.store.record_keys(ns, id);
+        let value = self.store.seal(&keys[0], data);
+        for key in &keys[1..] {
+            self.ops.push(BatchOp::Del(key.clone()));
+        }
+        self.put(&keys[0], &value)
+    }
+
+    /**
+     * Put plain value under exact store key (as returned by `scan`), sealed when encryption enabled.
+     */
+    pub fn put_sealed(self, key:&str, data:&str) -> Self {
+        let value = self.store.seal(key, data);
+        self.put(key, &value)
+    }
+
+    pub fn del_record(mut self, ns:Namespace, id:&str) -> Self {
+        for key in self.store.record_keys(ns, id) {
+            self.ops.push(BatchOp::Del(key));
+        }
+        self
+    }
+
+    pub fn commit(self) -> StoreResult<()> {
+        let ops = self.ops;
+        self.store.timed("write", |backend| backend.write(ops))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use rand::{self, Rng};
+
+    use config::StoreConf;
+    use super::*;
+
+    fn temp_path(name:&str) -> String {
+        let suffix:String = rand::thread_rng().gen_ascii_chars().take(10).collect();
+        env::temp_dir().join(format!("sso-test-{}-{}", name, suffix)).to_str().unwrap().to_string()
+    }
+
+    // same behavior expected from every backend.
+    fn exercise(store:Store){
+        assert_eq!(store.get("robin").unwrap(), None);
+
+        store.put("robin", "token1").unwrap();
+        assert_eq!(store.get("robin").unwrap(), Some("token1".to_string()));
+
+        store.put("robin", "token2").unwrap();
+        assert_eq!(store.get("robin").unwrap(), Some("token2".to_string()));
+
+        store.del("robin").unwrap();
+        assert_eq!(store.get("robin").unwrap(), None);
+
+        // deleting missing key is fine
+        store.del("robin").unwrap();
+
+        store.batch()
+            .put("token3", "robin")
+            .put("robin", "token3")
+            .put("dn_token3", "dc=example,dc=com")
+            .commit().unwrap();
+
+        assert_eq!(store.get("token3").unwrap(), Some("robin".to_string()));
+        assert_eq!(store.get("dn_token3").unwrap(), Some("dc=example,dc=com".to_string()));
+
+        store.batch()
+            .del("token3")
+            .put("robin", "token4")
+            .commit().unwrap();
+
+        assert_eq!(store.get("token3").unwrap(), None);
+        assert_eq!(store.get("robin").unwrap(), Some("token4".to_string()));
+
+        // unicode and separator characters
+        store.put("key with spaces\r\n", "nilai ✓").unwrap();
+        assert_eq!(store.get("key with spaces\r\n").unwrap(), Some("nilai ✓".to_string()));
+
+        // prefix scan, wildcard-like characters are literal
+        store.batch()
+            .put("token/b", "2")
+            .put("token/a", "1")
+            .put("token%/c", "3")
+            .put("tokens/d", "4")
+            .commit().unwrap();
+        assert_eq!(store.scan("token/").unwrap(),
+            vec![("token/a".to_string(), "1".to_string()), ("token/b".to_string(), "2".to_string())]);
+        assert_eq!(store.scan("token%").unwrap().len(), 1);
+
+        // typed records
+        let record = TokenRecord { uid: "robin".to_string(), dn: "dc=example,dc=com".to_string() };
+        store.put_record(Namespace::Token, "token5", &record).unwrap();
+        assert_eq!(store.get_record::<TokenRecord>(Namespace::Token, "token5").unwrap(), Some(record));
+        assert_eq!(store.get_record::<TokenRecord>(Namespace::Token, "robin").unwrap(), None);
+
+        // user named like a token doesn't collide
+        store.batch()
+            .put_record(Namespace::User, "token5", &UserIndex { token: "token6".to_string() })
+            .commit().unwrap();
+        assert!(store.get_record::<TokenRecord>(Namespace::Token, "token5").unwrap().is_some());
+
+        store.del_record(Namespace::Token, "token5").unwrap();
+        assert_eq!(store.get_record::<TokenRecord>(Namespace::Token, "token5").unwrap(), None);
+    }
+
+    const KEY1:&'static str = "k1:AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+    const KEY2:&'static str = "k2:HxsdHBsaGRgXFhUUExIREA8ODQwLCgkIBwYFBAMCAQA=";
+
+    #[test]
+    fn memory_backend_encrypted() {
+        exercise(Store::with_backend(Box::new(MemoryStore::new()))
+            .with_keyring(Some(Keyring::parse(KEY1).unwrap())));
+    }
+
+    #[test]
+    fn key_rotation_keeps_records() {
+        let store = Store::with_backend(Box::new(MemoryStore::new()))
+            .with_keyring(Some(Keyring::parse(KEY1).unwrap()));
+
+        let record = TokenRecord { uid: "robin".to_string(), dn: "dc=example,dc=com".to_string() };
+        store.put_record(Namespace::Token, "token1", &record).unwrap();
+        store.put_record(Namespace::User, "robin", &UserIndex { token: "token1".to_string() }).unwrap();
+
+        let stored = store.scan("").unwrap();
+        assert_eq!(stored.len(), 2);
+        assert!(stored.iter().all(|&(ref k, ref v)| !k.contains("token1") && v.starts_with("enc:k1:")));
+
+        // new key added in front, old one kept for reading
+        let store = store.with_keyring(Some(Keyring::parse(&format!("{},{}", KEY2, KEY1)).unwrap()));
+        assert_eq!(store.get_record::<TokenRecord>(Namespace::Token, "token1").unwrap(), Some(record.clone()));
+        assert!(store.get_record::<UserIndex>(Namespace::User, "robin").unwrap().is_some());
+
+        // re-written with the new key on access
+        let stored = store.scan("").unwrap();
+        assert_eq!(stored.len(), 2);
+        assert!(stored.iter().all(|&(_, ref v)| v.starts_with("enc:k2:")));
+
+        // so old key can be removed later
+        let store = store.with_keyring(Some(Keyring::parse(KEY2).unwrap()));
+        assert_eq!(store.get_record::<TokenRecord>(Namespace::Token, "token1").unwrap(), Some(record));
+
+        store.del_record(Namespace::Token, "token1").unwrap();
+        assert_eq!(store.get_record::<TokenRecord>(Namespace::Token, "token1").unwrap(), None);
+    }
+
+    #[test]
+    fn record_version_checked() {
+        let store = Store::with_backend(Box::new(MemoryStore::new()));
+
+        store.put("token/legacy", "robin").unwrap();
+        assert!(store.get_record::<TokenRecord>(Namespace::Token, "legacy").is_err());
+
+        store.put("token/future", r#"{"v":99,"data":{"uid":"robin","dn":"dc=example,dc=com"}}"#).unwrap();
+        match store.get_record::<TokenRecord>(Namespace::Token, "future") {
+            Err(StoreError::Decode(_)) => (),
+            _ => panic!("expecting StoreError::Decode")
+        }
+    }
+
+    #[test]
+    fn memory_backend() {
+        exercise(Store::with_backend(Box::new(MemoryStore::new())));
+    }
+
+    #[test]
+    fn rocksdb_locked_by_another_process() {
+        let path = temp_path("rocksdb-lock");
+        let _first = RocksStore::open(&path).unwrap();
+        match RocksStore::open(&path) {
+            Err(StoreError::Locked(_)) => (),
+            _ => panic!("expecting StoreError::Locked")
+        }
+        let _ = fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn unknown_backend() {
+        let mut conf:StoreConf = Default::default();
+        conf.backend = "floppy".to_string();
+        match Store::new(&conf) {
+            Err(StoreError::Config(_)) => (),
+            _ => panic!("expecting StoreError::Config")
+        }
+    }
+
+    #[test]
+    fn rocksdb_backend() {
+        let path = temp_path("rocksdb");
+        exercise(Store::with_backend(Box::new(RocksStore::open(&path).unwrap())));
+        let _ = fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn sqlite_backend() {
+        let path = temp_path("sqlite");
+        exercise(Store::with_backend(Box::new(SqliteStore::open(&path).unwrap())));
+        let _ = fs::remove_file(&path);
+    }
+
+    // requires redis-server listening on 127.0.0.1:6379, run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn redis_backend() {
+        let prefix = format!("{}:", temp_path("redis"));
+        exercise(Store::with_backend(Box::new(RedisStore::open("redis://127.0.0.1:6379/15", &prefix).unwrap())));
+    }
+}
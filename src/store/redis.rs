@@ -0,0 +1,358 @@
+
+// Storage engine talking Redis protocol (RESP), so multiple SSO instances
+// behind load balancer can share the same sessions.
+
+use std::io::{self, Read, Write, BufRead, BufReader};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::time::Duration;
+use url::Url;
+
+use super::{SessionStore, BatchOp, StoreError, StoreResult};
+
+const IO_TIMEOUT_SECS:u64 = 5;
+
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>)
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream
+}
+
+pub struct RedisStore {
+    addr: String,
+    password: Option<String>,
+    db: i64,
+    prefix: String,
+    conn: Mutex<Option<Connection>>
+}
+
+impl RedisStore {
+    /**
+     * Open store, eg: `redis://:password@127.0.0.1:6379/0`,
+     * every key prefixed with `prefix`.
+     */
+    pub fn open(redis_url:&str, prefix:&str) -> StoreResult<RedisStore> {
+        let url = try!(Url::parse(redis_url).map_err(|e| StoreError::Config(format!("invalid `redis_url`: {}", e))));
+        if url.scheme() != "redis" {
+            return Err(StoreError::Config("invalid `redis_url` scheme, expecting redis://".to_string()));
+        }
+
+        let store = RedisStore {
+            addr: format!("{}:{}", url.host_str().unwrap_or("127.0.0.1"), url.port().unwrap_or(6379)),
+            password: url.password().map(|p| p.to_string()),
+            db: url.path().trim_matches('/').parse().unwrap_or(0),
+            prefix: prefix.to_string(),
+            conn: Mutex::new(None)
+        };
+
+        // fail early when server unreachable.
+        try!(store.command(&["PING"]));
+
+        Ok(store)
+    }
+
+    fn connect(&self) -> io::Result<Connection> {
+        let stream = try!(TcpStream::connect(&self.addr[..]));
+        try!(stream.set_read_timeout(Some(Duration::from_secs(IO_TIMEOUT_SECS))));
+        try!(stream.set_write_timeout(Some(Duration::from_secs(IO_TIMEOUT_SECS))));
+
+        let mut conn = Connection {
+            reader: BufReader::new(try!(stream.try_clone())),
+            writer: stream
+        };
+
+        if let Some(ref password) = self.password {
+            try!(check(try!(conn.call(&[b"AUTH", password.as_bytes()]))));
+        }
+        if self.db != 0 {
+            try!(check(try!(conn.call(&[b"SELECT", self.db.to_string().as_bytes()]))));
+        }
+        Ok(conn)
+    }
+
+    // run commands in single round-trip, reconnect once when connection broken.
+    fn pipeline(&self, commands:&[Vec<Vec<u8>>]) -> StoreResult<Vec<Reply>> {
+        let mut guard = self.conn.lock().unwrap();
+
+        for attempt in 0..2 {
+            if guard.is_none() {
+                *guard = Some(try!(self.connect().map_err(|e|
+                    StoreError::Backend(format!("Cannot connect to redis at {}: {}", self.addr, e)))));
+            }
+
+            let result = {
+                let conn = guard.as_mut().unwrap();
+                conn.pipeline(commands)
+            };
+
+            match result {
+                Ok(replies) => return Ok(replies),
+                Err(e) => {
+                    *guard = None;
+                    if attempt > 0 {
+                        return Err(StoreError::Backend(format!("Redis error: {}", e)));
+                    }
+                    warn!("Redis connection error, reconnecting: {}", e);
+                }
+            }
+        }
+        unreachable!()
+    }
+
+    fn command(&self, args:&[&str]) -> StoreResult<Reply> {
+        let cmd = args.iter().map(|a| a.as_bytes().to_vec()).collect();
+        let mut replies = try!(self.pipeline(&[cmd]));
+        match replies.remove(0) {
+            Reply::Error(e) => Err(StoreError::Backend(format!("Redis error: {}", e))),
+            reply => Ok(reply)
+        }
+    }
+
+    fn key(&self, key:&str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+// escape glob characters for `SCAN ... MATCH`.
+fn glob_escape(s:&str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        match c {
+            '*' | '?' | '[' | ']' | '^' | '\\' => { out.push('\\'); out.push(c); },
+            c => out.push(c)
+        }
+    }
+    out
+}
+
+fn check(reply:Reply) -> io::Result<Reply> {
+    match reply {
+        Reply::Error(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        r => Ok(r)
+    }
+}
+
+impl Connection {
+    fn call(&mut self, args:&[&[u8]]) -> io::Result<Reply> {
+        let cmd:Vec<Vec<u8>> = args.iter().map(|a| a.to_vec()).collect();
+        self.pipeline(&[cmd]).map(|mut r| r.remove(0))
+    }
+
+    fn pipeline(&mut self, commands:&[Vec<Vec<u8>>]) -> io::Result<Vec<Reply>> {
+        let mut buf = Vec::new();
+        for cmd in commands {
+            encode_command(cmd, &mut buf);
+        }
+        try!(self.writer.write_all(&buf));
+
+        let mut replies = Vec::new();
+        for _ in commands {
+            replies.push(try!(read_reply(&mut self.reader)));
+        }
+        Ok(replies)
+    }
+}
+
+fn encode_command(args:&Vec<Vec<u8>>, buf:&mut Vec<u8>){
+    buf.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
+fn protocol_error(desc:&str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Redis protocol error: {}", desc))
+}
+
+fn read_reply<R:BufRead>(reader:&mut R) -> io::Result<Reply> {
+    let mut line = String::new();
+    if try!(reader.read_line(&mut line)) == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed by redis"));
+    }
+    if !line.ends_with("\r\n") || line.len() < 3 {
+        return Err(protocol_error("malformed reply line"));
+    }
+    let (kind, rest) = line[..line.len() - 2].split_at(1);
+
+    let parse_int = |s:&str| s.parse::<i64>().map_err(|_| protocol_error("invalid integer"));
+
+    match kind {
+        "+" => Ok(Reply::Status(rest.to_string())),
+        "-" => Ok(Reply::Error(rest.to_string())),
+        ":" => Ok(Reply::Integer(try!(parse_int(rest)))),
+        "$" => {
+            let len = try!(parse_int(rest));
+            if len < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            let mut data = vec![0u8; len as usize + 2];
+            try!(reader.read_exact(&mut data));
+            data.truncate(len as usize);
+            Ok(Reply::Bulk(Some(data)))
+        },
+        "*" => {
+            let len = try!(parse_int(rest));
+            if len < 0 {
+                return Ok(Reply::Array(None));
+            }
+            let mut items = Vec::new();
+            for _ in 0..len {
+                items.push(try!(read_reply(reader)));
+            }
+            Ok(Reply::Array(Some(items)))
+        },
+        _ => Err(protocol_error("unknown reply type"))
+    }
+}
+
+impl SessionStore for RedisStore {
+
+    fn put(&self, key:&str, value:&str) -> StoreResult<()> {
+        self.command(&["SET", &self.key(key), value]).map(|_| ())
+    }
+
+    fn get(&self, key:&str) -> StoreResult<Option<String>> {
+        match try!(self.command(&["GET", &self.key(key)])) {
+            Reply::Bulk(Some(data)) => String::from_utf8(data).map(Some)
+                .map_err(|_| StoreError::Decode(format!("value of `{}` is not valid utf-8", key))),
+            Reply::Bulk(None) => Ok(None),
+            other => Err(StoreError::Backend(format!("Unexpected redis reply: {:?}", other)))
+        }
+    }
+
+    fn del(&self, key:&str) -> StoreResult<()> {
+        self.command(&["DEL", &self.key(key)]).map(|_| ())
+    }
+
+    fn write(&self, ops:Vec<BatchOp>) -> StoreResult<()> {
+        let mut commands:Vec<Vec<Vec<u8>>> = vec![vec![b"MULTI".to_vec()]];
+        for op in ops {
+            commands.push(match op {
+                BatchOp::Put(key, value) => vec![b"SET".to_vec(), self.key(&key).into_bytes(), value.into_bytes()],
+                BatchOp::Del(key) => vec![b"DEL".to_vec(), self.key(&key).into_bytes()]
+            });
+        }
+        commands.push(vec![b"EXEC".to_vec()]);
+
+        let replies = try!(self.pipeline(&commands));
+
+        match replies.last() {
+            Some(&Reply::Array(Some(ref results))) => {
+                for r in results {
+                    if let Reply::Error(ref e) = *r {
+                        return Err(StoreError::Backend(format!("Redis error: {}", e)));
+                    }
+                }
+                Ok(())
+            },
+            _ => {
+                // queued command rejected, transaction discarded.
+                for r in &replies {
+                    if let Reply::Error(ref e) = *r {
+                        return Err(StoreError::Backend(format!("Redis transaction aborted: {}", e)));
+                    }
+                }
+                Err(StoreError::Backend("Redis transaction aborted".to_string()))
+            }
+        }
+    }
+
+    fn scan(&self, prefix:&str) -> StoreResult<Vec<(String, String)>> {
+        let pattern = format!("{}*", glob_escape(&self.key(prefix)));
+        let mut keys:Vec<String> = Vec::new();
+        let mut cursor = "0".to_string();
+
+        loop {
+            let reply = try!(self.command(&["SCAN", &cursor, "MATCH", &pattern, "COUNT", "1000"]));
+            match reply {
+                Reply::Array(Some(mut parts)) if parts.len() == 2 => {
+                    if let Reply::Array(Some(items)) = parts.pop().unwrap() {
+                        for item in items {
+                            if let Reply::Bulk(Some(key)) = item {
+                                keys.push(try!(String::from_utf8(key)
+                                    .map_err(|_| StoreError::Decode("key is not valid utf-8".to_string()))));
+                            }
+                        }
+                    }
+                    cursor = match parts.pop().unwrap() {
+                        Reply::Bulk(Some(c)) => String::from_utf8_lossy(&c).into_owned(),
+                        other => return Err(StoreError::Backend(format!("Unexpected redis reply: {:?}", other)))
+                    };
+                },
+                other => return Err(StoreError::Backend(format!("Unexpected redis reply: {:?}", other)))
+            }
+            if cursor == "0" {
+                break;
+            }
+        }
+
+        // SCAN may return the same key more than once.
+        keys.sort();
+        keys.dedup();
+
+        let commands:Vec<Vec<Vec<u8>>> = keys.iter()
+            .map(|k| vec![b"GET".to_vec(), k.as_bytes().to_vec()])
+            .collect();
+        let replies = try!(self.pipeline(&commands));
+
+        let mut result = Vec::new();
+        for (key, reply) in keys.into_iter().zip(replies.into_iter()) {
+            match reply {
+                Reply::Bulk(Some(data)) => {
+                    let value = try!(String::from_utf8(data)
+                        .map_err(|_| StoreError::Decode(format!("value of `{}` is not valid utf-8", key))));
+                    result.push((key[self.prefix.len()..].to_string(), value));
+                },
+                // removed in the meantime
+                Reply::Bulk(None) => (),
+                other => return Err(StoreError::Backend(format!("Unexpected redis reply: {:?}", other)))
+            }
+        }
+        Ok(result)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::{read_reply, encode_command, Reply};
+
+    #[test]
+    fn encode() {
+        let mut buf = Vec::new();
+        encode_command(&vec![b"SET".to_vec(), b"k".to_vec(), b"v 1".to_vec()], &mut buf);
+        assert_eq!(buf, b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$3\r\nv 1\r\n".to_vec());
+    }
+
+    #[test]
+    fn decode_replies() {
+        let mut data = Cursor::new(b"+OK\r\n-ERR wrong\r\n:42\r\n$5\r\nhe\r\no\r\n$-1\r\n*2\r\n+QUEUED\r\n$0\r\n\r\n".to_vec());
+        assert_eq!(read_reply(&mut data).unwrap(), Reply::Status("OK".to_string()));
+        assert_eq!(read_reply(&mut data).unwrap(), Reply::Error("ERR wrong".to_string()));
+        assert_eq!(read_reply(&mut data).unwrap(), Reply::Integer(42));
+        assert_eq!(read_reply(&mut data).unwrap(), Reply::Bulk(Some(b"he\r\no".to_vec())));
+        assert_eq!(read_reply(&mut data).unwrap(), Reply::Bulk(None));
+        assert_eq!(read_reply(&mut data).unwrap(),
+            Reply::Array(Some(vec![Reply::Status("QUEUED".to_string()), Reply::Bulk(Some(Vec::new()))])));
+        assert!(read_reply(&mut data).is_err());
+    }
+
+    #[test]
+    fn decode_malformed() {
+        assert!(read_reply(&mut Cursor::new(b"?what\r\n".to_vec())).is_err());
+        assert!(read_reply(&mut Cursor::new(b":abc\r\n".to_vec())).is_err());
+        assert!(read_reply(&mut Cursor::new(b"$10\r\nshort\r\n".to_vec())).is_err());
+    }
+}
@@ -0,0 +1,593 @@
+
+// Multi-node replication: every write applied locally together with per-key
+// version and appended to write-ahead log (WAL), background thread per peer
+// ships the log over HTTP (`POST /_replication/apply`) until acknowledged.
+// Every node lists all other nodes as peers, received entries aren't forwarded.
+//
+// Conflicts resolved per key, newer version (millis, node id) wins, except
+// deleted bearer records (tokens, sessions) stay deleted, so revocation
+// can't be undone by concurrent write on another node.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread;
+use std::time::Duration;
+use std::io::Read;
+use serialize::json;
+use serialize::hex::{ToHex, FromHex};
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use nickel::hyper::Client;
+use nickel::hyper::header::ContentType;
+
+use config::ReplicationConf;
+use utils;
+use super::{SessionStore, BatchOp, StoreError, StoreResult};
+use super::schema::NAMESPACES;
+
+pub const APPLY_PATH:&'static str = "/_replication/apply";
+pub const SIGNATURE_HEADER:&'static str = "X-Replication-Signature";
+
+// replication bookkeeping, hidden from `scan`.
+pub const META_PREFIX:&'static str = "_repl/";
+const VERSION_PREFIX:&'static str = "_repl/ver/";
+const WAL_PREFIX:&'static str = "_repl/wal/";
+const ACK_PREFIX:&'static str = "_repl/ack/";
+const SEQ_KEY:&'static str = "_repl/seq";
+
+const MAX_ENTRIES_PER_REQUEST:usize = 500;
+const REQUEST_TIMEOUT_SECS:u64 = 10;
+const TOMBSTONE_GC_INTERVAL_SECS:u64 = 3600;
+// WAL entries removed per write when trimming.
+const TRIM_BATCH:usize = 100;
+
+
+#[derive(Decodable, Encodable, Clone, Debug, PartialEq)]
+pub struct WalOp {
+    pub key: String,
+    pub value: Option<String>, // None for delete
+    pub millis: u64,
+    pub node: String
+}
+
+#[derive(Decodable, Encodable, Clone, Debug)]
+pub struct WalEntry {
+    pub seq: u64,
+    pub node: String,
+    pub ops: Vec<WalOp>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Version {
+    millis: u64,
+    node: String,
+    deleted: bool
+}
+
+impl Version {
+    fn of(op:&WalOp) -> Version {
+        Version { millis: op.millis, node: op.node.clone(), deleted: op.value.is_none() }
+    }
+
+    // `<millis>:<node>`, `:d` suffix for deleted key.
+    fn parse(s:&str) -> Option<Version> {
+        let parts:Vec<&str> = s.split(':').collect();
+        match (parts.get(0).and_then(|m| m.parse::<u64>().ok()), parts.get(1)) {
+            (Some(millis), Some(node)) => Some(Version {
+                millis: millis,
+                node: node.to_string(),
+                deleted: parts.get(2) == Some(&"d")
+            }),
+            _ => None
+        }
+    }
+
+    fn encode(&self) -> String {
+        format!("{}:{}{}", self.millis, self.node, if self.deleted { ":d" } else { "" })
+    }
+
+    fn newer_than(&self, other:&Version) -> bool {
+        (self.millis, &self.node) > (other.millis, &other.node)
+    }
+}
+
+fn is_bearer(key:&str) -> bool {
+    NAMESPACES.iter().any(|ns| ns.is_secret() && key.starts_with(&ns.prefix()))
+}
+
+// whether incoming write replaces current state of the key.
+fn wins(key:&str, incoming:&Version, current:Option<&Version>) -> bool {
+    let current = match current {
+        Some(current) => current,
+        None => return true
+    };
+    if is_bearer(key) {
+        if current.deleted {
+            return false;
+        }
+        if incoming.deleted {
+            return true;
+        }
+    }
+    incoming.newer_than(current)
+}
+
+fn version_key(key:&str) -> String {
+    format!("{}{}", VERSION_PREFIX, key)
+}
+
+fn wal_key(seq:u64) -> String {
+    format!("{}{:020}", WAL_PREFIX, seq)
+}
+
+fn ack_key(peer:&str) -> String {
+    format!("{}{}", ACK_PREFIX, peer)
+}
+
+/**
+ * Resolve ops against current versions, returns batch to write locally
+ * and the ops that won (in order, later op in the same batch wins).
+ */
+fn resolve(local:&SessionStore, ops:Vec<WalOp>) -> StoreResult<(Vec<BatchOp>, Vec<WalOp>)> {
+    let mut pending:HashMap<String, Version> = HashMap::new();
+    let mut batch = Vec::new();
+    let mut applied = Vec::new();
+
+    for op in ops {
+        let current = match pending.get(&op.key) {
+            Some(v) => Some(v.clone()),
+            None => try!(local.get(&version_key(&op.key))).and_then(|v| Version::parse(&v))
+        };
+        let incoming = Version::of(&op);
+
+        if !wins(&op.key, &incoming, current.as_ref()) {
+            debug!("replication: ignoring stale write of `{}`", op.key);
+            continue;
+        }
+
+        batch.push(match op.value {
+            Some(ref value) => BatchOp::Put(op.key.clone(), value.clone()),
+            None => BatchOp::Del(op.key.clone())
+        });
+        batch.push(BatchOp::Put(version_key(&op.key), incoming.encode()));
+        pending.insert(op.key.clone(), incoming);
+        applied.push(op);
+    }
+    Ok((batch, applied))
+}
+
+pub fn sign(secret:&str, body:&str) -> String {
+    let mut mac = Hmac::new(Sha256::new(), secret.as_bytes());
+    mac.input(body.as_bytes());
+    mac.result().code().to_hex()
+}
+
+pub fn verify_signature(secret:&str, signature:&str, body:&str) -> bool {
+    match signature.from_hex() {
+        Ok(given) => !secret.is_empty()
+            && fixed_time_eq(&given, &sign(secret, body).from_hex().unwrap()),
+        Err(_) => false
+    }
+}
+
+pub fn decode_entries(body:&str) -> StoreResult<Vec<WalEntry>> {
+    json::decode(body).map_err(|e| StoreError::Decode(format!("replication entries: {}", e)))
+}
+
+
+struct Shared {
+    local: Mutex<Box<SessionStore>>,
+    // last written WAL sequence, shipper threads wait for it to change.
+    seq: Mutex<u64>,
+    changed: Condvar
+}
+
+pub struct ReplicatedStore {
+    shared: Arc<Shared>,
+    node: String,
+    // last version millis, never goes backward.
+    clock: Mutex<u64>
+}
+
+impl ReplicatedStore {
+    /**
+     * Wrap local storage engine and start shipping its WAL to the peers.
+     */
+    pub fn open(local:Box<SessionStore>, conf:&ReplicationConf) -> StoreResult<ReplicatedStore> {
+        if conf.node_id.is_empty() || !conf.node_id.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+            return Err(StoreError::Config("`replication.node_id` required, letters, digits, `-` and `_` only".to_string()));
+        }
+        if conf.secret.is_empty() {
+            return Err(StoreError::Config("`replication.secret` required".to_string()));
+        }
+
+        let seq = try!(local.get(SEQ_KEY)).and_then(|s| s.parse::<u64>().ok()).unwrap_or(0);
+
+        let store = ReplicatedStore {
+            shared: Arc::new(Shared {
+                local: Mutex::new(local),
+                seq: Mutex::new(seq),
+                changed: Condvar::new()
+            }),
+            node: conf.node_id.clone(),
+            clock: Mutex::new(0)
+        };
+
+        for peer in &conf.peers {
+            let shared = store.shared.clone();
+            let conf = conf.clone();
+            let peer = peer.trim_right_matches('/').to_string();
+            thread::spawn(move || ship(shared, conf, peer));
+        }
+
+        {
+            let shared = store.shared.clone();
+            let ttl_millis = conf.tombstone_ttl_secs * 1000;
+            thread::spawn(move || loop {
+                thread::sleep(Duration::from_secs(TOMBSTONE_GC_INTERVAL_SECS));
+                let local = shared.local.lock().unwrap();
+                if let Err(e) = collect_tombstones(&**local, ttl_millis) {
+                    error!("replication: tombstone cleanup failed: {}", e);
+                }
+            });
+        }
+
+        info!("Store replication enabled, node `{}`, peers: {:?}", conf.node_id, conf.peers);
+
+        Ok(store)
+    }
+
+    // next version millis, also moved forward by versions received from peers.
+    fn tick(&self) -> u64 {
+        let mut clock = self.clock.lock().unwrap();
+        let now = utils::current_time_millis();
+        *clock = if now > *clock { now } else { *clock + 1 };
+        *clock
+    }
+
+    fn observe(&self, millis:u64) {
+        let mut clock = self.clock.lock().unwrap();
+        if millis > *clock {
+            *clock = millis;
+        }
+    }
+}
+
+impl SessionStore for ReplicatedStore {
+
+    fn put(&self, key:&str, value:&str) -> StoreResult<()> {
+        self.write(vec![BatchOp::Put(key.to_string(), value.to_string())])
+    }
+
+    fn get(&self, key:&str) -> StoreResult<Option<String>> {
+        self.shared.local.lock().unwrap().get(key)
+    }
+
+    fn del(&self, key:&str) -> StoreResult<()> {
+        self.write(vec![BatchOp::Del(key.to_string())])
+    }
+
+    fn write(&self, ops:Vec<BatchOp>) -> StoreResult<()> {
+        let local = self.shared.local.lock().unwrap();
+
+        let ops = ops.into_iter().map(|op| {
+            let (key, value) = match op {
+                BatchOp::Put(key, value) => (key, Some(value)),
+                BatchOp::Del(key) => (key, None)
+            };
+            WalOp { key: key, value: value, millis: self.tick(), node: self.node.clone() }
+        }).collect();
+
+        let (mut batch, applied) = try!(resolve(&**local, ops));
+        if applied.is_empty() {
+            return Ok(());
+        }
+
+        let mut seq = self.shared.seq.lock().unwrap();
+        let entry = WalEntry { seq: *seq + 1, node: self.node.clone(), ops: applied };
+
+        batch.push(BatchOp::Put(wal_key(entry.seq), json::encode(&entry).unwrap()));
+        batch.push(BatchOp::Put(SEQ_KEY.to_string(), entry.seq.to_string()));
+        try!(local.write(batch));
+
+        *seq = entry.seq;
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    fn scan(&self, prefix:&str) -> StoreResult<Vec<(String, String)>> {
+        let entries = try!(self.shared.local.lock().unwrap().scan(prefix));
+        Ok(entries.into_iter().filter(|&(ref k, _)| !k.starts_with(META_PREFIX)).collect())
+    }
+
+    fn apply_remote(&self, entries:Vec<WalEntry>) -> StoreResult<usize> {
+        let local = self.shared.local.lock().unwrap();
+        let mut ops = Vec::new();
+
+        for entry in entries {
+            if entry.node == self.node {
+                continue;
+            }
+            for op in entry.ops {
+                self.observe(op.millis);
+                ops.push(op);
+            }
+        }
+
+        let (batch, applied) = try!(resolve(&**local, ops));
+        try!(local.write(batch));
+        Ok(applied.len())
+    }
+}
+
+// up to `limit` entries following `after`, read from there on instead of the whole log.
+fn read_wal(local:&SessionStore, after:u64, limit:usize) -> StoreResult<Vec<WalEntry>> {
+    let mut entries = Vec::new();
+    for (key, value) in try!(local.scan_range(WAL_PREFIX, &wal_key(after + 1), limit)) {
+        let entry:WalEntry = try!(json::decode(&value)
+            .map_err(|e| StoreError::Decode(format!("value of `{}`: {}", key, e))));
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn acked(local:&SessionStore, peer:&str) -> StoreResult<u64> {
+    Ok(try!(local.get(&ack_key(peer))).and_then(|s| s.parse::<u64>().ok()).unwrap_or(0))
+}
+
+// remove WAL entries every peer already received, and the ones beyond
+// last `max_entries` still kept for peers not receiving them.
+fn trim_wal(local:&SessionStore, peers:&Vec<String>, max_entries:u64) -> StoreResult<()> {
+    let last = try!(local.get(SEQ_KEY)).and_then(|s| s.parse::<u64>().ok()).unwrap_or(0);
+    let mut min = last;
+    for peer in peers {
+        let seq = try!(acked(local, peer.trim_right_matches('/')));
+        if seq < min {
+            min = seq;
+        }
+    }
+    let until = cmp::max(min, last.saturating_sub(max_entries));
+
+    loop {
+        let batch:Vec<BatchOp> = try!(local.scan_range(WAL_PREFIX, WAL_PREFIX, TRIM_BATCH)).into_iter()
+            .take_while(|&(ref key, _)| key[WAL_PREFIX.len()..].parse::<u64>().map(|seq| seq <= until).unwrap_or(false))
+            .map(|(key, _)| BatchOp::Del(key))
+            .collect();
+        let done = batch.len() < TRIM_BATCH;
+        if !batch.is_empty() {
+            try!(local.write(batch));
+        }
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+/**
+ * Forget deletions older than `ttl_millis`.
+ */
+fn collect_tombstones(local:&SessionStore, ttl_millis:u64) -> StoreResult<usize> {
+    let until = utils::current_time_millis().saturating_sub(ttl_millis);
+    let mut batch = Vec::new();
+
+    for (key, value) in try!(local.scan(VERSION_PREFIX)) {
+        match Version::parse(&value) {
+            Some(ref v) if v.deleted && v.millis < until => batch.push(BatchOp::Del(key)),
+            _ => ()
+        }
+    }
+
+    let count = batch.len();
+    if count > 0 {
+        try!(local.write(batch));
+        debug!("replication: {} tombstones removed", count);
+    }
+    Ok(count)
+}
+
+fn send(client:&Client, peer:&str, secret:&str, entries:&Vec<WalEntry>) -> Result<(), String> {
+    let body = json::encode(entries).unwrap();
+    let url = format!("{}{}", peer, APPLY_PATH);
+
+    let mut resp = try!(client.post(&url)
+        .header(ContentType::json())
+        .body(&body[..])
+        .headers({
+            let mut headers = ::nickel::hyper::header::Headers::new();
+            headers.set_raw(SIGNATURE_HEADER, vec![sign(secret, &body).into_bytes()]);
+            headers
+        })
+        .send()
+        .map_err(|e| format!("{}", e)));
+
+    let mut ignored = String::new();
+    let _ = resp.read_to_string(&mut ignored);
+
+    if resp.status.is_success() {
+        Ok(())
+    }else{
+        Err(format!("unexpected response status {}", resp.status))
+    }
+}
+
+// ship WAL to single peer, forever.
+fn ship(shared:Arc<Shared>, conf:ReplicationConf, peer:String) {
+    let mut client = Client::new();
+    client.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)));
+    client.set_write_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)));
+
+    let retry = Duration::from_millis(conf.retry_millis);
+    let mut failing = false;
+
+    loop {
+        let pending = {
+            let local = shared.local.lock().unwrap();
+            acked(&**local, &peer).and_then(|after| read_wal(&**local, after, MAX_ENTRIES_PER_REQUEST).map(|e| (after, e)))
+        };
+
+        let entries = match pending {
+            Ok((after, ref entries)) if entries.is_empty() => {
+                // nothing to send, wait for next write.
+                let seq = shared.seq.lock().unwrap();
+                if *seq <= after {
+                    let _ = shared.changed.wait_timeout(seq, Duration::from_secs(1));
+                }
+                continue;
+            },
+            Ok((after, entries)) => {
+                if entries[0].seq > after + 1 {
+                    error!("replication: peer {} missed entries {} to {} dropped after `wal_max_entries`, \
+                            restore it from a backup of this node", peer, after + 1, entries[0].seq - 1);
+                }
+                entries
+            },
+            Err(e) => {
+                error!("replication: cannot read WAL: {}", e);
+                thread::sleep(retry);
+                continue;
+            }
+        };
+
+        match send(&client, &peer, &conf.secret, &entries) {
+            Ok(_) => {
+                if failing {
+                    info!("replication: peer {} reachable again", peer);
+                    failing = false;
+                }
+                let last = entries.last().map(|e| e.seq).unwrap_or(0);
+                let local = shared.local.lock().unwrap();
+                let result = local.put(&ack_key(&peer), &last.to_string())
+                    .and_then(|_| trim_wal(&**local, &conf.peers, conf.wal_max_entries));
+                if let Err(e) = result {
+                    error!("replication: cannot record ack of {}: {}", peer, e);
+                }
+            },
+            Err(e) => {
+                if !failing {
+                    warn!("replication: cannot send to peer {}, will retry: {}", peer, e);
+                    failing = true;
+                }
+                // log of unreachable peer kept up to `wal_max_entries`.
+                if let Err(e) = trim_wal(&**shared.local.lock().unwrap(), &conf.peers, conf.wal_max_entries) {
+                    error!("replication: cannot trim WAL: {}", e);
+                }
+                thread::sleep(retry);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use config::ReplicationConf;
+    use utils;
+    use store::{MemoryStore, SessionStore};
+    use super::*;
+
+    fn op(key:&str, value:Option<&str>, millis:u64, node:&str) -> WalOp {
+        WalOp { key: key.to_string(), value: value.map(|v| v.to_string()), millis: millis, node: node.to_string() }
+    }
+
+    fn entry(ops:Vec<WalOp>) -> WalEntry {
+        WalEntry { seq: 1, node: "b".to_string(), ops: ops }
+    }
+
+    fn conf(node:&str, peers:Vec<String>) -> ReplicationConf {
+        ReplicationConf {
+            node_id: node.to_string(),
+            peers: peers,
+            secret: "s3cret".to_string(),
+            retry_millis: 50,
+            tombstone_ttl_secs: 3600,
+            wal_max_entries: 1000
+        }
+    }
+
+    #[test]
+    fn last_writer_wins() {
+        let store = ReplicatedStore::open(Box::new(MemoryStore::new()), &conf("a", vec![])).unwrap();
+
+        store.apply_remote(vec![entry(vec![op("user/robin", Some("v2"), 200, "b")])]).unwrap();
+        // older write arriving late ignored
+        store.apply_remote(vec![entry(vec![op("user/robin", Some("v1"), 100, "c")])]).unwrap();
+        assert_eq!(store.get("user/robin").unwrap(), Some("v2".to_string()));
+
+        // same millis, node id breaks the tie
+        store.apply_remote(vec![entry(vec![op("user/robin", Some("v3"), 200, "c")])]).unwrap();
+        assert_eq!(store.get("user/robin").unwrap(), Some("v3".to_string()));
+
+        // non bearer key can be written again after delete
+        store.apply_remote(vec![entry(vec![op("user/robin", None, 300, "b")])]).unwrap();
+        store.apply_remote(vec![entry(vec![op("user/robin", Some("v4"), 400, "b")])]).unwrap();
+        assert_eq!(store.get("user/robin").unwrap(), Some("v4".to_string()));
+
+        // local write gets newer version than anything seen
+        store.put("user/robin", "local").unwrap();
+        store.apply_remote(vec![entry(vec![op("user/robin", Some("v5"), 401, "z")])]).unwrap();
+        assert_eq!(store.get("user/robin").unwrap(), Some("local".to_string()));
+    }
+
+    #[test]
+    fn revocation_wins() {
+        let store = ReplicatedStore::open(Box::new(MemoryStore::new()), &conf("a", vec![])).unwrap();
+
+        store.put("token/t1", "robin").unwrap();
+        // revoked on other node, even with older clock
+        store.apply_remote(vec![entry(vec![op("token/t1", None, 1, "b")])]).unwrap();
+        assert_eq!(store.get("token/t1").unwrap(), None);
+
+        // concurrent newer write (eg: session save) doesn't bring it back
+        store.apply_remote(vec![entry(vec![op("token/t1", Some("robin"), utils::current_time_millis() + 3600 * 1000, "b")])]).unwrap();
+        store.put("token/t1", "robin").unwrap();
+        assert_eq!(store.get("token/t1").unwrap(), None);
+    }
+
+    #[test]
+    fn bookkeeping_hidden_and_gc() {
+        let store = ReplicatedStore::open(Box::new(MemoryStore::new()), &conf("a", vec![])).unwrap();
+        store.put("user/robin", "x").unwrap();
+        store.apply_remote(vec![entry(vec![op("token/old", None, 1, "b")])]).unwrap();
+
+        assert_eq!(store.scan("").unwrap(), vec![("user/robin".to_string(), "x".to_string())]);
+
+        let local = store.shared.local.lock().unwrap();
+        assert_eq!(collect_tombstones(&**local, 1000).unwrap(), 1);
+        assert_eq!(collect_tombstones(&**local, 1000).unwrap(), 0);
+    }
+
+    #[test]
+    fn signature() {
+        let sig = sign("s3cret", "[]");
+        assert!(verify_signature("s3cret", &sig, "[]"));
+        assert!(!verify_signature("s3cret", &sig, "[ ]"));
+        assert!(!verify_signature("other", &sig, "[]"));
+        assert!(!verify_signature("", &sign("", "[]"), "[]"));
+        assert!(!verify_signature("s3cret", "zz", "[]"));
+    }
+
+    #[test]
+    fn wal_read_from_position_and_capped() {
+        let store = ReplicatedStore::open(Box::new(MemoryStore::new()), &conf("a", vec![])).unwrap();
+        for i in 0..5 {
+            store.put("user/robin", &i.to_string()).unwrap();
+        }
+        let local = store.shared.local.lock().unwrap();
+        let seqs = |after, limit| read_wal(&**local, after, limit).unwrap().iter().map(|e| e.seq).collect::<Vec<u64>>();
+        assert_eq!(seqs(1, 2), vec![2, 3]);
+        assert_eq!(seqs(4, 10), vec![5]);
+
+        // peer that never received anything keeps only the last 3
+        let peers = vec!["http://dead".to_string()];
+        trim_wal(&**local, &peers, 3).unwrap();
+        assert_eq!(seqs(0, 10), vec![3, 4, 5]);
+
+        // acknowledged ones dropped
+        local.put(&ack_key("http://dead"), "4").unwrap();
+        trim_wal(&**local, &peers, 3).unwrap();
+        assert_eq!(seqs(0, 10), vec![5]);
+    }
+}
@@ -0,0 +1,187 @@
+
+use std::ffi::{CStr, CString};
+use std::ptr;
+use libc::{self, c_char, c_uchar};
+use rocksdb::{DB, Writable, WriteBatch, IteratorMode, Direction};
+
+use super::{SessionStore, BatchOp, StoreError, StoreResult};
+use super::replica::META_PREFIX;
+
+
+// Storage engine, backed by RocksDB
+pub struct RocksStore {
+    path:String,
+    db:DB
+}
+
+impl RocksStore {
+    pub fn open(path:&str) -> StoreResult<RocksStore> {
+        match DB::open_default(path) {
+            Ok(db) => Ok(RocksStore {
+                path: path.to_string(),
+                db: db
+            }),
+            // RocksDB hold `LOCK` file in the directory while opened.
+            Err(e) => if e.contains("LOCK") || e.contains("lock") {
+                Err(StoreError::Locked(format!("{}: {}", path, e)))
+            }else{
+                Err(StoreError::Backend(format!("Cannot open RocksDB at {}: {}", path, e)))
+            }
+        }
+    }
+}
+
+impl SessionStore for RocksStore {
+
+    fn put(&self, key:&str, value:&str) -> StoreResult<()> {
+        self.db.put(key.as_bytes(), value.as_bytes()).map_err(StoreError::Backend)
+    }
+
+    fn get(&self, key:&str) -> StoreResult<Option<String>> {
+        match self.db.get(key.as_bytes()){
+            Ok(Some(value)) => match value.to_utf8() {
+                Some(v) => Ok(Some(v.to_string())),
+                None => Err(StoreError::Decode(format!("value of `{}` is not valid utf-8", key)))
+            },
+            Ok(None) => Ok(None),
+            Err(e) => Err(StoreError::Backend(e))
+        }
+    }
+
+    fn del(&self, key:&str) -> StoreResult<()> {
+        self.db.delete(key.as_bytes()).map_err(StoreError::Backend)
+    }
+
+    fn write(&self, ops:Vec<BatchOp>) -> StoreResult<()> {
+        let wb = WriteBatch::default();
+        for op in ops {
+            match op {
+                BatchOp::Put(key, value) => try!(wb.put(key.as_bytes(), value.as_bytes()).map_err(StoreError::Backend)),
+                BatchOp::Del(key) => try!(wb.delete(key.as_bytes()).map_err(StoreError::Backend))
+            }
+        }
+        self.db.write(wb).map_err(StoreError::Backend)
+    }
+
+    fn scan(&self, prefix:&str) -> StoreResult<Vec<(String, String)>> {
+        self.scan_range(prefix, prefix, usize::max_value())
+    }
+
+    fn scan_range(&self, prefix:&str, from:&str, limit:usize) -> StoreResult<Vec<(String, String)>> {
+        let mut result = Vec::new();
+        let from = if from < prefix { prefix } else { from };
+        // keys are ordered, seek to the start and stop after passing the prefix range.
+        for (key, value) in self.db.iterator(IteratorMode::From(from.as_bytes(), Direction::forward)) {
+            if !key.starts_with(prefix.as_bytes()) || result.len() >= limit {
+                break;
+            }
+            let key = try!(String::from_utf8(key.to_vec())
+                .map_err(|_| StoreError::Decode("key is not valid utf-8".to_string())));
+            let value = try!(String::from_utf8(value.to_vec())
+                .map_err(|_| StoreError::Decode(format!("value of `{}` is not valid utf-8", key))));
+            result.push((key, value));
+        }
+        Ok(result)
+    }
+}
+
+
+// RocksDB backup engine, part of the C API of the linked library
+// but not wrapped by the `rocksdb` crate.
+#[allow(non_camel_case_types)] enum rocksdb_t {}
+#[allow(non_camel_case_types)] enum rocksdb_options_t {}
+#[allow(non_camel_case_types)] enum rocksdb_backup_engine_t {}
+#[allow(non_camel_case_types)] enum rocksdb_restore_options_t {}
+
+extern "C" {
+    fn rocksdb_options_create() -> *mut rocksdb_options_t;
+    fn rocksdb_options_destroy(options:*mut rocksdb_options_t);
+    fn rocksdb_options_set_create_if_missing(options:*mut rocksdb_options_t, value:c_uchar);
+    fn rocksdb_open(options:*const rocksdb_options_t, name:*const c_char, errptr:*mut *mut c_char) -> *mut rocksdb_t;
+    fn rocksdb_close(db:*mut rocksdb_t);
+    fn rocksdb_backup_engine_open(options:*const rocksdb_options_t, path:*const c_char,
+                                  errptr:*mut *mut c_char) -> *mut rocksdb_backup_engine_t;
+    fn rocksdb_backup_engine_create_new_backup(engine:*mut rocksdb_backup_engine_t, db:*mut rocksdb_t,
+                                               errptr:*mut *mut c_char);
+    fn rocksdb_backup_engine_close(engine:*mut rocksdb_backup_engine_t);
+    fn rocksdb_restore_options_create() -> *mut rocksdb_restore_options_t;
+    fn rocksdb_restore_options_destroy(options:*mut rocksdb_restore_options_t);
+    fn rocksdb_backup_engine_restore_db_from_latest_backup(engine:*mut rocksdb_backup_engine_t,
+        db_dir:*const c_char, wal_dir:*const c_char, options:*const rocksdb_restore_options_t,
+        errptr:*mut *mut c_char);
+}
+
+fn c_path(path:&str) -> StoreResult<CString> {
+    CString::new(path).map_err(|_| StoreError::Config(format!("invalid path `{}`", path)))
+}
+
+// error message set by the C API is malloc'ed, freed here.
+unsafe fn check(err:*mut c_char) -> StoreResult<()> {
+    if err.is_null() {
+        return Ok(());
+    }
+    let message = CStr::from_ptr(err).to_string_lossy().into_owned();
+    libc::free(err as *mut libc::c_void);
+    Err(StoreError::Backend(message))
+}
+
+/**
+ * Consistent copy of the database at `path` into new `backup_dir`, by RocksDB
+ * backup engine (live files as of single point in time, values as stored).
+ * Replication bookkeeping stays in the backup, `restore` drops it.
+ */
+pub fn backup(path:&str, backup_dir:&str) -> StoreResult<()> {
+    let (path_c, backup_c) = (try!(c_path(path)), try!(c_path(backup_dir)));
+    unsafe {
+        let options = rocksdb_options_create();
+        let mut err = ptr::null_mut();
+
+        let db = rocksdb_open(options, path_c.as_ptr(), &mut err);
+        let result = check(err).and_then(|_| {
+            let engine = rocksdb_backup_engine_open(options, backup_c.as_ptr(), &mut err);
+            try!(check(err));
+            rocksdb_backup_engine_create_new_backup(engine, db, &mut err);
+            rocksdb_backup_engine_close(engine);
+            check(err)
+        });
+
+        if !db.is_null() {
+            rocksdb_close(db);
+        }
+        rocksdb_options_destroy(options);
+        result
+    }
+}
+
+/**
+ * Replace the database at `path` with the latest backup in `backup_dir`,
+ * fails when the database is opened (eg: by running server).
+ * Replication bookkeeping (`_repl/`) of the backup is removed, peers resend what they have.
+ */
+pub fn restore(backup_dir:&str, path:&str) -> StoreResult<()> {
+    // holds `LOCK` while checking nobody else does.
+    drop(try!(RocksStore::open(path)));
+
+    let (path_c, backup_c) = (try!(c_path(path)), try!(c_path(backup_dir)));
+    unsafe {
+        let options = rocksdb_options_create();
+        rocksdb_options_set_create_if_missing(options, 0);
+        let mut err = ptr::null_mut();
+
+        let engine = rocksdb_backup_engine_open(options, backup_c.as_ptr(), &mut err);
+        let result = check(err).and_then(|_| {
+            let restore_options = rocksdb_restore_options_create();
+            rocksdb_backup_engine_restore_db_from_latest_backup(engine, path_c.as_ptr(), path_c.as_ptr(),
+                                                                 restore_options, &mut err);
+            rocksdb_restore_options_destroy(restore_options);
+            rocksdb_backup_engine_close(engine);
+            check(err)
+        });
+        rocksdb_options_destroy(options);
+        try!(result);
+    }
+
+    let restored = try!(RocksStore::open(path));
+    let meta = try!(restored.scan(META_PREFIX)).into_iter().map(|(key, _)| BatchOp::Del(key)).collect();
+    restored.write(meta)
+}
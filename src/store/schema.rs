@@ -0,0 +1,146 @@
+
+// Key schema of the store, every record lives under `<namespace>/<id>` key
+// and serialized as versioned JSON, eg: `{"v":1,"data":{...}}`,
+// so user named like a token can't collide with other records.
+
+use serialize::{json, Decodable, Encodable};
+
+use super::{StoreError, StoreResult};
+
+pub const SCHEMA_VERSION:u32 = 1;
+
+// holds schema version of the whole store, missing on legacy (unversioned) store.
+pub const SCHEMA_VERSION_KEY:&'static str = "meta/schema_version";
+
+// set once records encrypted at rest, so the store is never read without the key.
+pub const ENCRYPTED_KEY:&'static str = "meta/encrypted";
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Namespace {
+    // access token -> TokenRecord
+    Token,
+    // uid -> UserIndex, last token issued by password login
+    User,
+    // SSO session id -> session::Session
+    Session,
+    // uid -> registered security keys
+    Webauthn,
+    // challenge id -> pending WebAuthn ceremony
+    WebauthnChallenge,
+    // `<user|ip>/<id>` -> failed login attempts
+    Attempts,
+    // `<user|ip>/<id>` -> login lockout
+    Lockout,
+    // revoked token session id -> RevokedRecord, see `jwt`
+    Revoked,
+    // `<millis>-<random>` -> audit::Event
+    Audit
+}
+
+pub const NAMESPACES:[Namespace; 9] = [
+    Namespace::Token,
+    Namespace::User,
+    Namespace::Session,
+    Namespace::Webauthn,
+    Namespace::WebauthnChallenge,
+    Namespace::Attempts,
+    Namespace::Lockout,
+    Namespace::Revoked,
+    Namespace::Audit
+];
+
+impl Namespace {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Namespace::Token => "token",
+            Namespace::User => "user",
+            Namespace::Session => "session",
+            Namespace::Webauthn => "webauthn",
+            Namespace::WebauthnChallenge => "webauthn_challenge",
+            Namespace::Attempts => "attempts",
+            Namespace::Lockout => "lockout",
+            Namespace::Revoked => "revoked",
+            Namespace::Audit => "audit"
+        }
+    }
+
+    // id is bearer credential, hashed when encryption at rest enabled.
+    pub fn is_secret(&self) -> bool {
+        match *self {
+            Namespace::Token | Namespace::Session | Namespace::WebauthnChallenge => true,
+            _ => false
+        }
+    }
+
+    pub fn prefix(&self) -> String {
+        format!("{}/", self.name())
+    }
+
+    pub fn key(&self, id:&str) -> String {
+        format!("{}/{}", self.name(), id)
+    }
+}
+
+
+#[derive(Decodable, Encodable, Clone, Debug, PartialEq)]
+pub struct TokenRecord {
+    pub uid: String,
+    pub dn: String
+}
+
+#[derive(Decodable, Encodable, Clone, Debug, PartialEq)]
+pub struct UserIndex {
+    pub token: String
+}
+
+// failed login timestamps (millis) still in throttle window.
+#[derive(Decodable, Encodable, Clone, Debug, PartialEq)]
+pub struct AttemptsRecord {
+    pub timestamps: Vec<u64>
+}
+
+#[derive(Decodable, Encodable, Clone, Debug, PartialEq)]
+pub struct LockoutRecord {
+    pub until: u64
+}
+
+// signed tokens of the session rejected until `expires` (secs), when they expire anyway.
+#[derive(Decodable, Encodable, Clone, Debug, PartialEq)]
+pub struct RevokedRecord {
+    pub expires: u64
+}
+
+#[derive(Decodable, Encodable)]
+struct Versioned<T> {
+    v: u32,
+    data: T
+}
+
+
+pub fn encode<T:Encodable>(record:&T) -> String {
+    json::encode(&Versioned { v: SCHEMA_VERSION, data: record }).unwrap()
+}
+
+pub fn decode<T:Decodable>(key:&str, data:&str) -> StoreResult<T> {
+    let json = try!(json::Json::from_str(data)
+        .map_err(|e| StoreError::Decode(format!("value of `{}`: {}", key, e))));
+
+    match json.find("v").and_then(|v| v.as_u64()) {
+        Some(v) if v == SCHEMA_VERSION as u64 => (),
+        Some(v) => return Err(StoreError::Decode(format!("value of `{}` has unsupported schema version {}", key, v))),
+        None => return Err(StoreError::Decode(format!("value of `{}` is not versioned", key)))
+    }
+
+    json::decode::<Versioned<T>>(data)
+        .map(|versioned| versioned.data)
+        .map_err(|e| StoreError::Decode(format!("value of `{}`: {}", key, e)))
+}
+
+/**
+ * Wrap already JSON encoded record, used when migrating legacy values.
+ */
+pub fn wrap_json(data:&str) -> Option<String> {
+    json::Json::from_str(data).ok()
+        .map(|_| format!("{{\"v\":{},\"data\":{}}}", SCHEMA_VERSION, data))
+}
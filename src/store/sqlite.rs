@@ -0,0 +1,105 @@
+
+use rusqlite::{Connection, Error};
+
+use super::{SessionStore, BatchOp, StoreError, StoreResult};
+
+
+fn backend_error(e:Error) -> StoreError {
+    StoreError::Backend(format!("{}", e))
+}
+
+// Storage engine, backed by SQLite database file.
+pub struct SqliteStore {
+    path:String,
+    conn:Connection
+}
+
+impl SqliteStore {
+    pub fn open(path:&str) -> StoreResult<SqliteStore> {
+        let conn = try!(Connection::open(path)
+            .map_err(|e| StoreError::Backend(format!("Cannot open SQLite database at {}: {}", path, e))));
+
+        try!(conn.execute_batch("PRAGMA journal_mode = WAL;
+                                 CREATE TABLE IF NOT EXISTS kv (k TEXT PRIMARY KEY NOT NULL, v TEXT NOT NULL);")
+            .map_err(|e| if format!("{}", e).contains("locked") {
+                StoreError::Locked(format!("{}: {}", path, e))
+            }else{
+                StoreError::Backend(format!("{}", e))
+            }));
+
+        Ok(SqliteStore {
+            path: path.to_string(),
+            conn: conn
+        })
+    }
+
+    fn apply(&self, op:&BatchOp) -> StoreResult<()> {
+        let result = match *op {
+            BatchOp::Put(ref key, ref value) =>
+                self.conn.execute("INSERT OR REPLACE INTO kv (k, v) VALUES (?1, ?2)", &[key, value]),
+            BatchOp::Del(ref key) =>
+                self.conn.execute("DELETE FROM kv WHERE k = ?1", &[key])
+        };
+        result.map(|_| ()).map_err(backend_error)
+    }
+}
+
+impl SessionStore for SqliteStore {
+
+    fn put(&self, key:&str, value:&str) -> StoreResult<()> {
+        self.apply(&BatchOp::Put(key.to_string(), value.to_string()))
+    }
+
+    fn get(&self, key:&str) -> StoreResult<Option<String>> {
+        let mut stmt = try!(self.conn.prepare("SELECT v FROM kv WHERE k = ?1").map_err(backend_error));
+        let mut rows = try!(stmt.query(&[&key]).map_err(backend_error));
+        match rows.next() {
+            Some(Ok(row)) => row.get_checked(0).map(Some)
+                .map_err(|e| StoreError::Decode(format!("value of `{}`: {}", key, e))),
+            Some(Err(e)) => Err(backend_error(e)),
+            None => Ok(None)
+        }
+    }
+
+    fn del(&self, key:&str) -> StoreResult<()> {
+        self.apply(&BatchOp::Del(key.to_string()))
+    }
+
+    fn write(&self, ops:Vec<BatchOp>) -> StoreResult<()> {
+        try!(self.conn.execute_batch("BEGIN IMMEDIATE").map_err(backend_error));
+        for op in &ops {
+            if let Err(e) = self.apply(op) {
+                let _ = self.conn.execute_batch("ROLLBACK");
+                return Err(e);
+            }
+        }
+        self.conn.execute_batch("COMMIT").map_err(backend_error)
+    }
+
+    fn scan(&self, prefix:&str) -> StoreResult<Vec<(String, String)>> {
+        // substr instead of LIKE, so `%` and `_` in prefix are literal.
+        let mut stmt = try!(self.conn.prepare("SELECT k, v FROM kv WHERE substr(k, 1, length(?1)) = ?1 ORDER BY k")
+            .map_err(backend_error));
+        let rows = try!(stmt.query_map(&[&prefix], |row| (row.get(0), row.get(1))).map_err(backend_error));
+
+        let mut result = Vec::new();
+        for row in rows {
+            result.push(try!(row.map_err(backend_error)));
+        }
+        Ok(result)
+    }
+
+    fn scan_range(&self, prefix:&str, from:&str, limit:usize) -> StoreResult<Vec<(String, String)>> {
+        let limit = limit.min(i64::max_value() as usize) as i64;
+        let mut stmt = try!(self.conn.prepare(
+            "SELECT k, v FROM kv WHERE substr(k, 1, length(?1)) = ?1 AND k >= ?2 ORDER BY k LIMIT ?3")
+            .map_err(backend_error));
+        let rows = try!(stmt.query_map(&[&prefix, &from, &limit], |row| (row.get(0), row.get(1))).map_err(backend_error));
+
+        let mut result = Vec::new();
+        for row in rows {
+            result.push(try!(row.map_err(backend_error)));
+        }
+        Ok(result)
+    }
+}
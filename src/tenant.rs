@@ -0,0 +1,191 @@
+
+// Tenants: directories served by one SSO instance, each with its own LDAP
+// server, base DN, credentials, branding and continue url allow-list.
+//
+// Tenant selected by path prefix (`/acme/`, `/acme/login`) or request host,
+// otherwise the default one configured in `[ldap]` section. Tokens and sessions
+// keep the DN, tenant found again by it. DN not belonging to any tenant is rejected.
+
+use config::{Conf, LdapConf};
+use continue_url::AllowList;
+
+pub const DEFAULT_ID:&'static str = "default";
+
+// first path segments already used by other routes and static files.
+const RESERVED_PREFIXES:&'static [&'static str] = &["api", "login", "logout", "webauthn", "assets", "themes",
+                                                     "genPass", "_replication", ".well-known"];
+
+pub struct Tenant {
+    pub id:String,
+    pub hosts:Vec<String>,
+    pub path_prefix:String,
+    pub ldap:LdapConf,
+    pub login_caption:String,
+    pub logo:String,
+    pub continue_allow:AllowList,
+}
+
+impl Tenant {
+    pub fn base_dn(&self) -> &str {
+        &self.ldap.default_dn
+    }
+
+    pub fn owns_dn(&self, dn:&str) -> bool {
+        !dn.is_empty() && self.base_dn().eq_ignore_ascii_case(dn.trim())
+    }
+}
+
+/**
+ * Path prefix should be single segment, eg: `/acme`.
+ */
+pub fn check_path_prefix(prefix:&str) -> Result<(), String> {
+    let name = if prefix.starts_with('/') { &prefix[1..] } else { "" };
+    let valid = !name.is_empty() && name.chars().all(|c| match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '_' => true,
+        _ => false
+    });
+    if !valid {
+        return Err(format!("`{}` should be single path segment, eg: /acme", prefix));
+    }
+    if RESERVED_PREFIXES.contains(&name) {
+        return Err(format!("`{}` is already used by SSO", prefix));
+    }
+    Ok(())
+}
+
+/**
+ * Every configured tenant, the default one (from `[ldap]`) first when configured.
+ * Registered clients' continue urls allowed only for tenants listed in their `tenants`.
+ */
+pub fn from_conf(conf:&Conf) -> Result<Vec<Tenant>, String> {
+    let mut tenants = Vec::new();
+
+    if !conf.ldap.uri.is_empty() {
+        tenants.push(Tenant {
+            id: DEFAULT_ID.to_string(),
+            hosts: Vec::new(),
+            path_prefix: String::new(),
+            ldap: conf.ldap.clone(),
+            login_caption: conf.login_caption.clone(),
+            logo: String::new(),
+            continue_allow: try!(AllowList::from_conf(conf))
+        });
+    }
+
+    for tenant in &conf.tenants {
+        let mut urls = tenant.allowed_continue_urls.clone();
+        for client in conf.clients.iter().filter(|c| c.serves_tenant(&tenant.id)) {
+            urls.extend(client.continue_urls.iter().cloned());
+        }
+
+        tenants.push(Tenant {
+            id: tenant.id.clone(),
+            hosts: tenant.hosts.clone(),
+            path_prefix: tenant.path_prefix.clone(),
+            ldap: tenant.ldap.clone(),
+            login_caption: tenant.login_caption.clone(),
+            logo: tenant.logo.clone(),
+            continue_allow: try!(AllowList::new(&urls))
+        });
+    }
+    Ok(tenants)
+}
+
+/**
+ * Tenant for request: by first path segment of prefixed route, or by host
+ * (without port), the default one otherwise. Unknown prefix selects nothing.
+ */
+pub fn select<'a>(tenants:&'a [Tenant], host:Option<&str>, prefix:Option<&str>) -> Option<&'a Tenant> {
+    if let Some(prefix) = prefix {
+        return tenants.iter().find(|t| !t.path_prefix.is_empty() && t.path_prefix[1..] == *prefix);
+    }
+    let host = host.map(|h| h.to_lowercase());
+    if let Some(ref host) = host {
+        if let Some(tenant) = tenants.iter().find(|t| t.hosts.contains(host)) {
+            return Some(tenant);
+        }
+    }
+    tenants.iter().find(|t| t.id == DEFAULT_ID)
+}
+
+/**
+ * Tenant owning `dn`, for token and session already issued.
+ */
+pub fn by_dn<'a>(tenants:&'a [Tenant], dn:&str) -> Option<&'a Tenant> {
+    tenants.iter().find(|t| t.owns_dn(dn))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use config::Conf;
+    use super::*;
+
+    fn tenants() -> Vec<Tenant> {
+        let data = "login_caption = \"SSO\"\n\
+                    allowed_continue_urls = [\"https://app.example.com\"]\n\
+                    [ldap]\nuri = \"ldap://main\"\ndefault_dn = \"dc=example,dc=com\"\n\n\
+                    [tenants.acme]\nhosts = [\"SSO.acme.com\"]\npath_prefix = \"/acme\"\n\
+                    ldap_uri = \"ldap://acme\"\nbase_dn = \"dc=acme,dc=com\"\nlogin_caption = \"ACME\"\n\
+                    allowed_continue_urls = [\"https://*.acme.com\"]\n\n\
+                    [clients.shared]\nhost = \"shared.example.net\"\ncontinue_urls = [\"https://shared.example.net\"]\n\
+                    tenants = [\"default\", \"acme\"]\n\n\
+                    [clients.app]\nhost = \"app.example.org\"\ncontinue_urls = [\"https://app.example.org\"]\n\n\
+                    [clients.acme_app]\nhost = \"crm.acme.org\"\ncontinue_urls = [\"https://crm.acme.org\"]\n\
+                    tenants = [\"acme\"]\n";
+        from_conf(&Conf::read_str(data, &HashMap::new()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn selection() {
+        let tenants = tenants();
+        assert_eq!(tenants.len(), 2);
+
+        assert_eq!(select(&tenants, Some("sso.acme.com"), None).unwrap().id, "acme");
+        assert_eq!(select(&tenants, Some("sso.example.com"), Some("acme")).unwrap().id, "acme");
+        assert_eq!(select(&tenants, Some("sso.example.com"), None).unwrap().id, DEFAULT_ID);
+        assert_eq!(select(&tenants, None, None).unwrap().id, DEFAULT_ID);
+        assert!(select(&tenants, Some("sso.acme.com"), Some("other")).is_none());
+
+        let acme = select(&tenants, None, Some("acme")).unwrap();
+        assert_eq!(acme.login_caption, "ACME");
+        assert_eq!(acme.ldap.uri, "ldap://acme");
+    }
+
+    #[test]
+    fn by_base_dn() {
+        let tenants = tenants();
+        assert_eq!(by_dn(&tenants, "dc=acme,dc=com").unwrap().id, "acme");
+        assert_eq!(by_dn(&tenants, "DC=Example,DC=com").unwrap().id, DEFAULT_ID);
+        assert!(by_dn(&tenants, "dc=ansvia,dc=org").is_none());
+        assert!(by_dn(&tenants, "").is_none());
+    }
+
+    #[test]
+    fn continue_urls_per_tenant() {
+        let tenants = tenants();
+        let (default, acme) = (&tenants[0], &tenants[1]);
+
+        assert!(default.continue_allow.is_allowed("https://app.example.com/"));
+        assert!(!default.continue_allow.is_allowed("https://app.acme.com/"));
+        assert!(acme.continue_allow.is_allowed("https://app.acme.com/"));
+        assert!(!acme.continue_allow.is_allowed("https://app.example.com/"));
+        // registered clients only for their tenants, default one when not listed
+        assert!(default.continue_allow.is_allowed("https://shared.example.net/"));
+        assert!(acme.continue_allow.is_allowed("https://shared.example.net/"));
+        assert!(default.continue_allow.is_allowed("https://app.example.org/"));
+        assert!(!acme.continue_allow.is_allowed("https://app.example.org/"));
+        assert!(acme.continue_allow.is_allowed("https://crm.acme.org/"));
+        assert!(!default.continue_allow.is_allowed("https://crm.acme.org/"));
+    }
+
+    #[test]
+    fn path_prefixes() {
+        assert!(check_path_prefix("/acme").is_ok());
+        assert!(check_path_prefix("acme").is_err());
+        assert!(check_path_prefix("/acme/x").is_err());
+        assert!(check_path_prefix("/").is_err());
+        assert_eq!(check_path_prefix("/api").err().unwrap(), "`/api` is already used by SSO");
+    }
+}
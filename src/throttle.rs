@@ -0,0 +1,241 @@
+
+// Brute-force protection for login, failed attempts counted per username
+// and per client ip in sliding window, stored as list of timestamps.
+
+use config::ThrottleConf;
+use store::{Store, StoreResult, Namespace, AttemptsRecord, LockoutRecord};
+use utils;
+
+
+pub enum Status {
+    // request allowed, the attempt (its timestamp) already counts as failure, see `begin`.
+    Allowed { attempt: u64 },
+    // too soon after previous failure of the user, retry after given time millis.
+    // Not counted, so nobody waits on a worker thread for the progressive delay.
+    Delayed { until: u64 },
+    // locked until given time millis.
+    Locked { until: u64 }
+}
+
+// record id, eg: `user/robin`, `ip/127.0.0.1`.
+fn record_id(kind:&str, id:&str) -> String {
+    format!("{}/{}", kind, id)
+}
+
+// get failed attempts timestamps still in the window.
+fn attempts(store:&Store, conf:&ThrottleConf, kind:&str, id:&str, now:u64) -> StoreResult<Vec<u64>> {
+    let since = now.saturating_sub(conf.window_secs * 1000);
+    Ok(try!(store.get_record::<AttemptsRecord>(Namespace::Attempts, &record_id(kind, id)))
+        .map(|record| record.timestamps.into_iter().filter(|ts| *ts > since).collect())
+        .unwrap_or(Vec::new()))
+}
+
+fn locked_until(store:&Store, kind:&str, id:&str, now:u64) -> StoreResult<Option<u64>> {
+    match try!(store.get_record::<LockoutRecord>(Namespace::Lockout, &record_id(kind, id))) {
+        Some(LockoutRecord { until }) if until > now => Ok(Some(until)),
+        _ => Ok(None)
+    }
+}
+
+fn lock(store:&Store, conf:&ThrottleConf, kind:&str, id:&str, now:u64) -> StoreResult<u64> {
+    warn!("Too many failed login attempts for {} `{}`, locked for {} secs", kind, id, conf.lockout_secs);
+    let until = now + conf.lockout_secs * 1000;
+    try!(store.batch()
+        .put_record(Namespace::Lockout, &record_id(kind, id), &LockoutRecord { until: until })
+        .del_record(Namespace::Attempts, &record_id(kind, id))
+        .commit());
+    Ok(until)
+}
+
+/**
+ * Check whether login attempt for `user_name` from `ip` allowed. Allowed attempt
+ * is counted as failure right away, in the same store lock, so parallel attempts
+ * can't all pass before the first one fails. Call `record_success` or `release`
+ * once the outcome is known, failures need nothing more.
+ */
+pub fn begin(store:&Store, conf:&ThrottleConf, ip:&str, user_name:&str) -> StoreResult<Status> {
+    let now = utils::current_time_millis();
+
+    let until = match (try!(locked_until(store, "ip", ip, now)), try!(locked_until(store, "user", user_name, now))) {
+        (Some(a), Some(b)) => Some(if a > b { a } else { b }),
+        (a, b) => a.or(b)
+    };
+
+    if let Some(until) = until {
+        return Ok(Status::Locked { until: until });
+    }
+
+    let mut ip_attempts = try!(attempts(store, conf, "ip", ip, now));
+    let mut user_attempts = try!(attempts(store, conf, "user", user_name, now));
+
+    if conf.max_attempts_per_ip > 0 && ip_attempts.len() as u64 >= conf.max_attempts_per_ip {
+        return Ok(Status::Locked { until: try!(lock(store, conf, "ip", ip, now)) });
+    }
+    if !user_name.is_empty() && conf.max_attempts_per_user > 0 && user_attempts.len() as u64 >= conf.max_attempts_per_user {
+        return Ok(Status::Locked { until: try!(lock(store, conf, "user", user_name, now)) });
+    }
+
+    // progressive delay since the latest failure of the user
+    let delay = conf.delay_millis.saturating_mul(user_attempts.len() as u64);
+    let delay = if delay > conf.max_delay_millis { conf.max_delay_millis } else { delay };
+    if let Some(&latest) = user_attempts.iter().max() {
+        if delay > 0 && now < latest.saturating_add(delay) {
+            return Ok(Status::Delayed { until: latest + delay });
+        }
+    }
+
+    ip_attempts.push(now);
+    let mut batch = store.batch()
+        .put_record(Namespace::Attempts, &record_id("ip", ip), &AttemptsRecord { timestamps: ip_attempts });
+    if !user_name.is_empty() {
+        user_attempts.push(now);
+        batch = batch.put_record(Namespace::Attempts, &record_id("user", user_name), &AttemptsRecord { timestamps: user_attempts });
+    }
+    try!(batch.commit());
+
+    Ok(Status::Allowed { attempt: now })
+}
+
+// drop one counted attempt, others kept.
+fn forget(store:&Store, conf:&ThrottleConf, kind:&str, id:&str, attempt:u64) -> StoreResult<()> {
+    let mut ts = try!(attempts(store, conf, kind, id, utils::current_time_millis()));
+    match ts.iter().position(|t| *t == attempt) {
+        Some(i) => { ts.remove(i); },
+        None => return Ok(())
+    }
+    store.put_record(Namespace::Attempts, &record_id(kind, id), &AttemptsRecord { timestamps: ts })
+}
+
+/**
+ * Attempt that couldn't be checked (directory or store error) isn't user's failure.
+ */
+pub fn release(store:&Store, conf:&ThrottleConf, ip:&str, user_name:&str, attempt:u64) -> StoreResult<()> {
+    try!(forget(store, conf, "ip", ip, attempt));
+    forget(store, conf, "user", user_name, attempt)
+}
+
+fn record(store:&Store, conf:&ThrottleConf, kind:&str, id:&str, max_attempts:u64, now:u64) -> StoreResult<()> {
+    let mut ts = try!(attempts(store, conf, kind, id, now));
+    ts.push(now);
+
+    let count = ts.len() as u64;
+    try!(store.put_record(Namespace::Attempts, &record_id(kind, id), &AttemptsRecord { timestamps: ts }));
+
+    if max_attempts > 0 && count >= max_attempts {
+        lock(store, conf, kind, id, now).map(|_| ())
+    }else{
+        Ok(())
+    }
+}
+
+/**
+ * Record failed attempt not started with `begin` (second factor), may trigger lockout.
+ */
+pub fn record_failure(store:&Store, conf:&ThrottleConf, ip:&str, user_name:&str) -> StoreResult<()> {
+    let now = utils::current_time_millis();
+    try!(record(store, conf, "ip", ip, conf.max_attempts_per_ip, now));
+    if !user_name.is_empty() {
+        try!(record(store, conf, "user", user_name, conf.max_attempts_per_user, now));
+    }
+    Ok(())
+}
+
+/**
+ * Successful `attempt` reset username failure counter, earlier ip failures
+ * kept to slow down password spraying.
+ */
+pub fn record_success(store:&Store, conf:&ThrottleConf, ip:&str, user_name:&str, attempt:u64) -> StoreResult<()> {
+    try!(forget(store, conf, "ip", ip, attempt));
+    store.del_record(Namespace::Attempts, &record_id("user", user_name))
+}
+
+pub fn clear_user(store:&Store, user_name:&str) -> StoreResult<()> {
+    store.batch()
+        .del_record(Namespace::Attempts, &record_id("user", user_name))
+        .del_record(Namespace::Lockout, &record_id("user", user_name))
+        .commit()
+}
+
+pub fn clear_ip(store:&Store, ip:&str) -> StoreResult<()> {
+    store.batch()
+        .del_record(Namespace::Attempts, &record_id("ip", ip))
+        .del_record(Namespace::Lockout, &record_id("ip", ip))
+        .commit()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use config::ThrottleConf;
+    use store::{Store, MemoryStore};
+    use super::*;
+
+    fn conf() -> ThrottleConf {
+        ThrottleConf { delay_millis: 0, ..Default::default() }
+    }
+
+    fn allowed(status:StoreResult<Status>) -> Option<u64> {
+        match status.unwrap() {
+            Status::Allowed { attempt } => Some(attempt),
+            Status::Delayed { .. } | Status::Locked { .. } => None
+        }
+    }
+
+    #[test]
+    fn lockout_after_failures() {
+        let store = Store::with_backend(Box::new(MemoryStore::new()));
+        for _ in 0..5 {
+            assert!(allowed(begin(&store, &conf(), "10.0.0.1", "robin")).is_some());
+        }
+        assert!(allowed(begin(&store, &conf(), "10.0.0.1", "robin")).is_none());
+        assert!(allowed(begin(&store, &conf(), "10.0.0.2", "robin")).is_none());
+        assert!(allowed(begin(&store, &conf(), "10.0.0.1", "alice")).is_some());
+    }
+
+    #[test]
+    fn success_and_release_undo_attempt() {
+        let store = Store::with_backend(Box::new(MemoryStore::new()));
+        let conf = ThrottleConf { max_attempts_per_ip: 2, ..conf() };
+        for _ in 0..5 {
+            let attempt = allowed(begin(&store, &conf, "10.0.0.1", "robin")).unwrap();
+            record_success(&store, &conf, "10.0.0.1", "robin", attempt).unwrap();
+            let attempt = allowed(begin(&store, &conf, "10.0.0.1", "robin")).unwrap();
+            release(&store, &conf, "10.0.0.1", "robin", attempt).unwrap();
+        }
+        assert!(store.get_record::<AttemptsRecord>(Namespace::Attempts, "user/robin").unwrap().unwrap().timestamps.is_empty());
+    }
+
+    #[test]
+    fn delay_after_failure() {
+        let store = Store::with_backend(Box::new(MemoryStore::new()));
+        let conf = ThrottleConf { delay_millis: 60000, max_delay_millis: 90000, ..Default::default() };
+        assert!(allowed(begin(&store, &conf, "10.0.0.1", "robin")).is_some());
+        let now = utils::current_time_millis();
+        match begin(&store, &conf, "10.0.0.2", "robin").unwrap() {
+            Status::Delayed { until } => assert!(until > now + 59000 && until <= now + 60000),
+            _ => panic!("should be delayed")
+        }
+        // delayed attempt not counted, other users not delayed
+        assert_eq!(store.get_record::<AttemptsRecord>(Namespace::Attempts, "user/robin").unwrap().unwrap().timestamps.len(), 1);
+        assert!(allowed(begin(&store, &conf, "10.0.0.1", "alice")).is_some());
+    }
+
+    #[test]
+    fn concurrent_attempts_locked() {
+        let store = Arc::new(Mutex::new(Store::with_backend(Box::new(MemoryStore::new()))));
+        let threads:Vec<_> = (0..20).map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                let status = begin(&store.lock().unwrap(), &conf(), "10.0.0.1", "robin");
+                allowed(status).is_some()
+            })
+        }).collect();
+
+        let passed = threads.into_iter().map(|t| t.join().unwrap()).filter(|ok| *ok).count();
+        assert_eq!(passed, 5);
+        assert!(allowed(begin(&store.lock().unwrap(), &conf(), "10.0.0.1", "robin")).is_none());
+    }
+}
@@ -0,0 +1,68 @@
+// Native HTTPS for `http.https_listen`.
+//
+// Served by hyper's own `Openssl` (the `ssl` feature nickel's hyper 0.9 is built
+// with), so `openssl` has to stay at the version that hyper pins (0.7).
+//
+// Certificate and key are read again on SIGHUP, new connections use the new
+// ones, so renewed certificate doesn't need restart.
+
+use std::sync::{Arc, RwLock};
+
+use nickel::hyper;
+use nickel::hyper::net::{HttpStream, Openssl, SslServer};
+use openssl::ssl::{SslContext, SslMethod, SSL_OP_NO_SSLV2, SSL_OP_NO_SSLV3};
+use openssl::x509::X509FileType;
+
+const CIPHERS:&'static str = "HIGH:!aNULL:!eNULL:!MD5:!RC4:!3DES";
+
+#[derive(Clone)]
+pub struct TlsServer {
+    cert:String,
+    key:String,
+    current:Arc<RwLock<Openssl>>
+}
+
+fn acceptor(cert:&str, key:&str) -> Result<Openssl, String> {
+    let mut ctx = try!(SslContext::new(SslMethod::Sslv23)
+        .map_err(|e| format!("cannot initialize TLS: {}", e)));
+    ctx.set_options(SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3);
+    try!(ctx.set_cipher_list(CIPHERS)
+        .map_err(|e| format!("cannot set TLS ciphers: {}", e)));
+    try!(ctx.set_certificate_chain_file(cert, X509FileType::PEM)
+        .map_err(|e| format!("cannot load certificate `{}`: {}", cert, e)));
+    try!(ctx.set_private_key_file(key, X509FileType::PEM)
+        .map_err(|e| format!("cannot load private key `{}`: {}", key, e)));
+    try!(ctx.check_private_key()
+        .map_err(|e| format!("private key `{}` doesn't match certificate `{}`: {}", key, cert, e)));
+    Ok(Openssl { context: Arc::new(ctx) })
+}
+
+impl TlsServer {
+    pub fn load(cert:&str, key:&str) -> Result<TlsServer, String> {
+        let current = try!(acceptor(cert, key));
+        Ok(TlsServer {
+            cert: cert.to_string(),
+            key: key.to_string(),
+            current: Arc::new(RwLock::new(current))
+        })
+    }
+
+    /**
+     * Read certificate and key again, current ones kept when failed.
+     */
+    pub fn reload(&self) -> Result<(), String> {
+        let next = try!(acceptor(&self.cert, &self.key));
+        *self.current.write().unwrap() = next;
+        Ok(())
+    }
+}
+
+impl SslServer for TlsServer {
+    type Stream = <Openssl as SslServer>::Stream;
+
+    fn wrap_server(&self, stream:HttpStream) -> hyper::Result<Self::Stream> {
+        // handshake without holding the lock
+        let current = self.current.read().unwrap().clone();
+        current.wrap_server(stream)
+    }
+}
@@ -1,11 +1,230 @@
 
+// Opaque bearer tokens: random bytes from OS CSPRNG, base64url or hex encoded.
+//
+// With `token.prefixed` enabled token looks like `sso_at_<random><checksum>`,
+// the prefix tells the kind and CRC32 checksum (6 base62 chars) lets secret
+// scanners recognize leaked token without asking the server. With signed access
+// tokens the opaque one is the refresh handle (`sso_rt_`), see `jwt`.
 
-use rand;
-use rand::Rng;
+use rand::{Rng, OsRng};
+use crypto::util::fixed_time_eq;
+use serialize::base64::{self, ToBase64};
+use serialize::hex::ToHex;
 
-pub fn generate() -> String {
-    rand::thread_rng()
-        .gen_ascii_chars()
-        .take(50)
-        .collect()
+use config::TokenConf;
+
+pub const MIN_BYTES:u64 = 16;
+pub const MAX_BYTES:u64 = 128;
+
+const CHECKSUM_LEN:usize = 6;
+const BASE62:&'static [u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenKind {
+    Access,
+    Refresh
+}
+
+impl TokenKind {
+    pub fn prefix(&self) -> &'static str {
+        match *self {
+            TokenKind::Access => "sso_at_",
+            TokenKind::Refresh => "sso_rt_"
+        }
+    }
+
+    /**
+     * Kind of opaque token issued with the config.
+     */
+    pub fn issued(conf:&TokenConf) -> TokenKind {
+        if conf.signed() { TokenKind::Refresh } else { TokenKind::Access }
+    }
+}
+
+const KINDS:[TokenKind; 2] = [TokenKind::Access, TokenKind::Refresh];
+
+/**
+ * Check `[token]` config, returns offending key and description.
+ */
+pub fn check_conf(conf:&TokenConf) -> Result<(), (&'static str, String)> {
+    if conf.bytes < MIN_BYTES || conf.bytes > MAX_BYTES {
+        return Err(("bytes", format!("should be between {} and {}", MIN_BYTES, MAX_BYTES)));
+    }
+    match conf.encoding.as_ref() {
+        "base64url" | "hex" => (),
+        other => return Err(("encoding", format!("unknown encoding `{}`, supported: base64url, hex", other)))
+    }
+    match conf.format.as_ref() {
+        "opaque" | "jwt" => Ok(()),
+        other => Err(("format", format!("unknown format `{}`, supported: opaque, jwt", other)))
+    }
+}
+
+/**
+ * Generate new token, of the kind issued with the config.
+ */
+pub fn generate(conf:&TokenConf) -> String {
+    let mut buf = vec![0u8; conf.bytes as usize];
+    OsRng::new().expect("Cannot access OS random source").fill_bytes(&mut buf);
+
+    let body = match conf.encoding.as_ref() {
+        "hex" => buf.to_hex(),
+        _ => buf.to_base64(base64::URL_SAFE)
+    };
+
+    if !conf.prefixed {
+        return body;
+    }
+    let token = format!("{}{}", TokenKind::issued(conf).prefix(), body);
+    let sum = checksum(&token);
+    token + &sum
+}
+
+// CRC32 (IEEE) of prefix and random part, base62, zero padded.
+fn checksum(data:&str) -> String {
+    let mut crc:u32 = 0xffffffff;
+    for b in data.bytes() {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    let mut n = !crc as u64;
+
+    let mut out = [b'0'; CHECKSUM_LEN];
+    for i in (0..CHECKSUM_LEN).rev() {
+        out[i] = BASE62[(n % 62) as usize];
+        n /= 62;
+    }
+    String::from_utf8(out.to_vec()).unwrap()
+}
+
+/**
+ * Kind of prefixed token, None for unprefixed one.
+ */
+pub fn kind_of(token:&str) -> Option<TokenKind> {
+    KINDS.iter().find(|k| token.starts_with(k.prefix())).map(|k| *k)
+}
+
+/**
+ * Cheap check before store lookup: allowed characters and length,
+ * prefixed token should have valid checksum.
+ * Tokens issued before prefixing was enabled still accepted.
+ */
+pub fn is_well_formed(token:&str) -> bool {
+    let allowed = |b:u8| match b {
+        b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'-' | b'_' => true,
+        _ => false
+    };
+    if token.is_empty() || token.len() > 512 || !token.bytes().all(allowed) {
+        return false;
+    }
+    match kind_of(token) {
+        Some(kind) if token.len() > kind.prefix().len() + CHECKSUM_LEN => {
+            let (data, sum) = token.split_at(token.len() - CHECKSUM_LEN);
+            eq(&checksum(data), sum)
+        },
+        Some(_) => false,
+        None => true
+    }
+}
+
+/**
+ * Compare tokens in constant time.
+ */
+pub fn eq(a:&str, b:&str) -> bool {
+    a.len() == b.len() && fixed_time_eq(a.as_bytes(), b.as_bytes())
+}
+
+/**
+ * Short form safe to write into logs, eg: `sso_at_Xk3v…`.
+ */
+pub fn redact(token:&str) -> String {
+    let keep = kind_of(token).map(|k| k.prefix().len()).unwrap_or(0) + 4;
+    match token.char_indices().nth(keep) {
+        Some((idx, _)) => format!("{}…", &token[..idx]),
+        None => "…".to_string()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use config::TokenConf;
+    use super::*;
+
+    fn conf(bytes:u64, encoding:&str, prefixed:bool) -> TokenConf {
+        TokenConf { bytes: bytes, encoding: encoding.to_string(), prefixed: prefixed, ..Default::default() }
+    }
+
+    #[test]
+    fn plain_tokens() {
+        let t = generate(&conf(32, "base64url", false));
+        assert_eq!(t.len(), 43);
+        assert!(is_well_formed(&t));
+        assert!(t != generate(&conf(32, "base64url", false)));
+
+        let t = generate(&conf(16, "hex", false));
+        assert_eq!(t.len(), 32);
+        assert!(t.chars().all(|c| c.is_digit(16)));
+    }
+
+    #[test]
+    fn prefixed_tokens() {
+        let mut c = conf(32, "base64url", true);
+        let at = generate(&c);
+        // opaque token only refreshes signed access token
+        c.format = "jwt".to_string();
+        let rt = generate(&c);
+
+        assert!(at.starts_with("sso_at_"));
+        assert!(rt.starts_with("sso_rt_"));
+        assert_eq!(at.len(), 7 + 43 + 6);
+        assert_eq!(kind_of(&at), Some(TokenKind::Access));
+        assert_eq!(kind_of(&rt), Some(TokenKind::Refresh));
+        assert!(is_well_formed(&at));
+        assert!(is_well_formed(&rt));
+
+        // single character changed
+        let mut bad = at.clone().into_bytes();
+        bad[10] = if bad[10] == b'a' { b'b' } else { b'a' };
+        assert!(!is_well_formed(&String::from_utf8(bad).unwrap()));
+        // prefix swapped
+        assert!(!is_well_formed(&at.replace("sso_at_", "sso_rt_")));
+        assert!(!is_well_formed("sso_at_abc"));
+    }
+
+    #[test]
+    fn known_checksum() {
+        // CRC32 of "123456789" is 0xcbf43926
+        assert_eq!(checksum("123456789"), "3jZRME");
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(!is_well_formed(""));
+        assert!(!is_well_formed("abc def"));
+        assert!(!is_well_formed("abc/../def"));
+        // legacy 50 alphanumeric token
+        assert!(is_well_formed("aBcDeFgHiJkLmNoPqRsTuVwXyZ0123456789aBcDeFgHiJkLmN"));
+    }
+
+    #[test]
+    fn compare_and_redact() {
+        assert!(eq("sso_at_abc", "sso_at_abc"));
+        assert!(!eq("sso_at_abc", "sso_at_abd"));
+        assert!(!eq("sso_at_abc", "sso_at_ab"));
+
+        assert_eq!(redact("sso_at_Xk3vQQQQ"), "sso_at_Xk3v…");
+        assert_eq!(redact("sso_rt_Xk3vQQQQ"), "sso_rt_Xk3v…");
+        assert_eq!(redact("abcdefgh"), "abcd…");
+        assert_eq!(redact("abc"), "…");
+    }
+
+    #[test]
+    fn conf_checked() {
+        assert!(check_conf(&conf(32, "base64url", true)).is_ok());
+        assert!(check_conf(&conf(8, "base64url", true)).is_err());
+        assert!(check_conf(&conf(32, "base32", true)).is_err());
+    }
 }
@@ -1,6 +1,10 @@
 
 
 use url::percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET};
+use url::form_urlencoded;
+use std::collections::HashMap;
+use std::str;
+use nickel::hyper::header::{Headers, Host};
 use time;
 
 
@@ -21,3 +25,76 @@ pub fn current_time_millis() -> u64 {
     let ts = time::get_time();
     ( (ts.sec * 1000) as f64 + (ts.nsec as f64 / 1000.0 / 1000.0) ) as u64
 }
+
+
+/**
+ * Parse `application/x-www-form-urlencoded` body into map.
+ */
+pub fn parse_form(body:&str) -> HashMap<String, String> {
+    form_urlencoded::parse(body.as_bytes()).into_owned().collect()
+}
+
+
+/**
+ * Get cookie value by name from request headers.
+ */
+pub fn get_cookie(headers:&Headers, name:&str) -> Option<String> {
+    let raw = match headers.get_raw("Cookie") {
+        Some(raw) => raw,
+        None => return None
+    };
+    for line in raw {
+        let line = match str::from_utf8(line) {
+            Ok(l) => l,
+            Err(_) => continue
+        };
+        for pair in line.split(';') {
+            let mut kv = pair.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k.trim() == name => return Some(v.trim().to_string()),
+                _ => ()
+            }
+        }
+    }
+    None
+}
+
+/**
+ * Append `Set-Cookie` header, keep previously set cookies.
+ */
+pub fn set_cookie(headers:&mut Headers, cookie:String) {
+    let mut cookies:Vec<Vec<u8>> = headers.get_raw("Set-Cookie").map(|v| v.to_vec()).unwrap_or(Vec::new());
+    cookies.push(cookie.into_bytes());
+    headers.set_raw("Set-Cookie", cookies);
+}
+
+/**
+ * Host name of request from `Host` header, lowercase and without port.
+ */
+pub fn request_host(headers:&Headers) -> Option<String> {
+    headers.get::<Host>().map(|host| host.hostname.to_lowercase())
+}
+
+pub const REQUEST_ID_HEADER:&'static str = "X-Request-Id";
+
+/**
+ * Request ID from reverse proxy is kept when it looks sane, eg: UUID.
+ */
+pub fn valid_request_id(id:&str) -> bool {
+    !id.is_empty() && id.len() <= 64
+        && id.chars().all(|c| match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '_' | '.' => true,
+            _ => false
+        })
+}
+
+/**
+ * ID of request set by `RequestId` middleware, for logs and API responses.
+ */
+pub fn request_id(headers:&Headers) -> String {
+    headers.get_raw(REQUEST_ID_HEADER)
+        .and_then(|v| v.first())
+        .and_then(|v| str::from_utf8(v).ok())
+        .unwrap_or("")
+        .to_string()
+}
@@ -0,0 +1,612 @@
+
+// WebAuthn (FIDO2) second factor support.
+//
+// Only the EdDSA (Ed25519, COSE alg -8) credential algorithm is supported
+// since that is the only signature scheme provided by our crypto backend,
+// supported attestation formats are "none" and self "packed".
+
+use rand::{Rng, OsRng};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use crypto::ed25519;
+use serialize::base64::{self, ToBase64, FromBase64};
+use serialize::json::Json;
+
+use cbor;
+use config::WebauthnConf;
+use store::{Store, StoreResult, Namespace};
+use utils;
+
+// COSE constants
+const COSE_KTY_OKP:i64 = 1;
+const COSE_ALG_EDDSA:i64 = -8;
+const COSE_CRV_ED25519:i64 = 6;
+
+// authenticator data flags
+const FLAG_USER_PRESENT:u8 = 0x01;
+const FLAG_ATTESTED_CRED_DATA:u8 = 0x40;
+
+// how long a challenge stay valid.
+pub const CHALLENGE_TIMEOUT_MILLIS:u64 = 5 * 60 * 1000;
+
+
+#[derive(Decodable, Encodable, Clone, Debug)]
+pub struct Credential {
+    pub id: String,         // base64url credential id
+    pub public_key: String, // base64url raw Ed25519 public key
+    pub sign_count: u32,
+    pub created: u64
+}
+
+// Ceremony the challenge was issued for, one can't be answered on the other's endpoint.
+#[derive(Decodable, Encodable, Clone, Copy, Debug, PartialEq)]
+pub enum ChallengeKind {
+    Registration,
+    Login
+}
+
+// Challenge waiting for client response, for both registration and assertion.
+#[derive(Decodable, Encodable, Clone, Debug)]
+pub struct PendingChallenge {
+    pub kind: ChallengeKind,
+    pub challenge: String,
+    pub uid: String,
+    pub dn: String,
+    pub cont: String,
+    pub created: u64
+}
+
+impl PendingChallenge {
+    pub fn new(kind:ChallengeKind, uid:&str, dn:&str, cont:&str) -> Self {
+        PendingChallenge {
+            kind: kind,
+            challenge: new_challenge(),
+            uid: uid.to_string(),
+            dn: dn.to_string(),
+            cont: cont.to_string(),
+            created: utils::current_time_millis()
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        utils::current_time_millis() > self.created + CHALLENGE_TIMEOUT_MILLIS
+    }
+}
+
+pub fn b64url(data:&[u8]) -> String {
+    data.to_base64(base64::URL_SAFE)
+}
+
+/**
+ * Generate random 32 bytes challenge, base64url encoded.
+ */
+pub fn new_challenge() -> String {
+    let mut buf = [0u8; 32];
+    OsRng::new().expect("Cannot access OS random source").fill_bytes(&mut buf);
+    b64url(&buf)
+}
+
+fn sha256(data:&[u8]) -> [u8; 32] {
+    let mut sha = Sha256::new();
+    sha.input(data);
+    let mut out = [0u8; 32];
+    sha.result(&mut out);
+    out
+}
+
+// ---- store ----
+
+pub fn load_credentials(store:&Store, uid:&str) -> StoreResult<Vec<Credential>> {
+    // undecodable record is an error, empty list would silently skip the second factor.
+    store.get_record(Namespace::Webauthn, uid).map(|creds| creds.unwrap_or(Vec::new()))
+}
+
+pub fn save_credentials(store:&Store, uid:&str, creds:&Vec<Credential>) -> StoreResult<()> {
+    store.put_record(Namespace::Webauthn, uid, creds)
+}
+
+/**
+ * Save pending challenge and return it's id.
+ */
+pub fn put_challenge(store:&Store, pending:&PendingChallenge) -> StoreResult<String> {
+    let id = new_challenge();
+    try!(store.put_record(Namespace::WebauthnChallenge, &id, pending));
+    Ok(id)
+}
+
+/**
+ * Take (get and remove) pending challenge of `kind`, so every challenge only usable once.
+ * Challenge of another ceremony is removed too.
+ */
+pub fn take_challenge(store:&Store, id:&str, kind:ChallengeKind) -> StoreResult<Option<PendingChallenge>> {
+    let pending = try!(store.get_record::<PendingChallenge>(Namespace::WebauthnChallenge, id));
+    try!(store.del_record(Namespace::WebauthnChallenge, id));
+    match pending {
+        Some(ref p) if p.is_expired() || p.kind != kind => Ok(None),
+        p => Ok(p)
+    }
+}
+
+/**
+ * Remove challenges never answered, returns how many.
+ */
+pub fn purge_challenges(store:&Store) -> StoreResult<usize> {
+    let mut batch = store.batch();
+    let mut count = 0;
+
+    for (key, pending) in try!(store.scan_records::<PendingChallenge>(Namespace::WebauthnChallenge)) {
+        if pending.is_expired() {
+            batch = batch.del(&key);
+            count += 1;
+        }
+    }
+    if count > 0 {
+        try!(batch.commit());
+    }
+    Ok(count)
+}
+
+// ---- ceremony options (sent to browser) ----
+
+pub fn creation_options(conf:&WebauthnConf, pending:&PendingChallenge, existing:&Vec<Credential>) -> String {
+    let exclude:Vec<String> = existing.iter()
+        .map(|c| format!(r#"{{"type":"public-key","id":{}}}"#, Json::String(c.id.clone())))
+        .collect();
+
+    format!(r#"{{"challenge":{},"rp":{{"id":{},"name":{}}},"user":{{"id":{},"name":{},"displayName":{}}},"pubKeyCredParams":[{{"type":"public-key","alg":{}}}],"timeout":{},"attestation":"none","excludeCredentials":[{}]}}"#,
+        Json::String(pending.challenge.clone()),
+        Json::String(conf.rp_id.clone()),
+        Json::String(conf.rp_name.clone()),
+        Json::String(b64url(pending.uid.as_bytes())),
+        Json::String(pending.uid.clone()),
+        Json::String(pending.uid.clone()),
+        COSE_ALG_EDDSA,
+        CHALLENGE_TIMEOUT_MILLIS,
+        exclude.join(","))
+}
+
+pub fn request_options(conf:&WebauthnConf, pending:&PendingChallenge, creds:&Vec<Credential>) -> String {
+    let allow:Vec<String> = creds.iter()
+        .map(|c| format!(r#"{{"type":"public-key","id":{}}}"#, Json::String(c.id.clone())))
+        .collect();
+
+    format!(r#"{{"challenge":{},"rpId":{},"timeout":{},"userVerification":"discouraged","allowCredentials":[{}]}}"#,
+        Json::String(pending.challenge.clone()),
+        Json::String(conf.rp_id.clone()),
+        CHALLENGE_TIMEOUT_MILLIS,
+        allow.join(","))
+}
+
+// ---- verification ----
+
+struct AuthData {
+    rp_id_hash: Vec<u8>,
+    flags: u8,
+    sign_count: u32,
+    credential_id: Vec<u8>,
+    public_key: Option<cbor::Value>
+}
+
+fn parse_auth_data(data:&[u8]) -> Result<AuthData, String> {
+    if data.len() < 37 {
+        return Err("Authenticator data too short".to_string());
+    }
+    let flags = data[32];
+    let sign_count = ((data[33] as u32) << 24) | ((data[34] as u32) << 16)
+        | ((data[35] as u32) << 8) | (data[36] as u32);
+
+    let mut auth_data = AuthData {
+        rp_id_hash: data[..32].to_vec(),
+        flags: flags,
+        sign_count: sign_count,
+        credential_id: Vec::new(),
+        public_key: None
+    };
+
+    if flags & FLAG_ATTESTED_CRED_DATA != 0 {
+        // aaguid (16) + credential id length (2)
+        if data.len() < 55 {
+            return Err("Attested credential data too short".to_string());
+        }
+        let cred_len = ((data[53] as usize) << 8) | (data[54] as usize);
+        if data.len() < 55 + cred_len {
+            return Err("Credential id truncated".to_string());
+        }
+        auth_data.credential_id = data[55..55 + cred_len].to_vec();
+        let (key, _) = try!(cbor::decode(&data[55 + cred_len..]));
+        auth_data.public_key = Some(key);
+    }
+
+    Ok(auth_data)
+}
+
+fn check_client_data(conf:&WebauthnConf, client_data_json:&[u8], expected_type:&str, challenge:&str) -> Result<(), String> {
+    let text = try!(::std::str::from_utf8(client_data_json).map_err(|_| "Invalid client data encoding".to_string()));
+    let client_data = try!(Json::from_str(text).map_err(|_| "Invalid client data".to_string()));
+
+    let field = |name:&str| client_data.find(name).and_then(|v| v.as_string()).unwrap_or("").to_string();
+
+    if field("type") != expected_type {
+        return Err(format!("Unexpected client data type, expecting `{}`", expected_type));
+    }
+    // browser may or may not include padding, compare decoded bytes instead.
+    let given = field("challenge").from_base64().unwrap_or(Vec::new());
+    if given.is_empty() || given != challenge.from_base64().unwrap_or(Vec::new()) {
+        return Err("Challenge mismatch".to_string());
+    }
+    if field("origin") != conf.origin {
+        return Err(format!("Origin mismatch: {}", field("origin")));
+    }
+    Ok(())
+}
+
+fn check_auth_data(conf:&WebauthnConf, auth_data:&AuthData) -> Result<(), String> {
+    if auth_data.rp_id_hash[..] != sha256(conf.rp_id.as_bytes())[..] {
+        return Err("RP ID hash mismatch".to_string());
+    }
+    if auth_data.flags & FLAG_USER_PRESENT == 0 {
+        return Err("User not present".to_string());
+    }
+    Ok(())
+}
+
+fn cose_to_ed25519(key:&cbor::Value) -> Result<Vec<u8>, String> {
+    let kty = key.get_int(1).and_then(|v| v.as_int());
+    let alg = key.get_int(3).and_then(|v| v.as_int());
+    let crv = key.get_int(-1).and_then(|v| v.as_int());
+
+    if kty != Some(COSE_KTY_OKP) || alg != Some(COSE_ALG_EDDSA) || crv != Some(COSE_CRV_ED25519) {
+        return Err("Unsupported credential public key algorithm, only EdDSA (Ed25519) supported".to_string());
+    }
+
+    match key.get_int(-2).and_then(|v| v.as_bytes()) {
+        Some(x) if x.len() == 32 => Ok(x.to_vec()),
+        _ => Err("Invalid Ed25519 public key".to_string())
+    }
+}
+
+// `ed25519::verify` slices its inputs and panics on short ones, both come from the client.
+fn verify_ed25519(message:&[u8], public_key:&[u8], signature:&[u8]) -> bool {
+    public_key.len() == 32 && signature.len() == 64 && ed25519::verify(message, public_key, signature)
+}
+
+/**
+ * Verify registration (attestation) response, returns new credential to be stored.
+ */
+pub fn verify_registration(conf:&WebauthnConf, challenge:&str,
+                           client_data_json:&[u8], attestation_object:&[u8]) -> Result<Credential, String> {
+
+    try!(check_client_data(conf, client_data_json, "webauthn.create", challenge));
+
+    let (att_obj, _) = try!(cbor::decode(attestation_object));
+
+    let fmt = att_obj.get("fmt").and_then(|v| v.as_text()).unwrap_or("");
+    let raw_auth_data = try!(att_obj.get("authData").and_then(|v| v.as_bytes())
+                                .ok_or("Missing authData".to_string()));
+
+    let auth_data = try!(parse_auth_data(raw_auth_data));
+    try!(check_auth_data(conf, &auth_data));
+
+    let public_key = match auth_data.public_key {
+        Some(ref key) if !auth_data.credential_id.is_empty() => try!(cose_to_ed25519(key)),
+        _ => return Err("Missing attested credential data".to_string())
+    };
+
+    match fmt {
+        "none" => (),
+        "packed" => {
+            // only self attestation accepted (no x5c), signed by the credential key itself.
+            let stmt = try!(att_obj.get("attStmt").ok_or("Missing attStmt".to_string()));
+            if stmt.get("x5c").is_some() {
+                return Err("Packed attestation with certificate is not supported".to_string());
+            }
+            if stmt.get("alg").and_then(|v| v.as_int()) != Some(COSE_ALG_EDDSA) {
+                return Err("Attestation algorithm mismatch".to_string());
+            }
+            let sig = try!(stmt.get("sig").and_then(|v| v.as_bytes()).ok_or("Missing attestation signature".to_string()));
+
+            let mut signed = raw_auth_data.to_vec();
+            signed.extend_from_slice(&sha256(client_data_json));
+
+            if !verify_ed25519(&signed, &public_key, sig) {
+                return Err("Invalid attestation signature".to_string());
+            }
+        },
+        other => return Err(format!("Unsupported attestation format: {}", other))
+    }
+
+    Ok(Credential {
+        id: b64url(&auth_data.credential_id),
+        public_key: b64url(&public_key),
+        sign_count: auth_data.sign_count,
+        created: utils::current_time_millis()
+    })
+}
+
+/**
+ * Verify assertion response against stored credential,
+ * returns new signature counter to be stored.
+ */
+pub fn verify_assertion(conf:&WebauthnConf, challenge:&str, cred:&Credential,
+                        client_data_json:&[u8], authenticator_data:&[u8], signature:&[u8]) -> Result<u32, String> {
+
+    try!(check_client_data(conf, client_data_json, "webauthn.get", challenge));
+
+    let auth_data = try!(parse_auth_data(authenticator_data));
+    try!(check_auth_data(conf, &auth_data));
+
+    let public_key = try!(cred.public_key.from_base64().map_err(|_| "Invalid stored public key".to_string()));
+
+    let mut signed = authenticator_data.to_vec();
+    signed.extend_from_slice(&sha256(client_data_json));
+
+    if !verify_ed25519(&signed, &public_key, signature) {
+        return Err("Invalid assertion signature".to_string());
+    }
+
+    // authenticator that doesn't support counter always send zero.
+    if (auth_data.sign_count != 0 || cred.sign_count != 0) && auth_data.sign_count <= cred.sign_count {
+        return Err("Signature counter did not increase, possible cloned authenticator".to_string());
+    }
+
+    Ok(auth_data.sign_count)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crypto::ed25519;
+    use serialize::base64::FromBase64;
+
+    use cbor::{self, Value};
+    use config::WebauthnConf;
+    use store::{Store, MemoryStore, Keyring};
+    use super::*;
+
+    // Software authenticator, acting like a FIDO2 security key so the
+    // ceremonies can be tested without hardware.
+    struct SoftAuthenticator {
+        rp_id: String,
+        origin: String,
+        credential_id: Vec<u8>,
+        secret: [u8; 64],
+        public: [u8; 32],
+        counter: u32
+    }
+
+    impl SoftAuthenticator {
+        fn new(rp_id:&str, origin:&str) -> SoftAuthenticator {
+            let seed = new_challenge().from_base64().unwrap();
+            let (secret, public) = ed25519::keypair(&seed);
+            SoftAuthenticator {
+                rp_id: rp_id.to_string(),
+                origin: origin.to_string(),
+                credential_id: new_challenge().from_base64().unwrap()[..16].to_vec(),
+                secret: secret,
+                public: public,
+                counter: 0
+            }
+        }
+
+        fn client_data(&self, typ:&str, challenge:&str) -> Vec<u8> {
+            format!(r#"{{"type":"{}","challenge":"{}","origin":"{}","crossOrigin":false}}"#,
+                typ, challenge, self.origin).into_bytes()
+        }
+
+        fn auth_data(&self, attested:bool) -> Vec<u8> {
+            let mut data = super::sha256(self.rp_id.as_bytes()).to_vec();
+            data.push(if attested { 0x41 } else { 0x01 });
+            for i in (0..4).rev() {
+                data.push((self.counter >> (i * 8)) as u8);
+            }
+            if attested {
+                data.extend_from_slice(&[0u8; 16]); // aaguid
+                data.push((self.credential_id.len() >> 8) as u8);
+                data.push(self.credential_id.len() as u8);
+                data.extend_from_slice(&self.credential_id);
+                data.extend(cbor::encode(&Value::Map(vec![
+                    (Value::Unsigned(1), Value::Unsigned(1)),
+                    (Value::Unsigned(3), Value::Negative(-8)),
+                    (Value::Negative(-1), Value::Unsigned(6)),
+                    (Value::Negative(-2), Value::Bytes(self.public.to_vec()))
+                ])));
+            }
+            data
+        }
+
+        fn sign(&self, auth_data:&[u8], client_data:&[u8]) -> Vec<u8> {
+            let mut signed = auth_data.to_vec();
+            signed.extend_from_slice(&super::sha256(client_data));
+            ed25519::signature(&signed, &self.secret).to_vec()
+        }
+
+        // returns (clientDataJSON, attestationObject)
+        fn make_credential(&self, challenge:&str, fmt:&str) -> (Vec<u8>, Vec<u8>) {
+            let client_data = self.client_data("webauthn.create", challenge);
+            let auth_data = self.auth_data(true);
+            let att_stmt = if fmt == "packed" {
+                Value::Map(vec![
+                    (Value::Text("alg".to_string()), Value::Negative(-8)),
+                    (Value::Text("sig".to_string()), Value::Bytes(self.sign(&auth_data, &client_data)))
+                ])
+            }else{
+                Value::Map(vec![])
+            };
+            let att_obj = Value::Map(vec![
+                (Value::Text("fmt".to_string()), Value::Text(fmt.to_string())),
+                (Value::Text("attStmt".to_string()), att_stmt),
+                (Value::Text("authData".to_string()), Value::Bytes(auth_data))
+            ]);
+            (client_data, cbor::encode(&att_obj))
+        }
+
+        // returns (clientDataJSON, authenticatorData, signature)
+        fn get_assertion(&mut self, challenge:&str) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+            self.counter += 1;
+            let client_data = self.client_data("webauthn.get", challenge);
+            let auth_data = self.auth_data(false);
+            let sig = self.sign(&auth_data, &client_data);
+            (client_data, auth_data, sig)
+        }
+    }
+
+    fn conf() -> WebauthnConf {
+        WebauthnConf {
+            rp_id: "sso.example.com".to_string(),
+            rp_name: "SSO".to_string(),
+            origin: "https://sso.example.com".to_string()
+        }
+    }
+
+    fn register(auth:&SoftAuthenticator) -> Credential {
+        let challenge = new_challenge();
+        let (client_data, att_obj) = auth.make_credential(&challenge, "none");
+        verify_registration(&conf(), &challenge, &client_data, &att_obj).unwrap()
+    }
+
+    #[test]
+    fn registration_none_attestation() {
+        let auth = SoftAuthenticator::new("sso.example.com", "https://sso.example.com");
+        let cred = register(&auth);
+        assert_eq!(cred.id, b64url(&auth.credential_id));
+        assert_eq!(cred.public_key, b64url(&auth.public));
+    }
+
+    #[test]
+    fn registration_packed_self_attestation() {
+        let auth = SoftAuthenticator::new("sso.example.com", "https://sso.example.com");
+        let challenge = new_challenge();
+        let (client_data, att_obj) = auth.make_credential(&challenge, "packed");
+        assert!(verify_registration(&conf(), &challenge, &client_data, &att_obj).is_ok());
+    }
+
+    #[test]
+    fn registration_rejects_wrong_challenge_and_origin() {
+        let auth = SoftAuthenticator::new("sso.example.com", "https://sso.example.com");
+        let (client_data, att_obj) = auth.make_credential(&new_challenge(), "none");
+        assert!(verify_registration(&conf(), &new_challenge(), &client_data, &att_obj).is_err());
+
+        let evil = SoftAuthenticator::new("sso.example.com", "https://evil.example.net");
+        let challenge = new_challenge();
+        let (client_data, att_obj) = evil.make_credential(&challenge, "none");
+        assert!(verify_registration(&conf(), &challenge, &client_data, &att_obj).is_err());
+    }
+
+    #[test]
+    fn registration_rejects_wrong_rp_id() {
+        let auth = SoftAuthenticator::new("other.example.com", "https://sso.example.com");
+        let challenge = new_challenge();
+        let (client_data, att_obj) = auth.make_credential(&challenge, "none");
+        assert!(verify_registration(&conf(), &challenge, &client_data, &att_obj).is_err());
+    }
+
+    #[test]
+    fn challenge_taken_once_for_its_kind() {
+        let store = Store::with_backend(Box::new(MemoryStore::new()));
+        let pending = PendingChallenge::new(ChallengeKind::Registration, "robin", "dc=example,dc=com", "");
+
+        let id = put_challenge(&store, &pending).unwrap();
+        assert!(take_challenge(&store, &id, ChallengeKind::Login).unwrap().is_none());
+        // consumed by the wrong ceremony too
+        assert!(take_challenge(&store, &id, ChallengeKind::Registration).unwrap().is_none());
+
+        let id = put_challenge(&store, &pending).unwrap();
+        assert_eq!(take_challenge(&store, &id, ChallengeKind::Registration).unwrap().unwrap().uid, "robin");
+        assert!(take_challenge(&store, &id, ChallengeKind::Registration).unwrap().is_none());
+    }
+
+    #[test]
+    fn expired_challenges_purged() {
+        let store = Store::with_backend(Box::new(MemoryStore::new()))
+            .with_keyring(Some(Keyring::parse("k1:AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=").unwrap()));
+        let mut pending = PendingChallenge::new(ChallengeKind::Login, "robin", "dc=example,dc=com", "");
+
+        let fresh = put_challenge(&store, &pending).unwrap();
+        pending.created -= CHALLENGE_TIMEOUT_MILLIS + 1;
+        put_challenge(&store, &pending).unwrap();
+
+        assert_eq!(purge_challenges(&store).unwrap(), 1);
+        assert_eq!(purge_challenges(&store).unwrap(), 0);
+        assert!(take_challenge(&store, &fresh, ChallengeKind::Login).unwrap().is_some());
+    }
+
+    #[test]
+    fn cbor_rejects_oversized_length() {
+        // byte string claiming u64::MAX bytes, then array of them
+        let data = [0x5b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+        assert!(cbor::decode(&data).is_err());
+        assert!(cbor::decode(&[&[0x81][..], &data[..]].concat()).is_err());
+        assert!(cbor::decode(&[0x7b, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, b'a']).is_err());
+    }
+
+    #[test]
+    fn assertion_ok_and_counter_updated() {
+        let mut auth = SoftAuthenticator::new("sso.example.com", "https://sso.example.com");
+        let mut cred = register(&auth);
+
+        for expected in 1..4 {
+            let challenge = new_challenge();
+            let (client_data, auth_data, sig) = auth.get_assertion(&challenge);
+            let count = verify_assertion(&conf(), &challenge, &cred, &client_data, &auth_data, &sig).unwrap();
+            assert_eq!(count, expected);
+            cred.sign_count = count;
+        }
+    }
+
+    #[test]
+    fn assertion_rejects_tampered_signature() {
+        let mut auth = SoftAuthenticator::new("sso.example.com", "https://sso.example.com");
+        let cred = register(&auth);
+        let challenge = new_challenge();
+        let (client_data, auth_data, mut sig) = auth.get_assertion(&challenge);
+        sig[0] ^= 0xff;
+        assert!(verify_assertion(&conf(), &challenge, &cred, &client_data, &auth_data, &sig).is_err());
+    }
+
+    #[test]
+    fn assertion_rejects_truncated_signature() {
+        let mut auth = SoftAuthenticator::new("sso.example.com", "https://sso.example.com");
+        let mut cred = register(&auth);
+        let challenge = new_challenge();
+        let (client_data, auth_data, sig) = auth.get_assertion(&challenge);
+        for len in &[0, 10, 32, 63] {
+            assert!(verify_assertion(&conf(), &challenge, &cred, &client_data, &auth_data, &sig[..*len]).is_err());
+        }
+
+        cred.public_key = b64url(&auth.public[..16]);
+        assert!(verify_assertion(&conf(), &challenge, &cred, &client_data, &auth_data, &sig).is_err());
+    }
+
+    #[test]
+    fn assertion_rejects_other_authenticator() {
+        let auth = SoftAuthenticator::new("sso.example.com", "https://sso.example.com");
+        let cred = register(&auth);
+        let mut other = SoftAuthenticator::new("sso.example.com", "https://sso.example.com");
+        let challenge = new_challenge();
+        let (client_data, auth_data, sig) = other.get_assertion(&challenge);
+        assert!(verify_assertion(&conf(), &challenge, &cred, &client_data, &auth_data, &sig).is_err());
+    }
+
+    #[test]
+    fn assertion_rejects_replayed_counter() {
+        let mut auth = SoftAuthenticator::new("sso.example.com", "https://sso.example.com");
+        let mut cred = register(&auth);
+        let challenge = new_challenge();
+        let (client_data, auth_data, sig) = auth.get_assertion(&challenge);
+        cred.sign_count = verify_assertion(&conf(), &challenge, &cred, &client_data, &auth_data, &sig).unwrap();
+
+        // same response again
+        assert!(verify_assertion(&conf(), &challenge, &cred, &client_data, &auth_data, &sig).is_err());
+    }
+
+    #[test]
+    fn assertion_rejects_registration_client_data() {
+        let mut auth = SoftAuthenticator::new("sso.example.com", "https://sso.example.com");
+        let cred = register(&auth);
+        let challenge = new_challenge();
+        let (_, auth_data, _) = auth.get_assertion(&challenge);
+        let client_data = auth.client_data("webauthn.create", &challenge);
+        let sig = auth.sign(&auth_data, &client_data);
+        assert!(verify_assertion(&conf(), &challenge, &cred, &client_data, &auth_data, &sig).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+
+use serialize::base64::{FromBase64};
+use nickel::{Nickel, HttpRouter, QueryString};
+use std::io::Read;
+use mustache::{MapBuilder};
+use nickel_mustache::Render;
+
+// module
+use Context;
+use webauthn;
+use token;
+use store::{Namespace, TokenRecord};
+use build;
+use utils;
+
+
+macro_rules! show_register_page{
+    ($conf:ident, $_resp:ident, $builder:expr) => {{
+        let data = $builder
+            .insert_str("login_caption", $conf.login_caption.clone())
+            .insert_str("version", build::VERSION.to_string())
+            .build();
+
+        return Render::render_data($_resp, "tmpl/webauthn_register.html", &data);
+    }}
+}
+
+macro_rules! show_register_error{
+    ($error:expr, $conf:ident, $_resp:ident) => {{
+        show_register_page!($conf, $_resp, MapBuilder::new()
+            .insert_bool("error", true)
+            .insert_str("error_desc", $error.to_string()))
+    }}
+}
+
+
+macro_rules! try_store{
+    ($result:expr, $conf:ident, $_resp:ident) => {{
+        match $result {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Store error: {}", e);
+                show_register_error!("Internal server error. Silahkan coba beberapa saat lagi.", $conf, $_resp)
+            }
+        }
+    }}
+}
+
+
+// Security key registration, user need to login first (has valid access token).
+pub fn setup(ctx:&Context, server: &mut Nickel){
+
+    if !ctx.conf.webauthn.enabled() {
+        info!("WebAuthn not configured, security key registration disabled.");
+        return;
+    }
+
+    {
+        let store = ctx.store.clone();
+        let live = ctx.live.clone();
+
+        server.get("/webauthn/register", middleware! { |_req, mut _resp|
+            let settings = live.current();
+            let conf = &settings.conf;
+            let query = _req.query();
+            let access_token = query.get("access_token").unwrap_or("");
+
+            if !token::is_well_formed(access_token) {
+                warn!("Malformed access token: {}", token::redact(access_token));
+                show_register_error!("Sesi tidak valid, silahkan login terlebih dahulu.", conf, _resp)
+            }
+
+            let store = store.lock().unwrap();
+
+            let (uid, dn) = match try_store!(store.get_record::<TokenRecord>(Namespace::Token, access_token), conf, _resp) {
+                Some(TokenRecord { uid, dn }) => (uid, dn),
+                _ => {
+                    warn!("Invalid access token or already expired: {}", token::redact(access_token));
+                    show_register_error!("Sesi tidak valid, silahkan login terlebih dahulu.", conf, _resp)
+                }
+            };
+
+            let creds = try_store!(webauthn::load_credentials(&store, &uid), conf, _resp);
+            let pending = webauthn::PendingChallenge::new(webauthn::ChallengeKind::Registration, &uid, &dn, "");
+            let pending_id = try_store!(webauthn::put_challenge(&store, &pending), conf, _resp);
+
+            show_register_page!(conf, _resp, MapBuilder::new()
+                .insert_bool("register", true)
+                .insert_str("uid", uid)
+                .insert_str("key_count", creds.len().to_string())
+                .insert_str("pending_id", pending_id)
+                .insert_str("options", webauthn::creation_options(&conf.webauthn, &pending, &creds)))
+        });
+    }
+
+    {
+        let store = ctx.store.clone();
+        let live = ctx.live.clone();
+
+        server.post("/webauthn/register", middleware! { |_req, mut _resp|
+            let settings = live.current();
+            let conf = &settings.conf;
+
+            let mut body = String::new();
+            _req.origin.read_to_string(&mut body).unwrap();
+
+            let form = utils::parse_form(&body);
+            let field = |name:&str| form.get(name).map(|v| v.as_str()).unwrap_or("");
+
+            let store = store.lock().unwrap();
+
+            let pending = match try_store!(webauthn::take_challenge(&store, field("pending_id"), webauthn::ChallengeKind::Registration), conf, _resp) {
+                Some(p) => p,
+                None => show_register_error!("Sesi pendaftaran telah kedaluwarsa, silahkan ulangi.", conf, _resp)
+            };
+
+            let decode = |name:&str| field(name).from_base64().unwrap_or(Vec::new());
+
+            match webauthn::verify_registration(&conf.webauthn, &pending.challenge,
+                    &decode("client_data_json"), &decode("attestation_object")) {
+                Ok(cred) => {
+                    let mut creds = try_store!(webauthn::load_credentials(&store, &pending.uid), conf, _resp);
+
+                    if creds.iter().any(|c| c.id == cred.id) {
+                        show_register_error!("Kunci keamanan sudah terdaftar.", conf, _resp);
+                    }
+
+                    info!("Security key registered for `{}`: {}", pending.uid, cred.id);
+
+                    creds.push(cred);
+                    try_store!(webauthn::save_credentials(&store, &pending.uid, &creds), conf, _resp);
+
+                    show_register_page!(conf, _resp, MapBuilder::new()
+                        .insert_bool("registered", true)
+                        .insert_str("uid", pending.uid.clone()))
+                },
+                Err(e) => {
+                    warn!("WebAuthn registration failed for `{}`: {}", pending.uid, e);
+                    show_register_error!("Pendaftaran kunci keamanan gagal.", conf, _resp)
+                }
+            }
+        });
+    }
+}
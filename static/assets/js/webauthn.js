@@ -0,0 +1,43 @@
+// base64url <-> ArrayBuffer helpers for WebAuthn ceremonies.
+var WebAuthn = (function() {
+
+    function encode(buffer) {
+        var bytes = new Uint8Array(buffer);
+        var str = '';
+        for (var i = 0; i < bytes.length; i++) {
+            str += String.fromCharCode(bytes[i]);
+        }
+        return btoa(str).replace(/\+/g, '-').replace(/\//g, '_').replace(/=+$/, '');
+    }
+
+    function decode(value) {
+        var str = atob(value.replace(/-/g, '+').replace(/_/g, '/'));
+        var bytes = new Uint8Array(str.length);
+        for (var i = 0; i < str.length; i++) {
+            bytes[i] = str.charCodeAt(i);
+        }
+        return bytes.buffer;
+    }
+
+    function decodeCredentials(list) {
+        return (list || []).map(function(c) {
+            return { type: c.type, id: decode(c.id) };
+        });
+    }
+
+    return {
+        encode: encode,
+        decode: decode,
+        decodeCreationOptions: function(options) {
+            options.challenge = decode(options.challenge);
+            options.user.id = decode(options.user.id);
+            options.excludeCredentials = decodeCredentials(options.excludeCredentials);
+            return options;
+        },
+        decodeRequestOptions: function(options) {
+            options.challenge = decode(options.challenge);
+            options.allowCredentials = decodeCredentials(options.allowCredentials);
+            return options;
+        }
+    };
+})();